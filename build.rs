@@ -0,0 +1,60 @@
+//! web/ 以下のファイルの SHA-256 を計算し、ビルド時点の Web アセットのハッシュ表を生成する。
+//!
+//! プラグインは起動時にこの表と WEB_ROOT の中身を突き合わせ、
+//! 中途半端に更新された Web UI を検出する。
+//! あわせて、WEB_ROOT に無いときのフォールバック用にファイルの中身も埋め込む。
+
+use sha2::{Digest, Sha256};
+use std::{
+    env,
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+};
+
+fn collect_files(dir: &Path, out: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_files(&path, out);
+        } else {
+            out.push(path);
+        }
+    }
+}
+
+fn main() {
+    let web_dir = Path::new("web");
+    println!("cargo:rerun-if-changed=web");
+
+    let mut files = Vec::new();
+    collect_files(web_dir, &mut files);
+    files.sort();
+
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let mut manifest = String::from("const ASSET_MANIFEST: &[(&str, &str)] = &[\n");
+    let mut embedded = String::from("const EMBEDDED_ASSETS: &[(&str, &[u8])] = &[\n");
+    for path in files {
+        let data = fs::read(&path).expect("failed to read web asset");
+        let hash = Sha256::digest(&data);
+        let hex: String = hash.iter().map(|b| format!("{b:02x}")).collect();
+
+        let rel = path
+            .strip_prefix(web_dir)
+            .unwrap()
+            .to_string_lossy()
+            .replace('\\', "/");
+        writeln!(manifest, "    ({rel:?}, {hex:?}),").unwrap();
+        let abs = manifest_dir.join(&path);
+        writeln!(embedded, "    ({rel:?}, include_bytes!({:?})),", abs.to_string_lossy()).unwrap();
+    }
+    manifest.push_str("];\n");
+    embedded.push_str("];\n");
+    manifest.push_str(&embedded);
+
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("asset_manifest.rs");
+    fs::write(out, manifest).expect("failed to write asset manifest");
+}
@@ -0,0 +1,69 @@
+//! AviUtl2 を使わずに、任意の画像で Web UI を動かす単体サーバー。
+//!
+//! 使い方: `sam_frame_export_server <画像ファイル> [--out <保存先フォルダ>] [--no-browser] [--sandbox]`
+//!
+//! `--sandbox` を付けると書き込みをすべて一時フォルダに向け、ブラウザも開かない（`--out` は無視）。
+
+use anyhow::Context;
+use sam_frame_export_filter::{config, frame, server, state};
+use std::{path::PathBuf, process::ExitCode, thread};
+
+/// 単体起動時にマスクを紐づけるオブジェクト ID（AviUtl2 のオブジェクトは無いので固定）
+const STANDALONE_OBJECT_ID: i64 = 0;
+
+const USAGE: &str = "usage: sam_frame_export_server <image> [--out <dir>] [--no-browser] [--sandbox]";
+
+fn main() -> anyhow::Result<ExitCode> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let mut image_path = None;
+    let mut out_dir = None;
+    let mut open_browser = true;
+    let mut sandbox = false;
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--out") => {
+                out_dir = Some(PathBuf::from(args.next().context("--out needs a directory")?));
+            }
+            Some("--no-browser") => open_browser = false,
+            Some("--sandbox") => sandbox = true,
+            Some("-h" | "--help") => {
+                println!("{USAGE}");
+                return Ok(ExitCode::SUCCESS);
+            }
+            _ => image_path = Some(PathBuf::from(arg)),
+        }
+    }
+    let Some(image_path) = image_path else {
+        eprintln!("{USAGE}");
+        return Ok(ExitCode::from(2));
+    };
+
+    if sandbox && let Some(dir) = config::enable_sandbox() {
+        println!("Sandbox: {}", dir.display());
+    }
+    config::init_config_once();
+    if let Some(dir) = out_dir {
+        state::set_export_root(dir);
+    }
+
+    let img = image::open(&image_path)
+        .with_context(|| format!("failed to open {}", image_path.display()))?
+        .to_rgba8();
+    state::set_edit_object(STANDALONE_OBJECT_ID);
+    let frame_path = frame::set_current_frame(&img)?;
+    log::info!("Frame saved to {}", frame_path.display());
+
+    server::start_http_server_once()?;
+    let url = server::server_url().context("failed to start the HTTP server")?;
+    println!("Serving {} at {}", image_path.display(), url);
+    if open_browser {
+        server::open_browser_if_needed();
+    }
+
+    // サーバーは別スレッドで動くので、Ctrl+C で終了するまで待つ
+    loop {
+        thread::park();
+    }
+}
@@ -0,0 +1,136 @@
+//! フレームやマスクなどのバイト列をメモリに置いておくキャッシュ。
+//!
+//! AviUtl2 のプロセス内で動くので、合計サイズが予算（既定 256 MB、config.toml の
+//! `memory_budget_mb` で変更）を超えたら最後に使われてから最も時間が経ったものから捨てる。
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+    time::SystemTime,
+};
+
+/// 既定のメモリ予算（MB）
+pub(crate) const DEFAULT_MEMORY_BUDGET_MB: u64 = 256;
+
+struct CacheEntry {
+    data: Arc<[u8]>,
+    /// 元ファイルの更新日時など、内容が古くなっていないかの目印
+    stamp: Option<SystemTime>,
+    /// 最後に使われた順番（大きいほど新しい）
+    last_used: u64,
+}
+
+/// LRU で追い出すバイト列のキャッシュ
+pub(crate) struct MemoryCache {
+    entries: HashMap<String, CacheEntry>,
+    budget: usize,
+    used: usize,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+impl MemoryCache {
+    fn new(budget: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            budget,
+            used: 0,
+            tick: 0,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    /// key の内容を返す。stamp が保存時と違えば古いものとして捨てる。
+    pub(crate) fn get(&mut self, key: &str, stamp: Option<SystemTime>) -> Option<Arc<[u8]>> {
+        self.tick += 1;
+        match self.entries.get_mut(key) {
+            Some(entry) if entry.stamp == stamp => {
+                entry.last_used = self.tick;
+                self.hits += 1;
+                Some(entry.data.clone())
+            }
+            Some(_) => {
+                self.remove(key);
+                self.misses += 1;
+                None
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// key に data を入れる。予算を超える大きさのものは入れない。
+    pub(crate) fn insert(&mut self, key: &str, stamp: Option<SystemTime>, data: Arc<[u8]>) {
+        self.remove(key);
+        if data.len() > self.budget {
+            return;
+        }
+
+        self.tick += 1;
+        self.used += data.len();
+        self.entries.insert(
+            key.to_string(),
+            CacheEntry {
+                data,
+                stamp,
+                last_used: self.tick,
+            },
+        );
+        self.evict_to_budget();
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.used -= entry.data.len();
+        }
+    }
+
+    /// 予算を変更する（小さくしたときは超えた分をすぐ追い出す）。
+    pub(crate) fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+        self.evict_to_budget();
+    }
+
+    fn evict_to_budget(&mut self) {
+        while self.used > self.budget {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            self.remove(&oldest);
+            self.evictions += 1;
+        }
+    }
+
+    /// /api/stats 用の使用状況
+    pub(crate) fn stats_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "budget_bytes": self.budget,
+            "used_bytes": self.used,
+            "entries": self.entries.len(),
+            "hits": self.hits,
+            "misses": self.misses,
+            "evictions": self.evictions,
+        })
+    }
+}
+
+/// プロセス全体で共有するキャッシュ
+pub(crate) fn memory_cache() -> &'static Mutex<MemoryCache> {
+    static CACHE: OnceLock<Mutex<MemoryCache>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(MemoryCache::new(mb_to_bytes(DEFAULT_MEMORY_BUDGET_MB))))
+}
+
+pub(crate) fn mb_to_bytes(mb: u64) -> usize {
+    usize::try_from(mb.saturating_mul(1024 * 1024)).unwrap_or(usize::MAX)
+}
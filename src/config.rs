@@ -0,0 +1,450 @@
+//! 設定ファイル（`config.toml`）の読み込みと監視。
+//!
+//! ファイルが無ければ既定値のまま動く。AviUtl2 の起動中に書き換えられたら読み直し、
+//! その場で反映できる項目（保存先・ffmpeg）は反映し、ポートのように再起動が要る項目はログで知らせる。
+//! フィルタで選んだ保存先は export_root に書き戻し、AviUtl2 を再起動しても同じ場所を使う。
+//!
+//! サンドボックスモード（`SAM_SANDBOX=1` か `sandbox = true`）では書き込みをすべて一時フォルダに向け、
+//! ブラウザも開かない。動作確認や結合テストで ProgramData を汚さないためのもの。
+
+use crate::{
+    cache::{DEFAULT_MEMORY_BUDGET_MB, mb_to_bytes, memory_cache},
+    state::{ffmpeg_path, lock_recover, set_export_root},
+};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fs::{create_dir_all, read_to_string},
+    path::{Path, PathBuf},
+    sync::{Mutex, Once, OnceLock},
+};
+
+/// %PROGRAMDATA% が取れないときの既定値
+const DEFAULT_PROGRAM_DATA: &str = r"C:\ProgramData";
+
+/// AviUtl2 のデータフォルダ（`Plugin` や `Export` の親）。
+///
+/// プラグインの DLL が `...\Plugin\` の下にあればその親、無ければ `%PROGRAMDATA%\aviutl2`。
+/// 別ドライブやポータブル構成でも DLL と同じ場所を使う。
+pub(crate) fn aviutl2_dir() -> &'static Path {
+    static DIR: OnceLock<PathBuf> = OnceLock::new();
+    DIR.get_or_init(|| {
+        plugin_module_path()
+            .and_then(|dll| {
+                dll.ancestors()
+                    .find(|dir| {
+                        dir.file_name()
+                            .is_some_and(|name| name.eq_ignore_ascii_case("Plugin"))
+                    })
+                    .and_then(Path::parent)
+                    .map(Path::to_path_buf)
+            })
+            .unwrap_or_else(|| {
+                env_path("PROGRAMDATA")
+                    .unwrap_or_else(|| PathBuf::from(DEFAULT_PROGRAM_DATA))
+                    .join("aviutl2")
+            })
+    })
+}
+
+/// このプラグイン用のフォルダ（設定ファイル・Web UI・モデルなどを置く）
+pub(crate) fn plugin_data_dir() -> PathBuf {
+    aviutl2_dir().join("Plugin").join("sam_frame_export_filter")
+}
+
+/// 設定ファイルの場所
+pub(crate) fn config_file() -> &'static Path {
+    static PATH: OnceLock<PathBuf> = OnceLock::new();
+    PATH.get_or_init(|| plugin_data_dir().join("config.toml"))
+}
+
+/// このコードを含む DLL（スタンドアロンのサーバーなら exe）のパス。
+#[cfg(windows)]
+fn plugin_module_path() -> Option<PathBuf> {
+    use std::{
+        ffi::{OsString, c_void},
+        os::windows::ffi::OsStringExt,
+    };
+
+    const GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS: u32 = 0x4;
+    const GET_MODULE_HANDLE_EX_FLAG_UNCHANGED_REFCOUNT: u32 = 0x2;
+
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn GetModuleHandleExW(flags: u32, address: *const u16, module: *mut *mut c_void) -> i32;
+        fn GetModuleFileNameW(module: *mut c_void, filename: *mut u16, size: u32) -> u32;
+    }
+
+    // この関数のアドレスから、読み込まれているモジュール（= 自分の DLL）を引く
+    let mut module = std::ptr::null_mut();
+    let mut buf = vec![0u16; 32 * 1024];
+    // SAFETY: address はこのモジュール内の関数、module と buf は呼び出しの間有効
+    let len = unsafe {
+        if GetModuleHandleExW(
+            GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS | GET_MODULE_HANDLE_EX_FLAG_UNCHANGED_REFCOUNT,
+            plugin_module_path as *const u16,
+            &mut module,
+        ) == 0
+        {
+            return None;
+        }
+        GetModuleFileNameW(module, buf.as_mut_ptr(), buf.len() as u32)
+    };
+    (len > 0).then(|| PathBuf::from(OsString::from_wide(&buf[..len as usize])))
+}
+
+#[cfg(not(windows))]
+fn plugin_module_path() -> Option<PathBuf> {
+    None
+}
+
+/// config.toml の内容。書かれていない項目は None（既定値を使う）。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    /// HTTP サーバーが最初に試すポート（変更は再起動後に反映）
+    pub port: Option<u16>,
+    /// フレームやマスクの保存先ルート
+    pub export_root: Option<PathBuf>,
+    /// 連番マスクの動画化に使う ffmpeg
+    pub ffmpeg: Option<PathBuf>,
+    /// true ならサンドボックスモードで起動する（変更は再起動後に反映）
+    pub sandbox: Option<bool>,
+    /// フレームやマスクをメモリに置いておく上限（MB）
+    pub memory_budget_mb: Option<u64>,
+    /// ネイティブ推論（native-sam）に使う SAM のエンコーダ・デコーダ（ONNX）
+    pub sam_encoder: Option<PathBuf>,
+    pub sam_decoder: Option<PathBuf>,
+    /// ネイティブ推論に使う onnxruntime.dll（変更は再起動後に反映）
+    pub onnxruntime: Option<PathBuf>,
+    /// 受け付けるリクエストボディの上限（MB）
+    pub max_body_mb: Option<u64>,
+    /// リクエストの読み取りが止まってから諦めるまでの秒数
+    pub read_timeout_secs: Option<u64>,
+    /// true なら起動ごとに作るアクセストークンを持たないリクエストを 401 で断る
+    pub require_token: Option<bool>,
+    /// HTTP サーバーが待ち受けるアドレス（既定は 127.0.0.1。変更は再起動後に反映）
+    pub bind: Option<String>,
+    /// true なら自己署名証明書で HTTPS でも待ち受ける（変更は再起動後に反映）
+    pub https: Option<bool>,
+    /// HTTPS のポート（無ければ HTTP のポートの次から空いているもの）
+    pub https_port: Option<u16>,
+    /// 静的ファイルの拡張子と Content-Type の対応の追加・上書き（`[mime_types]` の表）
+    pub mime_types: Option<HashMap<String, String>>,
+    /// 別オリジンから API を呼んでよい Origin の一覧（無ければすべて許可）
+    pub cors_origins: Option<Vec<String>>,
+    /// true なら HTTP のリクエストを 1 行ずつ logs/access.log に残す
+    pub access_log: Option<bool>,
+    /// ログのレベル（"info" や "debug"、env_logger の書式）。リリース版はこれか SAM_LOG があるときだけログを残す
+    pub log_level: Option<String>,
+    /// Log フォルダに残す動作ログのファイル数の上限（起動時に古いものから消す）
+    pub log_max_files: Option<usize>,
+    /// Log フォルダに残す動作ログの合計サイズの上限（MB）
+    pub log_max_total_mb: Option<u64>,
+}
+
+/// 環境変数による上書き（config.toml や既定値より優先する）
+pub(crate) const ENV_PORT: &str = "SAM_EXPORT_PORT";
+pub(crate) const ENV_EXPORT_ROOT: &str = "SAM_EXPORT_ROOT";
+pub(crate) const ENV_WEB_ROOT: &str = "SAM_WEB_ROOT";
+pub(crate) const ENV_SANDBOX: &str = "SAM_SANDBOX";
+pub(crate) const ENV_LOG: &str = "SAM_LOG";
+
+/// パスを指す環境変数を読む（未設定・空なら None）。
+pub(crate) fn env_path(name: &str) -> Option<PathBuf> {
+    std::env::var_os(name)
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+}
+
+/// SAM_EXPORT_PORT を読む（数値でなければ警告して無視する）。
+pub(crate) fn env_port() -> Option<u16> {
+    let value = std::env::var(ENV_PORT).ok().filter(|v| !v.trim().is_empty())?;
+    match value.trim().parse() {
+        Ok(port) => Some(port),
+        Err(e) => {
+            log::warn!("Ignoring {}={:?}: {e}", ENV_PORT, value);
+            None
+        }
+    }
+}
+
+/// 真偽値の環境変数を読む（1 / true / yes / on なら true）。
+fn env_flag(name: &str) -> bool {
+    std::env::var(name).is_ok_and(|v| {
+        matches!(
+            v.trim().to_ascii_lowercase().as_str(),
+            "1" | "true" | "yes" | "on"
+        )
+    })
+}
+
+fn sandbox_cell() -> &'static OnceLock<Option<PathBuf>> {
+    static SANDBOX: OnceLock<Option<PathBuf>> = OnceLock::new();
+    &SANDBOX
+}
+
+/// サンドボックスモードの書き込み先（無効なら None）。
+///
+/// 最初に呼ばれた時点で決まり、以降は変わらない。init_config_once より前に呼ばれた場合は
+/// 環境変数だけで判定する。
+pub fn sandbox_dir() -> Option<&'static Path> {
+    sandbox_cell()
+        .get_or_init(|| env_flag(ENV_SANDBOX).then(create_sandbox_dir).flatten())
+        .as_deref()
+}
+
+/// サンドボックスモードを有効にする（init_config_once より前に呼ぶこと）。
+pub fn enable_sandbox() -> Option<&'static Path> {
+    sandbox_cell().get_or_init(create_sandbox_dir).as_deref()
+}
+
+/// プロセスごとの一時フォルダを作る。
+fn create_sandbox_dir() -> Option<PathBuf> {
+    let dir = std::env::temp_dir().join(format!(
+        "sam_frame_export_sandbox_{}",
+        std::process::id()
+    ));
+    match create_dir_all(&dir) {
+        Ok(()) => {
+            log::info!("Sandbox mode: writing to {}", dir.display());
+            Some(dir)
+        }
+        Err(e) => {
+            log::error!("Failed to create sandbox directory {}: {e}", dir.display());
+            None
+        }
+    }
+}
+
+/// 現在反映している設定ファイルの内容
+pub(crate) fn file_config() -> &'static Mutex<FileConfig> {
+    static CONFIG: OnceLock<Mutex<FileConfig>> = OnceLock::new();
+    CONFIG.get_or_init(|| Mutex::new(FileConfig::default()))
+}
+
+/// 設定ファイルを読み込んで反映し、以降の変更を監視する（1 度だけ）。
+pub fn init_config_once() {
+    static INIT: Once = Once::new();
+
+    INIT.call_once(|| {
+        let config = load_config_file();
+        // 保存先より先にサンドボックスかどうかを決める
+        if config.as_ref().is_some_and(|c| c.sandbox == Some(true)) {
+            enable_sandbox();
+        } else {
+            sandbox_dir();
+        }
+        if let Some(config) = config {
+            apply_config(config);
+        }
+        if let Err(e) = watch_config_file() {
+            log::warn!("Failed to watch {}: {e:?}", config_file().display());
+        }
+    });
+}
+
+/// 設定ファイルを読む。ファイルが無ければ既定値、壊れていれば None。
+fn load_config_file() -> Option<FileConfig> {
+    let text = match read_to_string(config_file()) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Some(FileConfig::default()),
+        Err(e) => {
+            log::warn!("Failed to read {}: {e}", config_file().display());
+            return None;
+        }
+    };
+
+    match toml::from_str(&text) {
+        Ok(config) => Some(config),
+        Err(e) => {
+            log::warn!("Ignoring invalid {}: {e}", config_file().display());
+            None
+        }
+    }
+}
+
+/// ログのレベルの指定（SAM_LOG > config.toml の log_level。無い・"off" なら None）。
+///
+/// ロガーは設定の読み込みより先に作るので、config.toml はここで別に読む。
+pub(crate) fn log_level_setting() -> Option<String> {
+    std::env::var(ENV_LOG)
+        .ok()
+        .or_else(|| load_config_file()?.log_level)
+        .map(|level| level.trim().to_string())
+        .filter(|level| !level.is_empty() && !level.eq_ignore_ascii_case("off"))
+}
+
+/// 動作ログを残すファイル数の既定の上限
+pub(crate) const DEFAULT_LOG_MAX_FILES: usize = 20;
+
+/// 動作ログを残す合計サイズの既定の上限（MB）
+pub(crate) const DEFAULT_LOG_MAX_TOTAL_MB: u64 = 100;
+
+/// 動作ログを残すファイル数と合計バイト数の上限（config.toml の log_max_files / log_max_total_mb）。
+pub(crate) fn log_retention_setting() -> (usize, u64) {
+    let config = load_config_file().unwrap_or_default();
+    (
+        config.log_max_files.unwrap_or(DEFAULT_LOG_MAX_FILES),
+        config
+            .log_max_total_mb
+            .unwrap_or(DEFAULT_LOG_MAX_TOTAL_MB)
+            .saturating_mul(1024 * 1024),
+    )
+}
+
+/// 前回の内容と比べ、変わった項目を反映する。
+fn apply_config(new: FileConfig) {
+    let mut current = lock_recover(file_config());
+    if *current == new {
+        return;
+    }
+
+    // 環境変数で指定されている項目は設定ファイルより優先する
+    if new.export_root != current.export_root
+        && env_path(ENV_EXPORT_ROOT).is_none()
+        && let Some(root) = &new.export_root
+    {
+        set_export_root(root.clone());
+    }
+    if new.ffmpeg != current.ffmpeg
+        && let Some(ffmpeg) = &new.ffmpeg
+    {
+        *lock_recover(ffmpeg_path()) = ffmpeg.clone();
+        log::info!("ffmpeg path changed to {}", ffmpeg.display());
+    }
+    if new.memory_budget_mb != current.memory_budget_mb {
+        let mb = new.memory_budget_mb.unwrap_or(DEFAULT_MEMORY_BUDGET_MB);
+        lock_recover(memory_cache()).set_budget(mb_to_bytes(mb));
+        log::info!("Memory budget changed to {} MB", mb);
+    }
+    if new.port != current.port
+        && env_port().is_none()
+        && crate::server::server_url().is_some()
+    {
+        log::warn!(
+            "{}: port changed to {:?}; restart AviUtl2 to apply",
+            config_file().display(),
+            new.port
+        );
+    }
+    if (new.https != current.https || new.https_port != current.https_port)
+        && crate::server::server_url().is_some()
+    {
+        log::warn!(
+            "{}: https changed to {:?} (port {:?}); restart AviUtl2 to apply",
+            config_file().display(),
+            new.https,
+            new.https_port
+        );
+    }
+    if new.log_level != current.log_level && crate::server::server_url().is_some() {
+        log::warn!(
+            "{}: log_level changed to {:?}; restart AviUtl2 to apply",
+            config_file().display(),
+            new.log_level
+        );
+    }
+    if new.sandbox != current.sandbox && crate::server::server_url().is_some() {
+        log::warn!(
+            "{}: sandbox changed to {:?}; restart AviUtl2 to apply",
+            config_file().display(),
+            new.sandbox
+        );
+    }
+    if new.onnxruntime != current.onnxruntime && crate::server::server_url().is_some() {
+        log::warn!(
+            "{}: onnxruntime changed to {:?}; restart AviUtl2 to apply",
+            config_file().display(),
+            new.onnxruntime
+        );
+    }
+    #[cfg(feature = "native-sam")]
+    let models_changed =
+        new.sam_encoder != current.sam_encoder || new.sam_decoder != current.sam_decoder;
+
+    *current = new;
+    drop(current);
+
+    // 読み込み済みのモデルは捨て、次の推論で新しいパスから読み直す
+    #[cfg(feature = "native-sam")]
+    if models_changed {
+        crate::sam::unload();
+    }
+}
+
+/// フィルタで選んだ保存先を config.toml の export_root に書き残す（次回の起動でも使う）。
+///
+/// 同じ値なら何もしない。環境変数やサンドボックスで保存先を決めているときは書かない。
+pub(crate) fn remember_export_root(dir: &Path) {
+    if sandbox_dir().is_some() || env_path(ENV_EXPORT_ROOT).is_some() {
+        return;
+    }
+    if lock_recover(file_config()).export_root.as_deref() == Some(dir) {
+        return;
+    }
+    let value = toml::Value::String(dir.display().to_string());
+    if let Err(e) = save_config_value("export_root", value) {
+        log::warn!("Failed to save export_root to {}: {e:?}", config_file().display());
+    }
+}
+
+/// config.toml の 1 項目を書き換えて保存する。
+///
+/// ほかの項目はそのまま残す（コメントと並び順は残らない）。読み込み済みの内容も先に更新し、
+/// 書き込みを監視で拾っても反映し直さないようにする。
+fn save_config_value(key: &str, value: toml::Value) -> anyhow::Result<()> {
+    let path = config_file();
+    let mut table: toml::Table = match read_to_string(path) {
+        Ok(text) => toml::from_str(&text)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => toml::Table::new(),
+        Err(e) => return Err(e.into()),
+    };
+    table.insert(key.to_string(), value);
+    let config: FileConfig = table.clone().try_into()?;
+
+    *lock_recover(file_config()) = config;
+    if let Some(dir) = path.parent() {
+        create_dir_all(dir)?;
+    }
+    std::fs::write(path, toml::to_string(&table)?)?;
+    log::info!("Saved {} to {}", key, path.display());
+    Ok(())
+}
+
+/// 設定ファイルのあるフォルダを監視し、config.toml が変わったら読み直す。
+fn watch_config_file() -> anyhow::Result<()> {
+    static WATCHER: OnceLock<Mutex<RecommendedWatcher>> = OnceLock::new();
+
+    let path = config_file();
+    let dir = path.parent().unwrap_or(Path::new("."));
+    // サンドボックスでは ProgramData にフォルダを作らない（無ければ監視しない）
+    if sandbox_dir().is_none() {
+        create_dir_all(dir)?;
+    }
+
+    // エディタによっては一時ファイルを経由して置き換えるので、フォルダごと見てファイル名で絞る
+    let mut watcher = notify::recommended_watcher(|res: notify::Result<notify::Event>| {
+        match res {
+            Ok(event) => {
+                let touched = event
+                    .paths
+                    .iter()
+                    .any(|p| p.file_name() == config_file().file_name());
+                if touched
+                    && !event.kind.is_access()
+                    && let Some(config) = load_config_file()
+                {
+                    apply_config(config);
+                }
+            }
+            Err(e) => log::warn!("Config watcher error: {e:?}"),
+        }
+    })?;
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    let _ = WATCHER.set(Mutex::new(watcher));
+
+    log::info!("Watching {} for changes", config_file().display());
+    Ok(())
+}
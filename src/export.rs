@@ -0,0 +1,1405 @@
+//! 受信したマスクの保存と後処理。
+//!
+//! マスク PNG のファイル名、サイドカーのマニフェスト（`{stem}.json`）、
+//! 輪郭線・影・サムネイル・差分などの派生画像をここで作る。
+
+#[cfg(feature = "ffmpeg")]
+pub(crate) mod video;
+
+use anyhow::Result as AnyResult;
+use chrono::{Datelike, Local, Timelike};
+use crate::{
+    cache::memory_cache,
+    frame::{
+        current_frame_path, frame_number_from_name, save_png, sequence_dir, sequence_frame_name,
+        sequence_mask_name, write_atomic,
+    },
+    state::{
+        AlphaCombine, HistoryKind, ObjectFrameInfo, SceneCrop, SceneFormat, ShadowSettings,
+        alpha_combine_for_object, export_dir_for_object, export_root_dir, exported_frame_for_object,
+        frame_file_for_object, last_frame_size, last_scene_format, lock_recover, mask_sequences, mask_settings, name_template_for_object,
+        record_history, scene_crop_for_object, sequential_names_for_object, session_tag_for_object,
+        set_mask_path_for_object,
+    },
+};
+use std::{
+    fs::{File, create_dir_all, read, write},
+    io::{BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+/// 保存ルート配下にユニークなマスク PNG ファイルパスを作成する。
+///
+/// オブジェクトのセッションタグと label があればファイル名に含める
+/// （`sam_mask_{tag}_{label}_YYYYMMDD_HHMMSS_mmm.png`）。
+/// 通し番号モードのオブジェクトは `mask_obj{ID}_{tag}_{label}_0001.png` にする。
+/// ファイル名テンプレートが設定されていれば、どちらよりもそちらを優先する。
+/// オブジェクトごとのフォルダに保存する設定なら `object_{ID}/` の下に作る。
+pub(crate) fn make_unique_mask_path(object_id: i64, label: Option<&str>) -> AnyResult<PathBuf> {
+    let root = lock_recover(export_root_dir()).clone();
+    let dir = export_dir_for_object(object_id);
+    create_dir_all(&dir)?;
+
+    let template = name_template_for_object(object_id)
+        .map(|template| render_name_template(&template, &root, object_id, label))
+        .filter(|name| !name.is_empty());
+    if let Some(base) = template {
+        return Ok(unique_png_path(&dir, &base));
+    }
+
+    let sequential = sequential_names_for_object(object_id);
+    let mut prefix = if sequential {
+        format!("mask_obj{object_id}_")
+    } else {
+        "sam_mask_".to_string()
+    };
+    for part in [session_tag_for_object(object_id).as_deref(), label]
+        .into_iter()
+        .flatten()
+    {
+        prefix.push_str(&sanitize_label(part));
+        prefix.push('_');
+    }
+
+    if sequential {
+        let index = next_mask_index(&dir, &prefix);
+        return Ok(dir.join(format!("{prefix}{index:04}.png")));
+    }
+
+    // sam_mask_YYYYMMDD_HHMMSS_mmm.png
+    let base = format!("{prefix}{}", timestamp_for_name(Local::now()));
+    Ok(unique_png_path(&dir, &base))
+}
+
+/// `YYYYMMDD_HHMMSS_mmm`
+fn timestamp_for_name(now: chrono::DateTime<Local>) -> String {
+    format!(
+        "{:04}{:02}{:02}_{:02}{:02}{:02}_{:03}",
+        now.year(),
+        now.month(),
+        now.day(),
+        now.hour(),
+        now.minute(),
+        now.second(),
+        now.timestamp_subsec_millis(),
+    )
+}
+
+/// `root/{base}.png`。同名ファイルがすでに存在していたら、_1, _2... を付けてずらす
+fn unique_png_path(root: &Path, base: &str) -> PathBuf {
+    let mut path = root.join(format!("{base}.png"));
+    let mut counter = 1;
+    while path.exists() {
+        path = root.join(format!("{base}_{counter}.png"));
+        counter += 1;
+    }
+    path
+}
+
+/// ファイル名テンプレートのプレースホルダを埋め、ファイル名に使えない文字を `_` にする（拡張子は付けない）。
+///
+/// - `{project}`: 保存先フォルダの名前（プロジェクトごとに保存先を分けている前提）
+/// - `{object_id}`: オブジェクト ID
+/// - `{frame}`: 最後に書き出したオブジェクト内のフレーム番号（6 桁）
+/// - `{timestamp}`: 保存した日時（`YYYYMMDD_HHMMSS_mmm`）
+/// - `{label}` / `{tag}`: マスクのラベルとセッションタグ
+///
+/// 値の無いプレースホルダは空になる。
+pub(crate) fn render_name_template(
+    template: &str,
+    root: &Path,
+    object_id: i64,
+    label: Option<&str>,
+) -> String {
+    let project = root
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let frame = exported_frame_for_object(object_id)
+        .map(|info| format!("{:06}", info.frame))
+        .unwrap_or_default();
+    let name = template
+        .replace("{project}", &project)
+        .replace("{object_id}", &object_id.to_string())
+        .replace("{frame}", &frame)
+        .replace("{timestamp}", &timestamp_for_name(Local::now()))
+        .replace("{label}", label.unwrap_or_default())
+        .replace("{tag}", &session_tag_for_object(object_id).unwrap_or_default());
+
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_control() || matches!(c, '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*') {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    // Windows は末尾のドットと空白を落とすので、最初から付けない
+    let name = name.trim().trim_end_matches('.');
+    name.strip_suffix(".png").unwrap_or(name).to_string()
+}
+
+/// `{prefix}NNNN.png` の通し番号の次の値。
+///
+/// 保存先にあるファイルから数えるので、AviUtl2 を再起動しても続きの番号になる。
+fn next_mask_index(root: &Path, prefix: &str) -> u32 {
+    let Ok(entries) = std::fs::read_dir(root) else {
+        return 1;
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            name.strip_prefix(prefix)?
+                .strip_suffix(".png")?
+                .parse::<u32>()
+                .ok()
+        })
+        .max()
+        .map_or(1, |last| last + 1)
+}
+
+/// ラベルをファイル名に使える文字（英数字・`-`・`_`・非 ASCII）だけにする。
+fn sanitize_label(label: &str) -> String {
+    label
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+// ── マスクの後処理 ─────────────────────────────────────────────────────
+
+/// マスクを合わせるフレーム（大きさと、色やアルファを取るファイル）
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MaskFrame {
+    pub(crate) size: Option<(u32, u32)>,
+    pub(crate) path: Option<PathBuf>,
+}
+
+impl MaskFrame {
+    /// object_id のマスクを合わせるフレーム。
+    ///
+    /// そのオブジェクトのために書き出したフレームだけを使い、別のオブジェクトのフレームには合わせない。
+    /// ファイルがほかのオブジェクトに上書きされていれば大きさだけを使う。
+    /// オブジェクトが分からないときは最後に書き出したフレームを使う。
+    pub(crate) fn for_object(object_id: Option<i64>) -> Self {
+        match object_id {
+            Some(object_id) => frame_file_for_object(object_id)
+                .map(|(size, path)| Self { size: Some(size), path })
+                .unwrap_or_default(),
+            None => Self {
+                size: *lock_recover(last_frame_size()),
+                path: current_frame_path().ok(),
+            },
+        }
+    }
+}
+
+/// マニフェストに残したマスクのオブジェクト ID
+pub(crate) fn mask_object_id(mask_path: &Path) -> Option<i64> {
+    let manifest: serde_json::Value = serde_json::from_slice(&read(mask_manifest_path(mask_path)).ok()?).ok()?;
+    manifest.get("object_id")?.as_i64()
+}
+
+/// 保存済みのマスク PNG に後処理をかけて上書きする。
+///
+/// - マスクのサイズが frame と違えば、スーパーサンプリングで拡縮する
+/// - alpha が Some なら、frame のファイルのアルファと合成する
+/// - 設定に応じて、輪郭線だけの画像を `{stem}_outline.png` として隣に保存する
+/// - 設定に応じて、ドロップシャドウ用の影を `{stem}_shadow.png` として隣に保存する
+/// - 背景部分の代表色を推定してマスクのマニフェストに記録する
+pub(crate) fn postprocess_saved_mask(
+    mask_path: &Path,
+    frame: &MaskFrame,
+    alpha: Option<AlphaCombine>,
+) -> AnyResult<()> {
+    let settings = lock_recover(mask_settings()).clone();
+
+    let mut mask = image::open(mask_path)?.to_rgba8();
+
+    if let Some((frame_w, frame_h)) = frame.size
+        && mask.dimensions() != (frame_w, frame_h)
+    {
+        log::info!(
+            "Resizing mask {} from {}x{} to {}x{} ({}x supersampling)",
+            mask_path.display(),
+            mask.width(),
+            mask.height(),
+            frame_w,
+            frame_h,
+            settings.supersample
+        );
+        mask = resize_mask_supersampled(&mask, frame_w, frame_h, settings.supersample);
+        save_png(&mask, mask_path)?;
+    }
+
+    if settings.invert {
+        log::info!("Inverting mask {}", mask_path.display());
+        // 反転で見えるようになる背景の色はフレームから取る
+        let frame = frame_image_for(&mask, frame);
+        invert_mask(&mut mask, frame.as_ref());
+        save_png(&mask, mask_path)?;
+        update_mask_manifest(mask_path, |manifest| {
+            manifest.insert("inverted".into(), true.into());
+        })?;
+    }
+
+    if let Some(combine) = alpha
+        && combine != AlphaCombine::Replace
+    {
+        match &frame.path {
+            Some(frame_path) => {
+                combine_with_frame_alpha(&mut mask, combine, frame_path)?;
+                save_png(&mask, mask_path)?;
+                update_mask_manifest(mask_path, |manifest| {
+                    manifest.insert("alpha_combine".into(), combine.as_str().into());
+                })?;
+            }
+            None => log::warn!(
+                "The frame of {} is no longer on disk; alpha combine skipped",
+                mask_path.display()
+            ),
+        }
+    }
+
+    if settings.grow != 0 {
+        log::info!("Growing mask {} by {}px", mask_path.display(), settings.grow);
+        // 膨張で新たに見える部分の色はフレームから取る
+        let frame = frame_image_for(&mask, frame);
+        grow_mask(&mut mask, settings.grow, frame.as_ref());
+        save_png(&mask, mask_path)?;
+        update_mask_manifest(mask_path, |manifest| {
+            manifest.insert("grow".into(), settings.grow.into());
+        })?;
+    }
+
+    if settings.feather > 0.0 {
+        log::info!("Feathering mask {} (sigma {})", mask_path.display(), settings.feather);
+        feather_mask(&mut mask, settings.feather);
+        save_png(&mask, mask_path)?;
+        update_mask_manifest(mask_path, |manifest| {
+            manifest.insert("feather".into(), settings.feather.into());
+        })?;
+    }
+
+    if let Some((width, color)) = settings.outline {
+        let outline_path = sibling_path(mask_path, "outline");
+        log::info!("Saving mask outline to {}", outline_path.display());
+        save_png(&render_mask_outline(&mask, width, color), &outline_path)?;
+    }
+
+    if let Some(shadow) = &settings.shadow {
+        let shadow_path = sibling_path(mask_path, "shadow");
+        log::info!("Saving drop shadow to {}", shadow_path.display());
+        save_png(&render_drop_shadow(&mask, shadow), &shadow_path)?;
+    }
+
+    match estimate_background_colors(&mask, frame) {
+        Ok(colors) => {
+            update_mask_manifest(mask_path, |manifest| {
+                manifest.insert("background_colors".into(), colors);
+            })?;
+        }
+        Err(e) => log::warn!("Background color estimation skipped: {e:?}"),
+    }
+
+    Ok(())
+}
+
+/// マスクのアルファを元フレームのアルファと合成する。
+///
+/// 色は元フレームから取る（和集合では SAM が背景とした部分の色も必要になるため）。
+fn combine_with_frame_alpha(
+    mask: &mut image::RgbaImage,
+    combine: AlphaCombine,
+    frame_path: &Path,
+) -> AnyResult<()> {
+    let frame = image::open(frame_path)?.to_rgba8();
+    if frame.dimensions() != mask.dimensions() {
+        return Err(anyhow::anyhow!(
+            "frame {} is {:?} but the mask is {:?}",
+            frame_path.display(),
+            frame.dimensions(),
+            mask.dimensions()
+        ));
+    }
+
+    for (m, f) in mask.pixels_mut().zip(frame.pixels()) {
+        let alpha = combine.combine(m[3], f[3]);
+        *m = image::Rgba([f[0], f[1], f[2], alpha]);
+    }
+    Ok(())
+}
+
+/// 2 枚のマスクの比較結果（画素数）
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct MaskDiffStats {
+    pub(crate) area_a: u64,
+    pub(crate) area_b: u64,
+    pub(crate) intersection: u64,
+    pub(crate) union: u64,
+}
+
+impl MaskDiffStats {
+    pub(crate) fn iou(&self) -> f64 {
+        if self.union == 0 {
+            1.0
+        } else {
+            self.intersection as f64 / self.union as f64
+        }
+    }
+
+    pub(crate) fn to_json(self) -> serde_json::Value {
+        serde_json::json!({
+            "area_a": self.area_a,
+            "area_b": self.area_b,
+            "intersection": self.intersection,
+            "union": self.union,
+            "only_a": self.area_a - self.intersection,
+            "only_b": self.area_b - self.intersection,
+            "iou": self.iou(),
+        })
+    }
+}
+
+/// 2 枚のマスクを比較し、差分画像と統計を返す。
+///
+/// アルファ 128 以上を前景とみなす。差分画像は
+/// 両方 = 白（半透明）、a のみ = 赤、b のみ = 青、どちらも背景 = 透明。
+pub(crate) fn diff_masks(a_path: &Path, b_path: &Path) -> AnyResult<(image::RgbaImage, MaskDiffStats)> {
+    let a = image::open(a_path)?.to_rgba8();
+    let b = image::open(b_path)?.to_rgba8();
+    if a.dimensions() != b.dimensions() {
+        return Err(anyhow::anyhow!(
+            "mask sizes differ: {:?} vs {:?}",
+            a.dimensions(),
+            b.dimensions()
+        ));
+    }
+
+    let mut stats = MaskDiffStats::default();
+    let (w, h) = a.dimensions();
+    let diff = image::RgbaImage::from_fn(w, h, |x, y| {
+        let in_a = a.get_pixel(x, y)[3] >= 128;
+        let in_b = b.get_pixel(x, y)[3] >= 128;
+        stats.area_a += in_a as u64;
+        stats.area_b += in_b as u64;
+        stats.intersection += (in_a && in_b) as u64;
+        stats.union += (in_a || in_b) as u64;
+
+        match (in_a, in_b) {
+            (true, true) => image::Rgba([255, 255, 255, 96]),
+            (true, false) => image::Rgba([230, 57, 70, 255]),
+            (false, true) => image::Rgba([52, 152, 219, 255]),
+            (false, false) => image::Rgba([0, 0, 0, 0]),
+        }
+    });
+
+    Ok((diff, stats))
+}
+
+/// ギャラリー 1 ページあたりの件数
+const GALLERY_PAGE_SIZE: usize = 24;
+
+/// 保存ルートにあるマスクのマニフェストを新しい順に返す。
+///
+/// マニフェストがあり、対応する PNG も残っているものだけを対象にする。
+/// ファイル名はテンプレートで自由に変えられるので、`mask` が自分と同名の PNG を指す JSON をマニフェストとみなす。
+fn list_mask_manifests() -> AnyResult<Vec<serde_json::Map<String, serde_json::Value>>> {
+    let mut manifests = Vec::new();
+    for path in mask_dirs()
+        .iter()
+        .filter_map(|dir| std::fs::read_dir(dir).ok())
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+    {
+        if path.extension().is_none_or(|e| e != "json") {
+            continue;
+        }
+
+        let Some(manifest) = read(&path)
+            .ok()
+            .and_then(|data| serde_json::from_slice::<serde_json::Map<_, _>>(&data).ok())
+        else {
+            continue;
+        };
+        let png_path = path.with_extension("png");
+        let png_name = png_path.file_name().unwrap_or_default().to_string_lossy();
+        let is_manifest = manifest.get("mask").and_then(|v| v.as_str()) == Some(png_name.as_ref());
+        if is_manifest && png_path.is_file() {
+            manifests.push(manifest);
+        }
+    }
+
+    // created_at は RFC 3339 なので文字列比較で新しい順に並ぶ
+    manifests.sort_by(|a, b| {
+        let key = |m: &serde_json::Map<String, serde_json::Value>| {
+            m.get("created_at")
+                .and_then(|v| v.as_str())
+                .unwrap_or("")
+                .to_string()
+        };
+        key(b).cmp(&key(a))
+    });
+    Ok(manifests)
+}
+
+/// `GET /api/gallery` のレスポンス。
+///
+/// マニフェストの内容に、サムネイルとマニフェストの URL を足してページ単位で返す。
+pub(crate) fn gallery_json(
+    object_filter: Option<i64>,
+    tag_filter: Option<&str>,
+    page: usize,
+) -> AnyResult<serde_json::Value> {
+    let manifests: Vec<_> = list_mask_manifests()?
+        .into_iter()
+        .filter(|m| {
+            object_filter.is_none_or(|id| m.get("object_id").and_then(|v| v.as_i64()) == Some(id))
+        })
+        .filter(|m| {
+            tag_filter.is_none_or(|tag| m.get("session_tag").and_then(|v| v.as_str()) == Some(tag))
+        })
+        .collect();
+
+    let total = manifests.len();
+    let items: Vec<serde_json::Value> = manifests
+        .into_iter()
+        .skip((page - 1) * GALLERY_PAGE_SIZE)
+        .take(GALLERY_PAGE_SIZE)
+        .map(|mut m| {
+            let name = m
+                .get("mask")
+                .and_then(|v| v.as_str())
+                .unwrap_or_default()
+                .to_string();
+            m.insert("thumbnail".into(), format!("/api/masks/{name}/thumb.jpg").into());
+            m.insert("manifest".into(), format!("/api/masks/{name}/manifest").into());
+            serde_json::Value::Object(m)
+        })
+        .collect();
+
+    Ok(serde_json::json!({
+        "page": page,
+        "per_page": GALLERY_PAGE_SIZE,
+        "total": total,
+        "pages": total.div_ceil(GALLERY_PAGE_SIZE),
+        "items": items,
+    }))
+}
+
+/// `GET /masks` のレスポンス。
+///
+/// 保存済みマスクのファイル名・object_id・作成日時・ファイルサイズを新しい順にすべて返す。
+pub(crate) fn mask_list_json(object_filter: Option<i64>) -> AnyResult<serde_json::Value> {
+    let items: Vec<serde_json::Value> = list_mask_manifests()?
+        .into_iter()
+        .filter(|m| {
+            object_filter.is_none_or(|id| m.get("object_id").and_then(|v| v.as_i64()) == Some(id))
+        })
+        .map(|m| {
+            let name = m.get("mask").and_then(|v| v.as_str()).unwrap_or_default();
+            let size = existing_mask_path(name)
+                .and_then(|path| Ok(std::fs::metadata(path)?.len()))
+                .unwrap_or(0);
+            serde_json::json!({
+                "name": name,
+                "object_id": m.get("object_id"),
+                "label": m.get("label"),
+                "created_at": m.get("created_at"),
+                "size": size,
+                "url": format!("/mask/{name}"),
+                "thumbnail": format!("/api/masks/{name}/thumb.jpg"),
+                "manifest": format!("/api/masks/{name}/manifest"),
+            })
+        })
+        .collect();
+
+    Ok(serde_json::json!({
+        "total": items.len(),
+        "masks": items,
+    }))
+}
+
+/// サムネイルの長辺（px）
+const THUMBNAIL_SIZE: u32 = 256;
+
+/// マスクのサムネイル JPEG を返す。
+///
+/// 保存ルートの `.thumbs/{stem}.jpg` にキャッシュし、マスクの方が新しければ作り直す。
+/// JPEG はアルファを持てないので、透明部分はチェッカー柄の上に合成する。
+pub(crate) fn mask_thumbnail(mask_path: &Path) -> AnyResult<Arc<[u8]>> {
+    let thumbs_dir = mask_path.with_file_name(".thumbs");
+    let stem = mask_path.file_stem().unwrap_or_default().to_string_lossy();
+    let thumb_path = thumbs_dir.join(format!("{stem}.jpg"));
+    let cache_key = format!("thumb:{}", thumb_path.display());
+
+    let mask_modified = std::fs::metadata(mask_path)?.modified()?;
+    if let Some(data) = lock_recover(memory_cache()).get(&cache_key, Some(mask_modified)) {
+        return Ok(data);
+    }
+    if let Ok(thumb_modified) = std::fs::metadata(&thumb_path).and_then(|m| m.modified())
+        && thumb_modified >= mask_modified
+        && let Ok(data) = read(&thumb_path)
+    {
+        let data: Arc<[u8]> = data.into();
+        lock_recover(memory_cache())
+            .insert(&cache_key, Some(mask_modified), data.clone());
+        return Ok(data);
+    }
+
+    let mask = image::open(mask_path)?.to_rgba8();
+    let (w, h) = mask.dimensions();
+    let scale = (THUMBNAIL_SIZE as f32 / w.max(h).max(1) as f32).min(1.0);
+    let thumb_w = ((w as f32 * scale).round() as u32).max(1);
+    let thumb_h = ((h as f32 * scale).round() as u32).max(1);
+    let small = resize_linear(&mask, thumb_w, thumb_h, image::imageops::FilterType::Triangle);
+
+    let flattened = image::RgbImage::from_fn(thumb_w, thumb_h, |x, y| {
+        let [r, g, b, a] = small.get_pixel(x, y).0;
+        let checker = if (x / 8 + y / 8) % 2 == 0 { 255u16 } else { 204u16 };
+        let a = a as u16;
+        let blend = |c: u8| ((c as u16 * a + checker * (255 - a)) / 255) as u8;
+        image::Rgb([blend(r), blend(g), blend(b)])
+    });
+
+    let mut data = Vec::new();
+    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut data, 80).encode_image(&flattened)?;
+
+    create_dir_all(&thumbs_dir)?;
+    if let Err(e) = write(&thumb_path, &data) {
+        log::warn!("Failed to cache thumbnail {}: {e:?}", thumb_path.display());
+    }
+    let data: Arc<[u8]> = data.into();
+    lock_recover(memory_cache())
+        .insert(&cache_key, Some(mask_modified), data.clone());
+    Ok(data)
+}
+
+/// マスクのアルファを width x height に合わせた 1 画素 1 バイトの配列を返す。
+///
+/// proc_video で毎フレーム使うので、マスクの更新日時が変わるまではメモリキャッシュから返す。
+pub(crate) fn mask_alpha_for_size(mask_path: &Path, width: u32, height: u32) -> AnyResult<Arc<[u8]>> {
+    let cache_key = format!("alpha:{width}x{height}:{}", mask_path.display());
+    let mask_modified = std::fs::metadata(mask_path)?.modified()?;
+    if let Some(data) = lock_recover(memory_cache()).get(&cache_key, Some(mask_modified)) {
+        return Ok(data);
+    }
+
+    let mut mask = image::open(mask_path)?.to_rgba8();
+    if mask.dimensions() != (width, height) {
+        mask = resize_linear(&mask, width, height, image::imageops::FilterType::Triangle);
+    }
+    let alpha: Arc<[u8]> = mask.pixels().map(|p| p[3]).collect();
+    lock_recover(memory_cache())
+        .insert(&cache_key, Some(mask_modified), alpha.clone());
+    Ok(alpha)
+}
+
+/// マスクごとのマニフェスト（`{stem}.json`）のパス
+pub(crate) fn mask_manifest_path(mask_path: &Path) -> PathBuf {
+    mask_path.with_extension("json")
+}
+
+/// マスクのマニフェストを読み込み、edit で書き換えて保存する（無ければ新規作成）。
+fn update_mask_manifest(
+    mask_path: &Path,
+    edit: impl FnOnce(&mut serde_json::Map<String, serde_json::Value>),
+) -> AnyResult<()> {
+    let manifest_path = mask_manifest_path(mask_path);
+    let mut manifest: serde_json::Map<String, serde_json::Value> = read(&manifest_path)
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default();
+
+    manifest.insert(
+        "mask".into(),
+        mask_path.file_name().unwrap_or_default().to_string_lossy().into(),
+    );
+    edit(&mut manifest);
+
+    write_atomic(&manifest_path, &serde_json::to_vec_pretty(&manifest)?)
+}
+
+/// frame のファイルのうちマスク外（アルファ 0）の画素から代表色を最大 3 色求める。
+///
+/// 各チャンネル上位 4bit で量子化したヒストグラムの上位ビンを取り、
+/// ビン内の実際の色の平均を返す。戻り値は `[{"color": "#rrggbb", "ratio": 0.42}, ...]`。
+fn estimate_background_colors(mask: &image::RgbaImage, frame: &MaskFrame) -> AnyResult<serde_json::Value> {
+    let frame_path = frame
+        .path
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("the frame of this mask is no longer on disk"))?;
+    let frame = image::open(frame_path)?.to_rgba8();
+    if frame.dimensions() != mask.dimensions() {
+        return Err(anyhow::anyhow!(
+            "frame size {:?} does not match mask size {:?}",
+            frame.dimensions(),
+            mask.dimensions()
+        ));
+    }
+
+    // ビンごとの (画素数, R 合計, G 合計, B 合計)
+    let mut bins = vec![(0u64, 0u64, 0u64, 0u64); 16 * 16 * 16];
+    let mut total = 0u64;
+    for (frame_px, mask_px) in frame.pixels().zip(mask.pixels()) {
+        if mask_px[3] != 0 {
+            continue;
+        }
+        let [r, g, b, _] = frame_px.0;
+        let index = ((r >> 4) as usize) << 8 | ((g >> 4) as usize) << 4 | (b >> 4) as usize;
+        let bin = &mut bins[index];
+        bin.0 += 1;
+        bin.1 += r as u64;
+        bin.2 += g as u64;
+        bin.3 += b as u64;
+        total += 1;
+    }
+    if total == 0 {
+        return Ok(serde_json::Value::Array(Vec::new()));
+    }
+
+    bins.sort_by_key(|bin| std::cmp::Reverse(bin.0));
+    let colors = bins
+        .iter()
+        .take(3)
+        .filter(|bin| bin.0 > 0)
+        .map(|&(count, r, g, b)| {
+            serde_json::json!({
+                "color": format!("#{:02x}{:02x}{:02x}", r / count, g / count, b / count),
+                "ratio": count as f64 / total as f64,
+            })
+        })
+        .collect();
+    Ok(serde_json::Value::Array(colors))
+}
+
+/// `dir/name.png` → `dir/name_{suffix}.png`
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{stem}_{suffix}.png"))
+}
+
+/// マスクの境界線を color の線（太さ width px）として透明背景に描く。
+///
+/// 境界（不透明ピクセルのうち透明ピクセルと接するもの）からの距離を求め、
+/// 距離が width / 2 以内のピクセルを塗る。線の端は 1px でアンチエイリアスする。
+fn render_mask_outline(mask: &image::RgbaImage, width: f32, color: [u8; 3]) -> image::RgbaImage {
+    let (w, h) = mask.dimensions();
+    let inside = |x: i64, y: i64| {
+        x >= 0
+            && y >= 0
+            && x < w as i64
+            && y < h as i64
+            && mask.get_pixel(x as u32, y as u32)[3] >= 128
+    };
+
+    let mut dist = vec![f32::INFINITY; (w * h) as usize];
+    for y in 0..h as i64 {
+        for x in 0..w as i64 {
+            let on_boundary = inside(x, y)
+                && [(-1, 0), (1, 0), (0, -1), (0, 1)]
+                    .iter()
+                    .any(|(dx, dy)| !inside(x + dx, y + dy));
+            if on_boundary {
+                dist[(y as u32 * w + x as u32) as usize] = 0.0;
+            }
+        }
+    }
+    chamfer_distance(&mut dist, w, h);
+
+    let half = width / 2.0;
+    image::RgbaImage::from_fn(w, h, |x, y| {
+        let d = dist[(y * w + x) as usize];
+        let coverage = (half + 0.5 - d).clamp(0.0, 1.0);
+        image::Rgba([color[0], color[1], color[2], (coverage * 255.0).round() as u8])
+    })
+}
+
+/// マスクと同じ大きさの frame の画像（ファイルが無い・読めない・大きさが違うなら None）
+fn frame_image_for(mask: &image::RgbaImage, frame: &MaskFrame) -> Option<image::RgbaImage> {
+    image::open(frame.path.as_deref()?)
+        .map(|frame| frame.to_rgba8())
+        .ok()
+        .filter(|frame| frame.dimensions() == mask.dimensions())
+}
+
+/// マスクのアルファを反転し、前景ではなく背景を残す。
+///
+/// 色は frame があればそこから取る（切り抜き PNG の透明部分の色は当てにならないため）。
+pub(crate) fn invert_mask(mask: &mut image::RgbaImage, frame: Option<&image::RgbaImage>) {
+    let w = mask.width();
+    for (i, pixel) in mask.pixels_mut().enumerate() {
+        let alpha = 255 - pixel[3];
+        *pixel = match frame {
+            Some(frame) => {
+                let f = frame.get_pixel(i as u32 % w, i as u32 / w);
+                image::Rgba([f[0], f[1], f[2], alpha])
+            }
+            None => image::Rgba([pixel[0], pixel[1], pixel[2], alpha]),
+        };
+    }
+}
+
+/// マスクを amount px 膨張（正）・収縮（負）させる。
+///
+/// 不透明（アルファ 128 以上）の領域からの距離で判定する。膨張で不透明になった画素の色は
+/// frame があればそこから取る。画像の外は収縮の起点にしない（画面端に接した物体が削れないように）。
+pub(crate) fn grow_mask(mask: &mut image::RgbaImage, amount: i32, frame: Option<&image::RgbaImage>) {
+    let (w, h) = mask.dimensions();
+    let dilate = amount > 0;
+    let radius = amount.unsigned_abs() as f32;
+
+    // 膨張なら不透明の画素、収縮なら透明の画素からの距離を求める
+    let mut dist: Vec<f32> = mask
+        .pixels()
+        .map(|p| if (p[3] >= 128) == dilate { 0.0 } else { f32::INFINITY })
+        .collect();
+    chamfer_distance(&mut dist, w, h);
+
+    for (i, pixel) in mask.pixels_mut().enumerate() {
+        let d = dist[i];
+        if d == 0.0 || d > radius {
+            continue;
+        }
+        if dilate {
+            if let Some(frame) = frame {
+                let f = frame.get_pixel(i as u32 % w, i as u32 / w);
+                *pixel = image::Rgba([f[0], f[1], f[2], 255]);
+            } else {
+                pixel[3] = 255;
+            }
+        } else {
+            pixel[3] = 0;
+        }
+    }
+}
+
+/// マスクのアルファだけをガウスぼかしして境界をなじませる（色はそのまま）。
+pub(crate) fn feather_mask(mask: &mut image::RgbaImage, sigma: f32) {
+    let (w, h) = mask.dimensions();
+    let alpha = image::GrayImage::from_fn(w, h, |x, y| image::Luma([mask.get_pixel(x, y)[3]]));
+    let blurred = image::imageops::blur(&alpha, sigma);
+    for (pixel, a) in mask.pixels_mut().zip(blurred.pixels()) {
+        pixel[3] = a[0];
+    }
+}
+
+/// マスクのアルファから、ずらしてぼかした黒いシルエットを作る。
+///
+/// 切り抜き画像と同じキャンバスサイズで出力するので、同じ座標に重ねるだけで影になる。
+fn render_drop_shadow(mask: &image::RgbaImage, shadow: &ShadowSettings) -> image::RgbaImage {
+    let (w, h) = mask.dimensions();
+    let (dx, dy) = shadow.offset;
+
+    let silhouette = image::GrayImage::from_fn(w, h, |x, y| {
+        let sx = x as i64 - dx as i64;
+        let sy = y as i64 - dy as i64;
+        if sx < 0 || sy < 0 || sx >= w as i64 || sy >= h as i64 {
+            image::Luma([0])
+        } else {
+            image::Luma([mask.get_pixel(sx as u32, sy as u32)[3]])
+        }
+    });
+
+    let blurred = if shadow.blur > 0.0 {
+        image::imageops::blur(&silhouette, shadow.blur)
+    } else {
+        silhouette
+    };
+
+    image::RgbaImage::from_fn(w, h, |x, y| {
+        let a = blurred.get_pixel(x, y)[0] as f32 * shadow.opacity;
+        image::Rgba([0, 0, 0, a.round() as u8])
+    })
+}
+
+/// 0 の画素からの近似ユークリッド距離を 2 パスのチャムファー法で求める。
+fn chamfer_distance(dist: &mut [f32], w: u32, h: u32) {
+    const DIAG: f32 = std::f32::consts::SQRT_2;
+    let (w, h) = (w as usize, h as usize);
+
+    for y in 0..h {
+        for x in 0..w {
+            let mut d = dist[y * w + x];
+            if x > 0 {
+                d = d.min(dist[y * w + x - 1] + 1.0);
+            }
+            if y > 0 {
+                d = d.min(dist[(y - 1) * w + x] + 1.0);
+                if x > 0 {
+                    d = d.min(dist[(y - 1) * w + x - 1] + DIAG);
+                }
+                if x + 1 < w {
+                    d = d.min(dist[(y - 1) * w + x + 1] + DIAG);
+                }
+            }
+            dist[y * w + x] = d;
+        }
+    }
+
+    for y in (0..h).rev() {
+        for x in (0..w).rev() {
+            let mut d = dist[y * w + x];
+            if x + 1 < w {
+                d = d.min(dist[y * w + x + 1] + 1.0);
+            }
+            if y + 1 < h {
+                d = d.min(dist[(y + 1) * w + x] + 1.0);
+                if x + 1 < w {
+                    d = d.min(dist[(y + 1) * w + x + 1] + DIAG);
+                }
+                if x > 0 {
+                    d = d.min(dist[(y + 1) * w + x - 1] + DIAG);
+                }
+            }
+            dist[y * w + x] = d;
+        }
+    }
+}
+
+/// マスクを width x height に拡縮する。
+///
+/// いったん factor 倍の解像度に補間してから factor x factor の平均で縮小することで、
+/// 境界のアルファがなめらかになる。補間と平均はリニア光・乗算済みアルファで行い、
+/// 半透明の縁が黒ずむのを防ぐ。
+fn resize_mask_supersampled(
+    mask: &image::RgbaImage,
+    width: u32,
+    height: u32,
+    factor: u32,
+) -> image::RgbaImage {
+    use image::imageops::{FilterType, resize};
+
+    let linear = to_linear_premultiplied(mask);
+    if factor <= 1 {
+        return from_linear_premultiplied(&resize(&linear, width, height, FilterType::Triangle));
+    }
+
+    let large = resize(&linear, width * factor, height * factor, FilterType::Triangle);
+    let samples = (factor * factor) as f32;
+
+    let averaged = image::Rgba32FImage::from_fn(width, height, |x, y| {
+        let mut sum = [0f32; 4];
+        for sy in 0..factor {
+            for sx in 0..factor {
+                let p = large.get_pixel(x * factor + sx, y * factor + sy).0;
+                for c in 0..4 {
+                    sum[c] += p[c];
+                }
+            }
+        }
+        image::Rgba(sum.map(|v| v / samples))
+    });
+    from_linear_premultiplied(&averaged)
+}
+
+/// 画像をリニア光で拡縮する（プレビューやサムネイルの縮小用）。
+pub(crate) fn resize_linear(
+    img: &image::RgbaImage,
+    width: u32,
+    height: u32,
+    filter: image::imageops::FilterType,
+) -> image::RgbaImage {
+    let linear = to_linear_premultiplied(img);
+    from_linear_premultiplied(&image::imageops::resize(&linear, width, height, filter))
+}
+
+/// sRGB の 8bit 値 → リニア光（0.0〜1.0）の表
+fn srgb_to_linear_table() -> &'static [f32; 256] {
+    static TABLE: OnceLock<[f32; 256]> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        std::array::from_fn(|i| {
+            let c = i as f32 / 255.0;
+            if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            }
+        })
+    })
+}
+
+fn linear_to_srgb(c: f32) -> u8 {
+    let c = c.clamp(0.0, 1.0);
+    let v = if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    };
+    (v * 255.0).round() as u8
+}
+
+/// sRGB の RGBA8 → リニア光・乗算済みアルファの f32 画像
+fn to_linear_premultiplied(img: &image::RgbaImage) -> image::Rgba32FImage {
+    let table = srgb_to_linear_table();
+    let (w, h) = img.dimensions();
+    image::Rgba32FImage::from_fn(w, h, |x, y| {
+        let [r, g, b, a] = img.get_pixel(x, y).0;
+        let a = a as f32 / 255.0;
+        image::Rgba([
+            table[r as usize] * a,
+            table[g as usize] * a,
+            table[b as usize] * a,
+            a,
+        ])
+    })
+}
+
+/// to_linear_premultiplied の逆変換
+fn from_linear_premultiplied(img: &image::Rgba32FImage) -> image::RgbaImage {
+    let (w, h) = img.dimensions();
+    image::RgbaImage::from_fn(w, h, |x, y| {
+        let [r, g, b, a] = img.get_pixel(x, y).0;
+        let alpha = (a.clamp(0.0, 1.0) * 255.0).round() as u8;
+        if alpha == 0 {
+            return image::Rgba([0, 0, 0, 0]);
+        }
+        image::Rgba([
+            linear_to_srgb(r / a),
+            linear_to_srgb(g / a),
+            linear_to_srgb(b / a),
+            alpha,
+        ])
+    })
+}
+
+/// PNG ファイルの先頭 8 バイト
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+/// 受け取ったマスクが使える PNG かを調べ、使えなければ理由を返す。
+///
+/// object_id のフレームと倍率だけが違うマスクは保存時に拡縮するので、縦横比が同じなら受け付ける。
+pub(crate) fn validate_mask_png(path: &Path, object_id: Option<i64>) -> Result<(), String> {
+    let mut signature = [0u8; 8];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut signature))
+        .map_err(|_| "body is too short to be a PNG".to_string())?;
+    if signature != PNG_SIGNATURE {
+        return Err("body is not a PNG (bad signature)".to_string());
+    }
+
+    let mut reader = image::ImageReader::open(path).map_err(|e| format!("PNG could not be opened: {e}"))?;
+    reader.set_format(image::ImageFormat::Png);
+    let mask = reader
+        .decode()
+        .map_err(|e| format!("PNG could not be decoded: {e}"))?;
+    let (width, height) = (mask.width(), mask.height());
+    if let Some((frame_w, frame_h)) = MaskFrame::for_object(object_id).size
+        && (width, height) != (frame_w, frame_h)
+    {
+        let ratio = (width as f64 / height as f64) / (frame_w as f64 / frame_h as f64);
+        if (ratio - 1.0).abs() > 0.02 {
+            return Err(format!(
+                "mask is {width}x{height} but the frame is {frame_w}x{frame_h}"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// 保存済みのマスクをオブジェクトに紐づけ、マニフェスト作成と後処理を行う。
+pub(crate) fn register_saved_mask(object_id: i64, label: Option<&str>, mask_path: &Path) -> AnyResult<()> {
+    update_mask_manifest(mask_path, |manifest| {
+        manifest.insert("object_id".into(), object_id.into());
+        manifest.insert("label".into(), label.into());
+        manifest.insert("session_tag".into(), session_tag_for_object(object_id).into());
+        if let Some(info) = exported_frame_for_object(object_id) {
+            manifest.insert("object".into(), info.to_json());
+        }
+        manifest.insert("created_at".into(), Local::now().to_rfc3339().into());
+    })?;
+    let frame = MaskFrame::for_object(Some(object_id));
+    postprocess_saved_mask(mask_path, &frame, Some(alpha_combine_for_object(object_id)))?;
+    if let Some(crop) = scene_crop_for_object(object_id) {
+        save_scene_crop(mask_path, crop)?;
+    }
+    if lock_recover(mask_settings()).export_exo {
+        save_mask_exo(mask_path, exported_frame_for_object(object_id))?;
+    }
+
+    record_mask_export(
+        object_id,
+        label,
+        mask_path,
+        exported_frame_for_object(object_id).map(|info| info.frame),
+        frame.path.as_deref(),
+    );
+
+    // object_id → このファイルパス に紐づけ
+    set_mask_path_for_object(object_id, label, mask_path.to_path_buf());
+    record_history(
+        HistoryKind::MaskReceived,
+        Some(object_id),
+        match label {
+            Some(label) => format!("mask '{label}' saved to {}", mask_path.display()),
+            None => format!("mask saved to {}", mask_path.display()),
+        },
+    );
+    Ok(())
+}
+
+/// マスクと一緒に送られてきた任意の情報（フレーム番号やプロンプト座標など）をマニフェストの
+/// `metadata` に残す。
+pub(crate) fn record_mask_metadata(
+    mask_path: &Path,
+    metadata: &serde_json::Map<String, serde_json::Value>,
+) -> AnyResult<()> {
+    update_mask_manifest(mask_path, |manifest| {
+        manifest.insert("metadata".into(), metadata.clone().into());
+    })
+}
+
+/// マスクを画像ファイルオブジェクトとしてレイヤー 1 に置く `{stem}.exo` を書き出す。
+///
+/// 長さは書き出し元のオブジェクトと同じにする。位置は取れないので中央（X=0, Y=0）に置く
+/// （シーン全体のマスクならそのまま重なる）。シーンの形式が分からなければ書き出さない。
+fn save_mask_exo(mask_path: &Path, info: Option<ObjectFrameInfo>) -> AnyResult<()> {
+    let Some(scene) = info.map(|info| info.scene).or(*lock_recover(last_scene_format())) else {
+        log::warn!("Scene format is not known yet; {} has no .exo", mask_path.display());
+        return Ok(());
+    };
+    let length = info.map_or(1, |info| info.frame_total.max(1));
+    let exo_path = mask_path.with_extension("exo");
+    log::info!("Saving mask object to {}", exo_path.display());
+    write_atomic(&exo_path, mask_exo(&scene, length, mask_path).as_bytes())?;
+    update_mask_manifest(mask_path, |manifest| {
+        manifest.insert(
+            "exo".into(),
+            exo_path.file_name().map(|n| n.to_string_lossy().into_owned()).into(),
+        );
+    })?;
+    Ok(())
+}
+
+/// 画像ファイル 1 つをレイヤー 1 に置く .exo（UTF-8）
+pub(crate) fn mask_exo(scene: &SceneFormat, length: i32, image_path: &Path) -> String {
+    format!(
+        "[exedit]\r\n\
+         width={width}\r\n\
+         height={height}\r\n\
+         rate={rate}\r\n\
+         scale={scale}\r\n\
+         length={length}\r\n\
+         [0]\r\n\
+         start=1\r\n\
+         end={length}\r\n\
+         layer=1\r\n\
+         overlay=1\r\n\
+         camera=0\r\n\
+         [0.0]\r\n\
+         _name=画像ファイル\r\n\
+         file={file}\r\n\
+         [0.1]\r\n\
+         _name=標準描画\r\n\
+         X=0.0\r\n\
+         Y=0.0\r\n\
+         Z=0.0\r\n\
+         拡大率=100.00\r\n\
+         透明度=0.0\r\n\
+         回転=0.00\r\n",
+        width = scene.width,
+        height = scene.height,
+        rate = scene.rate,
+        scale = scene.scale,
+        file = image_path.display(),
+    )
+}
+
+/// シーン全体のマスクから対象オブジェクトの範囲を `{stem}_object.png` として切り出す。
+///
+/// 範囲がシーンの外にはみ出した部分は透明にする。切り出した範囲はマニフェストに残す。
+fn save_scene_crop(mask_path: &Path, crop: SceneCrop) -> AnyResult<()> {
+    let mask = image::open(mask_path)?.to_rgba8();
+    let mut object = image::RgbaImage::new(crop.width, crop.height);
+    image::imageops::overlay(&mut object, &mask, -(crop.x as i64), -(crop.y as i64));
+
+    let object_path = sibling_path(mask_path, "object");
+    log::info!(
+        "Saving object-space mask ({}x{} at {},{}) to {}",
+        crop.width,
+        crop.height,
+        crop.x,
+        crop.y,
+        object_path.display()
+    );
+    save_png(&object, &object_path)?;
+
+    update_mask_manifest(mask_path, |manifest| {
+        manifest.insert(
+            "scene_crop".into(),
+            serde_json::json!({
+                "x": crop.x,
+                "y": crop.y,
+                "width": crop.width,
+                "height": crop.height,
+                "mask": object_path.file_name().map(|n| n.to_string_lossy().into_owned()),
+            }),
+        );
+    })
+}
+
+/// 既存のマスクの一部を patch（PNG）で置き換える。
+///
+/// (x, y) はマスク上での patch の左上。マスクからはみ出した部分は捨てる。
+/// 置き換えた範囲はマニフェストの `patches` に追記し、patch の大きさを返す。
+pub(crate) fn apply_mask_patch(mask_path: &Path, patch: &[u8], x: i64, y: i64) -> AnyResult<(u32, u32)> {
+    let patch = image::load_from_memory(patch)?.to_rgba8();
+    let mut mask = image::open(mask_path)?.to_rgba8();
+    let (width, height) = patch.dimensions();
+    if x >= mask.width() as i64
+        || y >= mask.height() as i64
+        || x + width as i64 <= 0
+        || y + height as i64 <= 0
+    {
+        return Err(anyhow::anyhow!(
+            "patch {}x{} at {},{} is outside the {}x{} mask",
+            width,
+            height,
+            x,
+            y,
+            mask.width(),
+            mask.height()
+        ));
+    }
+
+    image::imageops::replace(&mut mask, &patch, x, y);
+    save_png(&mask, mask_path)?;
+
+    update_mask_manifest(mask_path, |manifest| {
+        let entry = serde_json::json!({
+            "x": x,
+            "y": y,
+            "width": width,
+            "height": height,
+            "patched_at": Local::now().to_rfc3339(),
+        });
+        match manifest.get_mut("patches").and_then(|v| v.as_array_mut()) {
+            Some(patches) => patches.push(entry),
+            None => {
+                manifest.insert("patches".into(), vec![entry].into());
+            }
+        }
+    })?;
+    Ok((width, height))
+}
+
+/// 連番マスクを 1 枚登録する（マニフェストにフレーム番号を残す）。
+pub(crate) fn register_sequence_mask(object_id: i64, frame: i32, mask_path: &Path) -> AnyResult<()> {
+    update_mask_manifest(mask_path, |manifest| {
+        manifest.insert("object_id".into(), object_id.into());
+        manifest.insert("frame".into(), frame.into());
+        manifest.insert("session_tag".into(), session_tag_for_object(object_id).into());
+        manifest.insert("created_at".into(), Local::now().to_rfc3339().into());
+    })?;
+    let frame_path = sequence_dir(object_id).join(sequence_frame_name(frame));
+    let mask_frame = MaskFrame {
+        size: image::image_dimensions(&frame_path).ok(),
+        path: Some(frame_path.clone()),
+    };
+    postprocess_saved_mask(mask_path, &mask_frame, Some(alpha_combine_for_object(object_id)))?;
+
+    record_mask_export(object_id, None, mask_path, Some(frame), Some(&frame_path));
+
+    lock_recover(mask_sequences())
+        .entry(object_id)
+        .or_default()
+        .insert(frame, mask_path.to_path_buf());
+    Ok(())
+}
+
+/// zip 内の PNG を、エントリ名のフレーム番号で連番マスクとして保存する。
+pub(crate) fn save_bulk_zip_masks(object_id: i64, zip_path: &Path) -> AnyResult<Vec<i32>> {
+    let dir = sequence_dir(object_id);
+    let mut archive = zip::ZipArchive::new(File::open(zip_path)?)?;
+    let mut frames = Vec::new();
+
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let name = entry.name().to_string();
+        if !entry.is_file() || !name.to_ascii_lowercase().ends_with(".png") {
+            continue;
+        }
+        let Some(frame) = frame_number_from_name(&name) else {
+            log::debug!("Ignoring bulk zip entry without frame number: {}", name);
+            continue;
+        };
+
+        let mask_path = dir.join(sequence_mask_name(frame));
+        let mut out = BufWriter::new(File::create(&mask_path)?);
+        std::io::copy(&mut entry, &mut out)?;
+        out.flush()?;
+        register_sequence_mask(object_id, frame, &mask_path)?;
+        frames.push(frame);
+    }
+
+    frames.sort_unstable();
+    frames.dedup();
+    Ok(frames)
+}
+
+// ── 書き出しの一覧（manifest.json） ───────────────────────────────────
+
+/// 保存ルートに置く、書き出したフレームとマスクの一覧
+const EXPORT_MANIFEST_NAME: &str = "manifest.json";
+
+/// 保存ルートからの相対パス（区切りは `/`）。保存ルートの外なら絶対パスのまま
+fn path_from_root(root: &Path, path: &Path) -> String {
+    path.strip_prefix(root)
+        .unwrap_or(path)
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// `manifest.json` の `entries` に 1 件追記する（ファイルが無ければ作る）。
+///
+/// 後段のスクリプトがマスクと元フレームの対応を機械的に辿れるよう、
+/// フレームの書き出しとマスクの保存のたびに呼ぶ。
+fn append_export_manifest(entry: serde_json::Value) -> AnyResult<()> {
+    static LOCK: OnceLock<Mutex<()>> = OnceLock::new();
+    let _guard = lock_recover(LOCK.get_or_init(Default::default));
+
+    let serde_json::Value::Object(mut entry) = entry else {
+        return Err(anyhow::anyhow!("manifest entry must be an object"));
+    };
+
+    let root = lock_recover(export_root_dir()).clone();
+    create_dir_all(&root)?;
+    let manifest_path = root.join(EXPORT_MANIFEST_NAME);
+    let mut manifest: serde_json::Map<String, serde_json::Value> = read(&manifest_path)
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default();
+
+    for key in ["file", "frame_file"] {
+        if let Some(path) = entry.get(key).and_then(|v| v.as_str()).map(PathBuf::from) {
+            entry.insert(key.into(), path_from_root(&root, &path).into());
+        }
+    }
+    entry.insert("created_at".into(), Local::now().to_rfc3339().into());
+
+    let entries = manifest
+        .entry("entries")
+        .or_insert_with(|| serde_json::Value::Array(Vec::new()));
+    if !entries.is_array() {
+        *entries = serde_json::Value::Array(Vec::new());
+    }
+    entries.as_array_mut().unwrap().push(entry.into());
+    write_atomic(&manifest_path, &serde_json::to_vec_pretty(&manifest)?)
+}
+
+/// 書き出したフレームを `manifest.json` に記録する（失敗してもログだけ残す）。
+pub(crate) fn record_frame_export(object_id: i64, frame: i32, frame_path: &Path) {
+    let entry = serde_json::json!({
+        "kind": "frame",
+        "file": frame_path,
+        "object_id": object_id,
+        "frame": frame,
+    });
+    if let Err(e) = append_export_manifest(entry) {
+        log::warn!("Failed to record frame in {}: {e:?}", EXPORT_MANIFEST_NAME);
+    }
+}
+
+/// 保存したマスクと、その元になったフレームを `manifest.json` に記録する（失敗してもログだけ残す）。
+fn record_mask_export(
+    object_id: i64,
+    label: Option<&str>,
+    mask_path: &Path,
+    frame: Option<i32>,
+    frame_path: Option<&Path>,
+) {
+    let entry = serde_json::json!({
+        "kind": "mask",
+        "file": mask_path,
+        "object_id": object_id,
+        "label": label,
+        "frame": frame,
+        "frame_file": frame_path,
+    });
+    if let Err(e) = append_export_manifest(entry) {
+        log::warn!("Failed to record mask in {}: {e:?}", EXPORT_MANIFEST_NAME);
+    }
+}
+
+/// マスクを置くフォルダ（保存ルートと、その下のオブジェクトごとの `object_{ID}/`）
+fn mask_dirs() -> Vec<PathBuf> {
+    let root = lock_recover(export_root_dir()).clone();
+    let mut dirs = vec![root.clone()];
+    if let Ok(entries) = std::fs::read_dir(&root) {
+        dirs.extend(
+            entries
+                .flatten()
+                .filter(|entry| entry.file_name().to_string_lossy().starts_with("object_"))
+                .map(|entry| entry.path())
+                .filter(|path| path.is_dir()),
+        );
+    }
+    dirs
+}
+
+/// name がフォルダを含まない 1 つのファイル名かどうか。
+///
+/// 区切り文字・`.`・`..` のほか、Windows でドライブや代替データストリームになる `:` も拒む。
+pub(crate) fn is_plain_file_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    !name.contains(['/', '\\', ':'])
+        && matches!(components.next(), Some(std::path::Component::Normal(_)))
+        && components.next().is_none()
+}
+
+/// 保存ルート（またはオブジェクトごとのフォルダ）にある既存マスク PNG のパスを返す。
+///
+/// name はファイル名のみ（サブディレクトリ不可）で、`.png` で終わる必要がある。
+/// 同じ名前が複数のフォルダにあれば保存ルートを優先する。
+pub(crate) fn existing_mask_path(name: &str) -> AnyResult<PathBuf> {
+    if !is_plain_file_name(name) || name.contains("..") || !name.ends_with(".png") {
+        return Err(anyhow::anyhow!("invalid mask name: {name}"));
+    }
+
+    mask_dirs()
+        .into_iter()
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+        .ok_or_else(|| anyhow::anyhow!("mask not found: {name}"))
+}
+
+/// 保存済みのマスクと、そこから作ったファイル（マニフェスト・.exo・縁取り・影・
+/// 切り出し・サムネイル）を削除する。消したファイルのパスを返す。
+///
+/// `.bak` はユーザーが残したバックアップなので消さない。
+pub(crate) fn delete_mask(mask_path: &Path) -> AnyResult<Vec<PathBuf>> {
+    let stem = mask_path.file_stem().unwrap_or_default().to_string_lossy();
+    let thumb_path = mask_path.with_file_name(".thumbs").join(format!("{stem}.jpg"));
+    lock_recover(memory_cache())
+        .remove(&format!("thumb:{}", thumb_path.display()));
+
+    // マスク本体が消せなければそこで止める（残りだけ消えた状態にしない）
+    std::fs::remove_file(mask_path)?;
+    let mut removed = vec![mask_path.to_path_buf()];
+    for path in [
+        mask_manifest_path(mask_path),
+        mask_path.with_extension("exo"),
+        sibling_path(mask_path, "outline"),
+        sibling_path(mask_path, "shadow"),
+        sibling_path(mask_path, "object"),
+        thumb_path,
+    ] {
+        if std::fs::remove_file(&path).is_ok() {
+            removed.push(path);
+        }
+    }
+    Ok(removed)
+}
@@ -0,0 +1,191 @@
+//! 連番マスクを ffmpeg で透過動画にして、対応する .exo を書き出す（`ffmpeg` feature）。
+
+use anyhow::Result as AnyResult;
+use crate::{
+    export::resize_linear,
+    frame::{save_png, sequence_dir, sequence_frame_name},
+    state::{
+        HistoryKind, SceneFormat, ffmpeg_path, last_scene_format, lock_recover, mask_sequences,
+        record_history,
+    },
+};
+use std::{
+    collections::HashMap,
+    fs::{create_dir_all, write},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Mutex, OnceLock},
+    thread,
+};
+
+// ── 連番マスクの動画化 ────────────────────────────────────────────────
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum VideoFormat {
+    /// VP9 + アルファ
+    WebM,
+    /// ProRes 4444 + アルファ
+    Mov,
+}
+
+impl VideoFormat {
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            VideoFormat::WebM => "webm",
+            VideoFormat::Mov => "mov",
+        }
+    }
+
+    fn codec_args(self) -> &'static [&'static str] {
+        match self {
+            VideoFormat::WebM => &[
+                "-c:v", "libvpx-vp9", "-pix_fmt", "yuva420p", "-b:v", "0", "-crf", "30",
+            ],
+            VideoFormat::Mov => &[
+                "-c:v", "prores_ks", "-profile:v", "4444", "-pix_fmt", "yuva444p10le",
+            ],
+        }
+    }
+}
+
+/// オブジェクトごとの動画化ジョブの状態（"running" / "done" / "error: ..."）
+pub(crate) fn encode_jobs() -> &'static Mutex<HashMap<i64, String>> {
+    static JOBS: OnceLock<Mutex<HashMap<i64, String>>> = OnceLock::new();
+    JOBS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// 連番マスクの動画化をバックグラウンドで始める。
+pub(crate) fn start_sequence_encode(object_id: i64, format: VideoFormat, video_path: PathBuf, exo_path: PathBuf) {
+    lock_recover(encode_jobs())
+        .insert(object_id, "running".into());
+
+    thread::spawn(move || {
+        let status = match encode_sequence(object_id, format, &video_path, &exo_path) {
+            Ok(()) => {
+                log::info!("Encoded mask sequence of object {} to {}", object_id, video_path.display());
+                record_history(
+                    HistoryKind::VideoEncoded,
+                    Some(object_id),
+                    format!("encoded to {}", video_path.display()),
+                );
+                "done".to_string()
+            }
+            Err(e) => {
+                log::error!("Encoding mask sequence of object {} failed: {e:?}", object_id);
+                record_history(HistoryKind::Error, Some(object_id), format!("encoding failed: {e:#}"));
+                format!("error: {e:#}")
+            }
+        };
+        lock_recover(encode_jobs()).insert(object_id, status);
+    });
+}
+
+/// 連番フレームにマスクを適用した切り抜きを作り、ffmpeg で透過動画にして .exo も書き出す。
+fn encode_sequence(
+    object_id: i64,
+    format: VideoFormat,
+    video_path: &Path,
+    exo_path: &Path,
+) -> AnyResult<()> {
+    let masks = lock_recover(mask_sequences())
+        .get(&object_id)
+        .cloned()
+        .unwrap_or_default();
+    if masks.is_empty() {
+        return Err(anyhow::anyhow!("no mask sequence for object {object_id}"));
+    }
+
+    // ffmpeg の連番入力は番号の抜けで止まるので、詰めて cut_000000.png から振り直す
+    let dir = sequence_dir(object_id);
+    let cut_dir = dir.join("cut");
+    let _ = std::fs::remove_dir_all(&cut_dir);
+    create_dir_all(&cut_dir)?;
+    let mut count = 0;
+    for (frame, mask_path) in &masks {
+        let frame_path = dir.join(sequence_frame_name(*frame));
+        if !frame_path.is_file() {
+            log::warn!("Frame {} of object {} is not exported; skipped", frame, object_id);
+            continue;
+        }
+
+        let mut cut = image::open(&frame_path)?.to_rgba8();
+        let mut mask = image::open(mask_path)?.to_rgba8();
+        if mask.dimensions() != cut.dimensions() {
+            mask = resize_linear(
+                &mask,
+                cut.width(),
+                cut.height(),
+                image::imageops::FilterType::Triangle,
+            );
+        }
+        for (px, m) in cut.pixels_mut().zip(mask.pixels()) {
+            px[3] = ((px[3] as u16 * m[3] as u16) / 255) as u8;
+        }
+        save_png(&cut, &cut_dir.join(format!("cut_{count:06}.png")))?;
+        count += 1;
+    }
+    if count == 0 {
+        return Err(anyhow::anyhow!("no exported frame matches the mask sequence"));
+    }
+
+    let scene = lock_recover(last_scene_format())
+        .ok_or_else(|| anyhow::anyhow!("scene format is not known yet"))?;
+    let ffmpeg = lock_recover(ffmpeg_path()).clone();
+    let output = Command::new(&ffmpeg)
+        .args(["-y", "-loglevel", "error", "-framerate"])
+        .arg(format!("{}/{}", scene.rate, scene.scale))
+        .arg("-i")
+        .arg(cut_dir.join("cut_%06d.png"))
+        .args(format.codec_args())
+        .arg(video_path)
+        .output()
+        .map_err(|e| anyhow::anyhow!("failed to run {}: {e}", ffmpeg.display()))?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "ffmpeg exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    write(exo_path, sequence_exo(&scene, count, video_path))?;
+    Ok(())
+}
+
+/// 動画ファイル 1 つをレイヤー 1 に置く .exo（UTF-8）
+fn sequence_exo(scene: &SceneFormat, length: usize, video_path: &Path) -> String {
+    format!(
+        "[exedit]\r\n\
+         width={width}\r\n\
+         height={height}\r\n\
+         rate={rate}\r\n\
+         scale={scale}\r\n\
+         length={length}\r\n\
+         [0]\r\n\
+         start=1\r\n\
+         end={length}\r\n\
+         layer=1\r\n\
+         overlay=1\r\n\
+         camera=0\r\n\
+         [0.0]\r\n\
+         _name=動画ファイル\r\n\
+         再生位置=1\r\n\
+         再生速度=100.0\r\n\
+         ループ再生=0\r\n\
+         アルファチャンネルを読み込む=1\r\n\
+         file={file}\r\n\
+         [0.1]\r\n\
+         _name=標準描画\r\n\
+         X=0.0\r\n\
+         Y=0.0\r\n\
+         Z=0.0\r\n\
+         拡大率=100.00\r\n\
+         透明度=0.0\r\n\
+         回転=0.00\r\n",
+        width = scene.width,
+        height = scene.height,
+        rate = scene.rate,
+        scale = scene.scale,
+        file = video_path.display(),
+    )
+}
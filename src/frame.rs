@@ -0,0 +1,458 @@
+//! フレーム画像の書き出し。
+//!
+//! Web UI に渡す `current_frame.png`（`.webp` / `.jpg`）と、連番書き出し（`sam_seq_{object_id}/`）のファイル名を扱う。
+
+use anyhow::Result as AnyResult;
+use crate::{
+    cache::memory_cache,
+    server::notify_frame_updated,
+    state::{
+        FrameFormat, HistoryKind, PngCompression, current_edit_object_id, export_dir_for_object,
+        export_root_dir, frame_format, last_frame_size, lock_recover, png_compression, record_history,
+        set_frame_file_for_object, start_export_session,
+    },
+};
+use image::{
+    ExtendedColorType, ImageEncoder,
+    codecs::png::{CompressionType, FilterType as PngFilterType, PngEncoder},
+};
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs::{File, create_dir_all, remove_file, rename},
+    hash::{Hash, Hasher},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock, mpsc},
+    thread,
+    time::SystemTime,
+};
+
+/// 連番書き出しの 1 フレーム分
+pub(crate) struct SequenceFrame {
+    pub(crate) path: PathBuf,
+    pub(crate) image: image::RgbaImage,
+}
+
+/// 連番 PNG のエンコードと保存を行うバックグラウンドスレッドへの送信口
+pub(crate) fn sequence_writer() -> &'static Mutex<mpsc::Sender<SequenceFrame>> {
+    static WRITER: OnceLock<Mutex<mpsc::Sender<SequenceFrame>>> = OnceLock::new();
+    WRITER.get_or_init(|| {
+        let (tx, rx) = mpsc::channel::<SequenceFrame>();
+        thread::spawn(move || {
+            for frame in rx {
+                let result = frame
+                    .path
+                    .parent()
+                    .map_or(Ok(()), create_dir_all)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|_| save_png(&frame.image, &frame.path));
+                if let Err(e) = result {
+                    log::error!("failed to write sequence frame {}: {e}", frame.path.display());
+                    record_history(
+                        HistoryKind::Error,
+                        None,
+                        format!("failed to write sequence frame {}: {e}", frame.path.display()),
+                    );
+                }
+            }
+        });
+        Mutex::new(tx)
+    })
+}
+
+/// オブジェクトの連番 PNG の保存先
+pub(crate) fn sequence_dir(object_id: i64) -> PathBuf {
+    lock_recover(export_root_dir())
+        .join(format!("sam_seq_{object_id}"))
+}
+
+pub(crate) fn sequence_frame_name(frame: i32) -> String {
+    format!("frame_{frame:06}.png")
+}
+
+pub(crate) fn sequence_mask_name(frame: i32) -> String {
+    format!("mask_{frame:06}.png")
+}
+
+/// 書き出した `current_frame.png` の版（条件付き GET で使う）
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FrameVersion {
+    /// 画素から求めたハッシュ（引用符付きの ETag）
+    pub(crate) etag: String,
+    pub(crate) exported_at: SystemTime,
+    /// ファイルの形式
+    pub(crate) format: FrameFormat,
+    /// 書き出したファイル（オブジェクトごとのフォルダに書き出すこともある）
+    pub(crate) path: PathBuf,
+    /// 書き出し元のオブジェクトに発行したセッション ID（編集中のオブジェクトが無ければ None）
+    pub(crate) session: Option<String>,
+    /// 1 つ前のフレームからの差分（前のフレームが無い・全体が変わったときは None）
+    pub(crate) delta: Option<FrameDelta>,
+}
+
+/// 連続する 2 フレームの間で変わった範囲
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FrameDelta {
+    /// 差分の元になったフレームの ETag
+    pub(crate) base_etag: String,
+    pub(crate) x: u32,
+    pub(crate) y: u32,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    /// 変わった範囲だけを切り出した PNG
+    pub(crate) png: Arc<[u8]>,
+}
+
+/// 差分計算用に前のフレームの画素を置いておくメモリキャッシュのキー
+const PREVIOUS_FRAME_KEY: &str = "frame:previous_rgba";
+
+/// 最後に書き出したフレームのファイルの中身を置いておくメモリキャッシュのキー（stamp は書き出し時刻）
+const CURRENT_FRAME_KEY: &str = "frame:current";
+
+/// 最後に set_current_frame で書き出したフレームの版
+pub(crate) fn current_frame_version() -> &'static Mutex<Option<FrameVersion>> {
+    static VERSION: OnceLock<Mutex<Option<FrameVersion>>> = OnceLock::new();
+    VERSION.get_or_init(|| Mutex::new(None))
+}
+
+/// 最後に書き出した `current_frame.{png,webp,jpg}` を返す（まだなら保存ルートの PNG）。
+pub(crate) fn current_frame_path() -> AnyResult<PathBuf> {
+    if let Some(version) = lock_recover(current_frame_version()).as_ref() {
+        return Ok(version.path.clone());
+    }
+    let root = lock_recover(export_root_dir()).clone();
+    create_dir_all(&root)?;
+    Ok(current_frame_path_for(&root, FrameFormat::Png))
+}
+
+fn current_frame_path_for(dir: &Path, format: FrameFormat) -> PathBuf {
+    dir.join(format!("current_frame.{}", format.extension()))
+}
+
+/// 最後に書き出したフレームの版とファイルの中身（メモリから追い出されていれば None）
+pub(crate) fn current_frame_data() -> Option<(FrameVersion, Arc<[u8]>)> {
+    let version = lock_recover(current_frame_version()).clone()?;
+    let data = lock_recover(memory_cache())
+        .get(CURRENT_FRAME_KEY, Some(version.exported_at))?;
+    Some((version, data))
+}
+
+/// PNG を設定の圧縮レベルでエンコードする。
+pub(crate) fn encode_png(img: &image::RgbaImage) -> AnyResult<Vec<u8>> {
+    let compression = match *lock_recover(png_compression()) {
+        PngCompression::Fast => CompressionType::Fast,
+        PngCompression::Default => CompressionType::Default,
+        PngCompression::Best => CompressionType::Best,
+    };
+    let mut png = Vec::new();
+    PngEncoder::new_with_quality(&mut png, compression, PngFilterType::Adaptive).write_image(
+        img.as_raw(),
+        img.width(),
+        img.height(),
+        ExtendedColorType::Rgba8,
+    )?;
+    Ok(png)
+}
+
+/// PNG を設定の圧縮レベルで保存する（拡張子に関係なく PNG で書く）。
+pub(crate) fn save_png(img: &image::RgbaImage, path: &Path) -> AnyResult<()> {
+    write_atomic(path, &encode_png(img)?)
+}
+
+/// `{path}.part` に書いてから path に rename する。
+///
+/// 書き込み中に AviUtl2 が落ちても、path には前の内容か新しい内容のどちらかしか残らない。
+pub(crate) fn write_atomic(path: &Path, data: &[u8]) -> AnyResult<()> {
+    let mut part_name = path.as_os_str().to_owned();
+    part_name.push(".part");
+    let part_path = PathBuf::from(part_name);
+
+    let result = (|| -> AnyResult<()> {
+        File::create(&part_path)?.write_all(data)?;
+        Ok(())
+    })();
+    match result.and_then(|_| rename(&part_path, path).map_err(Into::into)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = remove_file(&part_path);
+            Err(e)
+        }
+    }
+}
+
+/// フレームを format の形式でエンコードする。
+pub(crate) fn encode_frame(img: &image::RgbaImage, format: FrameFormat) -> AnyResult<Vec<u8>> {
+    let (width, height) = img.dimensions();
+    let data = match format {
+        FrameFormat::Png => encode_png(img)?,
+        FrameFormat::WebpLossless => webp::Encoder::from_rgba(img.as_raw(), width, height)
+            .encode_lossless()
+            .to_vec(),
+        FrameFormat::Webp(quality) => webp::Encoder::from_rgba(img.as_raw(), width, height)
+            .encode(quality as f32)
+            .to_vec(),
+        FrameFormat::Jpeg(quality) => {
+            let rgb = image::DynamicImage::ImageRgba8(img.clone()).into_rgb8();
+            let mut jpeg = Vec::new();
+            image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, quality).encode_image(&rgb)?;
+            jpeg
+        }
+    };
+    Ok(data)
+}
+
+/// Web UI に渡すフレームを `current_frame.png`（設定によっては `.webp` / `.jpg`）として保存する。
+///
+/// ファイルの中身はメモリにも置いておき、GET /frame/current.png はそこから返す。
+/// 受信したマスクは、同じオブジェクトのために書き出したフレームのサイズに合わせて拡縮される。
+pub fn set_current_frame(img: &image::RgbaImage) -> AnyResult<PathBuf> {
+    let format = *lock_recover(frame_format());
+    let object_id = *lock_recover(current_edit_object_id());
+    let dir = match object_id {
+        Some(object_id) => export_dir_for_object(object_id),
+        None => lock_recover(export_root_dir()).clone(),
+    };
+    create_dir_all(&dir)?;
+    let frame_path = current_frame_path_for(&dir, format);
+    let data = encode_frame(img, format)?;
+    write_atomic(&frame_path, &data)?;
+    // 形式を切り替えたときに古い形式のファイルを読まれないよう消しておく
+    for other in [FrameFormat::Png, FrameFormat::WebpLossless, FrameFormat::Jpeg(0)] {
+        if other.extension() != format.extension() {
+            let _ = remove_file(current_frame_path_for(&dir, other));
+        }
+    }
+    *lock_recover(last_frame_size()) = Some(img.dimensions());
+    if let Some(object_id) = object_id {
+        set_frame_file_for_object(object_id, &frame_path, img.dimensions());
+    }
+
+    let mut hasher = DefaultHasher::new();
+    img.dimensions().hash(&mut hasher);
+    img.as_raw().hash(&mut hasher);
+    let etag = format!("\"{:016x}\"", hasher.finish());
+
+    // 前のフレームと同じ大きさなら、変わった範囲だけの PNG を用意しておく
+    let previous = lock_recover(memory_cache()).get(PREVIOUS_FRAME_KEY, None);
+    let base_etag = lock_recover(current_frame_version())
+        .as_ref()
+        .map(|v| v.etag.clone());
+    let delta = match (previous, base_etag) {
+        (Some(previous), Some(base_etag)) if previous.len() == img.as_raw().len() => {
+            frame_delta(&previous, img, base_etag).unwrap_or_else(|e| {
+                log::warn!("Frame delta skipped: {e:?}");
+                None
+            })
+        }
+        _ => None,
+    };
+    lock_recover(memory_cache())
+        .insert(PREVIOUS_FRAME_KEY, None, Arc::from(img.as_raw().as_slice()));
+
+    let exported_at = SystemTime::now();
+    let session = object_id.map(start_export_session);
+    lock_recover(memory_cache())
+        .insert(CURRENT_FRAME_KEY, Some(exported_at), Arc::from(data));
+    *lock_recover(current_frame_version()) = Some(FrameVersion {
+        etag: etag.clone(),
+        exported_at,
+        format,
+        path: frame_path.clone(),
+        session: session.clone(),
+        delta,
+    });
+    notify_frame_updated(&etag, exported_at, session.as_deref());
+    record_history(
+        HistoryKind::FrameExported,
+        object_id,
+        format!("{}x{} frame saved to {}", img.width(), img.height(), frame_path.display()),
+    );
+    Ok(frame_path)
+}
+
+/// フレームをシステムのクリップボードに画像としてコピーする。
+///
+/// 大きなフレームの変換で proc_video を待たせないよう、別スレッドで行う。
+pub(crate) fn copy_frame_to_clipboard(img: image::RgbaImage, object_id: i64) {
+    thread::spawn(move || {
+        let (width, height) = img.dimensions();
+        let result = arboard::Clipboard::new().and_then(|mut clipboard| {
+            clipboard.set_image(arboard::ImageData {
+                width: width as usize,
+                height: height as usize,
+                bytes: img.into_raw().into(),
+            })
+        });
+        match result {
+            Ok(()) => log::info!("Copied {}x{} frame to the clipboard", width, height),
+            Err(e) => {
+                log::error!("Failed to copy the frame to the clipboard: {e}");
+                record_history(
+                    HistoryKind::Error,
+                    Some(object_id),
+                    format!("failed to copy the frame to the clipboard: {e}"),
+                );
+            }
+        }
+    });
+}
+
+/// 前のフレーム（同じ大きさの RGBA）と比べて、変わった範囲の FrameDelta を作る。
+///
+/// 変わった範囲がフレーム全体なら差分の意味が無いので None を返す。
+fn frame_delta(previous: &[u8], img: &image::RgbaImage, base_etag: String) -> AnyResult<Option<FrameDelta>> {
+    let Some((x, y, width, height)) = changed_region(previous, img) else {
+        return Ok(None);
+    };
+    if (width, height) == img.dimensions() {
+        return Ok(None);
+    }
+
+    let region = image::imageops::crop_imm(img, x, y, width, height).to_image();
+    let png = encode_png(&region)?;
+    Ok(Some(FrameDelta {
+        base_etag,
+        x,
+        y,
+        width,
+        height,
+        png: png.into(),
+    }))
+}
+
+/// 2 枚の RGBA（同じ大きさ）で画素が違う範囲の外接矩形 (x, y, 幅, 高さ)。同じなら None。
+pub(crate) fn changed_region(previous: &[u8], img: &image::RgbaImage) -> Option<(u32, u32, u32, u32)> {
+    let width = img.width() as usize;
+    let row_bytes = width * 4;
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (usize::MAX, usize::MAX, 0, 0);
+    for (y, (prev_row, row)) in previous
+        .chunks_exact(row_bytes)
+        .zip(img.as_raw().chunks_exact(row_bytes))
+        .enumerate()
+    {
+        if prev_row == row {
+            continue;
+        }
+        let first = (0..width).find(|&x| prev_row[x * 4..x * 4 + 4] != row[x * 4..x * 4 + 4])?;
+        let last = (0..width)
+            .rev()
+            .find(|&x| prev_row[x * 4..x * 4 + 4] != row[x * 4..x * 4 + 4])?;
+        min_x = min_x.min(first);
+        max_x = max_x.max(last);
+        min_y = min_y.min(y);
+        max_y = max_y.max(y);
+    }
+    if min_y == usize::MAX {
+        return None;
+    }
+    Some((
+        min_x as u32,
+        min_y as u32,
+        (max_x - min_x + 1) as u32,
+        (max_y - min_y + 1) as u32,
+    ))
+}
+
+/// "frame_000012.png" や "12.png" のような名前からフレーム番号を取り出す。
+///
+/// 拡張子を除いた末尾の数字列をフレーム番号とみなす。
+pub(crate) fn frame_number_from_name(name: &str) -> Option<i32> {
+    let file_name = name.rsplit(['/', '\\']).next()?;
+    let stem = file_name.split_once('.').map_or(file_name, |(stem, _)| stem);
+    let digits_start = stem
+        .rfind(|c: char| !c.is_ascii_digit())
+        .map_or(0, |i| i + 1);
+    stem[digits_start..].parse().ok()
+}
+
+/// YCbCr の変換行列（輝度の係数 Kr, Kb）
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum YcbcrMatrix {
+    Bt601,
+    Bt709,
+}
+
+impl YcbcrMatrix {
+    fn kr_kb(self) -> (f32, f32) {
+        match self {
+            Self::Bt601 => (0.299, 0.114),
+            Self::Bt709 => (0.2126, 0.0722),
+        }
+    }
+
+    /// RGB → YCbCr（Cb, Cr は -0.5〜0.5）
+    fn to_ycbcr(self) -> [[f32; 3]; 3] {
+        let (kr, kb) = self.kr_kb();
+        let kg = 1.0 - kr - kb;
+        [
+            [kr, kg, kb],
+            [-kr / (2.0 * (1.0 - kb)), -kg / (2.0 * (1.0 - kb)), 0.5],
+            [0.5, -kg / (2.0 * (1.0 - kr)), -kb / (2.0 * (1.0 - kr))],
+        ]
+    }
+
+    /// YCbCr → RGB
+    fn to_rgb(self) -> [[f32; 3]; 3] {
+        let (kr, kb) = self.kr_kb();
+        let kg = 1.0 - kr - kb;
+        [
+            [1.0, 0.0, 2.0 * (1.0 - kr)],
+            [1.0, -2.0 * kb * (1.0 - kb) / kg, -2.0 * kr * (1.0 - kr) / kg],
+            [1.0, 2.0 * (1.0 - kb), 0.0],
+        ]
+    }
+}
+
+fn mat_mul(a: [[f32; 3]; 3], b: [[f32; 3]; 3]) -> [[f32; 3]; 3] {
+    let mut out = [[0.0; 3]; 3];
+    for (i, row) in out.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    out
+}
+
+/// 書き出すフレームの色の補正。
+///
+/// AviUtl2 から受け取る RGB が、元動画と違う行列・レンジで YCbCr から変換されていた場合に
+/// 正しい RGB に戻す（例: BT.709 の動画が BT.601 として変換されて色がずれる）。
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct ColorCorrection {
+    /// (変換に使われた行列, 本来の行列)。None なら行列は補正しない
+    pub(crate) matrix: Option<(YcbcrMatrix, YcbcrMatrix)>,
+    /// RGB が 16〜235 のリミテッドレンジに収まっていれば 0〜255 に伸ばす
+    pub(crate) expand_range: bool,
+}
+
+impl ColorCorrection {
+    pub(crate) fn is_identity(&self) -> bool {
+        self.matrix.is_none_or(|(from, to)| from == to) && !self.expand_range
+    }
+
+    /// RGBA8 のバイト列をその場で補正する（アルファはそのまま）。
+    pub(crate) fn apply(&self, rgba: &mut [u8]) {
+        if self.is_identity() {
+            return;
+        }
+
+        // 行列の補正は「使われた行列で YCbCr に戻し、本来の行列で RGB にする」の合成
+        let m = match self.matrix {
+            Some((from, to)) => mat_mul(to.to_rgb(), from.to_ycbcr()),
+            None => [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+        };
+        let (offset, gain) = if self.expand_range {
+            (16.0, 255.0 / 219.0)
+        } else {
+            (0.0, 1.0)
+        };
+
+        for px in rgba.chunks_exact_mut(4) {
+            let c = [px[0] as f32, px[1] as f32, px[2] as f32].map(|v| (v - offset) * gain);
+            for (i, row) in m.iter().enumerate() {
+                let v = row[0] * c[0] + row[1] * c[1] + row[2] * c[2];
+                px[i] = v.round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+}
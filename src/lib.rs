@@ -1,8 +1,19 @@
+pub mod cache;
+pub mod config;
+pub mod export;
+pub mod frame;
+#[cfg(feature = "native-sam")]
+pub mod sam;
+pub mod server;
+pub mod state;
+
 use aviutl2::{
     AnyResult,
     filter::{
+        FilterConfigColorValue,
         FilterConfigItems,
         FilterConfigItem,
+        FilterConfigSelectItems,
         FilterPlugin,
         FilterPluginTable,
         FilterProcVideo,
@@ -10,70 +21,222 @@ use aviutl2::{
     },
 };
 use chrono::{Datelike, Local, Timelike};
+use config::remember_export_root;
 use env_logger::{Builder, Env, Target};
+use export::{mask_alpha_for_size, record_frame_export};
+use frame::{
+    ColorCorrection, SequenceFrame, YcbcrMatrix, copy_frame_to_clipboard, sequence_dir,
+    sequence_frame_name, sequence_writer, set_current_frame,
+};
+use server::{
+    install_embedded_web_assets, open_browser_if_needed, open_browser_now, server_port, start_http_server_once,
+    update_instances_file, verify_web_assets, web_root, web_root_installed,
+};
+use state::{
+    AlphaCombine, FrameFormat, HistoryKind, MaskSettings, ObjectFrameInfo, PngCompression,
+    SceneCrop, SceneFormat, ShadowSettings, alpha_combine_for_object, auto_open_browser,
+    ffmpeg_path, frame_format, last_scene_format, lock_recover, mask_sequences, mask_settings,
+    note_applied_mask, object_states, png_compression, record_history, selected_mask_for_object,
+    set_edit_object, set_export_root,
+};
 use std::{
-    collections::HashMap,
-    fs::{File, create_dir_all, read, write},
-    io::{Read, Write},
-    net::{TcpListener, TcpStream},
-    path::PathBuf,
-    process::Command,
-    sync::{Mutex, Once, OnceLock},
-    thread,
+    fs::{File, create_dir_all, read_dir, remove_file},
+    path::{Path, PathBuf},
+    sync::Once,
 };
 
-/// ロガー初期化（1プロセスにつき1回）
-#[cfg(debug_assertions)]
+/// ロガー初期化（1プロセスにつき1回）。
+///
+/// デバッグビルドは常に、リリースビルドは環境変数 SAM_LOG か config.toml の log_level で
+/// ログレベルを指定したときだけ、AviUtl2 の Log フォルダにログファイルを作る。
 fn init_logger() {
     static INIT: Once = Once::new();
 
     INIT.call_once(|| {
-        let log_dir = r"C:\ProgramData\aviutl2\Log";
+        let level = config::log_level_setting();
+        // リリース版は指定が無ければ何もしない（ログファイルも作らない）
+        if !cfg!(debug_assertions) && level.is_none() {
+            return;
+        }
+
+        let log_dir = config::aviutl2_dir().join("Log");
 
-        if let Err(e) = create_dir_all(log_dir) {
-            eprintln!("failed to create log directory {}: {e}", log_dir);
+        if let Err(e) = create_dir_all(&log_dir) {
+            eprintln!("failed to create log directory {}: {e}", log_dir.display());
             return;
         }
 
+        let (max_files, max_bytes) = config::log_retention_setting();
+        // これから作る 1 つの分を空けておく
+        prune_old_logs(&log_dir, max_files.saturating_sub(1), max_bytes);
+
         let now = Local::now();
         let filename = format!(
-            "sam_frame_export_{:04}_{:02}_{:02}_{:02}_{:02}.log",
+            "{LOG_FILE_PREFIX}{:04}_{:02}_{:02}_{:02}_{:02}{LOG_FILE_SUFFIX}",
             now.year(),
             now.month(),
             now.day(),
             now.hour(),
             now.minute(),
         );
-        let log_path = format!(r"{}\{}", log_dir, filename);
+        let log_path = log_dir.join(filename);
 
         let file = match File::create(&log_path) {
             Ok(f) => f,
             Err(e) => {
-                eprintln!("failed to create log file at {}: {e}", log_path);
+                eprintln!("failed to create log file at {}: {e}", log_path.display());
                 return;
             }
         };
 
-        let _ = Builder::from_env(Env::default().default_filter_or("debug"))
-            .target(Target::Pipe(Box::new(file)))
-            .try_init();
+        let mut builder = Builder::from_env(Env::default().default_filter_or("debug"));
+        if let Some(level) = &level {
+            builder.parse_filters(level);
+        }
+        let _ = builder.target(Target::Pipe(Box::new(file))).try_init();
     });
 }
 
-#[cfg(not(debug_assertions))]
-fn init_logger() {
-    // リリース版では何もしない（ログファイルも作らない）
+/// 動作ログのファイル名の先頭と末尾
+const LOG_FILE_PREFIX: &str = "sam_frame_export_";
+const LOG_FILE_SUFFIX: &str = ".log";
+
+/// dir の動作ログを古いものから消し、max_files 個以下・合計 max_bytes 以下にする。
+///
+/// ファイル名は日時を 0 埋めで並べたものなので、名前順がそのまま作った順になる。
+/// 消せなかったもの（別の AviUtl2 が書き込み中など）は飛ばす。
+pub(crate) fn prune_old_logs(dir: &Path, max_files: usize, max_bytes: u64) {
+    let Ok(entries) = read_dir(dir) else {
+        return;
+    };
+    let mut logs: Vec<(String, u64)> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            if !name.starts_with(LOG_FILE_PREFIX) || !name.ends_with(LOG_FILE_SUFFIX) {
+                return None;
+            }
+            let len = entry.metadata().ok().filter(|m| m.is_file())?.len();
+            Some((name, len))
+        })
+        .collect();
+    // 新しい順
+    logs.sort_by(|a, b| b.0.cmp(&a.0));
+
+    let mut kept_files = 0;
+    let mut kept_bytes = 0u64;
+    for (name, len) in logs {
+        if kept_files < max_files && kept_bytes.saturating_add(len) <= max_bytes {
+            kept_files += 1;
+            kept_bytes += len;
+            continue;
+        }
+        let path = dir.join(&name);
+        if let Err(e) = remove_file(&path) {
+            eprintln!("failed to remove old log file {}: {e}", path.display());
+        }
+    }
+}
+
+/// 設定画面の「元のアルファとの合成」の選択肢
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FilterConfigSelectItems)]
+enum AlphaCombineItem {
+    #[item(name = "置き換え（SAMのマスクのみ）")]
+    Replace,
+    #[item(name = "共通部分")]
+    Intersect,
+    #[item(name = "和集合")]
+    Union,
+}
+
+impl From<AlphaCombineItem> for AlphaCombine {
+    fn from(item: AlphaCombineItem) -> Self {
+        match item {
+            AlphaCombineItem::Replace => AlphaCombine::Replace,
+            AlphaCombineItem::Intersect => AlphaCombine::Intersect,
+            AlphaCombineItem::Union => AlphaCombine::Union,
+        }
+    }
+}
+
+/// 設定画面の「フレームの書き出し形式」の選択肢
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FilterConfigSelectItems)]
+enum FrameFormatItem {
+    #[item(name = "PNG")]
+    Png,
+    #[item(name = "WebP（ロスレス）")]
+    WebpLossless,
+    #[item(name = "WebP（品質指定）")]
+    Webp,
+    #[item(name = "JPEG（品質指定）")]
+    Jpeg,
+}
+
+/// 設定画面の「PNG の圧縮」の選択肢
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FilterConfigSelectItems)]
+enum PngCompressionItem {
+    #[item(name = "速さ優先")]
+    Fast,
+    #[item(name = "標準")]
+    Default,
+    #[item(name = "サイズ優先")]
+    Best,
+}
+
+impl From<PngCompressionItem> for PngCompression {
+    fn from(item: PngCompressionItem) -> Self {
+        match item {
+            PngCompressionItem::Fast => PngCompression::Fast,
+            PngCompressionItem::Default => PngCompression::Default,
+            PngCompressionItem::Best => PngCompression::Best,
+        }
+    }
 }
 
+/// 設定画面の「色の補正」の選択肢
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FilterConfigSelectItems)]
+enum ColorMatrixItem {
+    #[item(name = "補正しない")]
+    None,
+    #[item(name = "BT.709 の素材が BT.601 で変換されている")]
+    Bt709AsBt601,
+    #[item(name = "BT.601 の素材が BT.709 で変換されている")]
+    Bt601AsBt709,
+}
 
 /// フィルタの設定項目。
 ///
 /// run_sam: このフレームを SAM で前景抽出
 /// output_file の親ディレクトリを保存先ルートとして使う。
+/// mask_supersample: マスクをフレームサイズに拡縮するときの内部倍率（1 で無効）
+/// export_outline: マスクの輪郭線だけを描いた PNG（`*_outline.png`）も保存する
+/// export_shadow: ぼかしてずらした黒いシルエット（`*_shadow.png`）も保存する
+/// mask_feather: 受け取ったマスクのアルファをこの半径でぼかしてから保存する（0 で無効）
+/// mask_grow: 受け取ったマスクをこの画素数だけ膨張（正）・収縮（負）させてから保存する
+/// export_exo: 保存したマスクを画像ファイルオブジェクトとして置く `{マスク名}.exo` も書き出す
+/// open_browser: フレームを書き出したときにブラウザを開く（オフなら URL をログと /status に出すだけ）
+/// open_web_ui: チェックを入れるたびに Web UI をブラウザで開く（タブを閉じてしまったとき用）
+/// follow_frame: run_sam がオンの間、表示中のフレームが変わるたびに書き出し直す
+/// invert_mask: 受け取ったマスクのアルファを反転して背景だけを残す（保存するマスク・適用の両方）
+/// frame_format / frame_quality: current_frame の形式（PNG / WebP / JPEG）と、WebP・JPEG の品質
+/// png_compression: 書き出す PNG（フレーム・連番・マスク）の圧縮レベル
+/// mask_name_template: マスクのファイル名テンプレート（空なら既定の名前。`{project}` は保存先フォルダ名）
+/// object_folders: フレームとマスクを保存先直下ではなく `object_{id}/` サブフォルダに保存する
+/// apply_label: このオブジェクトに使うマスクのラベル（空なら最後に受け取ったもの）
+/// alpha_combine: 受け取ったマスクとオブジェクトが元から持つアルファの合成方法
+/// session_tag: 受け取ったマスクのファイル名とマニフェストに入れる任意の文字列（"take A" など）
+/// sequential_names: マスクを日時ではなくオブジェクトごとの通し番号（`mask_obj{ID}_0001.png`）で保存する
+/// export_sequence: 描画されたフレームを `sam_seq_{object_id}/frame_NNNNNN.png` に連番で書き出す
+/// sequence_start / sequence_end: 連番にするオブジェクト内フレームの範囲（終了 0 で最後まで）
+/// ffmpeg_file: 連番マスクを透過動画にするときの ffmpeg（未指定なら PATH 上のもの）
+/// color_matrix / expand_range: 書き出すフレームの YCbCr 行列・レンジのずれを補正する
+/// export_scene: フィルタオブジェクトとして置き、合成済みのシーン全体を SAM に渡す
+/// scene_crop_*: シーン全体のマスクから切り出す対象オブジェクトの範囲
+///   （X/Y は標準描画と同じ画面中央基準の中心座標、幅 0 なら切り出さない）
 #[derive(Debug, Clone, PartialEq, FilterConfigItems)]
 struct FilterConfig {
     #[check(
-        name = "※ ブラウザでhttp://127.0.0.1:17860/を開いて下さい",
+        name = "※ Web UI は「Web UI を開く」で開けます（使用中のポートはログと instances.json に出ます）",
         default = false
     )]
     _hint_open_web_ui: bool,
@@ -88,43 +251,275 @@ struct FilterConfig {
         }
     )]
     output_file: Option<PathBuf>,
-}
 
-/// デフォルトの出力先 (AviUtl2 標準の Export フォルダ)
-const EXPORT_DIR: &str = r"C:\ProgramData\aviutl2\Export";
-/// 現在の保存ルートディレクトリ
-/// 既定値: EXPORT_DIR
-/// ユーザーが #[file] で何かファイルを選んだら、その親ディレクトリに更新
-fn export_root_dir() -> &'static Mutex<PathBuf> {
-    static EXPORT_ROOT_DIR: OnceLock<Mutex<PathBuf>> = OnceLock::new();
-    EXPORT_ROOT_DIR.get_or_init(|| Mutex::new(PathBuf::from(EXPORT_DIR)))
+    #[track(
+        name = "マスク拡縮のスーパーサンプリング倍率",
+        range = 1.0..=4.0,
+        step = 1.0,
+        default = 2.0
+    )]
+    mask_supersample: f64,
+
+    #[check(name = "輪郭線のみの画像も書き出す", default = false)]
+    export_outline: bool,
+
+    #[track(name = "輪郭線の太さ", range = 1.0..=50.0, step = 1.0, default = 4.0)]
+    outline_width: f64,
+
+    #[color(name = "輪郭線の色", default = 0xffffff)]
+    outline_color: FilterConfigColorValue,
+
+    #[check(name = "ドロップシャドウ用の影画像も書き出す", default = false)]
+    export_shadow: bool,
+
+    #[track(name = "影のぼかし", range = 0.0..=50.0, step = 1.0, default = 8.0)]
+    shadow_blur: f64,
+
+    #[track(name = "影のずれ X", range = -200.0..=200.0, step = 1.0, default = 8.0)]
+    shadow_offset_x: f64,
+
+    #[track(name = "影のずれ Y", range = -200.0..=200.0, step = 1.0, default = 8.0)]
+    shadow_offset_y: f64,
+
+    #[track(name = "影の濃さ", range = 0.0..=100.0, step = 1.0, default = 60.0)]
+    shadow_opacity: f64,
+
+    #[string(name = "適用するマスクのラベル", default = "")]
+    apply_label: String,
+
+    #[select(name = "元のアルファとの合成", items = AlphaCombineItem, default = AlphaCombineItem::Replace)]
+    alpha_combine: AlphaCombineItem,
+
+    #[string(name = "セッションタグ（ファイル名に付加）", default = "")]
+    session_tag: String,
+
+    #[check(name = "マスクを連番のファイル名で保存（mask_obj{ID}_0001.png…）", default = false)]
+    sequential_names: bool,
+
+    #[check(name = "全フレームを連番PNGで書き出し（再生して書き出す）", default = false)]
+    export_sequence: bool,
+
+    #[track(name = "連番の開始フレーム", range = 0.0..=99999.0, step = 1.0, default = 0.0)]
+    sequence_start: f64,
+
+    #[track(name = "連番の終了フレーム（0で最後まで）", range = 0.0..=99999.0, step = 1.0, default = 0.0)]
+    sequence_end: f64,
+
+    #[file(
+        name = "ffmpeg の実行ファイル",
+        filters = {
+            "実行ファイル" => ["exe"],
+        }
+    )]
+    ffmpeg_file: Option<PathBuf>,
+
+    #[select(name = "色の補正（YCbCr 行列）", items = ColorMatrixItem, default = ColorMatrixItem::None)]
+    color_matrix: ColorMatrixItem,
+
+    #[check(name = "リミテッドレンジ（16-235）をフルレンジに伸ばす", default = false)]
+    expand_range: bool,
+
+    #[check(name = "シーン全体を書き出す（フィルタオブジェクトとして使用）", default = false)]
+    export_scene: bool,
+
+    #[track(name = "切り出すオブジェクトのX", range = -9999.0..=9999.0, step = 1.0, default = 0.0)]
+    scene_crop_x: f64,
+
+    #[track(name = "切り出すオブジェクトのY", range = -9999.0..=9999.0, step = 1.0, default = 0.0)]
+    scene_crop_y: f64,
+
+    #[track(name = "切り出すオブジェクトの幅（0で切り出さない）", range = 0.0..=9999.0, step = 1.0, default = 0.0)]
+    scene_crop_width: f64,
+
+    #[track(name = "切り出すオブジェクトの高さ", range = 0.0..=9999.0, step = 1.0, default = 0.0)]
+    scene_crop_height: f64,
+
+    #[check(name = "書き出したフレームをクリップボードにもコピー", default = false)]
+    copy_to_clipboard: bool,
+
+    #[check(name = "受け取ったマスクをこのオブジェクトに適用", default = false)]
+    apply_mask: bool,
+
+    #[track(name = "マスク境界のぼかし（フェザー）", range = 0.0..=50.0, step = 0.5, default = 0.0)]
+    mask_feather: f64,
+
+    #[track(name = "マスクの膨張・収縮（px、マイナスで収縮）", range = -50.0..=50.0, step = 1.0, default = 0.0)]
+    mask_grow: f64,
+
+    #[check(name = "マスクを読み込む .exo も書き出す", default = false)]
+    export_exo: bool,
+
+    #[check(name = "ブラウザを自動で開く", default = true)]
+    open_browser: bool,
+
+    #[check(name = "Web UI を開く（チェックを入れるたびに開く）", default = false)]
+    open_web_ui: bool,
+
+    #[check(name = "フレームが変わったら再抽出", default = false)]
+    follow_frame: bool,
+
+    #[check(name = "マスクを反転（背景を残す）", default = false)]
+    invert_mask: bool,
+
+    #[select(name = "フレームの書き出し形式", items = FrameFormatItem, default = FrameFormatItem::Png)]
+    frame_format: FrameFormatItem,
+
+    #[track(name = "WebP / JPEG の品質", range = 1.0..=100.0, step = 1.0, default = 90.0)]
+    frame_quality: f64,
+
+    #[select(name = "PNG の圧縮", items = PngCompressionItem, default = PngCompressionItem::Fast)]
+    png_compression: PngCompressionItem,
+
+    #[string(name = "マスクのファイル名（{project} {object_id} {frame} {timestamp} など）", default = "")]
+    mask_name_template: String,
+
+    #[check(name = "オブジェクトごとのフォルダ（object_{ID}）に保存", default = false)]
+    object_folders: bool,
 }
 
 /// プロジェクト内でSAMで切り抜いた背景の保存先を統一する
 fn update_export_root_from_config(config: &FilterConfig) {
-    if let Some(selected) = &config.output_file {
-        if let Some(parent) = selected.parent() {
-            let mut root = export_root_dir().lock().unwrap();
-            *root = parent.to_path_buf();
-            log::info!("Export root changed to {}", root.display());
+    if let Some(selected) = &config.output_file
+        && let Some(parent) = selected.parent()
+    {
+        set_export_root(parent.to_path_buf());
+        remember_export_root(parent);
+    }
+}
+
+fn update_mask_settings_from_config(config: &FilterConfig) {
+    let rgb = config.outline_color.0;
+    let new_settings = MaskSettings {
+        supersample: config.mask_supersample.round().clamp(1.0, 4.0) as u32,
+        outline: config.export_outline.then_some((
+            config.outline_width as f32,
+            [(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8],
+        )),
+        shadow: config.export_shadow.then(|| ShadowSettings {
+            blur: config.shadow_blur as f32,
+            offset: (
+                config.shadow_offset_x.round() as i32,
+                config.shadow_offset_y.round() as i32,
+            ),
+            opacity: (config.shadow_opacity / 100.0) as f32,
+        }),
+        feather: config.mask_feather.max(0.0) as f32,
+        grow: config.mask_grow.round() as i32,
+        export_exo: config.export_exo,
+        invert: config.invert_mask,
+    };
+
+    let mut settings = lock_recover(mask_settings());
+    if *settings != new_settings {
+        log::info!("Mask settings changed to {:?}", new_settings);
+        *settings = new_settings;
+    }
+}
+
+fn update_ffmpeg_path_from_config(config: &FilterConfig) {
+    if let Some(selected) = &config.ffmpeg_file {
+        let mut path = lock_recover(ffmpeg_path());
+        if *path != *selected {
+            *path = selected.clone();
+            log::info!("ffmpeg path changed to {}", path.display());
         }
     }
 }
 
-/// SAMの起動を確かめるグローバルなオブジェクト状態テーブル
-fn object_states() -> &'static Mutex<HashMap<i64, ObjectState>> {
-    static STATES: OnceLock<Mutex<HashMap<i64, ObjectState>>> = OnceLock::new();
-    STATES.get_or_init(|| Mutex::new(HashMap::new()))
+/// シーン全体を書き出すとき、マスクから切り出すオブジェクトの範囲（シーンの画素座標）
+fn scene_crop_from_config(config: &FilterConfig, scene: &SceneFormat) -> Option<SceneCrop> {
+    if !config.export_scene || config.scene_crop_width < 1.0 || config.scene_crop_height < 1.0 {
+        return None;
+    }
+    let width = config.scene_crop_width.round() as u32;
+    let height = config.scene_crop_height.round() as u32;
+    // 標準描画の座標は画面中央が原点、オブジェクトの中心を指す
+    Some(SceneCrop {
+        x: (scene.width as f64 / 2.0 + config.scene_crop_x - width as f64 / 2.0).round() as i32,
+        y: (scene.height as f64 / 2.0 + config.scene_crop_y - height as f64 / 2.0).round() as i32,
+        width,
+        height,
+    })
+}
+
+fn color_correction_from_config(config: &FilterConfig) -> ColorCorrection {
+    ColorCorrection {
+        matrix: match config.color_matrix {
+            ColorMatrixItem::None => None,
+            ColorMatrixItem::Bt709AsBt601 => Some((YcbcrMatrix::Bt601, YcbcrMatrix::Bt709)),
+            ColorMatrixItem::Bt601AsBt709 => Some((YcbcrMatrix::Bt709, YcbcrMatrix::Bt601)),
+        },
+        expand_range: config.expand_range,
+    }
 }
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-struct ObjectState {
-    last_run_sam: bool,
+
+fn frame_format_from_config(config: &FilterConfig) -> FrameFormat {
+    let quality = config.frame_quality.round().clamp(1.0, 100.0) as u8;
+    match config.frame_format {
+        FrameFormatItem::Png => FrameFormat::Png,
+        FrameFormatItem::WebpLossless => FrameFormat::WebpLossless,
+        FrameFormatItem::Webp => FrameFormat::Webp(quality),
+        FrameFormatItem::Jpeg => FrameFormat::Jpeg(quality),
+    }
 }
 
-/// Web UI のルートディレクトリ
-const WEB_ROOT: &str =
-    r"C:\ProgramData\aviutl2\Plugin\sam_frame_export_filter\web";
+/// 連番書き出しの対象フレームなら、現在フレームをバックグラウンドで保存する
+fn export_sequence_frame(
+    config: &FilterConfig,
+    video: &mut FilterProcVideo,
+) -> AnyResult<()> {
+    let object_id = video.object.id;
+    let frame = video.object.frame;
+    let last = video.object.frame_total - 1;
+    let start = (config.sequence_start.round() as i32).min(last);
+    let end = match config.sequence_end.round() as i32 {
+        0 => last,
+        e => e.min(last),
+    };
+
+    let (is_new, done) = {
+        let mut states = lock_recover(object_states());
+        let state = states.entry(object_id).or_default();
+        // チェックを入れ直したら最初から書き出し直す
+        if !state.last_export_sequence {
+            state.sequence_written.clear();
+        }
+        state.last_export_sequence = true;
+
+        if frame < start || frame > end {
+            return Ok(());
+        }
+        let is_new = state.sequence_written.insert(frame);
+        (is_new, state.sequence_written.len() as i32 == end - start + 1)
+    };
+    if !is_new {
+        return Ok(());
+    }
 
+    let (width, height, rgba_bytes) =
+        get_rgba_frame_from_video(video, color_correction_from_config(config))?;
+    let image = image::RgbaImage::from_vec(width, height, rgba_bytes)
+        .ok_or_else(|| anyhow::anyhow!("RGBA buffer size mismatch: {}x{}", width, height))?;
+    let dir = sequence_dir(object_id);
+    let path = dir.join(sequence_frame_name(frame));
+    lock_recover(sequence_writer())
+        .send(SequenceFrame { path, image })?;
+
+    if done {
+        log::info!(
+            "Sequence export of object {} finished ({} frames in {})",
+            object_id,
+            end - start + 1,
+            dir.display()
+        );
+        record_history(
+            HistoryKind::SequenceExported,
+            Some(object_id),
+            format!("frames {}-{} exported to {}", start, end, dir.display()),
+        );
+    }
+    Ok(())
+}
 
 #[aviutl2::plugin(FilterPlugin)]
 struct SamFrameExportFilter;
@@ -134,6 +529,28 @@ impl FilterPlugin for SamFrameExportFilter {
     fn new(_info: aviutl2::AviUtl2Info) -> AnyResult<Self> {
         init_logger();
         log::info!("SamFrameExportFilter::new - plugin initialized");
+        config::init_config_once();
+        let install_result = install_embedded_web_assets();
+        match &install_result {
+            Ok(files) if !files.is_empty() => {
+                log::info!("Installed the bundled Web UI into {}: {}", web_root().display(), files.join(", "));
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!(
+                "Failed to install the bundled Web UI into {}; the setup page will be served instead: {e:#}",
+                web_root().display()
+            ),
+        }
+        if web_root_installed() {
+            for (rel, problem) in verify_web_assets() {
+                log::warn!("Web UI asset {} is {}; POST /api/repair to fix", rel, problem);
+            }
+        } else if install_result.is_ok() {
+            log::warn!(
+                "Web UI assets not found in {}; the embedded copy will be served instead",
+                web_root().display()
+            );
+        }
         Ok(Self)
     }
 
@@ -146,7 +563,8 @@ impl FilterPlugin for SamFrameExportFilter {
                 env!("CARGO_PKG_VERSION")
             ),
             filter_type: aviutl2::filter::FilterType::Video,
-            as_object: false,
+            // シーン全体の書き出し用に、フィルタオブジェクトとしても置けるようにする
+            as_object: true,
             config_items: FilterConfig::to_config_items(),
         }
     }
@@ -162,29 +580,62 @@ impl FilterPlugin for SamFrameExportFilter {
         let config = FilterConfig::from_config_items(config_items);
 
         update_export_root_from_config(&config);
+        update_mask_settings_from_config(&config);
+        update_ffmpeg_path_from_config(&config);
+        *lock_recover(auto_open_browser()) = config.open_browser;
+        *lock_recover(frame_format()) = frame_format_from_config(&config);
+        *lock_recover(png_compression()) = config.png_compression.into();
+        let scene = SceneFormat {
+            width: video.scene.width,
+            height: video.scene.height,
+            rate: video.scene.rate,
+            scale: video.scene.scale,
+        };
+        *lock_recover(last_scene_format()) = Some(scene);
 
         let object_id = video.object.id; // ObjectInfo.id (i64)
 
         // 編集中オブジェクト ID を更新
-        {
-        let mut edit = current_edit_object_id().lock().unwrap();
-        *edit = Some(object_id);
-        }
+        set_edit_object(object_id);
 
         // ── オブジェクトごとの run_sam の立ち上がりを検出 ──
         // run_sam チェックを入れた瞬間のフレームだけ should_export == trueになる
+        // （follow_frame がオンなら、フレームが変わったときも）
+        let mut open_web_ui = false;
         let should_export = {
             let states_mutex = object_states();
-            let mut states = states_mutex.lock().unwrap();
-            let state = states
-                .entry(object_id)
-                .or_insert(ObjectState { last_run_sam: false });
+            let mut states = lock_recover(states_mutex);
+            let state = states.entry(object_id).or_default();
+            state.apply_label = config.apply_label.trim().to_string();
+            state.session_tag = config.session_tag.trim().to_string();
+            state.sequential_names = config.sequential_names;
+            state.name_template = config.mask_name_template.trim().to_string();
+            state.object_folders = config.object_folders;
+            state.alpha_combine = config.alpha_combine.into();
+            state.scene_crop = scene_crop_from_config(&config, &scene);
+
+            if config.open_web_ui && !state.last_open_web_ui {
+                open_web_ui = true;
+            }
+            state.last_open_web_ui = config.open_web_ui;
 
             let rising_edge = config.run_sam && !state.last_run_sam;
             state.last_run_sam = config.run_sam;
-            rising_edge // Should exportの返り値
+            // 「フレームが変わったら再抽出」なら、前回書き出したフレームと違うときも書き出す
+            let frame_changed = config.run_sam
+                && config.follow_frame
+                && state.exported_frame.map(|info| info.frame) != Some(video.object.frame);
+            rising_edge || frame_changed // Should exportの返り値
         };
 
+        // 「Web UI を開く」を入れた瞬間だけブラウザを開く
+        if open_web_ui {
+            start_http_server_once().inspect_err(|e| {
+                record_history(HistoryKind::Error, Some(object_id), format!("{e:#}"))
+            })?;
+            open_browser_now();
+        }
+
         // 立ち上がりのときだけ current_frame.png を書き出し、
         // Web UI を起動する。
         if should_export {
@@ -193,8 +644,22 @@ impl FilterPlugin for SamFrameExportFilter {
                 object_id
             );
 
+            if config.export_scene
+                && (video.video_object.width, video.video_object.height) != (scene.width, scene.height)
+            {
+                log::warn!(
+                    "Scene export is on but the image is {}x{}, not the scene size; place this filter as a filter object",
+                    video.video_object.width,
+                    video.video_object.height
+                );
+            }
+
             // 1) 現在フレームを RGBA で取得
-            let (width, height, rgba_bytes) = get_rgba_frame_from_video(video)?;
+            let correction = color_correction_from_config(&config);
+            let (width, height, rgba_bytes) = get_rgba_frame_from_video(video, correction)
+                .inspect_err(|e| {
+                    record_history(HistoryKind::Error, Some(object_id), format!("frame capture failed: {e:#}"))
+                })?;
 
             log::debug!(
                 "SamFrameExportFilter::proc_video - frame size: {}x{} ({} bytes)",
@@ -206,22 +671,52 @@ impl FilterPlugin for SamFrameExportFilter {
             let img = image::RgbaImage::from_vec(width, height, rgba_bytes)
                 .ok_or_else(|| anyhow::anyhow!("RGBA buffer size mismatch: {}x{}", width, height))?;
 
-            // 2) 固定ファイル名 current_frame.png に上書き保存
-            let png_path = current_frame_png_path()?;
+            // 2) 固定ファイル名 current_frame.png（.webp / .jpg）に上書き保存
+            let png_path = set_current_frame(&img).inspect_err(|e| {
+                record_history(HistoryKind::Error, Some(object_id), format!("frame export failed: {e:#}"))
+            })?;
             log::info!(
-                "SamFrameExportFilter::proc_video - saving PNG to {}",
+                "SamFrameExportFilter::proc_video - PNG saved to {}",
                 png_path.display()
             );
-            img.save(&png_path)?;
+            if config.copy_to_clipboard {
+                copy_frame_to_clipboard(img.clone(), object_id);
+            }
 
-            log::info!("SamFrameExportFilter::proc_video - PNG saved");
+            // 受信したマスクのマニフェストに残すオブジェクト情報
+            if let Some(state) = lock_recover(object_states()).get_mut(&object_id) {
+                state.exported_frame = Some(ObjectFrameInfo {
+                    frame: video.object.frame,
+                    frame_total: video.object.frame_total,
+                    time: video.object.time,
+                    time_total: video.object.time_total,
+                    width: video.video_object.width,
+                    height: video.video_object.height,
+                    scene,
+                });
+            }
+            record_frame_export(object_id, video.object.frame, &png_path);
 
             // 3) HTTP サーバーとブラウザを起動
-            start_http_server_once();
-            open_browser_once();
+            start_http_server_once().inspect_err(|e| {
+                record_history(HistoryKind::Error, Some(object_id), format!("{e:#}"))
+            })?;
+            open_browser_if_needed();
+        }
+
+        if config.export_sequence {
+            export_sequence_frame(&config, video).inspect_err(|e| {
+                record_history(HistoryKind::Error, Some(object_id), format!("sequence export failed: {e:#}"))
+            })?;
+        } else if let Some(state) = lock_recover(object_states()).get_mut(&object_id) {
+            state.last_export_sequence = false;
         }
 
-        // マスクは AviUtl2 に適用しない
+        if config.apply_mask {
+            apply_mask_to_video(video).inspect_err(|e| {
+                record_history(HistoryKind::Error, Some(object_id), format!("mask apply failed: {e:#}"))
+            })?;
+        }
 
         log::debug!("SamFrameExportFilter::proc_video - end");
         Ok(())
@@ -231,55 +726,85 @@ impl FilterPlugin for SamFrameExportFilter {
 impl Drop for SamFrameExportFilter {
     fn drop(&mut self) {
         log::info!("SamFrameExportFilter::drop - plugin dropped");
+        if server_port().get().is_some()
+            && let Err(e) = update_instances_file(None)
+        {
+            log::warn!("Failed to remove instance from discovery file: {e:?}");
+        }
     }
 }
 
 // Aviutl2 プラグイン登録マクロ
 aviutl2::register_filter_plugin!(SamFrameExportFilter);
 
+/// 受け取ったマスクのアルファをオブジェクトの画像に反映する。
+///
+/// このフレームの連番マスクがあればそれを、無ければ apply_label で選んだマスクを使う。
+/// 元のアルファとの合成は「元のアルファとの合成」の設定に従う。
+/// マスクがまだ無いときは何もしない。反映するマスクが変わったら操作履歴に残す。
+fn apply_mask_to_video(video: &mut FilterProcVideo) -> AnyResult<()> {
+    let object_id = video.object.id;
+    let sequence_mask = lock_recover(mask_sequences())
+        .get(&object_id)
+        .and_then(|masks| masks.get(&video.object.frame).cloned());
+    let from_sequence = sequence_mask.is_some();
+    let Some(mask_path) = sequence_mask.or_else(|| selected_mask_for_object(object_id).map(|m| m.path))
+    else {
+        return Ok(());
+    };
 
-/// 保存ルート配下の `current_frame.png` を返す。
-fn current_frame_png_path() -> AnyResult<PathBuf> {
-    let root = export_root_dir().lock().unwrap().clone();
-    create_dir_all(&root)?;
-    Ok(root.join("current_frame.png"))
-}
-
-/// 保存ルート配下にユニークなマスク PNG ファイルパスを作成する。
-fn make_unique_mask_path() -> AnyResult<PathBuf> {
-    let root = export_root_dir().lock().unwrap().clone();
-    create_dir_all(&root)?;
-
-    let now = Local::now();
-    let base = format!(
-        "sam_mask_{:04}{:02}{:02}_{:02}{:02}{:02}_{:03}",
-        now.year(),
-        now.month(),
-        now.day(),
-        now.hour(),
-        now.minute(),
-        now.second(),
-        now.timestamp_subsec_millis(),
-    );
+    let width = video.video_object.width.max(0) as u32;
+    let height = video.video_object.height.max(0) as u32;
+    let num_pixels = (width * height) as usize;
+    if num_pixels == 0 {
+        return Ok(());
+    }
+    let alpha = mask_alpha_for_size(&mask_path, width, height)?;
 
-    // sam_mask_YYYYMMDD_HHMMSS_mmm.png
-    let mut filename = format!("{base}.png");
-    let mut path = root.join(&filename);
+    let mut pixels = vec![RgbaPixel { r: 0, g: 0, b: 0, a: 0 }; num_pixels];
+    let written = video.get_image_data(&mut pixels[..]);
+    if written != num_pixels {
+        return Err(anyhow::anyhow!(
+            "get_image_data wrote {} pixels, expected {} ({}x{})",
+            written,
+            num_pixels,
+            width,
+            height
+        ));
+    }
+    apply_mask_alpha(&mut pixels, &alpha, alpha_combine_for_object(object_id));
+    video.set_image_data(&pixels[..], width, height);
 
-    // もし同名ファイルがすでに存在していたら、_1, _2... を付けてずらす
-    let mut counter = 1;
-    while path.exists() {
-        filename = format!("{base}_{counter}.png");
-        path = root.join(&filename);
-        counter += 1;
+    // 連番マスクは再生中にフレームごとに変わるので、フォルダ単位で 1 回だけ記録する
+    let source = match mask_path.parent() {
+        Some(dir) if from_sequence => dir,
+        _ => mask_path.as_path(),
+    };
+    if note_applied_mask(object_id, source) {
+        record_history(
+            HistoryKind::MaskApplied,
+            Some(object_id),
+            format!("mask applied: {}", source.display()),
+        );
     }
+    Ok(())
+}
 
-    Ok(path)
+/// マスクのアルファを combine で元のアルファと合成する。
+///
+/// 保存済みのマスクは intersect / union で合成済みのことがあるが、同じ合成をもう一度しても結果は変わらない。
+pub(crate) fn apply_mask_alpha(pixels: &mut [RgbaPixel], alpha: &[u8], combine: AlphaCombine) {
+    for (p, &m) in pixels.iter_mut().zip(alpha.iter()) {
+        p.a = combine.combine(m, p.a);
+    }
 }
 
 /// FilterProcVideo から RGBA8 のフレームを取り出すためのヘルパー。
+///
+/// correction で色の行列・レンジのずれを補正してから返す。
 fn get_rgba_frame_from_video(
     video: &mut FilterProcVideo,
+    correction: ColorCorrection,
 ) -> AnyResult<(u32, u32, Vec<u8>)> {
     let width = video.video_object.width.max(0) as u32;
     let height = video.video_object.height.max(0) as u32;
@@ -321,349 +846,7 @@ fn get_rgba_frame_from_video(
         rgba_bytes.push(p.b);
         rgba_bytes.push(p.a);
     }
+    correction.apply(&mut rgba_bytes);
 
     Ok((width, height, rgba_bytes))
 }
-
-/// object_id → マスク PNG のフルパス
-fn mask_paths() -> &'static Mutex<HashMap<i64, PathBuf>> {
-    static MASK_PATHS: OnceLock<Mutex<HashMap<i64, PathBuf>>> = OnceLock::new();
-    MASK_PATHS.get_or_init(|| Mutex::new(HashMap::new()))
-}
-
-fn set_mask_path_for_object(object_id: i64, path: PathBuf) {
-    let mut map = mask_paths().lock().unwrap();
-    map.insert(object_id, path);
-}
-
-// ── ローカル HTTP サーバー ─────────────────────────────────────────────
-
-/// HTTP サーバーを 1 度だけ起動する。
-fn start_http_server_once() {
-    static START: Once = Once::new();
-
-    START.call_once(|| {
-        log::info!("Starting local HTTP server thread...");
-
-        thread::spawn(|| {
-            if let Err(e) = run_http_server() {
-                log::error!("HTTP server error: {e:?}");
-            }
-        });
-    });
-}
-
-/// ブラウザを 1 度だけ起動する。
-fn open_browser_once() {
-    static OPEN: Once = Once::new();
-
-    OPEN.call_once(|| {
-        let url = "http://127.0.0.1:17860/";
-        log::info!("Opening browser: {}", url);
-
-        // Windows の既定ブラウザで URL を開く
-        // start "" "URL"
-        let result = Command::new("cmd")
-            .args(&["/C", "start", "", url])
-            .spawn();
-
-        if let Err(e) = result {
-            log::error!("Failed to open browser: {e:?}");
-        }
-    });
-}
-
-/// シンプルなローカル HTTP サーバー。
-///
-/// - 127.0.0.1:17860 で待ち受け
-/// - GET /frame/current.png に current_frame.png を返す
-/// - GET /, /index.html, /index.js, /index.css などに WEB_ROOT から静的ファイルを返す
-/// - POST /mask に「SAM で切り抜かれた PNG（前景のみ）」が飛んでくるので、それを保存する
-fn run_http_server() -> AnyResult<()> {
-    let addr = "127.0.0.1:17860";
-    let listener = TcpListener::bind(addr)?;
-    log::info!("HTTP server listening on http://{addr}");
-
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                if let Err(e) = handle_client(stream) {
-                    log::warn!("HTTP client error: {e:?}");
-                }
-            }
-            Err(e) => {
-                log::warn!("HTTP incoming error: {e:?}");
-            }
-        }
-    }
-
-    Ok(())
-}
-
-/// ヘッダ末尾 "\r\n\r\n" の位置を探す。
-fn find_header_end(buf: &[u8]) -> Option<usize> {
-    buf.windows(4).position(|w| w == b"\r\n\r\n")
-}
-
-fn handle_client(mut stream: TcpStream) -> AnyResult<()> {
-    // 1. リクエスト全体（ヘッダ＋ボディ）をバッファに読み込む
-    let mut buffer = Vec::new();
-    let mut temp = [0u8; 4096];
-    let mut header_end_pos: Option<usize> = None;
-
-    loop {
-        let n = stream.read(&mut temp)?;
-        if n == 0 {
-            break;
-        }
-        buffer.extend_from_slice(&temp[..n]);
-
-        if header_end_pos.is_none() {
-            if let Some(pos) = find_header_end(&buffer) {
-                header_end_pos = Some(pos);
-                break;
-            }
-        }
-
-        if buffer.len() > 16 * 1024 {
-            // ヘッダが異常に大きいのは想定外なので切る
-            return Err(anyhow::anyhow!("HTTP header too large"));
-        }
-    }
-
-    if buffer.is_empty() {
-        return Ok(());
-    }
-
-    let header_end = header_end_pos
-        .or_else(|| find_header_end(&buffer))
-        .unwrap_or(buffer.len());
-    let body_start = header_end + 4; // "\r\n\r\n" の分
-
-    let header_bytes = &buffer[..header_end];
-    let header_str = String::from_utf8_lossy(header_bytes);
-    let mut lines = header_str.lines();
-
-    let request_line = lines.next().unwrap_or("");
-    let mut parts = request_line.split_whitespace();
-    let method = parts.next().unwrap_or("");
-    let raw_path = parts.next().unwrap_or("/");
-    let path = raw_path.split('?').next().unwrap_or("/");
-
-    // Content-Length を取得（POST /mask 用）
-    let mut content_length: usize = 0;
-    for line in lines {
-        let line = line.trim();
-        if line.is_empty() {
-            continue;
-        }
-        if let Some(rest) = line.strip_prefix("Content-Length:") {
-            content_length = rest.trim().parse().unwrap_or(0);
-        } else if let Some(rest) = line.strip_prefix("content-length:") {
-            content_length = rest.trim().parse().unwrap_or(0);
-        }
-    }
-
-    log::debug!("HTTP request: {} {}", method, path);
-
-    // 2. ボディを取得（必要な場合）
-    let mut body = Vec::new();
-    if buffer.len() > body_start {
-        body.extend_from_slice(&buffer[body_start..]);
-    }
-
-    // 必要に応じて Content-Length まで読み足す
-    while body.len() < content_length {
-        let n = stream.read(&mut temp)?;
-        if n == 0 {
-            break;
-        }
-        body.extend_from_slice(&temp[..n]);
-    }
-
-    // 3. メソッドとパスに応じて処理
-    match method {
-        "GET" => handle_get(&mut stream, path),
-        "POST" => handle_post(&mut stream, path, &body),
-        _ => {
-            write_response(
-                &mut stream,
-                405,
-                "Method Not Allowed",
-                b"Method Not Allowed",
-                "text/plain",
-            )
-        }
-    }
-}
-
-/// GET リクエストの処理。
-fn handle_get(stream: &mut TcpStream, path: &str) -> AnyResult<()> {
-    if path == "/frame/current.png" {
-        let path = current_frame_png_path()?;
-        match read(&path) {
-            Ok(data) => {
-                write_response(
-                    stream,
-                    200,
-                    "OK",
-                    &data,
-                    "image/png",
-                )?;
-            }
-            Err(_) => {
-                write_response(
-                    stream,
-                    404,
-                    "Not Found",
-                    b"current_frame.png not found",
-                    "text/plain",
-                )?;
-            }
-        }
-        return Ok(());
-    }
-
-    // それ以外は WEB_ROOT から静的ファイルとして探す
-    match serve_static_file(path) {
-        Ok((body, content_type)) => {
-            write_response(
-                stream,
-                200,
-                "OK",
-                &body,
-                content_type,
-            )?;
-        }
-        Err(e) => {
-            log::debug!("Static file not found for {}: {:?}", path, e);
-            write_response(
-                stream,
-                404,
-                "Not Found",
-                b"Not Found",
-                "text/plain",
-            )?;
-        }
-    }
-
-    Ok(())
-}
-
-fn current_edit_object_id() -> &'static Mutex<Option<i64>> {
-    static EDIT_ID: OnceLock<Mutex<Option<i64>>> = OnceLock::new();
-    EDIT_ID.get_or_init(|| Mutex::new(None))
-}
-
-/// POST リクエストの処理。
-///
-/// `/mask` = 「SAMで切り抜かれた PNG を保存するだけ」
-fn handle_post(stream: &mut TcpStream, path: &str, body: &[u8]) -> AnyResult<()> {
-    if path == "/mask" {
-        // 現在編集中のオブジェクト ID を取得（これは「どのオブジェクトのマスクか」を
-        // マップに紐づけるためだけに使う。ファイル名には一切使わない）
-        let object_id_opt = {
-            let edit = current_edit_object_id().lock().unwrap();
-            *edit
-        };
-
-        if let Some(object_id) = object_id_opt {
-            let mask_path = make_unique_mask_path()?;
-            log::info!(
-                "Saving mask PNG for object {} to {} ({} bytes)",
-                object_id,
-                mask_path.display(),
-                body.len()
-            );
-
-            write(&mask_path, body)?;
-
-            // object_id → このファイルパス に紐づけ
-            set_mask_path_for_object(object_id, mask_path.clone());
-
-            write_response(stream, 200, "OK", b"OK", "text/plain")?;
-        } else {
-            log::warn!("POST /mask called but no current editing object id set");
-            write_response(
-                stream,
-                400,
-                "Bad Request",
-                b"No editing object",
-                "text/plain",
-            )?;
-        }
-        return Ok(());
-    }
-
-    // 未対応パス
-    write_response(
-        stream,
-        404,
-        "Not Found",
-        b"Not Found",
-        "text/plain",
-    )?;
-    Ok(())
-}
-
-/// 静的ファイルを WEB_ROOT から返すヘルパー。
-///
-/// path: "/index.html", "/index.js", "/" など
-fn serve_static_file(path: &str) -> AnyResult<(Vec<u8>, &'static str)> {
-    // "/" → "index.html"
-    let rel = if path == "/" || path.is_empty() {
-        "index.html"
-    } else {
-        path.trim_start_matches('/')
-    };
-
-    // 簡易的なパストラバーサル防止
-    if rel.contains("..") {
-        return Err(anyhow::anyhow!("invalid path"));
-    }
-
-    let full_path = PathBuf::from(WEB_ROOT).join(rel);
-    log::debug!("Serving static file: {}", full_path.display());
-
-    let data = read(&full_path)?;
-
-    let content_type = if rel.ends_with(".html") {
-        "text/html; charset=utf-8"
-    } else if rel.ends_with(".js") {
-        "text/javascript; charset=utf-8"
-    } else if rel.ends_with(".css") {
-        "text/css; charset=utf-8"
-    } else if rel.ends_with(".png") {
-        "image/png"
-    } else {
-        "application/octet-stream"
-    };
-
-    Ok((data, content_type))
-}
-
-fn write_response(
-    stream: &mut TcpStream,
-    status_code: u16,
-    reason: &str,
-    body: &[u8],
-    content_type: &str,
-) -> AnyResult<()> {
-    let header = format!(
-        "HTTP/1.1 {} {}\r\n\
-         Content-Type: {}\r\n\
-         Content-Length: {}\r\n\
-         Access-Control-Allow-Origin: *\r\n\
-         Connection: close\r\n\
-         \r\n",
-        status_code,
-        reason,
-        content_type,
-        body.len()
-    );
-
-    stream.write_all(header.as_bytes())?;
-    stream.write_all(body)?;
-    stream.flush()?;
-    Ok(())
-}
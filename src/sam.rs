@@ -0,0 +1,385 @@
+//! ONNX Runtime で SAM を直接実行するネイティブ推論（`native-sam` feature）。
+//!
+//! WebGPU の無いブラウザでも使えるよう、Web UI はクリックした点を送るだけにして、
+//! エンコーダ・デコーダの実行はプラグイン側で行う。モデルは segment-anything の ONNX 形式
+//! （エンコーダ: `[1, 3, 1024, 1024]` → `image_embeddings [1, 256, 64, 64]`、
+//! デコーダ: `scripts/export_onnx_model.py` の出力）を前提とする。
+//!
+//! onnxruntime.dll は実行時に読み込むので、プラグインと一緒に配置する必要がある。
+
+use anyhow::Result as AnyResult;
+use crate::{
+    config::{file_config, plugin_data_dir},
+    export::register_sequence_mask,
+    frame::{
+        current_frame_path, current_frame_version, frame_number_from_name, save_png, sequence_dir,
+        sequence_frame_name, sequence_mask_name,
+    },
+    state::lock_recover,
+};
+use ndarray::{Array, Array4, ArrayD};
+use ort::{session::Session, value::Tensor};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+/// エンコーダに渡す画像の一辺
+const INPUT_SIZE: u32 = 1024;
+
+/// SAM の前処理の平均と標準偏差（0〜255 の RGB）
+const PIXEL_MEAN: [f32; 3] = [123.675, 116.28, 103.53];
+const PIXEL_STD: [f32; 3] = [58.395, 57.12, 57.375];
+
+/// 使うファイルのパス（config.toml の sam_encoder / sam_decoder / onnxruntime、
+/// 無ければプラグインフォルダの `models\` と onnxruntime.dll）
+fn model_paths() -> (PathBuf, PathBuf, PathBuf) {
+    let config = lock_recover(file_config()).clone();
+    let dir = plugin_data_dir();
+    (
+        config
+            .sam_encoder
+            .unwrap_or_else(|| dir.join("models").join("sam_encoder.onnx")),
+        config
+            .sam_decoder
+            .unwrap_or_else(|| dir.join("models").join("sam_decoder.onnx")),
+        config
+            .onnxruntime
+            .unwrap_or_else(|| dir.join("onnxruntime.dll")),
+    )
+}
+
+struct SamSessions {
+    encoder: Session,
+    decoder: Session,
+}
+
+/// 読み込み済みのセッション（初回の推論で読み込む）
+fn sessions() -> &'static Mutex<Option<SamSessions>> {
+    static SESSIONS: OnceLock<Mutex<Option<SamSessions>>> = OnceLock::new();
+    SESSIONS.get_or_init(|| Mutex::new(None))
+}
+
+/// 最後にエンコードしたフレーム（同じフレームならエンコーダを回さない）
+#[derive(Clone)]
+struct FrameEmbedding {
+    /// エンコードした current_frame.png の ETag（連番フレームならパスと更新日時）
+    etag: String,
+    width: u32,
+    height: u32,
+    /// 長辺を INPUT_SIZE にしたときの倍率
+    scale: f32,
+    embeddings: ArrayD<f32>,
+}
+
+/// sessions() と同時に持たないこと（2 つのリクエストが逆の順でロックしてデッドロックしないように）
+fn embedding_cache() -> &'static Mutex<Option<FrameEmbedding>> {
+    static CACHE: OnceLock<Mutex<Option<FrameEmbedding>>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(None))
+}
+
+/// 読み込んだモデルを捨てる（config.toml でパスが変わったとき。次の推論で読み直す）。
+pub(crate) fn unload() {
+    *lock_recover(sessions()) = None;
+    *lock_recover(embedding_cache()) = None;
+}
+
+fn load_sessions() -> AnyResult<SamSessions> {
+    static ORT_INIT: OnceLock<Result<(), String>> = OnceLock::new();
+
+    let (encoder_path, decoder_path, dylib_path) = model_paths();
+    ORT_INIT
+        .get_or_init(|| {
+            ort::init_from(dylib_path.display().to_string())
+                .with_name("sam_frame_export_filter")
+                .commit()
+                .map(|_| ())
+                .map_err(|e| format!("failed to load {}: {e}", dylib_path.display()))
+        })
+        .clone()
+        .map_err(|e| anyhow::anyhow!(e))?;
+
+    log::info!(
+        "Loading SAM models: {} / {}",
+        encoder_path.display(),
+        decoder_path.display()
+    );
+    Ok(SamSessions {
+        encoder: Session::builder()?.commit_from_file(&encoder_path)?,
+        decoder: Session::builder()?.commit_from_file(&decoder_path)?,
+    })
+}
+
+/// ネイティブ推論が使えるか（/api/sam/status 用）
+pub(crate) fn status_json() -> serde_json::Value {
+    let (encoder, decoder, dylib) = model_paths();
+    let loaded = lock_recover(sessions()).is_some();
+    serde_json::json!({
+        "available": loaded || (encoder.is_file() && decoder.is_file() && dylib.is_file()),
+        "loaded": loaded,
+        "encoder": encoder.display().to_string(),
+        "decoder": decoder.display().to_string(),
+        "onnxruntime": dylib.display().to_string(),
+    })
+}
+
+/// current_frame.png をエンコードしておく（済んでいれば何もしない）。フレームの大きさを返す。
+pub(crate) fn encode_current_frame() -> AnyResult<(u32, u32)> {
+    let frame = encode_current()?;
+    Ok((frame.width, frame.height))
+}
+
+fn encode_current() -> AnyResult<FrameEmbedding> {
+    let etag = lock_recover(current_frame_version())
+        .as_ref()
+        .map(|v| v.etag.clone())
+        .unwrap_or_default();
+    encode_image(etag, || Ok(image::open(current_frame_path()?)?.to_rgb8()))
+}
+
+/// 連番書き出しした 1 フレームをエンコードする。
+fn encode_file(path: &Path) -> AnyResult<FrameEmbedding> {
+    let modified = std::fs::metadata(path)?.modified()?;
+    let etag = format!("{}@{modified:?}", path.display());
+    encode_image(etag, || Ok(image::open(path)?.to_rgb8()))
+}
+
+/// etag の画像をエンコードし、キャッシュしたものの写しを返す（同じ etag なら load を呼ばない）。
+fn encode_image(
+    etag: String,
+    load: impl FnOnce() -> AnyResult<image::RgbImage>,
+) -> AnyResult<FrameEmbedding> {
+    if let Some(cached) = lock_recover(embedding_cache()).as_ref()
+        && cached.etag == etag
+    {
+        return Ok(cached.clone());
+    }
+
+    let frame = load()?;
+    let (width, height) = frame.dimensions();
+    let (input, scale) = preprocess(&frame);
+
+    // sessions() を放してから embedding_cache() を取る
+    let embeddings = {
+        let mut guard = lock_recover(sessions());
+        if guard.is_none() {
+            *guard = Some(load_sessions()?);
+        }
+        let encoder = &guard.as_ref().unwrap().encoder;
+        let input_name = encoder.inputs[0].name.clone();
+        let outputs = encoder.run(ort::inputs![input_name => Tensor::from_array(input)?]?)?;
+        outputs[0].try_extract_tensor::<f32>()?.to_owned()
+    };
+    log::info!("Encoded {}x{} frame for native SAM", width, height);
+
+    let encoded = FrameEmbedding {
+        etag,
+        width,
+        height,
+        scale,
+        embeddings,
+    };
+    *lock_recover(embedding_cache()) = Some(encoded.clone());
+    Ok(encoded)
+}
+
+/// 長辺を INPUT_SIZE に縮めて正規化し、右下を 0 で埋めた `[1, 3, 1024, 1024]` にする。
+fn preprocess(frame: &image::RgbImage) -> (Array4<f32>, f32) {
+    let (width, height) = frame.dimensions();
+    let scale = INPUT_SIZE as f32 / width.max(height).max(1) as f32;
+    let resized_w = ((width as f32 * scale).round() as u32).clamp(1, INPUT_SIZE);
+    let resized_h = ((height as f32 * scale).round() as u32).clamp(1, INPUT_SIZE);
+    let resized = image::imageops::resize(
+        frame,
+        resized_w,
+        resized_h,
+        image::imageops::FilterType::Triangle,
+    );
+
+    let size = INPUT_SIZE as usize;
+    let mut input = Array4::<f32>::zeros((1, 3, size, size));
+    for (x, y, pixel) in resized.enumerate_pixels() {
+        for c in 0..3 {
+            input[[0, c, y as usize, x as usize]] = (pixel[c] as f32 - PIXEL_MEAN[c]) / PIXEL_STD[c];
+        }
+    }
+    (input, scale)
+}
+
+/// 点（フレームの画素座標）とラベル（1 = 前景、0 = 背景）からマスクを作る。
+///
+/// 戻り値はフレームと同じ大きさの 1 画素 1 バイトのマスク（0 / 255）と、モデルの IoU 予測値。
+pub(crate) fn predict(points: &[[f32; 2]], labels: &[f32]) -> AnyResult<(u32, u32, Vec<u8>, f32)> {
+    if points.is_empty() || points.len() != labels.len() {
+        return Err(anyhow::anyhow!("points and labels must have the same non-zero length"));
+    }
+    // 写しを受け取るので、embedding_cache() を sessions() と同時に持たない
+    let frame = encode_current()?;
+    let (mask, score) = decode(&frame, points, labels, None)?;
+    Ok((frame.width, frame.height, mask, score))
+}
+
+/// エンコード済みのフレームに点（と矩形 `[x0, y0, x1, y1]`）のプロンプトを与えてマスクを作る。
+fn decode(
+    frame: &FrameEmbedding,
+    points: &[[f32; 2]],
+    labels: &[f32],
+    bbox: Option<[f32; 4]>,
+) -> AnyResult<(Vec<u8>, f32)> {
+    let FrameEmbedding {
+        width,
+        height,
+        scale,
+        ..
+    } = *frame;
+
+    // 矩形は左上 = 2、右下 = 3 のラベルの 2 点。矩形が無いときは (0, 0) / -1 のパディング点を足す
+    // （export_onnx_model.py の仕様）
+    let mut points = points.to_vec();
+    let mut point_labels = labels.to_vec();
+    match bbox {
+        Some([x0, y0, x1, y1]) => {
+            points.extend([[x0, y0], [x1, y1]]);
+            point_labels.extend([2.0, 3.0]);
+        }
+        None => {
+            points.push([0.0, 0.0]);
+            point_labels.push(-1.0);
+        }
+    }
+    let n = points.len();
+    let coords: Vec<f32> = points
+        .iter()
+        .flat_map(|[x, y]| [x * scale, y * scale])
+        .collect();
+
+    let coords = Array::from_shape_vec((1, n, 2), coords)?;
+    let point_labels = Array::from_shape_vec((1, n), point_labels)?;
+
+    let guard = lock_recover(sessions());
+    let decoder = &guard
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("SAM models are not loaded"))?
+        .decoder;
+    let outputs = decoder.run(ort::inputs![
+        "image_embeddings" => Tensor::from_array(frame.embeddings.clone())?,
+        "point_coords" => Tensor::from_array(coords)?,
+        "point_labels" => Tensor::from_array(point_labels)?,
+        "mask_input" => Tensor::from_array(Array4::<f32>::zeros((1, 1, 256, 256)))?,
+        "has_mask_input" => Tensor::from_array(Array::from_vec(vec![0.0f32]))?,
+        "orig_im_size" => Tensor::from_array(Array::from_vec(vec![height as f32, width as f32]))?,
+    ]?)?;
+
+    // masks: [1, 1, H, W] のロジット（0 より大きければ前景）
+    let masks = outputs["masks"].try_extract_tensor::<f32>()?;
+    let score = outputs["iou_predictions"]
+        .try_extract_tensor::<f32>()?
+        .iter()
+        .next()
+        .copied()
+        .unwrap_or_default();
+    let expected = (width * height) as usize;
+    if masks.len() != expected {
+        return Err(anyhow::anyhow!(
+            "decoder returned {} mask values, expected {}",
+            masks.len(),
+            expected
+        ));
+    }
+    let mask = masks.iter().map(|&v| if v > 0.0 { 255 } else { 0 }).collect();
+    Ok((mask, score))
+}
+
+/// マスクのアルファ（1 画素 1 バイト）の前景（128 以上）を囲む矩形 `[x0, y0, x1, y1]` と、
+/// 前景の重心（重心が前景の外に落ちる形なら None）。前景が無ければ None。
+pub(crate) fn propagation_prompt(alpha: &[u8], width: u32) -> Option<([f32; 4], Option<[f32; 2]>)> {
+    let width = width as usize;
+    let (mut x0, mut y0, mut x1, mut y1) = (usize::MAX, usize::MAX, 0, 0);
+    let (mut sum_x, mut sum_y, mut count) = (0u64, 0u64, 0u64);
+    for (i, _) in alpha.iter().enumerate().filter(|&(_, &a)| a >= 128) {
+        let (x, y) = (i % width, i / width);
+        x0 = x0.min(x);
+        y0 = y0.min(y);
+        x1 = x1.max(x);
+        y1 = y1.max(y);
+        sum_x += x as u64;
+        sum_y += y as u64;
+        count += 1;
+    }
+    if count == 0 {
+        return None;
+    }
+
+    let (cx, cy) = ((sum_x / count) as usize, (sum_y / count) as usize);
+    let center = (alpha[cy * width + cx] >= 128).then_some([cx as f32 + 0.5, cy as f32 + 0.5]);
+    Some(([x0 as f32, y0 as f32, x1 as f32 + 1.0, y1 as f32 + 1.0], center))
+}
+
+/// seed のマスク（frame 番のフレームのもの）を、連番書き出しした同じオブジェクトの前後のフレームへ
+/// 1 枚ずつ伝播させる。
+///
+/// SAM2 のようなビデオ用のメモリは持たないので、隣のフレームで作ったマスクを囲む矩形と重心を
+/// 次のフレームのプロンプトにする。マスクが消えたらその向きはそこで止める。
+/// 作ったマスクは連番マスクとして登録し、登録したフレーム番号を返す。
+pub(crate) fn propagate_mask(object_id: i64, frame: i32, seed: &Path) -> AnyResult<Vec<i32>> {
+    let dir = sequence_dir(object_id);
+    let mut frames: Vec<i32> = std::fs::read_dir(&dir)?
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            name.starts_with("frame_").then(|| frame_number_from_name(&name))?
+        })
+        .collect();
+    frames.sort_unstable();
+    let start = frames
+        .binary_search(&frame)
+        .map_err(|_| anyhow::anyhow!("frame {frame} is not exported in {}", dir.display()))?;
+
+    let seed = image::open(seed)?.to_rgba8();
+    let forward = frames[start + 1..].to_vec();
+    let backward = frames[..start].iter().rev().copied().collect();
+    let mut propagated = Vec::new();
+    for order in [forward, backward] {
+        let mut previous = seed.clone();
+        for frame in order {
+            let encoded = encode_file(&dir.join(sequence_frame_name(frame)))?;
+            let (width, height) = (encoded.width, encoded.height);
+            if previous.dimensions() != (width, height) {
+                previous = image::imageops::resize(
+                    &previous,
+                    width,
+                    height,
+                    image::imageops::FilterType::Triangle,
+                );
+            }
+            let alpha: Vec<u8> = previous.pixels().map(|p| p[3]).collect();
+            let Some((bbox, center)) = propagation_prompt(&alpha, width) else {
+                log::info!("Mask of object {} is empty before frame {}; propagation stopped", object_id, frame);
+                break;
+            };
+            let (points, labels) = match center {
+                Some(center) => (vec![center], vec![1.0]),
+                None => (Vec::new(), Vec::new()),
+            };
+
+            let (mask, _) = decode(&encoded, &points, &labels, Some(bbox))?;
+            let image = image::RgbaImage::from_fn(width, height, |x, y| {
+                image::Rgba([255, 255, 255, mask[(y * width + x) as usize]])
+            });
+            let mask_path = dir.join(sequence_mask_name(frame));
+            save_png(&image, &mask_path)?;
+            register_sequence_mask(object_id, frame, &mask_path)?;
+            propagated.push(frame);
+            previous = image;
+        }
+    }
+
+    log::info!(
+        "Propagated the mask of object {} from frame {} to {} frames",
+        object_id,
+        frame,
+        propagated.len()
+    );
+    propagated.sort_unstable();
+    Ok(propagated)
+}
@@ -0,0 +1,4385 @@
+//! ローカル HTTP サーバー。
+//!
+//! ルーティング、multipart の受信、Web アセットのセットアップを持つ。AviUtl2 に依存しないので、
+//! プラグイン本体（`lib.rs`）と単体で動く `sam_frame_export_server` の両方から使う。
+
+use anyhow::Result as AnyResult;
+use chrono::{DateTime, Local, Utc};
+use crate::{
+    cache::memory_cache,
+    config::{ENV_WEB_ROOT, env_path, env_port, file_config, plugin_data_dir, sandbox_dir},
+    export::{
+        MaskFrame, delete_mask, diff_masks, existing_mask_path, gallery_json, is_plain_file_name,
+        make_unique_mask_path, mask_list_json, mask_manifest_path, mask_object_id,
+        apply_mask_patch, mask_thumbnail, postprocess_saved_mask, register_saved_mask, register_sequence_mask,
+        record_mask_metadata, save_bulk_zip_masks, validate_mask_png,
+    },
+    frame::{
+        current_frame_data, current_frame_path, current_frame_version, encode_png, frame_number_from_name,
+        sequence_dir, sequence_mask_name, write_atomic,
+    },
+    state::{
+        HistoryKind, LabeledMask, auto_open_browser, current_edit_object_id,
+        current_masks_for_object, export_root_dir, export_session_object, history,
+        last_frame_size, latest_mask_with_label, lock_recover, mask_paths, record_history, remove_mask_path,
+        selected_mask_for_object, undo_mask_for_object,
+    },
+};
+#[cfg(feature = "ffmpeg")]
+use crate::export::video::{VideoFormat, encode_jobs, start_sequence_encode};
+#[cfg(feature = "native-sam")]
+use crate::sam;
+use access_log::LoggedStream;
+use base64::Engine;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::{
+    any::Any,
+    collections::{HashMap, hash_map::RandomState},
+    fs::{File, copy, create_dir_all, read, remove_file, rename, write},
+    hash::BuildHasher,
+    io::{self, BufRead, BufReader, BufWriter, Cursor, IoSlice, Read, Seek, SeekFrom, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpListener, TcpStream, UdpSocket},
+    panic::{AssertUnwindSafe, catch_unwind, set_hook, take_hook},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        Arc, Mutex, Once, OnceLock, mpsc,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// GitHub 上のリポジトリ（owner/name）
+const GITHUB_REPO: &str = "clean262/sam_frame_export_filter";
+
+/// Web UI のルートディレクトリ（WEB_ROOT。環境変数 SAM_WEB_ROOT が無ければプラグインフォルダの web）
+pub(crate) fn web_root() -> &'static Path {
+    static WEB_ROOT_DIR: OnceLock<PathBuf> = OnceLock::new();
+    WEB_ROOT_DIR.get_or_init(|| env_path(ENV_WEB_ROOT).unwrap_or_else(|| plugin_data_dir().join("web")))
+}
+
+// ── ローカル HTTP サーバー ─────────────────────────────────────────────
+
+/// 最初に試すポート番号
+const DEFAULT_PORT: u16 = 17860;
+
+/// 最初のポートから順に何個のポートを試すか
+const PORT_ATTEMPTS: u16 = 20;
+
+/// このプロセスの HTTP サーバーが確保したポート
+pub(crate) fn server_port() -> &'static OnceLock<u16> {
+    static PORT: OnceLock<u16> = OnceLock::new();
+    &PORT
+}
+
+/// HTTPS で待ち受けているポート（https = true で起動できたときだけ設定）
+fn https_port() -> &'static OnceLock<u16> {
+    static PORT: OnceLock<u16> = OnceLock::new();
+    &PORT
+}
+
+/// HTTP サーバーが実際に待ち受けているアドレス（サーバー未起動なら未設定）
+fn bound_address() -> &'static OnceLock<IpAddr> {
+    static ADDRESS: OnceLock<IpAddr> = OnceLock::new();
+    &ADDRESS
+}
+
+/// config.toml の bind（無い・読めなければ 127.0.0.1）
+fn bind_address() -> IpAddr {
+    let Some(bind) = lock_recover(file_config()).bind.clone() else {
+        return IpAddr::V4(Ipv4Addr::LOCALHOST);
+    };
+    match bind.trim().parse() {
+        Ok(address) => address,
+        Err(e) => {
+            log::warn!("Ignoring bind = {:?}: {e}; listening on 127.0.0.1", bind);
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
+        }
+    }
+}
+
+/// LAN など、この PC の外からも接続できるアドレスで待ち受けているか
+fn exposed_to_network() -> bool {
+    bound_address().get().is_some_and(|address| !address.is_loopback())
+}
+
+/// このプロセスの Web UI の URL（サーバー未起動なら None）。
+///
+/// トークン認証が有効なら `?token=` を付ける（ブラウザはこの URL で開くとクッキーを受け取る）。
+pub fn server_url() -> Option<String> {
+    let port = server_port().get()?;
+    Some(web_ui_url("http", &local_host(), *port))
+}
+
+/// HTTPS の Web UI の URL（https = true で待ち受けていなければ None）
+fn https_url() -> Option<String> {
+    let port = https_port().get()?;
+    Some(web_ui_url("https", &local_host(), *port))
+}
+
+/// この PC から開くときのホスト。0.0.0.0 / :: で待ち受けていてもこの PC からは 127.0.0.1 で開ける
+fn local_host() -> String {
+    match bound_address().get() {
+        Some(address) if !address.is_unspecified() && !address.is_loopback() => url_host(*address),
+        _ => "127.0.0.1".to_string(),
+    }
+}
+
+/// 他の端末から開くための URL（この PC の中だけで待ち受けているなら None）
+fn lan_url() -> Option<String> {
+    let port = server_port().get()?;
+    Some(web_ui_url("http", &url_host(lan_address()?), *port))
+}
+
+/// 他の端末からこの PC に届くアドレス（この PC の中だけで待ち受けているなら None）
+fn lan_address() -> Option<IpAddr> {
+    let address = bound_address().get().copied().filter(|a| !a.is_loopback())?;
+    if address.is_unspecified() {
+        local_network_address()
+    } else {
+        Some(address)
+    }
+}
+
+/// IPv6 は URL では [] で囲む
+fn url_host(address: IpAddr) -> String {
+    match address {
+        IpAddr::V4(v4) => v4.to_string(),
+        IpAddr::V6(v6) => format!("[{v6}]"),
+    }
+}
+
+fn web_ui_url(scheme: &str, host: &str, port: u16) -> String {
+    let mut url = format!("{scheme}://{host}:{port}/");
+    if token_required() {
+        url.push_str(&format!("?token={}", access_token()));
+    }
+    url
+}
+
+/// LAN 側のこの PC のアドレス。
+///
+/// UDP ソケットを外向きに connect すると、OS が経路を選んで送信元アドレスが決まる（パケットは送らない）。
+fn local_network_address() -> Option<IpAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((Ipv4Addr::new(192, 0, 2, 1), 80)).ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
+
+/// このプロセスのアクセストークン（起動後に初めて使うときに作る）。
+///
+/// 乱数用のクレートは使わず、OS の乱数で鍵を決める RandomState と時刻・プロセス ID を
+/// SHA-256 に通して 32 桁の 16 進数にする。
+fn access_token() -> &'static str {
+    static TOKEN: OnceLock<String> = OnceLock::new();
+    TOKEN.get_or_init(|| {
+        let mut hasher = Sha256::new();
+        for _ in 0..4 {
+            let random = RandomState::new().hash_one(0u8);
+            hasher.update(random.to_le_bytes());
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        hasher.update(now.to_le_bytes());
+        hasher.update(std::process::id().to_le_bytes());
+        hasher.finalize()[..16]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    })
+}
+
+/// config.toml の require_token がオンか、LAN に公開しているか（その場合は必ずトークンを求める）
+fn token_required() -> bool {
+    exposed_to_network() || lock_recover(file_config()).require_token.unwrap_or(false)
+}
+
+/// トークンを受け取るクッキーの名前（2 つ目以降の AviUtl2 とクッキーが混ざらないようポートを含める）
+fn token_cookie_name() -> String {
+    let port = server_port().get().copied().unwrap_or(DEFAULT_PORT);
+    format!("sam_token_{port}")
+}
+
+/// リクエストのどこでトークンを受け取ったか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TokenSource {
+    /// `?token=`（ブラウザで最初に開く URL）
+    Query,
+    /// `X-Sam-Token` か `Authorization: Bearer`（外部ツール）
+    Header,
+    /// ?token= で開いたときに渡したクッキー（Web UI の以降のリクエスト）
+    Cookie,
+}
+
+/// リクエストに付いているトークンを探す。
+fn request_token(head: &RequestHead) -> Option<(String, TokenSource)> {
+    if let Some(token) = query_param(&head.query, "token") {
+        return Some((token.to_string(), TokenSource::Query));
+    }
+    let header_token = head.header("x-sam-token").or_else(|| {
+        head.header("authorization")
+            .and_then(|v| v.strip_prefix("Bearer "))
+    });
+    if let Some(token) = header_token {
+        return Some((token.trim().to_string(), TokenSource::Header));
+    }
+    let cookie_name = token_cookie_name();
+    head.header("cookie")?
+        .split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == cookie_name)
+        .map(|(_, value)| (value.to_string(), TokenSource::Cookie))
+}
+
+/// HTTP サーバーを 1 度だけ起動する。
+///
+/// AviUtl2 を複数起動している場合に備え、first_port() から順に空いているポートを探す。
+/// 空いているポートが無く起動できなかった場合はエラーを返し（proc_video から AviUtl2 に
+/// エラーとして伝えるため）、次に呼ばれたときにもう一度 bind を試す。
+pub fn start_http_server_once() -> AnyResult<()> {
+    static STARTED: Mutex<bool> = Mutex::new(false);
+
+    // 同時に呼ばれても bind するのは 1 回だけにする
+    let mut started = lock_recover(&STARTED);
+    if *started {
+        return Ok(());
+    }
+
+    install_panic_hook();
+    log::info!("Starting local HTTP server thread...");
+
+    let listeners = bind_first_free_port().map_err(|e| {
+        log::error!("HTTP server error: {e:?}");
+        anyhow::anyhow!("failed to start the HTTP server: {e:#}")
+    })?;
+    *started = true;
+
+    if let Err(e) = update_instances_file(server_port().get().copied()) {
+        log::warn!("Failed to write instance discovery file: {e:?}");
+    }
+
+    let mut servers: Vec<_> = listeners.into_iter().map(|listener| (listener, None)).collect();
+    if lock_recover(file_config()).https.unwrap_or(false) {
+        match bind_https_port() {
+            Ok((listeners, tls_config)) => {
+                servers.extend(listeners.into_iter().map(|listener| (listener, Some(Arc::clone(&tls_config)))));
+            }
+            // HTTPS が使えなくても HTTP はそのまま使えるので、起動は続ける
+            Err(e) => log::warn!("HTTPS server error: {e:#}"),
+        }
+    }
+
+    for (listener, tls_config) in servers {
+        spawn_supervised("http-accept", move || {
+            if let Err(e) = run_http_server(&listener, tls_config.clone()) {
+                log::error!("HTTP server error: {e:?}");
+            }
+        });
+    }
+    Ok(())
+}
+
+/// 最初に試すポート（SAM_EXPORT_PORT > config.toml の port > DEFAULT_PORT）
+fn first_port() -> u16 {
+    env_port()
+        .or(lock_recover(file_config()).port)
+        .unwrap_or(DEFAULT_PORT)
+}
+
+/// first_port() から順に bind を試し、最初に成功したポートのリスナーを返す。
+///
+/// LAN に公開するアドレスなら、トークン付きの URL をログに出す。
+fn bind_first_free_port() -> AnyResult<Vec<TcpListener>> {
+    let address = bind_address();
+    let first = first_port();
+    for port in first..first.saturating_add(PORT_ATTEMPTS) {
+        match bind_listeners(address, port, "http") {
+            Ok(listeners) => {
+                let _ = server_port().set(port);
+                let _ = bound_address().set(address);
+                if exposed_to_network() {
+                    log::warn!(
+                        "The Web UI is reachable from other devices; access token is required. Open: {}",
+                        lan_url().unwrap_or_else(|| web_ui_url("http", &url_host(address), port))
+                    );
+                }
+                return Ok(listeners);
+            }
+            Err(e) => {
+                log::info!("Port {} is not available: {e}", port);
+            }
+        }
+    }
+
+    Err(anyhow::anyhow!(
+        "no free port in {}..{}",
+        first,
+        first.saturating_add(PORT_ATTEMPTS)
+    ))
+}
+
+/// address:port で待ち受ける。
+///
+/// localhost が ::1 に解決される環境のため、同じポートで [::1] にも bind する
+/// （bind = "0.0.0.0" なら [::]）。IPv6 が無効な環境では IPv4 だけで待ち受ける。
+fn bind_listeners(address: IpAddr, port: u16, scheme: &str) -> io::Result<Vec<TcpListener>> {
+    let listener = TcpListener::bind((address, port))?;
+    log::info!("HTTP server listening on {scheme}://{}:{port}", url_host(address));
+
+    let mut listeners = vec![listener];
+    let v6_address = match address {
+        IpAddr::V4(v4) if v4.is_loopback() => Some(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+        IpAddr::V4(v4) if v4.is_unspecified() => Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+        _ => None,
+    };
+    if let Some(v6_address) = v6_address {
+        match TcpListener::bind((v6_address, port)) {
+            Ok(listener_v6) => {
+                log::info!("HTTP server listening on {scheme}://{}:{port}", url_host(v6_address));
+                listeners.push(listener_v6);
+            }
+            Err(e) => {
+                log::warn!("Could not bind {}:{}, IPv4 only: {e}", url_host(v6_address), port);
+            }
+        }
+    }
+    Ok(listeners)
+}
+
+/// config.toml の https = true のとき、自己署名証明書を用意して HTTPS でも待ち受ける。
+///
+/// ポートは https_port、無ければ HTTP のポートの次から空いているものを探す（既定は 17861）。
+fn bind_https_port() -> AnyResult<(Vec<TcpListener>, Arc<rustls::ServerConfig>)> {
+    let address = bound_address().get().copied().unwrap_or_else(bind_address);
+    let mut hosts = vec!["localhost".to_string(), "127.0.0.1".to_string(), "::1".to_string()];
+    if let Some(lan) = lan_address() {
+        hosts.push(lan.to_string());
+    }
+    let config = tls::server_config(&tls::certificate_dir(), &hosts)?;
+
+    let (first, attempts) = match lock_recover(file_config()).https_port {
+        Some(port) => (port, 1),
+        None => {
+            let http_port = server_port().get().copied().unwrap_or(DEFAULT_PORT);
+            (http_port.saturating_add(1), PORT_ATTEMPTS)
+        }
+    };
+    for port in first..first.saturating_add(attempts) {
+        match bind_listeners(address, port, "https") {
+            Ok(listeners) => {
+                let _ = https_port().set(port);
+                return Ok((listeners, config));
+            }
+            Err(e) => {
+                log::info!("Port {} is not available for HTTPS: {e}", port);
+            }
+        }
+    }
+
+    Err(anyhow::anyhow!(
+        "no free port for HTTPS in {}..{}",
+        first,
+        first.saturating_add(attempts)
+    ))
+}
+
+/// 起動中のインスタンス一覧のパス（サンドボックスではその中に置く）
+fn instances_file() -> PathBuf {
+    match sandbox_dir() {
+        Some(sandbox) => sandbox.join("instances.json"),
+        None => plugin_data_dir().join("instances.json"),
+    }
+}
+
+/// インスタンス一覧の自プロセスのエントリを更新する。
+///
+/// port が None ならエントリを削除する。同じポートを記録している古いエントリ
+/// （異常終了したプロセスの残り）は上書き時に取り除く。
+pub(crate) fn update_instances_file(port: Option<u16>) -> AnyResult<()> {
+    update_instances(&instances_file(), &std::process::id().to_string(), port)
+}
+
+/// path のインスタンス一覧の pid のエントリを更新する。
+///
+/// 同時に起動した AviUtl2 同士が読み書きの間に割り込んで互いのエントリを消さないよう、
+/// 書き終えるまでファイルの排他ロックを持つ（ファイルを閉じると外れる）。
+fn update_instances(path: &Path, pid: &str, port: Option<u16>) -> AnyResult<()> {
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    let mut file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    file.lock()?;
+
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    let mut instances: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_slice(&data).unwrap_or_default();
+
+    instances.remove(pid);
+    if let Some(port) = port {
+        instances.retain(|_, v| v.as_u64() != Some(port as u64));
+        instances.insert(pid.to_string(), port.into());
+    }
+
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&serde_json::to_vec_pretty(&instances)?)?;
+    Ok(())
+}
+
+/// インスタンス一覧をそのまま読む（書き換えの途中を読まないよう共有ロックを取る）。
+fn read_instances_file() -> io::Result<Vec<u8>> {
+    let mut file = File::open(instances_file())?;
+    file.lock_shared()?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Web UI のタブが /api/frame を確認しに来なくなってから「閉じられた」とみなすまでの時間
+const UI_CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Web UI が最後に /api/frame を確認しに来た時刻
+fn ui_last_seen() -> &'static Mutex<Option<Instant>> {
+    static LAST_SEEN: OnceLock<Mutex<Option<Instant>>> = OnceLock::new();
+    LAST_SEEN.get_or_init(|| Mutex::new(None))
+}
+
+/// 開いている Web UI のタブがあるか（/ws でつながっているか、最近 /api/frame を確認しに来た）
+fn ui_client_connected() -> bool {
+    !lock_recover(frame_subscribers()).is_empty()
+        || lock_recover(ui_last_seen())
+            .is_some_and(|seen| seen.elapsed() < UI_CLIENT_TIMEOUT)
+}
+
+/// WebSocket のハンドシェイクで鍵に連結する GUID（RFC 6455）
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// 送信がこれより長く詰まった WebSocket は切れたものとみなす
+const WEBSOCKET_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// /ws の接続 1 本分（接続の通し番号, 書き込み用のハンドル）。
+/// 通し番号は watch_websocket が閉じた接続を外すのに使う。
+type FrameSubscriber = (u64, Box<dyn Write + Send>);
+
+/// /ws でつながっている Web UI（新しいフレームを書き出したら通知する）
+fn frame_subscribers() -> &'static Mutex<Vec<FrameSubscriber>> {
+    static SUBSCRIBERS: OnceLock<Mutex<Vec<FrameSubscriber>>> = OnceLock::new();
+    SUBSCRIBERS.get_or_init(|| Mutex::new(Vec::new()))
+}
+
+/// WebSocket の Close フレームのオペコード
+const WEBSOCKET_OPCODE_CLOSE: u8 = 0x8;
+
+/// Sec-WebSocket-Key から Sec-WebSocket-Accept を求める。
+fn websocket_accept_key(key: &str) -> String {
+    let digest = Sha1::digest(format!("{}{}", key.trim(), WEBSOCKET_GUID));
+    base64::engine::general_purpose::STANDARD.encode(digest)
+}
+
+/// サーバーからクライアントへのテキストフレーム（マスク無し・分割無し）
+fn websocket_text_frame(text: &str) -> Vec<u8> {
+    let payload = text.as_bytes();
+    let mut frame = vec![0x81];
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// /ws の接続を受け入れ、以降のフレーム通知の送り先に加える。
+///
+/// ワーカーのスレッドを塞がないよう、接続は通知先に加えたら読み取りを watch_websocket のスレッドに任せて
+/// ここではすぐ戻る。
+fn accept_websocket(
+    stream: &mut dyn Write,
+    head: &RequestHead,
+    writer: Box<dyn Write + Send>,
+    watcher: Box<dyn Read + Send>,
+) -> AnyResult<()> {
+    let cors = head.cors_origin.as_deref();
+    let Some(key) = head
+        .header("sec-websocket-key")
+        .filter(|_| head.header("upgrade").is_some_and(|v| v.eq_ignore_ascii_case("websocket")))
+    else {
+        write_response(stream, cors, 400, "Bad Request", b"WebSocket upgrade required", "text/plain")?;
+        return Ok(());
+    };
+
+    let header = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        websocket_accept_key(key)
+    );
+    stream.write_all(header.as_bytes())?;
+    stream.flush()?;
+
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let mut subscribers = lock_recover(frame_subscribers());
+    subscribers.push((id, writer));
+    log::info!("Web UI connected to /ws ({} open)", subscribers.len());
+    thread::spawn(move || watch_websocket(id, watcher));
+    Ok(())
+}
+
+/// /ws の接続から届くフレームを読み捨て、Close フレームか EOF が来たら通知先から外す。
+///
+/// タブを閉じた後でも通知の書き込みは（送信バッファに積むだけなので）一度は成功してしまい、
+/// 書き込みの失敗だけでは切断に気づけない。気づかないと ui_client_connected が true のままになり、
+/// open_browser_if_needed が Web UI を開き直さなくなる。
+fn watch_websocket(id: u64, mut reader: Box<dyn Read + Send>) {
+    let reason = loop {
+        match read_websocket_frame(&mut reader) {
+            Ok(WEBSOCKET_OPCODE_CLOSE) => break "close frame".to_string(),
+            Ok(_) => {}
+            // 読み取りのタイムアウトは接続が黙っているだけ
+            Err(e) if is_timeout(&e) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break "EOF".to_string(),
+            Err(e) => break e.to_string(),
+        }
+    };
+
+    let removed = {
+        let mut subscribers = lock_recover(frame_subscribers());
+        subscribers
+            .iter()
+            .position(|(subscriber, _)| *subscriber == id)
+            .map(|index| subscribers.remove(index))
+    };
+    if let Some((_, mut writer)) = removed {
+        // Close フレームを返してから手放す（届かなくても構わない）
+        let _ = writer.write_all(&[0x80 | WEBSOCKET_OPCODE_CLOSE, 0]).and_then(|_| writer.flush());
+        log::info!("Web UI disconnected from /ws ({reason})");
+    }
+}
+
+/// クライアントからの WebSocket のフレームを 1 つ読み、ペイロードは捨ててオペコードを返す。
+fn read_websocket_frame(reader: &mut dyn Read) -> io::Result<u8> {
+    let mut head = [0u8; 2];
+    reader.read_exact(&mut head)?;
+    let len = match head[1] & 0x7f {
+        126 => {
+            let mut len = [0u8; 2];
+            reader.read_exact(&mut len)?;
+            u16::from_be_bytes(len) as u64
+        }
+        127 => {
+            let mut len = [0u8; 8];
+            reader.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        len => len as u64,
+    };
+    // クライアントからのフレームには 4 バイトのマスク鍵が付く
+    let mask_len = if head[1] & 0x80 != 0 { 4 } else { 0 };
+    let skipped = io::copy(&mut reader.take(len + mask_len), &mut io::sink())?;
+    if skipped < len + mask_len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(head[0] & 0x0f)
+}
+
+/// 新しいフレームを書き出したことを /ws の接続に知らせる（送れなかった接続は閉じる）。
+///
+/// proc_video を待たせないよう、送信は別スレッドで行う。
+pub(crate) fn notify_frame_updated(etag: &str, exported_at: SystemTime, session: Option<&str>) {
+    if lock_recover(frame_subscribers()).is_empty() {
+        return;
+    }
+    let message = serde_json::json!({
+        "type": "frame",
+        "etag": etag,
+        "exported_at": DateTime::<Local>::from(exported_at).to_rfc3339(),
+        "session": session,
+    });
+    let frame = websocket_text_frame(&message.to_string());
+    std::thread::spawn(move || {
+        let mut subscribers = lock_recover(frame_subscribers());
+        subscribers.retain_mut(|(_, writer)| writer.write_all(&frame).and_then(|_| writer.flush()).is_ok());
+    });
+}
+
+/// Web UI のタブが開いていなければブラウザを起動する。
+///
+/// サンドボックスや「ブラウザを自動で開く」がオフのときは URL をログに出すだけ。
+///
+/// 開いているタブは /ws の通知（または /api/frame）で新しいフレームに気づいて読み込み直すので、
+/// 書き出しのたびにウィンドウを増やさない。
+pub fn open_browser_if_needed() {
+    let Some(url) = server_url() else {
+        log::warn!("HTTP server is not running, not opening browser");
+        return;
+    };
+    if ui_client_connected() {
+        log::info!("Web UI is already open; it will reload the new frame");
+        return;
+    }
+    if !*lock_recover(auto_open_browser()) {
+        log::info!("Opening the browser automatically is off; Web UI: {}", url);
+        return;
+    }
+    launch_browser(&url);
+}
+
+/// 接続中のタブや「ブラウザを自動で開く」に関係なく Web UI を開く（設定の「Web UI を開く」用）。
+pub fn open_browser_now() {
+    let Some(url) = server_url() else {
+        log::warn!("HTTP server is not running, not opening browser");
+        return;
+    };
+    launch_browser(&url);
+}
+
+/// 既定のブラウザで url を開く（サンドボックスではログに出すだけ）。
+fn launch_browser(url: &str) {
+    if sandbox_dir().is_some() {
+        log::info!("Sandbox mode: not opening browser for {}", url);
+        return;
+    }
+    log::info!("Opening browser: {}", url);
+
+    // Windows の既定ブラウザで URL を開く
+    // start "" "URL"
+    let result = Command::new("cmd")
+        .args(["/C", "start", "", url])
+        .spawn();
+
+    if let Err(e) = result {
+        log::error!("Failed to open browser: {e:?}");
+    }
+}
+
+/// シンプルなローカル HTTP サーバー。
+///
+/// - 127.0.0.1:17860 と [::1]:17860（使用中なら 17861, 17862…）で待ち受け
+/// - GET /frame/current.png に current_frame.png（設定によっては WebP / JPEG）を返す
+/// - GET /, /index.html, /index.js, /index.css などに WEB_ROOT から静的ファイルを返す
+/// - POST /mask に「SAM で切り抜かれた PNG（前景のみ）」が飛んでくるので、それを保存して /done へ案内する
+/// - GET /api/history にプラグインの操作履歴を返す
+/// - GET /status にサーバー・フレーム・保存先などの状態を返す
+/// - GET /ws を WebSocket にし、新しいフレームを書き出したら通知する
+/// - tls_config があれば（https = true）、同じ処理を HTTPS で行う
+fn run_http_server(listener: &TcpListener, tls_config: Option<Arc<rustls::ServerConfig>>) -> AnyResult<()> {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                tune_socket(&stream);
+                let stream: Box<dyn HttpStream + Send> = match &tls_config {
+                    Some(tls_config) => match tls::TlsStream::new(Arc::clone(tls_config), stream) {
+                        Ok(stream) => Box::new(stream),
+                        Err(e) => {
+                            log::warn!("TLS connection error: {e:?}");
+                            continue;
+                        }
+                    },
+                    None => Box::new(stream),
+                };
+                if lock_recover(connection_queue()).send(stream).is_err() {
+                    log::error!("HTTP worker threads are gone; dropping connection");
+                }
+            }
+            Err(e) => {
+                log::warn!("HTTP incoming error: {e:?}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// 同時にリクエストを処理するワーカースレッドの数
+const HTTP_WORKERS: usize = 4;
+
+/// 受け付けた接続をワーカースレッドに渡す送信口。
+///
+/// 大きなマスクの POST を受けている間も静的ファイルや /api/frame を返せるよう、
+/// 接続は HTTP_WORKERS 個のスレッドのうち空いているもので処理する。
+fn connection_queue() -> &'static Mutex<mpsc::Sender<Box<dyn HttpStream + Send>>> {
+    static QUEUE: OnceLock<Mutex<mpsc::Sender<Box<dyn HttpStream + Send>>>> = OnceLock::new();
+    QUEUE.get_or_init(|| {
+        let (tx, rx) = mpsc::channel::<Box<dyn HttpStream + Send>>();
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..HTTP_WORKERS {
+            let rx = Arc::clone(&rx);
+            spawn_supervised("http-worker", move || {
+                loop {
+                    let Ok(stream) = lock_recover(&rx).recv() else {
+                        break;
+                    };
+                    if let Err(e) = handle_client(stream) {
+                        log::warn!("HTTP client error: {e:?}");
+                        record_history(HistoryKind::Error, None, format!("HTTP request failed: {e:#}"));
+                    }
+                }
+            });
+        }
+        Mutex::new(tx)
+    })
+}
+
+/// panic したスレッドを作り直すまでの待ち時間の最初の値（続けて落ちるたびに倍にする）
+const RESTART_DELAY_MIN: Duration = Duration::from_millis(100);
+
+/// panic したスレッドを作り直すまでの待ち時間の上限
+const RESTART_DELAY_MAX: Duration = Duration::from_secs(30);
+
+/// panic の内容を、どのスレッドのどこで起きたかと一緒にログに残す（元のフックも呼ぶ）。
+///
+/// 既定のフックは標準エラーに書くだけで、AviUtl2 の中では誰にも見えないため。
+fn install_panic_hook() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let previous = take_hook();
+        set_hook(Box::new(move |info| {
+            let thread = thread::current();
+            log::error!("Thread '{}' {info}", thread.name().unwrap_or("<unnamed>"));
+            previous(info);
+        }));
+    });
+}
+
+/// panic のペイロードから文言を取り出す
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+/// name のスレッドで body を動かし、panic したら記録して body をやり直す。
+///
+/// 1 つのリクエストの panic でワーカーや待ち受けが減っていき、UI が応答しなくなるのを防ぐ。
+/// すぐにまた落ちる場合に備え、やり直すまでの間を RESTART_DELAY_MAX まで倍々に空ける
+/// （しばらく動いていたら最初の間に戻す）。body が普通に戻ったらスレッドを終える。
+fn spawn_supervised<F>(name: &str, body: F)
+where
+    F: Fn() + Send + 'static,
+{
+    let thread_name = name.to_string();
+    let result = thread::Builder::new().name(thread_name.clone()).spawn(move || {
+        let mut delay = RESTART_DELAY_MIN;
+        loop {
+            let started = Instant::now();
+            let Err(payload) = catch_unwind(AssertUnwindSafe(&body)) else {
+                break;
+            };
+            let message = panic_message(&*payload);
+            log::error!("Thread '{thread_name}' panicked ({message}); restarting it");
+            record_history(
+                HistoryKind::Error,
+                None,
+                format!("{thread_name} crashed and was restarted: {message}"),
+            );
+
+            if started.elapsed() > RESTART_DELAY_MAX {
+                delay = RESTART_DELAY_MIN;
+            }
+            thread::sleep(delay);
+            delay = (delay * 2).min(RESTART_DELAY_MAX);
+        }
+    });
+    if let Err(e) = result {
+        log::error!("Failed to start thread '{name}': {e}");
+    }
+}
+
+/// 新しいマスクのファイル名を選んでから rename するまでを 1 つずつにするためのロック
+/// （同じ時刻・通し番号のファイル名を 2 つのリクエストが選ばないように）。
+///
+/// ボディの受信やダウンロードの間は持たない（遅いアップロードが他の書き込みを止めないように）。
+fn upload_lock() -> &'static Mutex<()> {
+    static LOCK: OnceLock<Mutex<()>> = OnceLock::new();
+    LOCK.get_or_init(|| Mutex::new(()))
+}
+
+/// 受信したボディを置く一時ファイルのパス（同時に受けても重ならないよう通し番号を付ける）
+fn new_spool_path(dir: &Path) -> PathBuf {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    dir.join(format!(
+        ".upload_{}_{}.part",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// 受信済みの spool_path に新しいマスクのファイル名を付ける（失敗したら一時ファイルを消す）。
+fn move_to_new_mask_path(spool_path: &Path, object_id: i64, label: Option<&str>) -> AnyResult<PathBuf> {
+    let _upload = lock_recover(upload_lock());
+    let result = (|| -> AnyResult<PathBuf> {
+        let mask_path = make_unique_mask_path(object_id, label)?;
+        rename(spool_path, &mask_path)?;
+        Ok(mask_path)
+    })();
+    if result.is_err() {
+        let _ = remove_file(spool_path);
+    }
+    result
+}
+
+/// 応答用のソケットの送信バッファ（20 MB 級のフレームを少ない往復で送るため）
+const SEND_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// リクエストボディの上限の既定値（MB、config.toml の max_body_mb で変更）
+const DEFAULT_MAX_BODY_MB: u64 = 100;
+
+/// 読み取りタイムアウトの既定値（秒、config.toml の read_timeout_secs で変更）
+const DEFAULT_READ_TIMEOUT_SECS: u64 = 30;
+
+/// 受け付けるリクエストボディの上限（バイト）
+fn max_body_bytes() -> u64 {
+    let mb = lock_recover(file_config())
+        .max_body_mb
+        .unwrap_or(DEFAULT_MAX_BODY_MB);
+    mb.saturating_mul(1024 * 1024)
+}
+
+/// 応答の遅延を減らすため、Nagle を切って送信バッファを広げる（失敗しても続行）。
+///
+/// 止まったクライアントでワーカーがふさがらないよう、読み取りタイムアウトも設定する。
+fn tune_socket(stream: &TcpStream) {
+    if let Err(e) = stream.set_nodelay(true) {
+        log::debug!("Failed to set TCP_NODELAY: {e}");
+    }
+    let timeout = lock_recover(file_config())
+        .read_timeout_secs
+        .unwrap_or(DEFAULT_READ_TIMEOUT_SECS)
+        .max(1);
+    if let Err(e) = stream.set_read_timeout(Some(Duration::from_secs(timeout))) {
+        log::debug!("Failed to set read timeout: {e}");
+    }
+    if let Err(e) = socket2::SockRef::from(stream).set_send_buffer_size(SEND_BUFFER_SIZE) {
+        log::debug!("Failed to set send buffer size: {e}");
+    }
+}
+
+/// ヘッダ末尾 "\r\n\r\n" の位置を探す。
+fn find_header_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4).position(|w| w == b"\r\n\r\n")
+}
+
+/// HTTP の接続 1 本分。TcpStream と TLS の接続のほか、テストではメモリ上のモックを使う。
+pub(crate) trait HttpStream: Read + Write {
+    /// 同じ接続からボディを読むためのハンドル（書き込み側とは別に持つ）
+    fn try_clone_reader(&self) -> std::io::Result<Box<dyn Read>>;
+
+    /// 応答の後も接続を保持して書き込むためのハンドル（/ws 用）
+    fn try_clone_writer(&self) -> std::io::Result<Box<dyn Write + Send>>;
+
+    /// 応答の後も別のスレッドで読み、接続が閉じられたのに気づくためのハンドル（/ws 用）
+    fn try_clone_watcher(&self) -> std::io::Result<Box<dyn Read + Send>>;
+}
+
+impl HttpStream for TcpStream {
+    fn try_clone_reader(&self) -> std::io::Result<Box<dyn Read>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+
+    fn try_clone_writer(&self) -> std::io::Result<Box<dyn Write + Send>> {
+        let writer = self.try_clone()?;
+        writer.set_write_timeout(Some(WEBSOCKET_WRITE_TIMEOUT))?;
+        Ok(Box::new(writer))
+    }
+
+    fn try_clone_watcher(&self) -> std::io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+}
+
+impl<T: HttpStream + ?Sized> HttpStream for Box<T> {
+    fn try_clone_reader(&self) -> std::io::Result<Box<dyn Read>> {
+        (**self).try_clone_reader()
+    }
+
+    fn try_clone_writer(&self) -> std::io::Result<Box<dyn Write + Send>> {
+        (**self).try_clone_writer()
+    }
+
+    fn try_clone_watcher(&self) -> std::io::Result<Box<dyn Read + Send>> {
+        (**self).try_clone_watcher()
+    }
+}
+
+fn handle_client(stream: impl HttpStream) -> AnyResult<()> {
+    let started = Instant::now();
+    let mut stream = LoggedStream::new(stream);
+    let result = handle_request(&mut stream);
+    stream.record(started.elapsed());
+    result
+}
+
+fn handle_request(mut stream: &mut LoggedStream<impl HttpStream>) -> AnyResult<()> {
+    // 1. リクエスト全体（ヘッダ＋ボディ）をバッファに読み込む
+    let mut buffer = Vec::new();
+    let mut temp = [0u8; 4096];
+    let mut header_end_pos: Option<usize> = None;
+
+    loop {
+        let n = match stream.read(&mut temp) {
+            Ok(n) => n,
+            // 先に接続だけ開いておくブラウザもあるので、何も送ってこなければ黙って閉じる
+            Err(e) if is_timeout(&e) && buffer.is_empty() => {
+                log::debug!("Idle HTTP connection timed out");
+                return Ok(());
+            }
+            Err(e) if is_timeout(&e) => {
+                return write_response(&mut stream, None, 408, "Request Timeout", b"Request Timeout", "text/plain");
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if n == 0 {
+            break;
+        }
+        buffer.extend_from_slice(&temp[..n]);
+
+        if header_end_pos.is_none()
+            && let Some(pos) = find_header_end(&buffer)
+        {
+            header_end_pos = Some(pos);
+            break;
+        }
+
+        if buffer.len() > 16 * 1024 {
+            // ヘッダが異常に大きいのは想定外なので切る
+            return Err(anyhow::anyhow!("HTTP header too large"));
+        }
+    }
+
+    if buffer.is_empty() {
+        return Ok(());
+    }
+
+    let header_end = header_end_pos
+        .or_else(|| find_header_end(&buffer))
+        .unwrap_or(buffer.len());
+    let body_start = header_end + 4; // "\r\n\r\n" の分
+
+    let mut head = parse_request_head(&buffer[..header_end]);
+    stream.set_request(&head);
+    head.cors_origin = allowed_origin(
+        head.header("origin"),
+        head.header("host"),
+        lock_recover(file_config()).cors_origins.as_deref(),
+    );
+    let cors = head.cors_origin.as_deref();
+
+    // CORS のプリフライトはトークンを付けずに来るので、認証より先に答える
+    if head.method == "OPTIONS" {
+        return write_preflight_response(&mut stream, &head);
+    }
+
+    // 許可していない Origin のページから送られた書き込み（プリフライトの要らないフォームの POST など）は断る
+    if !matches!(head.method.as_str(), "GET" | "HEAD") && head.header("origin").is_some() && cors.is_none() {
+        log::warn!("Rejecting {} {} from {:?}: origin not allowed", head.method, head.path, head.header("origin"));
+        return write_response(&mut stream, None, 403, "Forbidden", b"Forbidden: origin not allowed", "text/plain");
+    }
+
+    // トークン認証（config.toml の require_token）
+    if token_required() {
+        let token = request_token(&head);
+        if token.as_ref().is_none_or(|(token, _)| token != access_token()) {
+            log::warn!("Rejecting {} {}: missing or wrong access token", head.method, head.path);
+            return write_response_with_headers(
+                &mut stream,
+                cors,
+                401,
+                "Unauthorized",
+                b"Unauthorized: open the Web UI from AviUtl2 or use the URL with ?token= shown in the log",
+                "text/plain",
+                &[("WWW-Authenticate", "Bearer".into())],
+            );
+        }
+        // ブラウザで ?token= 付きの URL を開いたら、クッキーに移してトークンの無い URL に飛ばす
+        let navigating = head
+            .header("accept")
+            .is_some_and(|accept| accept.contains("text/html"));
+        if head.method == "GET" && navigating && token.is_some_and(|(_, source)| source == TokenSource::Query) {
+            let query: Vec<&str> = head
+                .query
+                .split('&')
+                .filter(|pair| !pair.is_empty() && !pair.starts_with("token="))
+                .collect();
+            // パスは戻してあるので、日本語のファイル名などは %XX に直して返す
+            let path = head.path.split('/').map(percent_encode).collect::<Vec<_>>().join("/");
+            let location = if query.is_empty() {
+                path
+            } else {
+                format!("{}?{}", path, query.join("&"))
+            };
+            return write_response_with_headers(
+                &mut stream,
+                cors,
+                303,
+                "See Other",
+                b"",
+                "text/plain",
+                &[
+                    ("Location", location),
+                    (
+                        "Set-Cookie",
+                        format!(
+                            "{}={}; Path=/; HttpOnly; SameSite=Strict",
+                            token_cookie_name(),
+                            access_token()
+                        ),
+                    ),
+                ],
+            );
+        }
+    }
+
+    // Transfer-Encoding: chunked なら長さは分からない。それ以外は Content-Length（POST /mask 用）
+    let transfer_codings: Vec<String> = head
+        .header("transfer-encoding")
+        .map(|v| v.split(',').map(|c| c.trim().to_ascii_lowercase()).collect())
+        .unwrap_or_default();
+    // gzip などを解かずにマスクとして保存しないよう、chunked 以外の転送コーディングは断る
+    if transfer_codings.iter().any(|c| c != "chunked") {
+        log::warn!(
+            "Rejecting {} {}: unsupported Transfer-Encoding {:?}",
+            head.method,
+            head.path,
+            transfer_codings
+        );
+        return write_response(
+            &mut stream,
+            cors,
+            501,
+            "Not Implemented",
+            b"Only Transfer-Encoding: chunked is supported",
+            "text/plain",
+        );
+    }
+    let chunked = !transfer_codings.is_empty();
+    let content_length: usize = head
+        .header("content-length")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+
+    log::debug!("HTTP request: {} {}", head.method, head.path);
+
+    // 2. ボディはここでは読まず、ハンドラが必要な分だけ読む
+    //    （大きなマスクをメモリに溜めずにファイルへ流し込むため）
+    let mut leftover = Vec::new();
+    if buffer.len() > body_start {
+        leftover.extend_from_slice(&buffer[body_start..]);
+    }
+    // 上限を超えるボディは読まずに断る（chunked は読みながら数える）
+    let limit = max_body_bytes();
+    if !chunked && content_length as u64 > limit {
+        log::warn!(
+            "Rejecting {} {}: body of {} bytes exceeds the {} byte limit",
+            head.method,
+            head.path,
+            content_length,
+            limit
+        );
+        return write_response(&mut stream, cors, 413, "Payload Too Large", b"Request body too large", "text/plain");
+    }
+    let mut body = if chunked {
+        let raw = Cursor::new(leftover).chain(stream.try_clone_reader()?);
+        RequestBody {
+            reader: stream.count_request_body(Box::new(LimitedReader {
+                inner: ChunkedReader::new(BufReader::new(raw)),
+                remaining: limit,
+            })),
+            content_length: None,
+        }
+    } else {
+        leftover.truncate(content_length);
+        let remaining = (content_length - leftover.len()) as u64;
+        RequestBody {
+            reader: stream.count_request_body(Box::new(
+                Cursor::new(leftover).chain(stream.try_clone_reader()?.take(remaining)),
+            )),
+            content_length: Some(content_length),
+        }
+    };
+
+    // curl などはボディが大きいと 100 Continue を待ってから送ってくる
+    if head
+        .header("expect")
+        .is_some_and(|v| v.eq_ignore_ascii_case("100-continue"))
+    {
+        stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        stream.flush()?;
+    }
+
+    // 3. メソッドとパスに応じて処理
+    if head.method == "GET" && head.path == "/ws" {
+        let writer = stream.try_clone_writer()?;
+        let watcher = stream.try_clone_watcher()?;
+        return accept_websocket(&mut stream, &head, writer, watcher);
+    }
+    let result = match head.method.as_str() {
+        "GET" => handle_get(&mut stream, &head),
+        "POST" => handle_post(&mut stream, &head, &mut body),
+        "PUT" => handle_put(&mut stream, &head, &mut body),
+        "DELETE" => handle_delete(&mut stream, &head),
+        "HEAD" => handle_head(&mut stream, &head),
+        _ => {
+            write_response_with_headers(
+                &mut stream,
+                cors,
+                405,
+                "Method Not Allowed",
+                b"Method Not Allowed",
+                "text/plain",
+                &[("Allow", ALLOWED_METHODS.to_string())],
+            )
+        }
+    };
+
+    // ボディの読み取りで止まった・上限を超えた場合は、ハンドラのエラーの代わりに 408 / 413 を返す
+    let io_error = result
+        .as_ref()
+        .err()
+        .and_then(|e| e.chain().find_map(|c| c.downcast_ref::<io::Error>()));
+    if let Some(e) = io_error {
+        if is_timeout(e) {
+            log::warn!("{} {} timed out reading the body", head.method, head.path);
+            return write_response(&mut stream, cors, 408, "Request Timeout", b"Request Timeout", "text/plain");
+        }
+        if e.get_ref().is_some_and(|inner| inner.is::<BodyTooLarge>()) {
+            log::warn!("{} {}: chunked body exceeds the {} byte limit", head.method, head.path, limit);
+            return write_response(&mut stream, cors, 413, "Payload Too Large", b"Request body too large", "text/plain");
+        }
+    }
+    result
+}
+
+/// 読み取りタイムアウトによるエラーか（Windows は TimedOut、Unix は WouldBlock になる）
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock)
+}
+
+/// chunked のボディが max_body_bytes を超えた
+#[derive(Debug)]
+struct BodyTooLarge;
+
+impl std::fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("request body too large")
+    }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+/// remaining バイトを超えて読もうとしたら BodyTooLarge のエラーにする Read
+struct LimitedReader<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.remaining = self
+            .remaining
+            .checked_sub(n as u64)
+            .ok_or_else(|| io::Error::other(BodyTooLarge))?;
+        Ok(n)
+    }
+}
+
+/// リクエストラインとヘッダ（"\r\n\r\n" の手前まで）を解釈する。
+fn parse_request_head(header_bytes: &[u8]) -> RequestHead {
+    let header_str = String::from_utf8_lossy(header_bytes);
+    let mut lines = header_str.lines();
+
+    let request_line = lines.next().unwrap_or("");
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let raw_path = parts.next().unwrap_or("/");
+    let (path, query) = raw_path.split_once('?').unwrap_or((raw_path, ""));
+
+    // ヘッダ名は小文字にそろえて保持する
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+
+    RequestHead {
+        method: method.to_string(),
+        path: percent_decode_path(path),
+        query: query.to_string(),
+        headers,
+        cors_origin: None,
+    }
+}
+
+/// リクエストラインとヘッダ。
+struct RequestHead {
+    method: String,
+    /// クエリを除いたパス（`%XX` は戻してある）
+    path: String,
+    /// `?` 以降（無ければ空文字列）
+    query: String,
+    /// (小文字のヘッダ名, 値)
+    headers: Vec<(String, String)>,
+    /// 応答に付ける Access-Control-Allow-Origin（handle_request が allowed_origin で決める）
+    cors_origin: Option<String>,
+}
+
+impl RequestHead {
+    /// ヘッダの値を取得する。name は小文字で指定する。
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// リクエストボディ。ヘッダと一緒に読めてしまった分 + ソケットの残り。
+struct RequestBody {
+    reader: Box<dyn Read>,
+    /// Content-Length（chunked で長さが分からなければ None）
+    content_length: Option<usize>,
+}
+
+impl RequestBody {
+    /// ボディを dir の一時ファイルにすべて受け取り、そのパスと大きさを返す。
+    ///
+    /// 保存先の名前はこの後で決めて rename する（受信中に upload_lock を持たないように）。
+    /// 途中で切断された場合は一時ファイルを消してエラーを返す。
+    fn spool(&mut self, dir: &Path) -> AnyResult<(PathBuf, u64)> {
+        let part_path = new_spool_path(dir);
+
+        let result = (|| -> AnyResult<u64> {
+            let mut file = BufWriter::new(File::create(&part_path)?);
+            let written = std::io::copy(&mut self.reader, &mut file)?;
+            file.flush()?;
+            if let Some(expected) = self.content_length
+                && written != expected as u64
+            {
+                return Err(anyhow::anyhow!(
+                    "request body truncated: got {} of {} bytes",
+                    written,
+                    expected
+                ));
+            }
+            Ok(written)
+        })();
+
+        match result {
+            Ok(written) => Ok((part_path, written)),
+            Err(e) => {
+                let _ = remove_file(&part_path);
+                Err(e)
+            }
+        }
+    }
+
+    /// ボディをすべてメモリに読み込む（小さいボディ用）。
+    fn read_to_vec(&mut self) -> AnyResult<Vec<u8>> {
+        let mut data = Vec::with_capacity(self.content_length.unwrap_or(0));
+        self.reader.read_to_end(&mut data)?;
+        if let Some(expected) = self.content_length
+            && data.len() != expected
+        {
+            return Err(anyhow::anyhow!(
+                "request body truncated: got {} of {} bytes",
+                data.len(),
+                expected
+            ));
+        }
+        Ok(data)
+    }
+}
+
+/// チャンクのサイズ行・trailer の 1 行の上限
+const CHUNK_LINE_LIMIT: u64 = 4096;
+
+/// `Transfer-Encoding: chunked` のボディから中身だけを取り出す Read。
+///
+/// 最後の 0 サイズのチャンクと trailer まで読んだら終わり（後ろのデータは読まない）。
+/// 途中で切断された・形式がおかしい場合はエラーを返す。
+struct ChunkedReader<R> {
+    inner: R,
+    /// 今のチャンクの残りバイト数
+    remaining: u64,
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            remaining: 0,
+            done: false,
+        }
+    }
+
+    /// CRLF までの 1 行（改行を除く）
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = Vec::new();
+        (&mut self.inner).take(CHUNK_LINE_LIMIT).read_until(b'\n', &mut line)?;
+        if line.last() != Some(&b'\n') {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "chunked body truncated",
+            ));
+        }
+        Ok(String::from_utf8_lossy(&line).trim_end_matches(['\r', '\n']).to_string())
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            // "1a2b;拡張" のような 16 進のサイズ行
+            let line = self.read_line()?;
+            let size = line.split(';').next().unwrap_or("").trim();
+            self.remaining = u64::from_str_radix(size, 16).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("invalid chunk size {line:?}"))
+            })?;
+            if self.remaining == 0 {
+                // trailer を空行まで読み飛ばす
+                while !self.read_line()?.is_empty() {}
+                self.done = true;
+                return Ok(0);
+            }
+        }
+
+        let max = buf.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let n = self.inner.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "chunked body truncated",
+            ));
+        }
+        self.remaining -= n as u64;
+        if self.remaining == 0 && !self.read_line()?.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "missing CRLF after chunk",
+            ));
+        }
+        Ok(n)
+    }
+}
+
+/// GET リクエストの処理。
+fn handle_get(stream: &mut dyn Write, head: &RequestHead) -> AnyResult<()> {
+    let cors = head.cors_origin.as_deref();
+    let path = head.path.as_str();
+    let query = head.query.as_str();
+
+    if path == "/api/version" {
+        let body = version_info_json(query_flag(query, "check"));
+        write_response(
+            stream,
+            cors,
+            200,
+            "OK",
+            body.to_string().as_bytes(),
+            "application/json",
+        )?;
+        return Ok(());
+    }
+
+    if path == "/done" {
+        let object_id = query_param(query, "object").unwrap_or("");
+        let masks: Vec<String> = query_params(query, "mask").map(percent_decode).collect();
+        let body = done_page_html(object_id, &masks);
+        write_response(
+            stream,
+            cors,
+            200,
+            "OK",
+            body.as_bytes(),
+            "text/html; charset=utf-8",
+        )?;
+        return Ok(());
+    }
+
+    if path == "/api/frame" {
+        // Web UI が定期的に確認しに来る（開いているタブがあるかの目印にもなる）
+        *lock_recover(ui_last_seen()) = Some(Instant::now());
+        let version = lock_recover(current_frame_version()).clone();
+        let body = serde_json::json!({
+            "etag": version.as_ref().map(|v| v.etag.clone()),
+            "exported_at": version.as_ref().map(|v| DateTime::<Local>::from(v.exported_at).to_rfc3339()),
+            "session": version.and_then(|v| v.session),
+        });
+        write_response(
+            stream,
+            cors,
+            200,
+            "OK",
+            body.to_string().as_bytes(),
+            "application/json",
+        )?;
+        return Ok(());
+    }
+
+    if path == "/diagnostics" {
+        write_response(
+            stream,
+            cors,
+            200,
+            "OK",
+            diagnostics_json().to_string().as_bytes(),
+            "application/json",
+        )?;
+        return Ok(());
+    }
+
+    if path == "/status" {
+        // 「Not Found しか出ない」ときの切り分け用に、プラグインの状態をまとめて返す
+        let frame_path = current_frame_path()?;
+        let body = serde_json::json!({
+            "server": "running",
+            "version": env!("CARGO_PKG_VERSION"),
+            "port": server_port().get(),
+            "current_frame": {
+                "exists": frame_path.is_file(),
+                "path": frame_path.display().to_string(),
+                "size": lock_recover(last_frame_size()).map(|(w, h)| [w, h]),
+            },
+            "url": server_url(),
+            "lan_url": lan_url(),
+            "https_url": https_url(),
+            "token_required": token_required(),
+            "auto_open_browser": *lock_recover(auto_open_browser()),
+            "edit_object_id": *lock_recover(current_edit_object_id()),
+            "export_root": lock_recover(export_root_dir()).display().to_string(),
+            "web_root": web_root().display().to_string(),
+            "web_ui_installed": web_root_installed(),
+            "web_ui_embedded": !EMBEDDED_ASSETS.is_empty(),
+            "sandbox": sandbox_dir().map(|dir| dir.display().to_string()),
+        });
+        write_response(
+            stream,
+            cors,
+            200,
+            "OK",
+            body.to_string().as_bytes(),
+            "application/json",
+        )?;
+        return Ok(());
+    }
+
+    #[cfg(feature = "native-sam")]
+    if path == "/api/sam/status" {
+        write_response(
+            stream,
+            cors,
+            200,
+            "OK",
+            sam::status_json().to_string().as_bytes(),
+            "application/json",
+        )?;
+        return Ok(());
+    }
+
+    if path == "/api/stats" {
+        let body = serde_json::json!({ "memory": lock_recover(memory_cache()).stats_json() });
+        write_response(
+            stream,
+            cors,
+            200,
+            "OK",
+            body.to_string().as_bytes(),
+            "application/json",
+        )?;
+        return Ok(());
+    }
+
+    if path == "/api/history" {
+        // ?object= で絞り込み、?since= より後の id だけ返す（ポーリング用）
+        let object_filter = query_param(query, "object").and_then(|v| v.parse::<i64>().ok());
+        let since = query_param(query, "since")
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(0);
+        let limit = query_param(query, "limit")
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(usize::MAX);
+
+        let history = lock_recover(history());
+        let mut entries: Vec<_> = history
+            .iter()
+            .rev()
+            .filter(|entry| entry.id > since)
+            .filter(|entry| object_filter.is_none() || entry.object_id == object_filter)
+            .take(limit)
+            .map(|entry| entry.to_json())
+            .collect();
+        drop(history);
+        entries.reverse();
+
+        let body = serde_json::json!({ "entries": entries });
+        write_response(
+            stream,
+            cors,
+            200,
+            "OK",
+            body.to_string().as_bytes(),
+            "application/json",
+        )?;
+        return Ok(());
+    }
+
+    if path == "/api/instances" {
+        let body = read_instances_file().unwrap_or_else(|_| b"{}".to_vec());
+        write_response(stream, cors, 200, "OK", &body, "application/json")?;
+        return Ok(());
+    }
+
+    if path == "/api/gallery" {
+        let object_filter = query_param(query, "object").and_then(|v| v.parse::<i64>().ok());
+        let tag_filter = query_param_decoded(query, "tag").filter(|t| !t.is_empty());
+        let page = query_param(query, "page")
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(1)
+            .max(1);
+        let body = gallery_json(object_filter, tag_filter.as_deref(), page)?;
+        write_response(
+            stream,
+            cors,
+            200,
+            "OK",
+            body.to_string().as_bytes(),
+            "application/json",
+        )?;
+        return Ok(());
+    }
+
+    if path == "/masks" {
+        let object_filter = query_param(query, "object").and_then(|v| v.parse::<i64>().ok());
+        let body = mask_list_json(object_filter)?;
+        write_response(
+            stream,
+            cors,
+            200,
+            "OK",
+            body.to_string().as_bytes(),
+            "application/json",
+        )?;
+        return Ok(());
+    }
+
+    if path == "/api/masks/diff" {
+        let (Some(a), Some(b)) = (query_param_decoded(query, "a"), query_param_decoded(query, "b"))
+        else {
+            write_response(stream, cors, 400, "Bad Request", b"a and b are required", "text/plain")?;
+            return Ok(());
+        };
+
+        let diff = existing_mask_path(&a)
+            .and_then(|a_path| Ok((a_path, existing_mask_path(&b)?)))
+            .and_then(|(a_path, b_path)| diff_masks(&a_path, &b_path));
+        let (diff_image, stats) = match diff {
+            Ok(result) => result,
+            Err(e) => {
+                log::debug!("Mask diff failed for {} / {}: {:?}", a, b, e);
+                let body = serde_json::json!({ "error": format!("{e:#}") });
+                write_response(
+                    stream,
+                    cors,
+                    400,
+                    "Bad Request",
+                    body.to_string().as_bytes(),
+                    "application/json",
+                )?;
+                return Ok(());
+            }
+        };
+
+        if query_param(query, "format") == Some("json") {
+            write_response(
+                stream,
+                cors,
+                200,
+                "OK",
+                stats.to_json().to_string().as_bytes(),
+                "application/json",
+            )?;
+        } else {
+            let png = encode_png(&diff_image)?;
+            write_response_with_headers(
+                stream,
+                cors,
+                200,
+                "OK",
+                &png,
+                "image/png",
+                &[
+                    ("X-Mask-IoU", format!("{:.6}", stats.iou())),
+                    ("X-Mask-Area-A", stats.area_a.to_string()),
+                    ("X-Mask-Area-B", stats.area_b.to_string()),
+                    (
+                        "Access-Control-Expose-Headers",
+                        "X-Mask-IoU, X-Mask-Area-A, X-Mask-Area-B".into(),
+                    ),
+                ],
+            )?;
+        }
+        return Ok(());
+    }
+
+    if let Some(name) = path.strip_prefix("/mask/") {
+        // 4K のマスクでも丸ごと読み込まず、静的ファイルと同じく少しずつ送る
+        match existing_mask_path(name).and_then(|p| open_file_streamed(&p, "image/png")) {
+            Ok(file) => send_file(stream, head, file)?,
+            Err(e) => {
+                log::debug!("GET /mask/{} failed: {:?}", name, e);
+                write_response(stream, cors, 404, "Not Found", b"Mask not found", "text/plain")?;
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some(name) = path
+        .strip_prefix("/api/masks/")
+        .and_then(|rest| rest.strip_suffix("/thumb.jpg"))
+    {
+        match existing_mask_path(name).and_then(|p| mask_thumbnail(&p)) {
+            Ok(data) => write_response(stream, cors, 200, "OK", &data, "image/jpeg")?,
+            Err(e) => {
+                log::debug!("Thumbnail not available for {}: {:?}", name, e);
+                write_response(stream, cors, 404, "Not Found", b"Not Found", "text/plain")?;
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some(name) = path
+        .strip_prefix("/api/masks/")
+        .and_then(|rest| rest.strip_suffix("/manifest"))
+    {
+        match existing_mask_path(name).and_then(|p| Ok(read(mask_manifest_path(&p))?)) {
+            Ok(data) => write_response(stream, cors, 200, "OK", &data, "application/json")?,
+            Err(e) => {
+                log::debug!("Mask manifest not found for {}: {:?}", name, e);
+                write_response(stream, cors, 404, "Not Found", b"Not Found", "text/plain")?;
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some((start, end)) = path
+        .strip_prefix("/api/frames/")
+        .and_then(|rest| rest.strip_suffix(".zip"))
+        .and_then(|range| range.split_once('-'))
+        .and_then(|(s, e)| Some((s.parse::<i32>().ok()?, e.parse::<i32>().ok()?)))
+    {
+        let object_id = query_param(query, "object")
+            .and_then(|v| v.parse::<i64>().ok())
+            .or(*lock_recover(current_edit_object_id()));
+        let Some(object_id) = object_id else {
+            write_response(stream, cors, 400, "Bad Request", b"object is required", "text/plain")?;
+            return Ok(());
+        };
+
+        // 範囲が広くても困らないよう、ディレクトリ側を走査して絞り込む
+        let mut frames: Vec<(i32, PathBuf)> = std::fs::read_dir(sequence_dir(object_id))
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .filter_map(|entry| {
+                        let name = entry.file_name();
+                        let frame = name
+                            .to_str()?
+                            .strip_prefix("frame_")?
+                            .strip_suffix(".png")?
+                            .parse::<i32>()
+                            .ok()?;
+                        (start..=end).contains(&frame).then(|| (frame, entry.path()))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        frames.sort();
+        let frames: Vec<PathBuf> = frames.into_iter().map(|(_, p)| p).collect();
+        if frames.is_empty() {
+            write_response(
+                stream,
+                cors,
+                404,
+                "Not Found",
+                b"no exported frames in range",
+                "text/plain",
+            )?;
+            return Ok(());
+        }
+
+        log::info!(
+            "Streaming {} frames of object {} as zip",
+            frames.len(),
+            object_id
+        );
+        write_zip_stream(
+            stream,
+            cors,
+            &format!("sam_seq_{object_id}_{start}-{end}.zip"),
+            &frames,
+        )?;
+        return Ok(());
+    }
+
+    #[cfg(feature = "ffmpeg")]
+    if let Some(object_id) = path
+        .strip_prefix("/api/sequences/")
+        .and_then(|rest| rest.strip_suffix("/encode"))
+        .and_then(|id| id.parse::<i64>().ok())
+    {
+        let status = lock_recover(encode_jobs()).get(&object_id).cloned();
+        let body = serde_json::json!({ "status": status.unwrap_or_else(|| "idle".into()) });
+        write_response(
+            stream,
+            cors,
+            200,
+            "OK",
+            body.to_string().as_bytes(),
+            "application/json",
+        )?;
+        return Ok(());
+    }
+
+    if path == "/api/objects" {
+        let body = objects_json();
+        write_response(
+            stream,
+            cors,
+            200,
+            "OK",
+            body.to_string().as_bytes(),
+            "application/json",
+        )?;
+        return Ok(());
+    }
+
+    if path == "/frame/delta.png" {
+        // ?since=<手元のフレームの ETag> から今のフレームまでに変わった範囲だけを返す。
+        // 範囲は X-Delta-Rect（x,y,幅,高さ）で、差分が無ければフレーム全体を返す
+        let Some(version) = lock_recover(current_frame_version()).clone() else {
+            write_response(stream, cors, 404, "Not Found", b"No frame exported yet", "text/plain")?;
+            return Ok(());
+        };
+        let since = query_param_decoded(query, "since");
+        if since.as_deref() == Some(version.etag.as_str()) {
+            let header = response_header(cors, 304, "Not Modified", "image/png", 0, &[("ETag", version.etag)]);
+            stream.write_all(header.as_bytes())?;
+            stream.flush()?;
+            return Ok(());
+        }
+
+        // 差分は常に PNG、フレーム全体は書き出した形式のまま返す
+        let (rect, data, content_type) = match version.delta {
+            Some(delta) if since.as_deref() == Some(delta.base_etag.as_str()) => (
+                format!("{},{},{},{}", delta.x, delta.y, delta.width, delta.height),
+                delta.png.to_vec(),
+                "image/png",
+            ),
+            _ => {
+                let (width, height) = lock_recover(last_frame_size()).unwrap_or_default();
+                (
+                    format!("0,0,{width},{height}"),
+                    read(current_frame_path()?)?,
+                    version.format.content_type(),
+                )
+            }
+        };
+        write_response_with_headers(
+            stream,
+            cors,
+            200,
+            "OK",
+            &data,
+            content_type,
+            &[("ETag", version.etag), ("X-Delta-Rect", rect)],
+        )?;
+        return Ok(());
+    }
+
+    if path == "/frame/current.png" {
+        // 最後に書き出したフレームはメモリから返す（保存先が遅いドライブでもファイルを読み直さない）
+        let (version, data) = match current_frame_data() {
+            Some((version, png)) => (Some(version), Some(png)),
+            None => (
+                lock_recover(current_frame_version()).clone(),
+                read(current_frame_path()?).ok().map(Arc::<[u8]>::from),
+            ),
+        };
+        let Some(data) = data else {
+            write_response(
+                stream,
+                cors,
+                404,
+                "Not Found",
+                b"current_frame.png not found",
+                "text/plain",
+            )?;
+            return Ok(());
+        };
+
+        // 新しいフレームがあるかのポーリングで毎回 PNG を送らないよう、ETag / Last-Modified で 304 を返す
+        let content_type = version.as_ref().map_or("image/png", |v| v.format.content_type());
+        let (etag, modified, session) = match version {
+            Some(version) => (version.etag, version.exported_at, version.session),
+            None => {
+                // プラグインが書き出していないファイル（前回のもの）は更新日時と大きさで代用する
+                let modified = std::fs::metadata(current_frame_path()?)
+                    .and_then(|metadata| metadata.modified())
+                    .unwrap_or(UNIX_EPOCH);
+                let secs = modified.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+                (format!("\"{:x}-{:x}\"", secs, data.len()), modified, None)
+            }
+        };
+        let mut validators = vec![
+            ("ETag", etag.clone()),
+            ("Last-Modified", http_date(modified)),
+            ("Cache-Control", "no-cache".to_string()),
+        ];
+        // マスクを送るときに付けてもらい、このフレームを書き出したオブジェクトに紐づける
+        if let Some(session) = session {
+            validators.push(("X-Sam-Session", session));
+        }
+        if is_not_modified(head, &etag, Some(modified)) {
+            let header = response_header(cors, 304, "Not Modified", content_type, data.len() as u64, &validators);
+            stream.write_all(header.as_bytes())?;
+            stream.flush()?;
+            return Ok(());
+        }
+
+        write_response_with_headers(stream, cors, 200, "OK", &data, content_type, &validators)?;
+        return Ok(());
+    }
+
+    let path = if path == "/" || path == "/index.html" {
+        index_page(head)
+    } else {
+        path
+    };
+
+    // ページを開こうとしたのなら、素の Not Found や展開できなかった埋め込みのコピーではなく、
+    // 配置の手順と診断結果を見せる
+    let wants_page = path == "/" || path.ends_with(".html") || is_spa_route(head);
+    if wants_page && setup_page_needed() {
+        return write_setup_page(stream, cors, path);
+    }
+
+    // それ以外は WEB_ROOT から静的ファイルとして探す。
+    // React Router などが使う深いパス（/editor など）は index.html を返し、ルーティングはページに任せる
+    let accept_encoding = head.header("accept-encoding");
+    let file = open_static_file(path, accept_encoding).or_else(|e| {
+        if is_spa_route(head) {
+            open_static_file(index_page(head), accept_encoding)
+        } else {
+            Err(e)
+        }
+    });
+    match file {
+        Ok(file) => send_file(stream, head, file)?,
+        Err(_) if wants_page && !web_root_installed() => write_setup_page(stream, cors, path)?,
+        Err(e) => {
+            log::debug!("Static file not found for {}: {:?}", path, e);
+            write_response(
+                stream,
+                cors,
+                404,
+                "Not Found",
+                b"Not Found",
+                "text/plain",
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Web UI の代わりにセットアップページを返す（/ と /index.html は 200、それ以外のページは 404）。
+fn write_setup_page(stream: &mut dyn Write, cors: Option<&str>, path: &str) -> AnyResult<()> {
+    log::info!("Web UI not installed, serving setup page for {}", path);
+    let (status, reason) = if path == "/" || path == "/index.html" {
+        (200, "OK")
+    } else {
+        (404, "Not Found")
+    };
+    write_response(
+        stream,
+        cors,
+        status,
+        reason,
+        setup_page_html().as_bytes(),
+        "text/html; charset=utf-8",
+    )
+}
+
+/// `GET /api/objects` のレスポンス。
+///
+/// オブジェクトごとに受け取ったラベル付きマスク（ラベルごとの最新）と、
+/// 設定で選ばれているマスク、`POST /mask/undo` で戻れる履歴を返す。
+fn objects_json() -> serde_json::Value {
+    let object_ids: Vec<i64> = lock_recover(mask_paths()).keys().copied().collect();
+
+    let objects: Vec<serde_json::Value> = object_ids
+        .into_iter()
+        .map(|object_id| {
+            let masks: Vec<serde_json::Value> = current_masks_for_object(object_id)
+                .iter()
+                .map(labeled_mask_json)
+                .collect();
+            let history: Vec<serde_json::Value> = lock_recover(mask_paths())
+                .get(&object_id)
+                .into_iter()
+                .flatten()
+                .map(labeled_mask_json)
+                .collect();
+            let selected = selected_mask_for_object(object_id)
+                .map(|m| m.path.file_name().unwrap_or_default().to_string_lossy().into_owned());
+
+            serde_json::json!({
+                "object_id": object_id,
+                "masks": masks,
+                "selected": selected,
+                "history": history,
+            })
+        })
+        .collect();
+
+    serde_json::json!({ "objects": objects })
+}
+
+fn labeled_mask_json(mask: &LabeledMask) -> serde_json::Value {
+    serde_json::json!({
+        "label": mask.label,
+        "file": mask.path.file_name().unwrap_or_default().to_string_lossy(),
+    })
+}
+
+/// ブラウザに返す index（モバイル端末には index.mobile.html があればそちら）
+fn index_page(head: &RequestHead) -> &'static str {
+    let has_mobile_page = web_root().join("index.mobile.html").is_file()
+        || embedded_asset("index.mobile.html").is_some();
+    if has_mobile_page && wants_mobile_ui(head) {
+        "/index.mobile.html"
+    } else {
+        "/index.html"
+    }
+}
+
+/// 見つからないパスに index.html を返してよいか（SPA のフォールバック）。
+///
+/// ブラウザのページ遷移（Accept: text/html）で、拡張子が無く /api/ でもないパスだけにする
+/// （index.js や API の 404 が HTML に化けないように）。
+fn is_spa_route(head: &RequestHead) -> bool {
+    let navigating = head
+        .header("accept")
+        .is_some_and(|accept| accept.contains("text/html"));
+    let last_segment = head.path.rsplit('/').next().unwrap_or_default();
+    navigating && !head.path.starts_with("/api/") && !last_segment.contains('.')
+}
+
+/// タッチ操作向け UI を返すべきか。
+///
+/// `?mobile=1` / `?mobile=0` で明示でき、指定が無ければ User-Agent で判定する。
+fn wants_mobile_ui(head: &RequestHead) -> bool {
+    match query_param(&head.query, "mobile") {
+        Some("1" | "true") => return true,
+        Some("0" | "false") => return false,
+        _ => {}
+    }
+
+    let ua = head.header("user-agent").unwrap_or("");
+    ["Mobi", "Android", "iPhone", "iPad"]
+        .iter()
+        .any(|keyword| ua.contains(keyword))
+}
+
+/// `GET /api/version` のレスポンスを組み立てる。
+///
+/// check が true のときだけ GitHub の最新リリースを問い合わせる。
+/// 問い合わせに失敗してもエラーにはせず、`latest` を null にして返す。
+fn version_info_json(check: bool) -> serde_json::Value {
+    let current = env!("CARGO_PKG_VERSION");
+
+    let latest = if check {
+        match latest_release_tag() {
+            Ok(tag) => Some(tag),
+            Err(e) => {
+                log::warn!("Failed to check latest release: {e:?}");
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let update_available = latest
+        .as_deref()
+        .is_some_and(|tag| is_newer_version(tag, current));
+
+    serde_json::json!({
+        "version": current,
+        "checked": check,
+        "latest": latest,
+        "update_available": update_available,
+        "release_url": format!("https://github.com/{GITHUB_REPO}/releases/latest"),
+    })
+}
+
+/// GitHub の最新リリースのタグ名（例: "v0.1.2"）を返す。
+///
+/// API のレート制限に引っかからないよう、成功した結果は 1 時間キャッシュする。
+fn latest_release_tag() -> AnyResult<String> {
+    static CACHE: Mutex<Option<(Instant, String)>> = Mutex::new(None);
+    const TTL: Duration = Duration::from_secs(60 * 60);
+
+    if let Some((fetched_at, tag)) = lock_recover(&CACHE).as_ref()
+        && fetched_at.elapsed() < TTL
+    {
+        return Ok(tag.clone());
+    }
+
+    let url = format!("https://api.github.com/repos/{GITHUB_REPO}/releases/latest");
+    log::info!("Checking latest release: {}", url);
+
+    let body = http_agent()
+        .get(&url)
+        .header("Accept", "application/vnd.github+json")
+        .call()?
+        .body_mut()
+        .read_to_string()?;
+
+    let json: serde_json::Value = serde_json::from_str(&body)?;
+    let tag = json["tag_name"]
+        .as_str()
+        .ok_or_else(|| anyhow::anyhow!("tag_name missing in release response"))?
+        .to_string();
+
+    *lock_recover(&CACHE) = Some((Instant::now(), tag.clone()));
+    Ok(tag)
+}
+
+/// 外部への HTTP(S) 通信に使うクライアント。
+fn http_agent() -> ureq::Agent {
+    let config = ureq::Agent::config_builder()
+        .timeout_global(Some(Duration::from_secs(10)))
+        .user_agent(format!("sam_frame_export_filter/{}", env!("CARGO_PKG_VERSION")))
+        .build();
+    config.into()
+}
+
+/// "v1.2.3" 形式のタグが current より新しいか。
+fn is_newer_version(tag: &str, current: &str) -> bool {
+    fn parse(v: &str) -> Vec<u64> {
+        v.trim_start_matches('v')
+            .split(['.', '-'])
+            .map_while(|part| part.parse().ok())
+            .collect()
+    }
+    parse(tag) > parse(current)
+}
+
+/// POST リクエストの処理。
+///
+/// `/mask` = 「SAMで切り抜かれた PNG を保存するだけ」
+fn handle_post(stream: &mut dyn Write, head: &RequestHead, body: &mut RequestBody) -> AnyResult<()> {
+    let cors = head.cors_origin.as_deref();
+    let path = head.path.as_str();
+
+    if path == "/mask" {
+        // multipart/form-data なら複数枚のマスク（フィールド名 = ラベル）と、テキストフィールドの
+        // object_id・session・任意のメタデータ（フレーム番号やプロンプト座標など）をまとめて受け取る
+        let parts = match head.header("content-type").and_then(multipart_boundary) {
+            Some(boundary) => {
+                let root = lock_recover(export_root_dir()).clone();
+                create_dir_all(&root)?;
+                Some(read_multipart(&mut body.reader, &boundary, &root)?)
+            }
+            None => None,
+        };
+        let fields = parts.as_deref().map(multipart_text_fields).unwrap_or_default();
+        let field = |name: &str| {
+            fields
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.as_str())
+        };
+
+        // どのオブジェクトのマスクかを決める（マップに紐づけるためだけに使う。ファイル名には一切使わない）。
+        // object_id フィールド（クエリなら `?object=`）、フレームと一緒に渡したセッション ID の書き出し元、
+        // 現在編集中のオブジェクトの順
+        let session = field("session")
+            .or_else(|| head.header("x-sam-session"))
+            .or_else(|| query_param(&head.query, "session"));
+        let object_id_opt = match (field("object_id").or_else(|| query_param(&head.query, "object")), session) {
+            (Some(value), _) => {
+                let Ok(object_id) = value.trim().parse::<i64>() else {
+                    discard_multipart(parts);
+                    write_response(
+                        stream,
+                        cors,
+                        400,
+                        "Bad Request",
+                        b"object_id must be an integer",
+                        "text/plain",
+                    )?;
+                    return Ok(());
+                };
+                Some(object_id)
+            }
+            (None, Some(session)) => {
+                let Some(object_id) = export_session_object(session) else {
+                    log::warn!("POST /mask called with unknown session {}", session);
+                    discard_multipart(parts);
+                    write_response(
+                        stream,
+                        cors,
+                        409,
+                        "Conflict",
+                        b"Unknown or expired session; reload the frame from AviUtl2",
+                        "text/plain",
+                    )?;
+                    return Ok(());
+                };
+                Some(object_id)
+            }
+            (None, None) => *lock_recover(current_edit_object_id()),
+        };
+
+        let Some(object_id) = object_id_opt else {
+            log::warn!("POST /mask called but no current editing object id set");
+            discard_multipart(parts);
+            write_response(
+                stream,
+                cors,
+                400,
+                "Bad Request",
+                b"No editing object",
+                "text/plain",
+            )?;
+            return Ok(());
+        };
+
+        if let Some(parts) = parts {
+            // 1 枚でも使えないマスクがあれば何も保存しない
+            let invalid = parts.iter().find_map(|part| match &part.data {
+                PartData::File(path) => validate_mask_png(path, Some(object_id))
+                    .err()
+                    .map(|reason| format!("Invalid mask in field {:?}: {reason}", part.name)),
+                PartData::Memory(_) => None,
+            });
+            if let Some(message) = invalid {
+                log::warn!("Rejected multipart masks for object {}: {}", object_id, message);
+                discard_multipart(Some(parts));
+                write_response(stream, cors, 400, "Bad Request", message.as_bytes(), "text/plain")?;
+                return Ok(());
+            }
+
+            // object_id と session 以外のテキストフィールドはマニフェストの metadata に残す
+            let metadata: serde_json::Map<String, serde_json::Value> = fields
+                .iter()
+                .filter(|(k, _)| k != "object_id" && k != "session")
+                .map(|(k, v)| {
+                    let value = serde_json::from_str(v).unwrap_or_else(|_| v.clone().into());
+                    (k.clone(), value)
+                })
+                .collect();
+            let saved = save_multipart_masks(object_id, parts, &metadata)?;
+            if saved.is_empty() {
+                write_response(
+                    stream,
+                    cors,
+                    400,
+                    "Bad Request",
+                    b"No mask file in multipart body",
+                    "text/plain",
+                )?;
+            } else {
+                write_mask_saved_response(stream, head, object_id, &saved)?;
+            }
+            return Ok(());
+        }
+
+        let root = lock_recover(export_root_dir()).clone();
+        create_dir_all(&root)?;
+        let (spool_path, written) = body.spool(&root)?;
+        if let Err(reason) = validate_mask_png(&spool_path, Some(object_id)) {
+            // 壊れたファイルを黙って残さない
+            let _ = remove_file(&spool_path);
+            log::warn!("Rejected mask for object {}: {}", object_id, reason);
+            let message = format!("Invalid mask: {reason}");
+            write_response(stream, cors, 400, "Bad Request", message.as_bytes(), "text/plain")?;
+            return Ok(());
+        }
+        let mask_path = move_to_new_mask_path(&spool_path, object_id, None)?;
+        log::info!(
+            "Saved mask PNG for object {} to {} ({} bytes)",
+            object_id,
+            mask_path.display(),
+            written
+        );
+        register_saved_mask(object_id, None, &mask_path)?;
+
+        write_mask_saved_response(stream, head, object_id, &[mask_path])?;
+        return Ok(());
+    }
+
+    if path == "/mask/undo" {
+        // 最新のマスクを履歴から外し、一つ前のマスクに戻す（ファイルは残す）
+        let object_id = query_param(&head.query, "object")
+            .and_then(|v| v.parse::<i64>().ok())
+            .or(*lock_recover(current_edit_object_id()));
+        let Some(object_id) = object_id else {
+            write_response(stream, cors, 400, "Bad Request", b"object is required", "text/plain")?;
+            return Ok(());
+        };
+        let label = query_param_decoded(&head.query, "label");
+
+        let Some((undone, current)) = undo_mask_for_object(object_id, label.as_deref()) else {
+            write_response(stream, cors, 404, "Not Found", b"No mask to undo", "text/plain")?;
+            return Ok(());
+        };
+        log::info!(
+            "Undid mask {} for object {} (now {})",
+            undone.path.display(),
+            object_id,
+            current
+                .as_ref()
+                .map_or("none".to_string(), |m| m.path.display().to_string())
+        );
+        record_history(
+            HistoryKind::MaskUndone,
+            Some(object_id),
+            format!("mask {} undone", undone.path.display()),
+        );
+
+        let body = serde_json::json!({
+            "object_id": object_id,
+            "undone": labeled_mask_json(&undone),
+            "current": current.as_ref().map(labeled_mask_json),
+            "selected": selected_mask_for_object(object_id)
+                .map(|m| m.path.file_name().unwrap_or_default().to_string_lossy().into_owned()),
+        });
+        write_response(
+            stream,
+            cors,
+            200,
+            "OK",
+            body.to_string().as_bytes(),
+            "application/json",
+        )?;
+        return Ok(());
+    }
+
+    if path == "/api/mask/patch" {
+        // 修正した範囲だけの PNG を受け取り、オブジェクトの今のマスクに書き込む
+        let object_id = query_param(&head.query, "object")
+            .and_then(|v| v.parse::<i64>().ok())
+            .or(*lock_recover(current_edit_object_id()));
+        let x = query_param(&head.query, "x").and_then(|v| v.parse::<i64>().ok());
+        let y = query_param(&head.query, "y").and_then(|v| v.parse::<i64>().ok());
+        let (Some(object_id), Some(x), Some(y)) = (object_id, x, y) else {
+            write_response(
+                stream,
+                cors,
+                400,
+                "Bad Request",
+                b"object, x and y are required",
+                "text/plain",
+            )?;
+            return Ok(());
+        };
+
+        let mask = match query_param_decoded(&head.query, "label") {
+            Some(label) => lock_recover(mask_paths())
+                .get(&object_id)
+                .and_then(|masks| latest_mask_with_label(masks, Some(&label)).cloned()),
+            None => selected_mask_for_object(object_id),
+        };
+        let Some(mask) = mask else {
+            write_response(stream, cors, 404, "Not Found", b"No mask to patch", "text/plain")?;
+            return Ok(());
+        };
+
+        let patch = body.read_to_vec()?;
+        // 同じマスクへの修正が読み書きの途中で重ならないよう、受信が済んでからロックする
+        let patched = {
+            let _upload = lock_recover(upload_lock());
+            apply_mask_patch(&mask.path, &patch, x, y)
+        };
+        match patched {
+            Ok((width, height)) => {
+                log::info!(
+                    "Patched {}x{} at {},{} into mask {}",
+                    width,
+                    height,
+                    x,
+                    y,
+                    mask.path.display()
+                );
+                postprocess_saved_mask(&mask.path, &MaskFrame::for_object(Some(object_id)), None)?;
+                record_history(
+                    HistoryKind::MaskReceived,
+                    Some(object_id),
+                    format!("mask {} patched ({width}x{height} at {x},{y})", mask.path.display()),
+                );
+                let body = serde_json::json!({
+                    "mask": mask.path.file_name().map(|n| n.to_string_lossy().into_owned()),
+                    "x": x,
+                    "y": y,
+                    "width": width,
+                    "height": height,
+                });
+                write_response(
+                    stream,
+                    cors,
+                    200,
+                    "OK",
+                    body.to_string().as_bytes(),
+                    "application/json",
+                )?;
+            }
+            Err(e) => {
+                log::warn!("Mask patch rejected: {e:?}");
+                let body = serde_json::json!({ "error": format!("{e:#}") });
+                write_response(
+                    stream,
+                    cors,
+                    400,
+                    "Bad Request",
+                    body.to_string().as_bytes(),
+                    "application/json",
+                )?;
+            }
+        }
+        return Ok(());
+    }
+
+    #[cfg(feature = "native-sam")]
+    if path == "/api/sam/encode" || path == "/api/sam/predict" {
+        // ネイティブ推論。encode は現在のフレームを先にエンコードしておくだけ
+        let result = if path == "/api/sam/encode" {
+            sam::encode_current_frame().map(|(width, height)| {
+                let body = serde_json::json!({ "width": width, "height": height });
+                (body.to_string().into_bytes(), "application/json", None)
+            })
+        } else {
+            body.read_to_vec()
+                .and_then(|request| sam_predict_png(&request))
+                .map(|(png, score)| (png, "image/png", Some(score)))
+        };
+        match result {
+            Ok((body, content_type, score)) => {
+                let headers: Vec<(&str, String)> = score
+                    .map(|score| ("X-Sam-Score", format!("{score:.4}")))
+                    .into_iter()
+                    .collect();
+                write_response_with_headers(stream, cors, 200, "OK", &body, content_type, &headers)?;
+            }
+            Err(e) => {
+                log::warn!("Native SAM failed: {e:?}");
+                let body = serde_json::json!({ "error": format!("{e:#}") });
+                write_response(
+                    stream,
+                    cors,
+                    500,
+                    "Internal Server Error",
+                    body.to_string().as_bytes(),
+                    "application/json",
+                )?;
+            }
+        }
+        return Ok(());
+    }
+
+    #[cfg(feature = "native-sam")]
+    if path == "/api/sam/propagate" {
+        // from 番のフレームのマスク（連番マスクが無ければ選ばれているマスク）を連番フレームへ伝播させる。
+        // from を省くと最後に current_frame.png を書き出したフレーム
+        let Some(object_id) = query_param(&head.query, "object").and_then(|v| v.parse::<i64>().ok()) else {
+            write_response(stream, cors, 400, "Bad Request", b"object is required", "text/plain")?;
+            return Ok(());
+        };
+        let from = query_param(&head.query, "from")
+            .and_then(|v| v.parse::<i32>().ok())
+            .or_else(|| crate::state::exported_frame_for_object(object_id).map(|info| info.frame));
+        let seed = from.and_then(|from| {
+            lock_recover(crate::state::mask_sequences())
+                .get(&object_id)
+                .and_then(|masks| masks.get(&from).cloned())
+                .or_else(|| selected_mask_for_object(object_id).map(|mask| mask.path))
+        });
+        let (Some(from), Some(seed)) = (from, seed) else {
+            write_response(stream, cors, 404, "Not Found", b"No mask to propagate", "text/plain")?;
+            return Ok(());
+        };
+
+        match sam::propagate_mask(object_id, from, &seed) {
+            Ok(frames) => {
+                record_history(
+                    HistoryKind::MaskReceived,
+                    Some(object_id),
+                    format!("mask of frame {from} propagated to {} frames", frames.len()),
+                );
+                let body = serde_json::json!({ "object_id": object_id, "from": from, "frames": frames });
+                write_response(stream, cors, 200, "OK", body.to_string().as_bytes(), "application/json")?;
+            }
+            Err(e) => {
+                log::warn!("Mask propagation failed: {e:?}");
+                record_history(
+                    HistoryKind::Error,
+                    Some(object_id),
+                    format!("mask propagation failed: {e:#}"),
+                );
+                let body = serde_json::json!({ "error": format!("{e:#}") });
+                write_response(
+                    stream,
+                    cors,
+                    500,
+                    "Internal Server Error",
+                    body.to_string().as_bytes(),
+                    "application/json",
+                )?;
+            }
+        }
+        return Ok(());
+    }
+
+    if path == "/api/open-folder" {
+        // 完了ページの「フォルダを開く」。mask があればそのファイルを選択した状態で開く
+        if sandbox_dir().is_some() {
+            write_response(
+                stream,
+                cors,
+                403,
+                "Forbidden",
+                b"Opening folders is disabled in sandbox mode",
+                "text/plain",
+            )?;
+            return Ok(());
+        }
+        let select = match query_param_decoded(&head.query, "mask") {
+            Some(name) => match existing_mask_path(&name) {
+                Ok(path) => Some(path),
+                Err(_) => {
+                    write_response(stream, cors, 404, "Not Found", b"Mask not found", "text/plain")?;
+                    return Ok(());
+                }
+            },
+            None => None,
+        };
+        let mut command = Command::new("explorer");
+        match &select {
+            Some(path) => command.arg(format!("/select,{}", path.display())),
+            None => command.arg(lock_recover(export_root_dir()).as_os_str()),
+        };
+        if let Err(e) = command.spawn() {
+            log::error!("Failed to open folder: {e:?}");
+        }
+        write_response(stream, cors, 200, "OK", b"OK", "text/plain")?;
+        return Ok(());
+    }
+
+    if path == "/api/setup" || path == "/api/repair" {
+        // サンドボックスではインストール済みの Web UI を書き換えない
+        if sandbox_dir().is_some() {
+            write_response(
+                stream,
+                cors,
+                403,
+                "Forbidden",
+                b"Web UI setup is disabled in sandbox mode",
+                "text/plain",
+            )?;
+            return Ok(());
+        }
+        let result = if path == "/api/setup" {
+            install_web_bundle()
+        } else {
+            repair_web_assets()
+        };
+        match result {
+            Ok(files) => {
+                let body = serde_json::json!({ "installed": files });
+                write_response(
+                    stream,
+                    cors,
+                    200,
+                    "OK",
+                    body.to_string().as_bytes(),
+                    "application/json",
+                )?;
+            }
+            Err(e) => {
+                log::error!("{} failed: {e:?}", path);
+                record_history(HistoryKind::Error, None, format!("{path} failed: {e:#}"));
+                let body = serde_json::json!({ "error": format!("{e:#}") });
+                write_response(
+                    stream,
+                    cors,
+                    500,
+                    "Internal Server Error",
+                    body.to_string().as_bytes(),
+                    "application/json",
+                )?;
+            }
+        }
+        return Ok(());
+    }
+
+    #[cfg(feature = "ffmpeg")]
+    if let Some(object_id) = path
+        .strip_prefix("/api/sequences/")
+        .and_then(|rest| rest.strip_suffix("/encode"))
+        .and_then(|id| id.parse::<i64>().ok())
+    {
+        let format = match query_param(&head.query, "format").unwrap_or("webm") {
+            "webm" => VideoFormat::WebM,
+            "mov" => VideoFormat::Mov,
+            other => {
+                let body = format!("unsupported format: {other}");
+                write_response(stream, cors, 400, "Bad Request", body.as_bytes(), "text/plain")?;
+                return Ok(());
+            }
+        };
+
+        let dir = sequence_dir(object_id);
+        let video_path = dir.join(format!("sam_seq_{object_id}.{}", format.extension()));
+        let exo_path = video_path.with_extension("exo");
+        start_sequence_encode(object_id, format, video_path.clone(), exo_path.clone());
+
+        let body = serde_json::json!({
+            "status": "running",
+            "video": video_path.display().to_string(),
+            "exo": exo_path.display().to_string(),
+        });
+        write_response(
+            stream,
+            cors,
+            202,
+            "Accepted",
+            body.to_string().as_bytes(),
+            "application/json",
+        )?;
+        return Ok(());
+    }
+
+    // /mask/sequence は SAM2 などのビデオトラッキングの出力を送る側から見た別名
+    if path == "/api/masks/bulk" || path == "/mask/sequence" {
+        let object_id = query_param(&head.query, "object")
+            .and_then(|v| v.parse::<i64>().ok())
+            .or(*lock_recover(current_edit_object_id()));
+        let Some(object_id) = object_id else {
+            write_response(stream, cors, 400, "Bad Request", b"object is required", "text/plain")?;
+            return Ok(());
+        };
+
+        let dir = sequence_dir(object_id);
+        create_dir_all(&dir)?;
+        let result = match head.header("content-type").and_then(multipart_boundary) {
+            Some(boundary) => read_multipart(&mut body.reader, &boundary, &dir)
+                .and_then(|parts| save_bulk_multipart_masks(object_id, parts)),
+            None => {
+                let (zip_path, _) = body.spool(&dir)?;
+                let result = save_bulk_zip_masks(object_id, &zip_path);
+                let _ = remove_file(&zip_path);
+                result
+            }
+        };
+
+        match result {
+            Ok(frames) if frames.is_empty() => {
+                write_response(
+                    stream,
+                    cors,
+                    400,
+                    "Bad Request",
+                    b"No mask named by frame number",
+                    "text/plain",
+                )?;
+            }
+            Ok(frames) => {
+                log::info!(
+                    "Registered {} sequence masks for object {}",
+                    frames.len(),
+                    object_id
+                );
+                record_history(
+                    HistoryKind::MaskReceived,
+                    Some(object_id),
+                    format!("{} sequence masks received", frames.len()),
+                );
+                #[allow(unused_mut)]
+                let mut body = serde_json::json!({ "object_id": object_id, "frames": frames });
+                #[cfg(feature = "ffmpeg")]
+                {
+                    body["encode"] = format!("/api/sequences/{object_id}/encode").into();
+                }
+                write_response(
+                    stream,
+                    cors,
+                    200,
+                    "OK",
+                    body.to_string().as_bytes(),
+                    "application/json",
+                )?;
+            }
+            Err(e) => {
+                log::error!("Bulk mask upload failed: {e:?}");
+                record_history(
+                    HistoryKind::Error,
+                    Some(object_id),
+                    format!("bulk mask upload failed: {e:#}"),
+                );
+                let body = serde_json::json!({ "error": format!("{e:#}") });
+                write_response(
+                    stream,
+                    cors,
+                    400,
+                    "Bad Request",
+                    body.to_string().as_bytes(),
+                    "application/json",
+                )?;
+            }
+        }
+        return Ok(());
+    }
+
+    // 未対応パス
+    write_response(
+        stream,
+        cors,
+        404,
+        "Not Found",
+        b"Not Found",
+        "text/plain",
+    )?;
+    Ok(())
+}
+
+/// multipart で受け取ったファイルパートをそれぞれマスクとして保存する。
+///
+/// フィールド名をラベルとして扱う（"mask" または空ならラベル無し）。
+/// 戻り値は保存したマスクの枚数。
+fn save_multipart_masks(
+    object_id: i64,
+    parts: Vec<MultipartPart>,
+    metadata: &serde_json::Map<String, serde_json::Value>,
+) -> AnyResult<Vec<PathBuf>> {
+    let mut saved = Vec::new();
+    let mut parts = parts.into_iter();
+
+    let result = (|| -> AnyResult<()> {
+        for part in parts.by_ref() {
+            // テキストフィールドは呼び出し側で object_id やメタデータとして読んでいる
+            let PartData::File(spool_path) = part.data else {
+                continue;
+            };
+            let label = Some(part.name.as_str()).filter(|n| !n.is_empty() && *n != "mask");
+
+            let mask_path = move_to_new_mask_path(&spool_path, object_id, label)?;
+            log::info!(
+                "Saved mask PNG for object {} (label {:?}) to {}",
+                object_id,
+                label,
+                mask_path.display()
+            );
+
+            register_saved_mask(object_id, label, &mask_path)?;
+            if !metadata.is_empty() {
+                record_mask_metadata(&mask_path, metadata)?;
+            }
+            saved.push(mask_path);
+        }
+        Ok(())
+    })();
+
+    // 途中で失敗した場合に残った一時ファイルを片付ける
+    for part in parts {
+        if let PartData::File(spool_path) = part.data {
+            let _ = remove_file(spool_path);
+        }
+    }
+
+    result.map(|_| saved)
+}
+
+/// multipart の各ファイルパートを、フィールド名のフレーム番号で連番マスクとして保存する。
+///
+/// 戻り値は登録したフレーム番号（昇順）。
+fn save_bulk_multipart_masks(object_id: i64, parts: Vec<MultipartPart>) -> AnyResult<Vec<i32>> {
+    let dir = sequence_dir(object_id);
+    let mut frames = Vec::new();
+    let mut parts = parts.into_iter();
+
+    let result = (|| -> AnyResult<()> {
+        for part in parts.by_ref() {
+            let PartData::File(spool_path) = part.data else {
+                continue;
+            };
+            let Some(frame) = frame_number_from_name(&part.name) else {
+                log::debug!("Ignoring bulk part without frame number: {}", part.name);
+                let _ = remove_file(&spool_path);
+                continue;
+            };
+
+            let mask_path = dir.join(sequence_mask_name(frame));
+            rename(&spool_path, &mask_path)?;
+            register_sequence_mask(object_id, frame, &mask_path)?;
+            frames.push(frame);
+        }
+        Ok(())
+    })();
+
+    for part in parts {
+        if let PartData::File(spool_path) = part.data {
+            let _ = remove_file(spool_path);
+        }
+    }
+
+    frames.sort_unstable();
+    frames.dedup();
+    result.map(|_| frames)
+}
+
+// ── multipart/form-data ────────────────────────────────────────────────
+
+/// multipart のパート 1 つ分
+struct MultipartPart {
+    /// Content-Disposition の name
+    name: String,
+    data: PartData,
+}
+
+enum PartData {
+    /// テキストフィールドはメモリに保持する
+    Memory(Vec<u8>),
+    /// ファイルパートは spool_dir の一時ファイルに書き出す
+    File(PathBuf),
+}
+
+/// multipart のテキストフィールド（名前と UTF-8 として読んだ値）
+fn multipart_text_fields(parts: &[MultipartPart]) -> Vec<(String, String)> {
+    parts
+        .iter()
+        .filter_map(|part| match &part.data {
+            PartData::Memory(data) => Some((part.name.clone(), String::from_utf8_lossy(data).into_owned())),
+            PartData::File(_) => None,
+        })
+        .collect()
+}
+
+/// 使わなかった multipart の一時ファイルを削除する。
+fn discard_multipart(parts: Option<Vec<MultipartPart>>) {
+    for part in parts.into_iter().flatten() {
+        if let PartData::File(path) = part.data {
+            let _ = remove_file(path);
+        }
+    }
+}
+
+/// Content-Type ヘッダから multipart の boundary を取り出す。
+fn multipart_boundary(content_type: &str) -> Option<String> {
+    let (mime, params) = content_type.split_once(';')?;
+    if !mime.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params
+        .split(';')
+        .filter_map(|p| p.trim().split_once('='))
+        .find(|(k, _)| k.eq_ignore_ascii_case("boundary"))
+        .map(|(_, v)| v.trim_matches('"').to_string())
+}
+
+/// multipart/form-data のボディを読み、パートの一覧を返す。
+///
+/// ファイルパートはメモリに溜めず、受信しながら spool_dir の一時ファイルへ書き出す。
+/// エラー時は作成済みの一時ファイルを削除する。
+fn read_multipart(
+    reader: &mut impl Read,
+    boundary: &str,
+    spool_dir: &Path,
+) -> AnyResult<Vec<MultipartPart>> {
+    let mut parts = Vec::new();
+    let result = read_multipart_into(reader, boundary, spool_dir, &mut parts);
+    if result.is_err() {
+        for part in &parts {
+            if let PartData::File(path) = &part.data {
+                let _ = remove_file(path);
+            }
+        }
+    }
+    result.map(|_| parts)
+}
+
+fn read_multipart_into(
+    reader: &mut impl Read,
+    boundary: &str,
+    spool_dir: &Path,
+    parts: &mut Vec<MultipartPart>,
+) -> AnyResult<()> {
+    /// テキストフィールドの上限
+    const MAX_FIELD_SIZE: usize = 64 * 1024;
+
+    // 先頭の "--boundary" も同じ形で探せるよう、バッファを "\r\n" で始める
+    let delimiter = format!("\r\n--{boundary}").into_bytes();
+    let mut buf = b"\r\n".to_vec();
+    let mut chunk = [0u8; 64 * 1024];
+    let mut eof = false;
+
+    // ソケットから 1 チャンク読み足す
+    let mut read_more = |buf: &mut Vec<u8>, eof: &mut bool| -> AnyResult<()> {
+        let n = reader.read(&mut chunk)?;
+        if n == 0 {
+            *eof = true;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        Ok(())
+    };
+    let find = |buf: &[u8], needle: &[u8]| buf.windows(needle.len()).position(|w| w == needle);
+
+    // プリアンブルを読み飛ばして最初の区切りへ
+    loop {
+        if let Some(pos) = find(&buf, &delimiter) {
+            buf.drain(..pos + delimiter.len());
+            break;
+        }
+        if eof {
+            return Err(anyhow::anyhow!("multipart boundary not found"));
+        }
+        let keep = buf.len().saturating_sub(delimiter.len());
+        buf.drain(..keep);
+        read_more(&mut buf, &mut eof)?;
+    }
+
+    loop {
+        // 区切りの直後が "--" なら終端
+        while buf.len() < 2 && !eof {
+            read_more(&mut buf, &mut eof)?;
+        }
+        if buf.starts_with(b"--") {
+            return Ok(());
+        }
+
+        // パートのヘッダ
+        let header_end = loop {
+            if let Some(pos) = find(&buf, b"\r\n\r\n") {
+                break pos;
+            }
+            if eof || buf.len() > 16 * 1024 {
+                return Err(anyhow::anyhow!("malformed multipart part header"));
+            }
+            read_more(&mut buf, &mut eof)?;
+        };
+        let header_str = String::from_utf8_lossy(&buf[..header_end]).into_owned();
+        buf.drain(..header_end + 4);
+
+        let mut name = String::new();
+        let mut is_file = false;
+        for line in header_str.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            if !key.trim().eq_ignore_ascii_case("content-disposition") {
+                continue;
+            }
+            for param in value.split(';').skip(1) {
+                match param.trim().split_once('=') {
+                    Some(("name", v)) => name = v.trim_matches('"').to_string(),
+                    Some(("filename", _)) => is_file = true,
+                    _ => {}
+                }
+            }
+        }
+
+        // 本文を次の区切りまで sink に流す
+        let mut memory = Vec::new();
+        let mut file = None;
+        if is_file {
+            let spool_path = new_spool_path(spool_dir);
+            file = Some((BufWriter::new(File::create(&spool_path)?), spool_path));
+        }
+
+        loop {
+            let (data_end, found) = match find(&buf, &delimiter) {
+                Some(pos) => (pos, true),
+                None => (buf.len().saturating_sub(delimiter.len()), false),
+            };
+
+            match &mut file {
+                Some((writer, _)) => writer.write_all(&buf[..data_end])?,
+                None => {
+                    memory.extend_from_slice(&buf[..data_end]);
+                    if memory.len() > MAX_FIELD_SIZE {
+                        return Err(anyhow::anyhow!("multipart field {name} too large"));
+                    }
+                }
+            }
+
+            if found {
+                buf.drain(..data_end + delimiter.len());
+                break;
+            }
+            buf.drain(..data_end);
+            if eof {
+                if let Some((_, spool_path)) = file {
+                    let _ = remove_file(spool_path);
+                }
+                return Err(anyhow::anyhow!("multipart body ended before closing boundary"));
+            }
+            read_more(&mut buf, &mut eof)?;
+        }
+
+        let data = match file {
+            Some((mut writer, spool_path)) => {
+                writer.flush()?;
+                PartData::File(spool_path)
+            }
+            None => PartData::Memory(memory),
+        };
+        parts.push(MultipartPart { name, data });
+    }
+}
+
+/// PUT リクエストの処理。
+///
+/// `/masks/{name}` = 既存のマスク PNG をその場で置き換える。
+/// タイムラインのオブジェクトが参照しているファイル名を変えずに修正を反映するためのもの。
+/// `?backup=1` を付けると置き換え前の内容を `{name}.bak` に退避する（毎回上書き）。
+fn handle_put(stream: &mut dyn Write, head: &RequestHead, body: &mut RequestBody) -> AnyResult<()> {
+    let cors = head.cors_origin.as_deref();
+    let path = head.path.as_str();
+    let query = head.query.as_str();
+
+    if let Some(name) = path.strip_prefix("/masks/") {
+        let mask_path = match existing_mask_path(name) {
+            Ok(p) => p,
+            Err(e) => {
+                log::warn!("PUT /masks/{} rejected: {e:?}", name);
+                write_response(stream, cors, 404, "Not Found", b"Mask not found", "text/plain")?;
+                return Ok(());
+            }
+        };
+
+        if body.content_length == Some(0) {
+            write_response(stream, cors, 400, "Bad Request", b"Empty body", "text/plain")?;
+            return Ok(());
+        }
+
+        if query_flag(query, "backup") {
+            let mut backup_name = mask_path.as_os_str().to_owned();
+            backup_name.push(".bak");
+            let backup_path = PathBuf::from(backup_name);
+            copy(&mask_path, &backup_path)?;
+            log::info!("Backed up {} to {}", mask_path.display(), backup_path.display());
+        }
+
+        // 同じフォルダに受け取ってから差し替える（同時の PUT が書きかけのファイルを混ぜないように）
+        let (spool_path, written) = body.spool(mask_path.parent().unwrap_or(Path::new(".")))?;
+        // 差し替え前のマスクのオブジェクトのフレームに合わせる
+        let object_id = mask_object_id(&mask_path);
+        if let Err(reason) = validate_mask_png(&spool_path, object_id) {
+            // 今のマスク（とバックアップ）はそのまま残す
+            let _ = remove_file(&spool_path);
+            log::warn!("Rejected replacement for mask {}: {}", mask_path.display(), reason);
+            let message = format!("Invalid mask: {reason}");
+            write_response(stream, cors, 400, "Bad Request", message.as_bytes(), "text/plain")?;
+            return Ok(());
+        }
+        if let Err(e) = rename(&spool_path, &mask_path) {
+            let _ = remove_file(&spool_path);
+            return Err(e.into());
+        }
+        log::info!(
+            "Replaced mask PNG {} ({} bytes)",
+            mask_path.display(),
+            written
+        );
+        postprocess_saved_mask(&mask_path, &MaskFrame::for_object(object_id), None)?;
+        record_history(
+            HistoryKind::MaskReceived,
+            None,
+            format!("mask {} replaced", mask_path.display()),
+        );
+
+        write_response(stream, cors, 200, "OK", b"OK", "text/plain")?;
+        return Ok(());
+    }
+
+    write_response(
+        stream,
+        cors,
+        404,
+        "Not Found",
+        b"Not Found",
+        "text/plain",
+    )?;
+    Ok(())
+}
+
+/// DELETE リクエストの処理。
+///
+/// `/mask/{name}` = 保存済みのマスクと派生ファイルを削除し、オブジェクトとの紐づけも外す。
+fn handle_delete(stream: &mut dyn Write, head: &RequestHead) -> AnyResult<()> {
+    let cors = head.cors_origin.as_deref();
+    let Some(name) = head.path.strip_prefix("/mask/") else {
+        write_response(stream, cors, 404, "Not Found", b"Not Found", "text/plain")?;
+        return Ok(());
+    };
+
+    let mask_path = match existing_mask_path(name) {
+        Ok(p) => p,
+        Err(e) => {
+            log::warn!("DELETE /mask/{} rejected: {e:?}", name);
+            write_response(stream, cors, 404, "Not Found", b"Mask not found", "text/plain")?;
+            return Ok(());
+        }
+    };
+
+    let removed = delete_mask(&mask_path)?;
+    let object_id = remove_mask_path(&mask_path);
+    log::info!("Deleted mask {} ({} files)", mask_path.display(), removed.len());
+    record_history(
+        HistoryKind::MaskDeleted,
+        object_id,
+        format!("mask {} deleted", mask_path.display()),
+    );
+
+    let body = serde_json::json!({
+        "deleted": name,
+        "object_id": object_id,
+        "files": removed
+            .iter()
+            .map(|p| p.file_name().unwrap_or_default().to_string_lossy().into_owned())
+            .collect::<Vec<_>>(),
+    });
+    write_response(
+        stream,
+        cors,
+        200,
+        "OK",
+        body.to_string().as_bytes(),
+        "application/json",
+    )?;
+    Ok(())
+}
+
+/// クエリ文字列 `a=1&b=2` から key の値を取り出す。
+fn query_param<'a>(query: &'a str, key: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+}
+
+/// query_param の値を percent_decode して返す（ファイル名などに使う値用）。
+fn query_param_decoded(query: &str, key: &str) -> Option<String> {
+    query_param(query, key).map(percent_decode)
+}
+
+/// クエリの値の `%XX` と `+` を戻す（不正な並びはそのまま残す）。
+fn percent_decode(value: &str) -> String {
+    decode_percent(value, true)
+}
+
+/// パスの `%XX` を戻す（パスの `+` は空白ではないのでそのまま）。
+fn percent_decode_path(value: &str) -> String {
+    decode_percent(value, false)
+}
+
+fn decode_percent(value: &str, plus_as_space: bool) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' if plus_as_space => out.push(b' '),
+            b'%' if let Some(byte) = value
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()) =>
+            {
+                out.push(byte);
+                i += 2;
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// クエリ文字列から同じ key の値をすべて取り出す（`mask=a&mask=b`）。
+fn query_params<'a>(query: &'a str, key: &'a str) -> impl Iterator<Item = &'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+        .filter(move |(k, _)| *k == key)
+        .map(|(_, v)| v)
+}
+
+/// クエリの値に使えない文字を `%XX` にする（percent_decode の逆）。
+fn percent_encode(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.') {
+            out.push(byte as char);
+        } else {
+            out.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    out
+}
+
+/// HTML に埋め込む文字列の `& < > " '` をエスケープする。
+fn html_escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// `?key=1` / `?key=true` / `?key` を真として扱う。
+fn query_flag(query: &str, key: &str) -> bool {
+    matches!(query_param(query, key), Some("" | "1" | "true"))
+}
+
+// ── 自己診断 ───────────────────────────────────────────────────────────
+
+/// 保存先の空き容量がこれより少なければ診断で警告する
+const LOW_DISK_SPACE_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// 診断の 1 項目（name は Web UI などが見分けるための名前、label は表示用）
+fn diagnostic_check(name: &str, label: &str, ok: bool, detail: String) -> serde_json::Value {
+    serde_json::json!({ "name": name, "label": label, "ok": ok, "detail": detail })
+}
+
+/// dir に実際にファイルを書いて消せるか試す（フォルダが無ければ作る）。
+fn probe_writable(dir: &Path) -> AnyResult<()> {
+    create_dir_all(dir)?;
+    let probe = dir.join(format!(".sam_write_test_{}", std::process::id()));
+    write(&probe, b"ok")?;
+    remove_file(&probe)?;
+    Ok(())
+}
+
+/// 初回セットアップでつまずきやすい所をまとめて確かめる（GET /diagnostics）。
+///
+/// 保存先に書けるか・WEB_ROOT と index.html があるか・Web アセットが壊れていないか・
+/// 保存先のドライブの空き容量を見て、すべて問題なければ "ok": true を返す。
+fn diagnostics_json() -> serde_json::Value {
+    let export_root = lock_recover(export_root_dir()).clone();
+    let root = web_root();
+    let embedded = !EMBEDDED_ASSETS.is_empty();
+
+    let writable = probe_writable(&export_root);
+    let index_exists = root.join("index.html").is_file();
+    let install_error = lock_recover(embedded_install_error()).clone();
+    let broken = if index_exists { verify_web_assets() } else { Vec::new() };
+    let free_space = fs4::available_space(&export_root);
+
+    let checks = vec![
+        diagnostic_check(
+            "export_root_writable",
+            "保存先に書き込めるか",
+            writable.is_ok(),
+            match &writable {
+                Ok(()) => export_root.display().to_string(),
+                Err(e) => format!("{}: {e:#}", export_root.display()),
+            },
+        ),
+        diagnostic_check(
+            "web_root_exists",
+            "Web UI のフォルダがあるか",
+            root.is_dir() || embedded,
+            if root.is_dir() {
+                root.display().to_string()
+            } else if embedded {
+                format!("{} がありません (プラグインに埋め込んだ Web UI を使います)", root.display())
+            } else {
+                format!("{} がありません", root.display())
+            },
+        ),
+        diagnostic_check(
+            "index_html_exists",
+            "index.html があるか",
+            index_exists || (embedded && install_error.is_none()),
+            if index_exists {
+                root.join("index.html").display().to_string()
+            } else if let Some(e) = &install_error {
+                format!("埋め込みの Web UI を展開できませんでした: {e}")
+            } else if embedded {
+                "埋め込みの index.html を使います".to_string()
+            } else {
+                format!("{} がありません", root.join("index.html").display())
+            },
+        ),
+        diagnostic_check(
+            "web_assets_match",
+            "Web UI のファイルがこのバージョンと合っているか",
+            broken.is_empty(),
+            if !index_exists {
+                "未配置のため確認していません".to_string()
+            } else if broken.is_empty() {
+                "OK".to_string()
+            } else {
+                broken
+                    .iter()
+                    .map(|(rel, problem)| format!("{rel} ({problem})"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            },
+        ),
+        diagnostic_check(
+            "disk_space",
+            "保存先のドライブの空き容量",
+            free_space.as_ref().is_ok_and(|&bytes| bytes >= LOW_DISK_SPACE_BYTES),
+            match &free_space {
+                Ok(bytes) => format!("{} MB", bytes / (1024 * 1024)),
+                Err(e) => format!("取得できません: {e}"),
+            },
+        ),
+    ];
+
+    serde_json::json!({
+        "ok": checks.iter().all(|check| check["ok"] == true),
+        "version": env!("CARGO_PKG_VERSION"),
+        "export_root": export_root.display().to_string(),
+        "web_root": root.display().to_string(),
+        "free_bytes": free_space.ok(),
+        "checks": checks,
+    })
+}
+
+// ── Web アセットのセットアップ ─────────────────────────────────────────
+
+/// リリースに添付している ZIP のファイル名（release.yml と合わせる）
+const RELEASE_ZIP_NAME: &str = "sam_frame_export_filter-windows-x64.zip";
+
+/// リリース ZIP 内で Web アセットが置かれているディレクトリ
+const RELEASE_ZIP_WEB_DIR: &str = "sam_frame_export_filter/web/";
+
+/// WEB_ROOT に index.html が配置済みか。
+pub(crate) fn web_root_installed() -> bool {
+    web_root().join("index.html").is_file()
+}
+
+/// WEB_ROOT が未配置のときに返すセットアップページのひな形（setup_page_html で埋める）。
+///
+/// SAM のモデル本体はブラウザ側（transformers.js）が初回利用時に取得するので、
+/// ここでは Web アセットだけをインストールする。
+const SETUP_PAGE_HTML: &str = r#"<!doctype html>
+<html lang="ja">
+  <head>
+    <meta charset="UTF-8" />
+    <title>SAM Frame Export - セットアップ</title>
+    <style>
+      body { font-family: sans-serif; max-width: 640px; margin: 32px auto; line-height: 1.6; }
+      code { background: #f3f4f6; padding: 0 4px; }
+      button { padding: 6px 12px; font-size: 16px; cursor: pointer; }
+      #result { margin-top: 1rem; white-space: pre-wrap; }
+      .ok { color: #15803d; }
+      .ng { color: #b91c1c; }
+    </style>
+  </head>
+  <body>
+    <h1>Web UI が見つかりません</h1>
+    <p>
+      <code>{{WEB_ROOT}}</code>
+      に index.html がありません。
+    </p>
+    <p>
+      下のボタンを押すと、このプラグインと同じバージョンの Web UI を GitHub Releases から
+      ダウンロードして配置します。SAM のモデルは初回の切り抜き時にブラウザが自動で取得します。
+    </p>
+    <button id="install">Web UI をインストール</button>
+    <div id="result"></div>
+    <script>
+      const button = document.getElementById("install");
+      const result = document.getElementById("result");
+      button.addEventListener("click", async () => {
+        button.disabled = true;
+        result.textContent = "ダウンロード中...";
+        try {
+          const res = await fetch("/api/setup", { method: "POST" });
+          const info = await res.json();
+          if (!res.ok) throw new Error(info.error || `HTTP ${res.status}`);
+          result.textContent = `インストールしました:\n${info.installed.join("\n")}`;
+          location.reload();
+        } catch (err) {
+          result.textContent = `失敗しました: ${err.message}`;
+          button.disabled = false;
+        }
+      });
+    </script>
+
+    <h2>手動で配置する</h2>
+    <p>ダウンロードできない環境では、次の手順で配置してください。</p>
+    <ol>
+      <li>
+        <a href="{{RELEASE_URL}}">{{RELEASE_URL}}</a>
+        から <code>{{RELEASE_ZIP_NAME}}</code> をダウンロードする
+      </li>
+      <li>ZIP の中の <code>sam_frame_export_filter\web</code> フォルダの中身を <code>{{WEB_ROOT}}</code> にコピーする</li>
+      <li><code>{{WEB_ROOT}}\index.html</code> があることを確かめて、このページを再読み込みする</li>
+    </ol>
+    <p>別の場所に置いた Web UI を使う場合は、環境変数 <code>SAM_WEB_ROOT</code> にそのフォルダを指定して AviUtl2 を起動し直してください。</p>
+
+    <h2>診断結果</h2>
+    <p>プラグイン v{{VERSION}}</p>
+    <ul>
+{{DIAGNOSTICS}}
+    </ul>
+  </body>
+</html>
+"#;
+
+/// SETUP_PAGE_HTML に、期待される WEB_ROOT・このバージョンのリリースの URL・診断結果を入れる。
+fn setup_page_html() -> String {
+    let diagnostics = diagnostics_json();
+    let checks: String = diagnostics["checks"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|check| {
+            let ok = check["ok"] == true;
+            format!(
+                "      <li class=\"{}\">{} {}: {}</li>\n",
+                if ok { "ok" } else { "ng" },
+                if ok { "✔" } else { "✖" },
+                html_escape(check["label"].as_str().unwrap_or_default()),
+                html_escape(check["detail"].as_str().unwrap_or_default()),
+            )
+        })
+        .collect();
+    let release_url = format!(
+        "https://github.com/{GITHUB_REPO}/releases/tag/v{}",
+        env!("CARGO_PKG_VERSION")
+    );
+
+    SETUP_PAGE_HTML
+        .replace("{{WEB_ROOT}}", &html_escape(&web_root().display().to_string()))
+        .replace("{{RELEASE_URL}}", &html_escape(&release_url))
+        .replace("{{RELEASE_ZIP_NAME}}", RELEASE_ZIP_NAME)
+        .replace("{{VERSION}}", env!("CARGO_PKG_VERSION"))
+        .replace("{{DIAGNOSTICS}}", checks.trim_end())
+}
+
+/// マスクの保存に成功したときの応答。
+///
+/// 保存したファイル名・フルパス・object_id・サイズ・時刻を JSON で返す。`Accept: application/json` の
+/// リクエスト（Web UI の fetch）には 200 で、それ以外には完了ページ（/done）への 303 で返す。
+fn write_mask_saved_response(
+    stream: &mut dyn Write,
+    head: &RequestHead,
+    object_id: i64,
+    saved: &[PathBuf],
+) -> AnyResult<()> {
+    let cors = head.cors_origin.as_deref();
+    let mut location = format!("/done?object={object_id}");
+    for path in saved {
+        if let Some(name) = path.file_name() {
+            location.push_str("&mask=");
+            location.push_str(&percent_encode(&name.to_string_lossy()));
+        }
+    }
+
+    let masks: Vec<serde_json::Value> = saved
+        .iter()
+        .map(|path| {
+            let size = image::image_dimensions(path).ok();
+            serde_json::json!({
+                "file": path.file_name().map(|n| n.to_string_lossy().into_owned()),
+                "path": path.display().to_string(),
+                "width": size.map(|(w, _)| w),
+                "height": size.map(|(_, h)| h),
+                "bytes": std::fs::metadata(path).map(|m| m.len()).ok(),
+            })
+        })
+        .collect();
+    let body = serde_json::json!({
+        "object_id": object_id,
+        "masks": masks,
+        "saved_at": Local::now().to_rfc3339(),
+        "done_url": location,
+    });
+
+    let wants_json = head
+        .header("accept")
+        .is_some_and(|accept| accept.contains("application/json"));
+    if wants_json {
+        write_response(
+            stream,
+            cors,
+            200,
+            "OK",
+            body.to_string().as_bytes(),
+            "application/json",
+        )
+    } else {
+        write_response_with_headers(
+            stream,
+            cors,
+            303,
+            "See Other",
+            body.to_string().as_bytes(),
+            "application/json",
+            &[("Location", location)],
+        )
+    }
+}
+
+/// マスク保存後の完了ページ。保存したファイル名・オブジェクト・次の操作を並べる。
+fn done_page_html(object_id: &str, masks: &[String]) -> String {
+    let folder = lock_recover(export_root_dir()).display().to_string();
+    let items: String = masks
+        .iter()
+        .map(|name| {
+            format!(
+                "<li><code>{}</code> <button class=\"delete\" data-mask=\"{}\">削除</button></li>",
+                html_escape(name),
+                percent_encode(name)
+            )
+        })
+        .collect();
+    let first_mask = masks.first().map(|name| percent_encode(name)).unwrap_or_default();
+    DONE_PAGE_HTML
+        .replace("{{OBJECT}}", &html_escape(object_id))
+        .replace("{{MASKS}}", &items)
+        .replace("{{FOLDER}}", &html_escape(&folder))
+        .replace("{{FIRST_MASK}}", &first_mask)
+}
+
+/// マスク保存後に表示する完了ページ（`{{...}}` は done_page_html で埋める）
+const DONE_PAGE_HTML: &str = r#"<!doctype html>
+<html lang="ja">
+  <head>
+    <meta charset="UTF-8" />
+    <title>SAM Frame Export - 保存しました</title>
+    <style>
+      body { font-family: sans-serif; max-width: 640px; margin: 32px auto; line-height: 1.6; }
+      code { background: #f3f4f6; padding: 0 4px; }
+      button, a.button { padding: 6px 12px; font-size: 16px; cursor: pointer; margin-right: 8px; }
+      li button { padding: 0 8px; font-size: 14px; }
+      #result { margin-top: 1rem; white-space: pre-wrap; }
+    </style>
+  </head>
+  <body>
+    <h1>マスクを保存しました</h1>
+    <p>オブジェクト <code>{{OBJECT}}</code> に次のマスクを紐づけました。</p>
+    <ul>{{MASKS}}</ul>
+    <p>保存先: <code>{{FOLDER}}</code></p>
+    <p>AviUtl2 のプレビューを更新すると切り抜きが反映されます。</p>
+    <a class="button" href="/">もう一度編集する</a>
+    <button id="open-folder">フォルダを開く</button>
+    <button id="close">閉じる</button>
+    <div id="result"></div>
+    <script>
+      const result = document.getElementById("result");
+      document.getElementById("open-folder").addEventListener("click", async () => {
+        const mask = "{{FIRST_MASK}}";
+        const url = mask ? `/api/open-folder?mask=${mask}` : "/api/open-folder";
+        try {
+          const res = await fetch(url, { method: "POST" });
+          if (!res.ok) throw new Error(await res.text());
+        } catch (err) {
+          result.textContent = `フォルダを開けませんでした: ${err.message}`;
+        }
+      });
+      for (const button of document.querySelectorAll("button.delete")) {
+        button.addEventListener("click", async () => {
+          try {
+            const res = await fetch(`/mask/${button.dataset.mask}`, { method: "DELETE" });
+            if (!res.ok) throw new Error(await res.text());
+            button.parentElement.style.textDecoration = "line-through";
+            button.disabled = true;
+          } catch (err) {
+            result.textContent = `削除できませんでした: ${err.message}`;
+          }
+        });
+      }
+      document.getElementById("close").addEventListener("click", () => {
+        window.close();
+        result.textContent = "このタブは閉じて構いません。";
+      });
+    </script>
+  </body>
+</html>
+"#;
+
+/// WEB_ROOT に index.html が無ければ、ビルド時に埋め込んだ Web アセットを展開する（初回起動時用）。
+///
+/// 手でコピーしなくても web フォルダができ、以後は普通の静的ファイルとして配信・修復できる。
+/// サンドボックスでは WEB_ROOT を書き換えない。戻り値は書き出したファイルの相対パス。
+pub(crate) fn install_embedded_web_assets() -> AnyResult<Vec<String>> {
+    if web_root_installed() || sandbox_dir().is_some() {
+        return Ok(Vec::new());
+    }
+    let result = extract_embedded_assets(web_root());
+    *lock_recover(embedded_install_error()) = result.as_ref().err().map(|e| format!("{e:#}"));
+    result
+}
+
+/// install_embedded_web_assets が失敗した理由（成功したか、まだ試していなければ None）
+fn embedded_install_error() -> &'static Mutex<Option<String>> {
+    static ERROR: OnceLock<Mutex<Option<String>>> = OnceLock::new();
+    ERROR.get_or_init(|| Mutex::new(None))
+}
+
+/// ページを開くリクエストに、Web UI の代わりにセットアップページを返すべきか。
+///
+/// WEB_ROOT に index.html が無く、埋め込みのコピーも無いか WEB_ROOT に展開できなかったとき。
+/// 展開に失敗した（書き込めない場所など）まま埋め込みのコピーで動かすより、配置の手順と診断結果を見せる。
+fn setup_page_needed() -> bool {
+    !web_root_installed()
+        && (embedded_asset("index.html").is_none() || lock_recover(embedded_install_error()).is_some())
+}
+
+/// 埋め込みの Web アセットのうち、dir にまだ無いものを書き出す（置いてあるファイルは上書きしない）。
+fn extract_embedded_assets(dir: &Path) -> AnyResult<Vec<String>> {
+    let mut installed = Vec::new();
+    for &(rel, data) in EMBEDDED_ASSETS {
+        let dest = dir.join(rel);
+        if dest.exists() {
+            continue;
+        }
+        if let Some(parent) = dest.parent() {
+            create_dir_all(parent)?;
+        }
+        write_atomic(&dest, data)?;
+        installed.push(rel.to_string());
+    }
+    Ok(installed)
+}
+
+/// このプラグインと同じバージョンのリリース ZIP をダウンロードする。
+fn download_release_bundle() -> AnyResult<Vec<u8>> {
+    let url = format!(
+        "https://github.com/{GITHUB_REPO}/releases/download/v{}/{RELEASE_ZIP_NAME}",
+        env!("CARGO_PKG_VERSION")
+    );
+    log::info!("Downloading release bundle: {}", url);
+
+    let bytes = http_agent()
+        .get(&url)
+        .call()?
+        .body_mut()
+        .with_config()
+        .limit(64 * 1024 * 1024)
+        .read_to_vec()?;
+
+    log::info!("Downloaded release bundle ({} bytes)", bytes.len());
+    Ok(bytes)
+}
+
+/// リリース ZIP から Web アセットを取り出して WEB_ROOT に配置する。
+///
+/// 戻り値は配置したファイルの WEB_ROOT からの相対パス。
+fn install_web_bundle() -> AnyResult<Vec<String>> {
+    extract_web_bundle(&download_release_bundle()?, |_| true)
+}
+
+/// 壊れている・バージョンが合わない Web アセットだけをリリース ZIP から取り直す。
+///
+/// 戻り値は置き換えたファイルの WEB_ROOT からの相対パス（問題が無ければ空）。
+fn repair_web_assets() -> AnyResult<Vec<String>> {
+    let broken: Vec<&str> = verify_web_assets()
+        .into_iter()
+        .map(|(rel, _)| rel)
+        .collect();
+    if broken.is_empty() {
+        log::info!("All web assets match the manifest, nothing to repair");
+        return Ok(Vec::new());
+    }
+
+    let repaired = extract_web_bundle(&download_release_bundle()?, |rel| broken.contains(&rel))?;
+
+    let still_broken: Vec<&str> = verify_web_assets()
+        .into_iter()
+        .map(|(rel, _)| rel)
+        .collect();
+    if !still_broken.is_empty() {
+        return Err(anyhow::anyhow!(
+            "release bundle does not match this plugin build: {}",
+            still_broken.join(", ")
+        ));
+    }
+    Ok(repaired)
+}
+
+// ビルド時点の web/ の (相対パス, SHA-256) 表。build.rs が生成する。
+include!(concat!(env!("OUT_DIR"), "/asset_manifest.rs"));
+
+/// ビルド時に埋め込んだ web/ のファイル（rel は "index.js" のような相対パス）
+fn embedded_asset(rel: &str) -> Option<&'static [u8]> {
+    EMBEDDED_ASSETS
+        .iter()
+        .find(|(name, _)| *name == rel)
+        .map(|(_, data)| *data)
+}
+
+/// WEB_ROOT の中身を ASSET_MANIFEST と突き合わせる。
+///
+/// 戻り値は問題のあったファイルと、その内容（"missing" / "modified"）。
+pub(crate) fn verify_web_assets() -> Vec<(&'static str, &'static str)> {
+    let mut problems = Vec::new();
+    for &(rel, expected) in ASSET_MANIFEST {
+        match read(web_root().join(rel)) {
+            Ok(data) if sha256_hex(&data) == expected => {}
+            Ok(_) => problems.push((rel, "modified")),
+            Err(_) => problems.push((rel, "missing")),
+        }
+    }
+    problems
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// リリース ZIP のうち、WEB_ROOT からの相対パスが wanted を満たすものを配置する。
+fn extract_web_bundle(bundle: &[u8], wanted: impl Fn(&str) -> bool) -> AnyResult<Vec<String>> {
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(bundle))?;
+
+    let mut installed = Vec::new();
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        if entry.is_dir() {
+            continue;
+        }
+
+        // Compress-Archive のバージョンによっては区切りが "\" になるので揃える
+        let name = entry.name().replace('\\', "/");
+        let Some(rel) = name.strip_prefix(RELEASE_ZIP_WEB_DIR) else {
+            continue;
+        };
+        if rel.is_empty() || !rel.split('/').all(is_plain_file_name) {
+            log::warn!("Skipping suspicious entry in release bundle: {}", name);
+            continue;
+        }
+        if !wanted(rel) {
+            continue;
+        }
+
+        let dest = web_root().join(rel);
+        if let Some(parent) = dest.parent() {
+            create_dir_all(parent)?;
+        }
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        write(&dest, &data)?;
+
+        log::info!("Installed {}", dest.display());
+        installed.push(rel.to_string());
+    }
+
+    if installed.is_empty() {
+        return Err(anyhow::anyhow!(
+            "no matching web assets found under {RELEASE_ZIP_WEB_DIR} in {RELEASE_ZIP_NAME}"
+        ));
+    }
+    Ok(installed)
+}
+
+/// これ以下の大きさの静的ファイルはメモリキャッシュから返す
+const SMALL_ASSET_MAX_BYTES: u64 = 1024 * 1024;
+
+/// 静的ファイルの中身の在りか
+enum StaticBody {
+    /// 小さいファイル（index.html/js/css など）はメモリに読み込み済み
+    Memory(Arc<[u8]>),
+    /// 大きいファイルは開いただけで、送るときに少しずつ読む
+    File(File),
+    /// WEB_ROOT に無いときの、バイナリに埋め込んだアセット
+    Embedded(&'static [u8]),
+}
+
+/// WEB_ROOT の静的ファイル
+struct StaticFile {
+    body: StaticBody,
+    len: u64,
+    content_type: String,
+    /// 圧縮済みの兄弟ファイルを選んだときの Content-Encoding（"br" / "gzip"）
+    encoding: Option<&'static str>,
+    /// 304 の判定に使う ETag（更新日時と大きさ、埋め込みのアセットはビルド時の SHA-256 から作る）
+    etag: String,
+    /// ファイルの更新日時（埋め込みのアセットには無い）
+    modified: Option<SystemTime>,
+}
+
+impl StaticFile {
+    /// 200 / 304 のどちらにも付けるキャッシュ用のヘッダ。
+    ///
+    /// 更新したファイルがすぐ反映されるよう、ブラウザには毎回 ETag で確かめさせる（no-cache）。
+    fn validators(&self) -> Vec<(&'static str, String)> {
+        let mut headers = vec![
+            ("ETag", self.etag.clone()),
+            ("Cache-Control", "no-cache".to_string()),
+        ];
+        if let Some(modified) = self.modified {
+            headers.push(("Last-Modified", http_date(modified)));
+        }
+        if self.encoding.is_some() {
+            headers.push(("Vary", "Accept-Encoding".to_string()));
+        }
+        headers
+    }
+}
+
+/// ディスク上の静的ファイルの ETag（圧縮版は別の表現なので Content-Encoding も含める）
+fn static_file_etag(modified: Option<SystemTime>, len: u64, encoding: Option<&str>) -> String {
+    let nanos = modified
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos());
+    match encoding {
+        Some(encoding) => format!("\"{nanos:x}-{len:x}-{encoding}\""),
+        None => format!("\"{nanos:x}-{len:x}\""),
+    }
+}
+
+/// 静的ファイルを WEB_ROOT から開くヘルパー。
+///
+/// path: "/index.html", "/index.js", "/" など
+/// 数百 MB のモデルもあるので、大きいものは write_file_response で少しずつ送る。
+/// 小さいものは一度読んだらメモリに置き、更新日時が変わるまでディスクを読まない。
+/// `{file}.br` / `{file}.gz` があり、Accept-Encoding が許すならそちらを返す。
+/// WEB_ROOT に無いファイルは、ビルド時に埋め込んだ web/ のコピーから返す。
+fn open_static_file(path: &str, accept_encoding: Option<&str>) -> AnyResult<StaticFile> {
+    // "/" → "index.html"
+    let rel = if path == "/" || path.is_empty() {
+        "index.html"
+    } else {
+        path.trim_start_matches('/')
+    };
+
+    // 簡易的なパストラバーサル防止
+    if rel.contains("..") {
+        return Err(anyhow::anyhow!("invalid path"));
+    }
+
+    let mut full_path = web_root().join(rel);
+    let mut encoding = None;
+    for (name, ext) in [("br", "br"), ("gzip", "gz")] {
+        if !accepts_encoding(accept_encoding, name) {
+            continue;
+        }
+        let mut compressed = full_path.as_os_str().to_owned();
+        compressed.push(format!(".{ext}"));
+        let compressed = PathBuf::from(compressed);
+        if compressed.is_file() {
+            full_path = compressed;
+            encoding = Some(name);
+            break;
+        }
+    }
+    log::debug!("Serving static file: {}", full_path.display());
+
+    let content_type = content_type_for(rel, lock_recover(file_config()).mime_types.as_ref());
+
+    let file = match File::open(&full_path) {
+        Ok(file) => file,
+        // WEB_ROOT に無ければバイナリに埋め込んだコピーを返す
+        Err(e) => match embedded_asset(rel) {
+            Some(data) => {
+                log::debug!("Serving embedded asset: {}", rel);
+                let hash = ASSET_MANIFEST
+                    .iter()
+                    .find(|(name, _)| *name == rel)
+                    .map_or("embedded", |(_, hash)| &hash[..16]);
+                return Ok(StaticFile {
+                    body: StaticBody::Embedded(data),
+                    len: data.len() as u64,
+                    content_type,
+                    encoding: None,
+                    etag: format!("\"{hash}\""),
+                    modified: None,
+                });
+            }
+            None => return Err(e.into()),
+        },
+    };
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Err(anyhow::anyhow!("not a file: {}", full_path.display()));
+    }
+
+    let len = metadata.len();
+    let stamp = metadata.modified().ok();
+    let etag = static_file_etag(stamp, len, encoding);
+    if len > SMALL_ASSET_MAX_BYTES {
+        return Ok(StaticFile {
+            body: StaticBody::File(file),
+            len,
+            content_type,
+            encoding,
+            etag,
+            modified: stamp,
+        });
+    }
+
+    let key = format!("static:{}", full_path.display());
+    let cached = lock_recover(memory_cache()).get(&key, stamp);
+    let data = match cached {
+        Some(data) => data,
+        None => {
+            let mut data = Vec::with_capacity(len as usize);
+            (&file).read_to_end(&mut data)?;
+            let data: Arc<[u8]> = data.into();
+            lock_recover(memory_cache()).insert(&key, stamp, data.clone());
+            data
+        }
+    };
+
+    Ok(StaticFile {
+        len: data.len() as u64,
+        body: StaticBody::Memory(data),
+        content_type,
+        encoding,
+        etag,
+        modified: stamp,
+    })
+}
+
+/// 拡張子と Content-Type の対応（config.toml の [mime_types] で追加・上書きできる）
+const MIME_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("webmanifest", "application/manifest+json"),
+    ("wasm", "application/wasm"),
+    // ONNX には登録された型が無いので、バイナリとして明示する
+    ("onnx", "application/octet-stream"),
+    ("ort", "application/octet-stream"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("webp", "image/webp"),
+    ("gif", "image/gif"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/x-icon"),
+    ("woff2", "font/woff2"),
+    ("woff", "font/woff"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+];
+
+/// rel の拡張子から Content-Type を決める（custom は config.toml の [mime_types]、大文字小文字は区別しない）。
+fn content_type_for(rel: &str, custom: Option<&HashMap<String, String>>) -> String {
+    let Some((_, ext)) = rel.rsplit_once('.') else {
+        return "application/octet-stream".to_string();
+    };
+    let ext = ext.to_ascii_lowercase();
+    let custom = custom.and_then(|types| {
+        types
+            .iter()
+            .find(|(key, _)| key.trim_start_matches('.').eq_ignore_ascii_case(&ext))
+            .map(|(_, content_type)| content_type.clone())
+    });
+    custom.unwrap_or_else(|| {
+        MIME_TYPES
+            .iter()
+            .find(|(known, _)| *known == ext)
+            .map_or("application/octet-stream", |(_, content_type)| *content_type)
+            .to_string()
+    })
+}
+
+/// ディスク上のファイルを読み込まずに開く（write_file_response が送るときに少しずつ読む）。
+fn open_file_streamed(path: &Path, content_type: &str) -> AnyResult<StaticFile> {
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Err(anyhow::anyhow!("not a file: {}", path.display()));
+    }
+    let modified = metadata.modified().ok();
+    Ok(StaticFile {
+        body: StaticBody::File(file),
+        len: metadata.len(),
+        content_type: content_type.to_string(),
+        encoding: None,
+        etag: static_file_etag(modified, metadata.len(), None),
+        modified,
+    })
+}
+
+/// Accept-Encoding が coding（"br" など）を受け付けるか（`;q=0` は拒否とみなす）。
+fn accepts_encoding(accept_encoding: Option<&str>, coding: &str) -> bool {
+    let Some(accept_encoding) = accept_encoding else {
+        return false;
+    };
+    accept_encoding.split(',').any(|item| {
+        let mut params = item.split(';').map(str::trim);
+        let name = params.next().unwrap_or("");
+        let rejected = params.any(|p| {
+            p.strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_some_and(|q| q <= 0.0)
+        });
+        (name.eq_ignore_ascii_case(coding) || name == "*") && !rejected
+    })
+}
+
+/// HTTP の日付形式（`Wed, 21 Oct 2015 07:28:00 GMT`）にする。
+fn http_date(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+/// If-None-Match / If-Modified-Since が今の版と一致するか（一致すれば 304 を返してよい）。
+///
+/// If-None-Match があればそちらを優先し、If-Modified-Since は秒単位で比べる
+/// （更新日時の分からないものは If-None-Match でしか 304 にしない）。
+fn is_not_modified(head: &RequestHead, etag: &str, modified: Option<SystemTime>) -> bool {
+    if let Some(if_none_match) = head.header("if-none-match") {
+        return if_none_match
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag);
+    }
+    let Some(modified) = modified else {
+        return false;
+    };
+    let Some(since) = head
+        .header("if-modified-since")
+        .and_then(|v| DateTime::parse_from_rfc2822(v.trim()).ok())
+    else {
+        return false;
+    };
+    DateTime::<Utc>::from(modified).timestamp() <= since.timestamp()
+}
+
+/// `Range: bytes=...` を解釈して (先頭, 末尾) を返す（末尾を含む）。
+///
+/// 範囲が 1 つだけのものに対応する。None = Range 無し・解釈できない（全体を返す）、
+/// Some(Err(())) = ファイルの範囲外（416）。
+fn parse_range(range: Option<&str>, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = range?.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        // bytes=-500 = 末尾 500 バイト
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            if suffix == 0 || len == 0 {
+                return Some(Err(()));
+            }
+            (len.saturating_sub(suffix), len - 1)
+        }
+        (start, "") => (start.parse().ok()?, len.saturating_sub(1)),
+        (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?.min(len.saturating_sub(1))),
+    };
+    if start >= len || start > end {
+        return Some(Err(()));
+    }
+    Some(Ok((start, end)))
+}
+
+/// 静的ファイルなどを、条件付きリクエスト・Range を見て 304 / 206 / 200 で送る。
+fn send_file(stream: &mut dyn Write, head: &RequestHead, file: StaticFile) -> AnyResult<()> {
+    let cors = head.cors_origin.as_deref();
+    // index.js や WEB_ROOT に置いた大きなモデルを毎回送り直さないよう、ETag / Last-Modified で 304 を返す
+    if is_not_modified(head, &file.etag, file.modified) {
+        let header = response_header(cors, 304, "Not Modified", &file.content_type, file.len, &file.validators());
+        stream.write_all(header.as_bytes())?;
+        stream.flush()?;
+        return Ok(());
+    }
+    // HEAD ではボディを捨てるので、数百 MB のファイルを読まずにヘッダだけ書く
+    if head.method == "HEAD" {
+        let header = response_header(cors, 200, "OK", &file.content_type, file.len, &static_file_headers(&file));
+        stream.write_all(header.as_bytes())?;
+        stream.flush()?;
+        return Ok(());
+    }
+    // If-Range が今の版と違えば（途中でファイルが変わった）、続きではなく全体を返す
+    let range = head
+        .header("range")
+        .filter(|_| if_range_matches(head, &file.etag, file.modified));
+    write_file_response(stream, cors, range, file)
+}
+
+/// 静的ファイルの 200 / 206 と HEAD に付けるヘッダ
+fn static_file_headers(file: &StaticFile) -> Vec<(&'static str, String)> {
+    let mut headers = vec![("Accept-Ranges", "bytes".to_string())];
+    if let Some(encoding) = file.encoding {
+        headers.push(("Content-Encoding", encoding.to_string()));
+    }
+    headers.extend(file.validators());
+    headers
+}
+
+/// If-Range が無いか、今の版の ETag / Last-Modified と一致するか（一致しなければ Range を無視する）。
+///
+/// 弱い ETag（W/）は範囲の組み立てに使えないので一致とみなさない。
+fn if_range_matches(head: &RequestHead, etag: &str, modified: Option<SystemTime>) -> bool {
+    let Some(if_range) = head.header("if-range").map(str::trim) else {
+        return true;
+    };
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        return if_range == etag;
+    }
+    let (Some(modified), Ok(date)) = (modified, DateTime::parse_from_rfc2822(if_range)) else {
+        return false;
+    };
+    DateTime::<Utc>::from(modified).timestamp() == date.timestamp()
+}
+
+/// HEAD は GET と同じ処理をして、ヘッダだけを返す（ヘルスチェックやダウンロード前の大きさの確認用）。
+fn handle_head(stream: &mut dyn Write, head: &RequestHead) -> AnyResult<()> {
+    handle_get(&mut HeadersOnly::new(stream), head)
+}
+
+/// 応答のヘッダ（空行まで）だけを通し、ボディを捨てる Write（HEAD 用）
+struct HeadersOnly<'a> {
+    inner: &'a mut dyn Write,
+    /// 直前までに "\r\n\r\n" の何バイト目まで一致しているか
+    matched: usize,
+    /// ヘッダを書き終えたか
+    done: bool,
+}
+
+impl<'a> HeadersOnly<'a> {
+    fn new(inner: &'a mut dyn Write) -> Self {
+        Self { inner, matched: 0, done: false }
+    }
+}
+
+impl Write for HeadersOnly<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.done {
+            return Ok(buf.len());
+        }
+        for (i, &byte) in buf.iter().enumerate() {
+            self.matched = if byte == b"\r\n\r\n"[self.matched] {
+                self.matched + 1
+            } else {
+                usize::from(byte == b'\r')
+            };
+            if self.matched == 4 {
+                self.done = true;
+                self.inner.write_all(&buf[..=i])?;
+                return Ok(buf.len());
+            }
+        }
+        self.inner.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// 静的ファイルを（Range があればその範囲だけ）チャンクに分けて送る。
+fn write_file_response(
+    stream: &mut dyn Write,
+    cors: Option<&str>,
+    range: Option<&str>,
+    mut file: StaticFile,
+) -> AnyResult<()> {
+    /// 1 回に読み書きする大きさ
+    const CHUNK_SIZE: usize = 256 * 1024;
+
+    let mut extra = static_file_headers(&file);
+    let (status, reason, start, count) = match parse_range(range, file.len) {
+        None => (200, "OK", 0, file.len),
+        Some(Ok((start, end))) => {
+            extra.push(("Content-Range", format!("bytes {start}-{end}/{}", file.len)));
+            (206, "Partial Content", start, end - start + 1)
+        }
+        Some(Err(())) => {
+            return write_response_with_headers(
+                stream,
+                cors,
+                416,
+                "Range Not Satisfiable",
+                b"",
+                "text/plain",
+                &[("Content-Range", format!("bytes */{}", file.len))],
+            );
+        }
+    };
+
+    let header = response_header(cors, status, reason, &file.content_type, count, &extra);
+    stream.write_all(header.as_bytes())?;
+
+    match &mut file.body {
+        StaticBody::Memory(data) => {
+            stream.write_all(&data[start as usize..(start + count) as usize])?;
+        }
+        StaticBody::Embedded(data) => {
+            stream.write_all(&data[start as usize..(start + count) as usize])?;
+        }
+        StaticBody::File(f) => {
+            f.seek(SeekFrom::Start(start))?;
+            let mut body = f.take(count);
+            let mut buf = vec![0u8; CHUNK_SIZE];
+            loop {
+                let n = body.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                stream.write_all(&buf[..n])?;
+            }
+        }
+    }
+    stream.flush()?;
+    Ok(())
+}
+
+/// /api/sam/predict の本体（`{"points": [[x, y], ...], "labels": [1, 0, ...]}`）から
+/// マスクを推論し、白に透明度を付けた PNG とモデルのスコアを返す。
+#[cfg(feature = "native-sam")]
+fn sam_predict_png(request: &[u8]) -> AnyResult<(Vec<u8>, f32)> {
+    #[derive(serde::Deserialize)]
+    struct PredictRequest {
+        points: Vec<[f32; 2]>,
+        labels: Vec<f32>,
+    }
+
+    let request: PredictRequest = serde_json::from_slice(request)?;
+    let (width, height, mask, score) = sam::predict(&request.points, &request.labels)?;
+    let image = image::RgbaImage::from_fn(width, height, |x, y| {
+        image::Rgba([255, 255, 255, mask[(y * width + x) as usize]])
+    });
+    Ok((encode_png(&image)?, score))
+}
+
+fn write_response(
+    stream: &mut dyn Write,
+    cors: Option<&str>,
+    status_code: u16,
+    reason: &str,
+    body: &[u8],
+    content_type: &str,
+) -> AnyResult<()> {
+    write_response_with_headers(stream, cors, status_code, reason, body, content_type, &[])
+}
+
+/// ファイル群を無圧縮の zip としてそのまま送る。
+///
+/// 全体のサイズは事前に分からないので Content-Length は付けず、接続を閉じて終端を示す。
+fn write_zip_stream(
+    stream: &mut dyn Write,
+    cors: Option<&str>,
+    download_name: &str,
+    files: &[PathBuf],
+) -> AnyResult<()> {
+    let header = format!(
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: application/zip\r\n\
+         Content-Disposition: attachment; filename=\"{}\"\r\n\
+         {}\
+         Connection: close\r\n\r\n",
+        download_name,
+        cors_header_lines(cors)
+    );
+    stream.write_all(header.as_bytes())?;
+
+    // PNG は圧縮済みなので Stored で十分
+    let options = zip::write::SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Stored);
+    let mut zip = zip::ZipWriter::new_stream(BufWriter::new(&mut *stream));
+    for file in files {
+        let Some(name) = file.file_name() else {
+            continue;
+        };
+        zip.start_file(name.to_string_lossy(), options)?;
+        std::io::copy(&mut File::open(file)?, &mut zip)?;
+    }
+    zip.finish()?.flush()?;
+    Ok(())
+}
+
+/// write_response に追加のヘッダ（name, value）を付けて返す版。
+///
+/// ヘッダとボディは 1 回の書き込み（write_vectored）でまとめて送る。
+fn write_response_with_headers(
+    stream: &mut dyn Write,
+    cors: Option<&str>,
+    status_code: u16,
+    reason: &str,
+    body: &[u8],
+    content_type: &str,
+    extra_headers: &[(&str, String)],
+) -> AnyResult<()> {
+    let header = response_header(
+        cors,
+        status_code,
+        reason,
+        content_type,
+        body.len() as u64,
+        extra_headers,
+    );
+    write_all_vectored(stream, &mut [IoSlice::new(header.as_bytes()), IoSlice::new(body)])?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// 受け付けるメソッド（405 と OPTIONS の Allow、CORS のプリフライトで返す）
+const ALLOWED_METHODS: &str = "GET, HEAD, POST, PUT, DELETE, OPTIONS";
+
+/// config.toml の cors_origins に照らして Access-Control-Allow-Origin の値を決める。
+///
+/// cors_origins があれば、"*" を含むときは `*`、一覧にある Origin ならその Origin。
+/// 無ければ同じオリジン（Host と同じ）かループバック（localhost など）の Origin だけを返す
+/// （トークン無しの既定の設定で、よそのサイトのページからマスクを書き換えたり消したりさせない）。
+/// Origin の無いリクエスト（ブラウザ以外や同じオリジンの GET）には `*`。
+/// それ以外は None（ヘッダを付けず、ブラウザに別オリジンからの読み取りを断らせる）。
+fn allowed_origin(origin: Option<&str>, host: Option<&str>, cors_origins: Option<&[String]>) -> Option<String> {
+    let Some(cors_origins) = cors_origins else {
+        let Some(origin) = origin.map(str::trim) else {
+            return Some("*".to_string());
+        };
+        return (is_loopback_origin(origin) || is_same_origin(origin, host)).then(|| origin.to_string());
+    };
+    if cors_origins.iter().any(|allowed| allowed.trim() == "*") {
+        return Some("*".to_string());
+    }
+    let origin = origin?.trim();
+    cors_origins
+        .iter()
+        .any(|allowed| allowed.trim().trim_end_matches('/').eq_ignore_ascii_case(origin))
+        .then(|| origin.to_string())
+}
+
+/// Origin（`http://host:port`）が Host ヘッダと同じ場所を指しているか
+fn is_same_origin(origin: &str, host: Option<&str>) -> bool {
+    let Some((_, authority)) = origin.split_once("://") else {
+        return false;
+    };
+    host.is_some_and(|host| authority.eq_ignore_ascii_case(host.trim()))
+}
+
+/// Origin のホストが localhost・127.0.0.1・[::1] などのループバックか
+fn is_loopback_origin(origin: &str) -> bool {
+    let Some(("http" | "https", authority)) = origin.split_once("://") else {
+        return false;
+    };
+    let host = match authority.strip_prefix('[') {
+        Some(bracketed) => bracketed.split(']').next().unwrap_or_default(),
+        None => authority.rsplit_once(':').map_or(authority, |(host, _)| host),
+    };
+    host.eq_ignore_ascii_case("localhost") || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// 応答に付ける CORS のヘッダ行（Origin ごとに値が変わるときはキャッシュが混ざらないよう Vary も付ける）
+///
+/// cors は allowed_origin で決めた Access-Control-Allow-Origin の値（None ならヘッダを付けない）。
+fn cors_header_lines(cors: Option<&str>) -> String {
+    match cors {
+        None => String::new(),
+        Some("*") => "Access-Control-Allow-Origin: *\r\n".to_string(),
+        Some(origin) => format!("Access-Control-Allow-Origin: {origin}\r\nVary: Origin\r\n"),
+    }
+}
+
+/// OPTIONS（CORS のプリフライト）に 204 で答える。
+///
+/// 別オリジンの開発中の UI がトークンや Content-Type を付けて呼べるよう、要求されたヘッダはそのまま許可する。
+/// 許可していない Origin からのプリフライトは 403 で断り、PUT / DELETE などを送らせない。
+fn write_preflight_response(stream: &mut dyn Write, head: &RequestHead) -> AnyResult<()> {
+    let cors = head.cors_origin.as_deref();
+    if head.header("origin").is_some() && cors.is_none() {
+        log::warn!("Rejecting preflight from {:?}: origin not allowed", head.header("origin"));
+        return write_response(stream, None, 403, "Forbidden", b"Forbidden: origin not allowed", "text/plain");
+    }
+    let allow_headers = head
+        .header("access-control-request-headers")
+        .unwrap_or("Content-Type, X-Sam-Token, Authorization");
+    let header = format!(
+        "HTTP/1.1 204 No Content\r\n\
+         Allow: {ALLOWED_METHODS}\r\n\
+         {}\
+         Access-Control-Allow-Methods: {ALLOWED_METHODS}\r\n\
+         Access-Control-Allow-Headers: {allow_headers}\r\n\
+         Access-Control-Max-Age: 600\r\n\
+         Connection: close\r\n\r\n",
+        cors_header_lines(cors)
+    );
+    stream.write_all(header.as_bytes())?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// ステータス行と共通ヘッダ（末尾の空行まで）を組み立てる。
+fn response_header(
+    cors: Option<&str>,
+    status_code: u16,
+    reason: &str,
+    content_type: &str,
+    content_length: u64,
+    extra_headers: &[(&str, String)],
+) -> String {
+    let mut header = format!(
+        "HTTP/1.1 {} {}\r\n\
+         Content-Type: {}\r\n\
+         Content-Length: {}\r\n\
+         {}\
+         Connection: close\r\n",
+        status_code, reason, content_type, content_length, cors_header_lines(cors)
+    );
+    for (name, value) in extra_headers {
+        header.push_str(&format!("{name}: {value}\r\n"));
+    }
+    header.push_str("\r\n");
+    header
+}
+
+/// 複数のバッファを write_vectored で書き切る（std の write_all_vectored は unstable のため）。
+fn write_all_vectored(stream: &mut dyn Write, mut bufs: &mut [IoSlice<'_>]) -> io::Result<()> {
+    IoSlice::advance_slices(&mut bufs, 0);
+    while !bufs.is_empty() {
+        match stream.write_vectored(bufs) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+mod access_log;
+mod tls;
+
+#[cfg(test)]
+mod tests;
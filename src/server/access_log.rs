@@ -0,0 +1,185 @@
+//! アクセスログ（config.toml の access_log = true）。
+//!
+//! 1 リクエスト 1 行で、メソッド・パス・ステータス・処理時間・受け取ったボディと返した応答の大きさを
+//! `logs/access.log` に追記する。サポートのときに「リクエストがどこまで届いているか」を確かめるためのもの。
+//! クエリにはアクセストークンが入ることがあるので、パスだけを残す。
+
+use anyhow::Result as AnyResult;
+use chrono::Local;
+use crate::{
+    config::{file_config, plugin_data_dir, sandbox_dir},
+    state::lock_recover,
+};
+use std::{
+    cell::Cell,
+    fs::{OpenOptions, create_dir_all, metadata, rename},
+    io::{self, IoSlice, Read, Write},
+    path::PathBuf,
+    rc::Rc,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use super::{HttpStream, RequestHead};
+
+/// これを超えたら access.log.1 に回して新しく書き始める
+const ACCESS_LOG_MAX_BYTES: u64 = 10 * 1024 * 1024;
+
+/// アクセスログのパス（サンドボックスではその中）
+pub(super) fn access_log_path() -> PathBuf {
+    match sandbox_dir() {
+        Some(sandbox) => sandbox.join("logs").join("access.log"),
+        None => plugin_data_dir().join("logs").join("access.log"),
+    }
+}
+
+/// 1 行を追記する（複数のワーカーの行が混ざらないよう 1 つずつ）。
+fn append_line(line: &str) -> AnyResult<()> {
+    static LOCK: OnceLock<Mutex<()>> = OnceLock::new();
+    let _guard = lock_recover(LOCK.get_or_init(|| Mutex::new(())));
+
+    let path = access_log_path();
+    if let Some(dir) = path.parent() {
+        create_dir_all(dir)?;
+    }
+    if metadata(&path).is_ok_and(|m| m.len() > ACCESS_LOG_MAX_BYTES) {
+        rename(&path, path.with_extension("log.1"))?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    file.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// 応答のステータスと大きさを数えながら読み書きする接続
+pub(super) struct LoggedStream<S> {
+    inner: S,
+    method: String,
+    path: String,
+    status: Option<u16>,
+    response_bytes: u64,
+    /// ボディのリーダーと共有する、受け取ったボディのバイト数
+    request_bytes: Rc<Cell<u64>>,
+}
+
+impl<S> LoggedStream<S> {
+    pub(super) fn new(inner: S) -> Self {
+        Self {
+            inner,
+            method: String::new(),
+            path: String::new(),
+            status: None,
+            response_bytes: 0,
+            request_bytes: Rc::new(Cell::new(0)),
+        }
+    }
+
+    /// ヘッダを読んだら呼ぶ
+    pub(super) fn set_request(&mut self, head: &RequestHead) {
+        self.method = head.method.clone();
+        self.path = head.path.clone();
+    }
+
+    /// ボディのリーダーを、読んだバイト数を数えるものに包む
+    pub(super) fn count_request_body(&self, reader: Box<dyn Read>) -> Box<dyn Read> {
+        Box::new(CountingReader {
+            inner: reader,
+            count: Rc::clone(&self.request_bytes),
+        })
+    }
+
+    fn observe(&mut self, buf: &[u8], written: usize) {
+        // 100 Continue の後に本当の応答を書くので、それまではステータスを上書きする
+        if self.status.is_none_or(|status| status == 100)
+            && let Some(status) = buf
+                .strip_prefix(b"HTTP/1.1 ")
+                .and_then(|rest| rest.get(..3))
+                .and_then(|code| std::str::from_utf8(code).ok()?.parse().ok())
+        {
+            self.status = Some(status);
+        }
+        self.response_bytes += written as u64;
+    }
+
+    /// ログの 1 行（何も受け取らずに閉じた接続なら None）
+    pub(super) fn access_line(&self, elapsed: Duration) -> Option<String> {
+        if self.method.is_empty() && self.status.is_none() {
+            return None;
+        }
+        let or_dash = |s: &str| if s.is_empty() { "-".to_string() } else { s.to_string() };
+        Some(format!(
+            "{} {} {} {} {}ms in={} out={}\n",
+            Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+            or_dash(&self.method),
+            or_dash(&self.path),
+            self.status.map_or("-".to_string(), |status| status.to_string()),
+            elapsed.as_millis(),
+            self.request_bytes.get(),
+            self.response_bytes
+        ))
+    }
+
+    /// access_log が有効なら 1 リクエスト分を追記する（失敗しても応答には影響させない）。
+    pub(super) fn record(&self, elapsed: Duration) {
+        if !lock_recover(file_config()).access_log.unwrap_or(false) {
+            return;
+        }
+        if let Some(line) = self.access_line(elapsed)
+            && let Err(e) = append_line(&line)
+        {
+            log::debug!("Failed to write the access log: {e:?}");
+        }
+    }
+}
+
+impl<S: Read> Read for LoggedStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Write> Write for LoggedStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.observe(buf, n);
+        Ok(n)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let n = self.inner.write_vectored(bufs)?;
+        let first = bufs.iter().find(|buf| !buf.is_empty()).map_or(&[][..], |buf| &buf[..]);
+        self.observe(first, n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: HttpStream> HttpStream for LoggedStream<S> {
+    fn try_clone_reader(&self) -> io::Result<Box<dyn Read>> {
+        self.inner.try_clone_reader()
+    }
+
+    fn try_clone_writer(&self) -> io::Result<Box<dyn Write + Send>> {
+        self.inner.try_clone_writer()
+    }
+
+    fn try_clone_watcher(&self) -> io::Result<Box<dyn Read + Send>> {
+        self.inner.try_clone_watcher()
+    }
+}
+
+/// 読んだバイト数を数える Read
+struct CountingReader {
+    inner: Box<dyn Read>,
+    count: Rc<Cell<u64>>,
+}
+
+impl Read for CountingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
@@ -0,0 +1,1731 @@
+//! HTTP の処理をメモリ上の接続で確かめるテスト。
+
+use super::*;
+use crate::state::{object_states, set_export_root};
+use std::{cell::RefCell, rc::Rc, sync::MutexGuard};
+
+/// 入力を先に用意し、書き込まれた応答を後から読めるモック接続
+struct MockStream {
+    input: Rc<RefCell<Cursor<Vec<u8>>>>,
+    output: Rc<RefCell<Vec<u8>>>,
+}
+
+/// MockStream と入力を共有するボディ読み出し用のハンドル
+struct SharedReader(Rc<RefCell<Cursor<Vec<u8>>>>);
+
+impl Read for SharedReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().read(buf)
+    }
+}
+
+impl Read for MockStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.input.borrow_mut().read(buf)
+    }
+}
+
+impl Write for MockStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.output.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl HttpStream for MockStream {
+    fn try_clone_reader(&self) -> std::io::Result<Box<dyn Read>> {
+        Ok(Box::new(SharedReader(self.input.clone())))
+    }
+
+    fn try_clone_writer(&self) -> std::io::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(std::io::sink()))
+    }
+
+    // モックのリクエストは送り終えたら EOF なので、/ws はすぐ閉じられたものとして扱われる
+    fn try_clone_watcher(&self) -> std::io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(std::io::empty()))
+    }
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).expect("response body is not JSON")
+    }
+}
+
+/// 生のリクエストを handle_client に通し、応答を解釈して返す。
+fn send(raw: &[u8]) -> Response {
+    test_root();
+
+    let output = Rc::new(RefCell::new(Vec::new()));
+    let stream = MockStream {
+        input: Rc::new(RefCell::new(Cursor::new(raw.to_vec()))),
+        output: output.clone(),
+    };
+    handle_client(stream).expect("handle_client failed");
+
+    let output = output.borrow();
+    let header_end = find_header_end(&output).expect("no header terminator in response");
+    let head = String::from_utf8_lossy(&output[..header_end]).to_string();
+    let mut lines = head.lines();
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .expect("no status line");
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect();
+
+    Response {
+        status,
+        headers,
+        body: output[header_end + 4..].to_vec(),
+    }
+}
+
+fn request(method: &str, target: &str, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+    let mut raw = format!("{method} {target} HTTP/1.1\r\nHost: 127.0.0.1\r\n");
+    for (name, value) in headers {
+        raw.push_str(&format!("{name}: {value}\r\n"));
+    }
+    raw.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+    let mut raw = raw.into_bytes();
+    raw.extend_from_slice(body);
+    raw
+}
+
+/// テスト用の保存ルート（全テストで共有する）
+fn test_root() -> &'static Path {
+    static ROOT: OnceLock<PathBuf> = OnceLock::new();
+    ROOT.get_or_init(|| {
+        let root = std::env::temp_dir().join(format!("sam_frame_export_test_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        create_dir_all(&root).unwrap();
+        set_export_root(root.clone());
+        root
+    })
+}
+
+/// Web UI の配置状態（embedded_install_error）を変えるテストと、ページの応答を見るテストを 1 つずつ流す
+fn web_ui_test_lock() -> MutexGuard<'static, ()> {
+    static LOCK: Mutex<()> = Mutex::new(());
+    lock_recover(&LOCK)
+}
+
+fn tiny_png() -> Vec<u8> {
+    let img = image::RgbaImage::from_pixel(4, 4, image::Rgba([255, 255, 255, 255]));
+    let mut png = Vec::new();
+    img.write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+    png
+}
+
+fn multipart_body(boundary: &str, parts: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (name, data) in parts {
+        body.extend_from_slice(
+            format!(
+                "--{boundary}\r\nContent-Disposition: form-data; name=\"{name}\"; filename=\"{name}.png\"\r\nContent-Type: image/png\r\n\r\n"
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(data);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+    body
+}
+
+/// 1 回の read で最大 n バイトしか返さないリーダー（パケット分割の再現）
+struct Trickle<'a> {
+    data: &'a [u8],
+    n: usize,
+}
+
+impl Read for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.n.min(buf.len()).min(self.data.len());
+        buf[..len].copy_from_slice(&self.data[..len]);
+        self.data = &self.data[len..];
+        Ok(len)
+    }
+}
+
+#[test]
+fn parses_request_line_query_and_headers() {
+    let head = parse_request_head(
+        b"GET /api/gallery?object=3&page=2 HTTP/1.1\r\nHost: x\r\nContent-Type: text/plain; a=b",
+    );
+    assert_eq!(head.method, "GET");
+    assert_eq!(head.path, "/api/gallery");
+    assert_eq!(head.query, "object=3&page=2");
+    assert_eq!(head.header("content-type"), Some("text/plain; a=b"));
+    assert_eq!(head.header("host"), Some("x"));
+    assert_eq!(head.header("missing"), None);
+}
+
+#[test]
+fn request_without_query_has_empty_query() {
+    let head = parse_request_head(b"POST /mask HTTP/1.1");
+    assert_eq!(head.path, "/mask");
+    assert_eq!(head.query, "");
+    assert!(head.headers.is_empty());
+}
+
+#[test]
+fn access_token_is_read_from_query_header_or_cookie() {
+    let token = |raw: &[u8]| request_token(&parse_request_head(raw));
+    assert_eq!(
+        token(b"GET /?token=abc HTTP/1.1"),
+        Some(("abc".into(), TokenSource::Query))
+    );
+    assert_eq!(
+        token(b"POST /mask HTTP/1.1\r\nX-Sam-Token: abc"),
+        Some(("abc".into(), TokenSource::Header))
+    );
+    assert_eq!(
+        token(b"POST /mask HTTP/1.1\r\nAuthorization: Bearer abc"),
+        Some(("abc".into(), TokenSource::Header))
+    );
+    let cookie = format!("GET /api/frame HTTP/1.1\r\nCookie: a=1; {}=abc", token_cookie_name());
+    assert_eq!(token(cookie.as_bytes()), Some(("abc".into(), TokenSource::Cookie)));
+    assert_eq!(token(b"GET /api/frame HTTP/1.1\r\nCookie: sam_token_1=abc"), None);
+
+    assert_eq!(access_token().len(), 32);
+    assert_eq!(access_token(), access_token());
+}
+
+#[test]
+fn url_hosts_bracket_ipv6() {
+    assert_eq!(url_host(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 5))), "192.168.1.5");
+    assert_eq!(url_host(IpAddr::V6(Ipv6Addr::LOCALHOST)), "[::1]");
+}
+
+#[test]
+fn https_serves_the_same_routes_with_a_saved_certificate() {
+    let dir = test_root().join("https_cert");
+    let hosts = vec!["localhost".to_string(), "127.0.0.1".to_string()];
+    let tls_config = tls::server_config(&dir, &hosts).unwrap();
+    let (cert, _) = tls::load_or_create_certificate(&dir, &hosts).unwrap();
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        handle_client(tls::TlsStream::new(tls_config, stream).unwrap()).unwrap();
+    });
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(cert).unwrap();
+    let client_config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    let server_name = rustls::pki_types::ServerName::try_from("127.0.0.1").unwrap();
+    let connection = rustls::ClientConnection::new(Arc::new(client_config), server_name).unwrap();
+    let mut client = rustls::StreamOwned::new(connection, TcpStream::connect(("127.0.0.1", port)).unwrap());
+    client.write_all(b"GET /nope HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n").unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).unwrap();
+    server.join().unwrap();
+
+    assert!(response.starts_with(b"HTTP/1.1 404"), "{}", String::from_utf8_lossy(&response));
+}
+
+#[test]
+fn query_helpers() {
+    assert_eq!(query_param("a=1&b=2", "b"), Some("2"));
+    assert_eq!(query_param("a=1&b=2", "c"), None);
+    assert!(query_flag("check", "check"));
+    assert!(query_flag("check=1", "check"));
+    assert!(query_flag("check=true", "check"));
+    assert!(!query_flag("check=0", "check"));
+    assert!(!query_flag("", "check"));
+}
+
+#[test]
+fn unsupported_method_is_405() {
+    let res = send(&request("PATCH", "/mask", &[], b""));
+    assert_eq!(res.status, 405);
+    assert_eq!(res.header("Allow"), Some(ALLOWED_METHODS));
+}
+
+#[test]
+fn head_returns_the_get_headers_without_a_body() {
+    let get = send(&request("GET", "/api/history", &[], b""));
+    let res = send(&request("HEAD", "/api/history", &[], b""));
+    assert_eq!(res.status, 200);
+    assert!(res.body.is_empty());
+    assert_eq!(res.header("Content-Type"), get.header("Content-Type"));
+    assert!(res.header("Content-Length").unwrap().parse::<usize>().unwrap() > 0);
+    assert_eq!(send(&request("HEAD", "/api/nope", &[], b"")).status, 404);
+
+    // ヘッダの終わりが書き込みの境目をまたいでも、ボディだけを捨てる
+    let mut out = Vec::new();
+    let mut writer = HeadersOnly::new(&mut out);
+    writer.write_all(b"HTTP/1.1 200 OK\r\nA: b\r").unwrap();
+    writer.write_all(b"\n\r\nbody").unwrap();
+    writer.write_all(b"more body").unwrap();
+    assert_eq!(out, b"HTTP/1.1 200 OK\r\nA: b\r\n\r\n");
+}
+
+#[test]
+fn access_log_lines_hold_method_path_status_and_sizes() {
+    let mut stream = access_log::LoggedStream::new(Vec::new());
+    assert_eq!(stream.access_line(Duration::ZERO), None);
+
+    stream.set_request(&parse_request_head(b"POST /mask?token=secret HTTP/1.1"));
+    let mut body = stream.count_request_body(Box::new(&b"12345"[..]));
+    body.read_to_end(&mut Vec::new()).unwrap();
+    stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").unwrap();
+    write_response(&mut stream, None, 201, "Created", b"ok", "text/plain").unwrap();
+
+    let line = stream.access_line(Duration::from_millis(12)).unwrap();
+    assert!(line.contains(" POST /mask 201 12ms in=5 out="), "{line}");
+    assert!(!line.contains("secret"));
+}
+
+#[test]
+fn preflight_allows_the_requested_headers() {
+    let res = send(&request(
+        "OPTIONS",
+        "/mask",
+        &[
+            ("Origin", "http://localhost:5173"),
+            ("Access-Control-Request-Method", "POST"),
+            ("Access-Control-Request-Headers", "content-type, x-sam-token"),
+        ],
+        b"",
+    ));
+    assert_eq!(res.status, 204);
+    assert_eq!(res.header("Access-Control-Allow-Origin"), Some("http://localhost:5173"));
+    assert_eq!(res.header("Access-Control-Allow-Methods"), Some(ALLOWED_METHODS));
+    assert_eq!(res.header("Access-Control-Allow-Headers"), Some("content-type, x-sam-token"));
+}
+
+#[test]
+fn cors_origins_limit_which_origins_are_echoed() {
+    let origins = vec!["http://localhost:5173/".to_string(), "https://ui.example".to_string()];
+    assert_eq!(
+        allowed_origin(Some("http://localhost:5173"), None, Some(&origins)),
+        Some("http://localhost:5173".into())
+    );
+    assert_eq!(allowed_origin(Some("http://evil.example"), None, Some(&origins)), None);
+    assert_eq!(allowed_origin(None, None, Some(&origins)), None);
+    assert_eq!(allowed_origin(Some("http://x"), None, Some(&["*".to_string()])), Some("*".into()));
+}
+
+#[test]
+fn without_cors_origins_only_same_origin_and_loopback_are_allowed() {
+    let host = Some("192.168.1.5:17860");
+    assert_eq!(allowed_origin(None, host, None), Some("*".into()));
+    for origin in ["http://192.168.1.5:17860", "http://localhost:5173", "http://127.0.0.1:17860", "http://[::1]:8080"] {
+        assert_eq!(allowed_origin(Some(origin), host, None), Some(origin.into()), "{origin}");
+    }
+    for origin in ["https://evil.example", "http://localhost.evil.example", "null", "http://192.168.1.6:17860"] {
+        assert_eq!(allowed_origin(Some(origin), host, None), None, "{origin}");
+    }
+}
+
+#[test]
+fn cross_site_writes_are_refused_by_default() {
+    let evil = ("Origin", "https://evil.example");
+    let res = send(&request(
+        "OPTIONS",
+        "/mask/sam_mask_cors.png",
+        &[evil, ("Access-Control-Request-Method", "DELETE")],
+        b"",
+    ));
+    assert_eq!(res.status, 403);
+    assert_eq!(res.header("Access-Control-Allow-Origin"), None);
+
+    let name = "sam_mask_cors.png";
+    write(test_root().join(name), tiny_png()).unwrap();
+    assert_eq!(send(&request("DELETE", &format!("/mask/{name}"), &[evil], b"")).status, 403);
+    assert_eq!(send(&request("POST", "/mask?object=9294", &[evil], &tiny_png())).status, 403);
+    assert!(test_root().join(name).exists());
+
+    // 同じオリジンのページからは今まで通り
+    let res = send(&request("GET", "/api/gallery?object=424242", &[("Origin", "http://127.0.0.1:17860")], b""));
+    assert_eq!(res.header("Access-Control-Allow-Origin"), Some("http://127.0.0.1:17860"));
+    assert_eq!(res.header("Vary"), Some("Origin"));
+}
+
+#[test]
+fn unknown_path_is_404() {
+    assert_eq!(send(&request("GET", "/no/such/file.js", &[], b"")).status, 404);
+    assert_eq!(send(&request("POST", "/no/such/route", &[], b"")).status, 404);
+    assert_eq!(send(&request("PUT", "/no/such/route", &[], b"")).status, 404);
+}
+
+#[test]
+fn empty_connection_writes_nothing() {
+    let output = Rc::new(RefCell::new(Vec::new()));
+    let stream = MockStream {
+        input: Rc::new(RefCell::new(Cursor::new(Vec::new()))),
+        output: output.clone(),
+    };
+    handle_client(stream).unwrap();
+    assert!(output.borrow().is_empty());
+}
+
+#[test]
+fn oversized_header_is_rejected() {
+    let mut raw = b"GET / HTTP/1.1\r\n".to_vec();
+    raw.extend(std::iter::repeat_n(b'a', 20 * 1024));
+    let stream = MockStream {
+        input: Rc::new(RefCell::new(Cursor::new(raw))),
+        output: Rc::new(RefCell::new(Vec::new())),
+    };
+    assert!(handle_client(stream).is_err());
+}
+
+#[test]
+fn responses_carry_length_and_cors_headers() {
+    let res = send(&request("GET", "/api/gallery?object=424242", &[], b""));
+    assert_eq!(res.status, 200);
+    assert_eq!(res.header("Content-Type"), Some("application/json"));
+    assert_eq!(res.header("Content-Length"), Some(res.body.len().to_string().as_str()));
+    assert_eq!(res.header("Access-Control-Allow-Origin"), Some("*"));
+    assert_eq!(res.header("Connection"), Some("close"));
+}
+
+#[test]
+fn gallery_filters_by_object_and_paginates() {
+    let json = send(&request("GET", "/api/gallery?object=424242&page=0", &[], b"")).json();
+    assert_eq!(json["total"], 0);
+    assert_eq!(json["page"], 1);
+    assert_eq!(json["items"].as_array().map(Vec::len), Some(0));
+}
+
+#[test]
+fn mask_list_reports_name_object_and_size() {
+    let res = send(&request(
+        "POST",
+        "/mask?object=9281",
+        &[("Content-Type", "image/png"), ("Accept", "application/json")],
+        &tiny_png(),
+    ));
+    assert_eq!(res.status, 200);
+    let name = res.json()["masks"][0]["file"].as_str().unwrap().to_string();
+
+    let json = send(&request("GET", "/masks?object=9281", &[], b"")).json();
+    let masks = json["masks"].as_array().unwrap();
+    assert_eq!(masks.len(), 1);
+    assert_eq!(masks[0]["name"], name.as_str());
+    assert_eq!(masks[0]["object_id"], 9281);
+    assert!(masks[0]["size"].as_u64().unwrap() > 0);
+    assert!(masks[0]["created_at"].is_string());
+}
+
+#[test]
+fn masks_can_be_fetched_and_deleted() {
+    let res = send(&request(
+        "POST",
+        "/mask?object=9282",
+        &[("Content-Type", "image/png"), ("Accept", "application/json")],
+        &tiny_png(),
+    ));
+    let name = res.json()["masks"][0]["file"].as_str().unwrap().to_string();
+    let mask_path = test_root().join(&name);
+
+    let res = send(&request("GET", &format!("/mask/{name}"), &[], b""));
+    assert_eq!(res.status, 200);
+    assert_eq!(res.header("Content-Type"), Some("image/png"));
+    assert_eq!(res.body, read(&mask_path).unwrap());
+    let etag = res.header("ETag").unwrap().to_string();
+    let res = send(&request("GET", &format!("/mask/{name}"), &[("Range", "bytes=0-7")], b""));
+    assert_eq!(res.status, 206);
+    assert_eq!(res.body, b"\x89PNG\r\n\x1a\n");
+    let res = send(&request("GET", &format!("/mask/{name}"), &[("If-None-Match", &etag)], b""));
+    assert_eq!(res.status, 304);
+
+    let res = send(&request("DELETE", &format!("/mask/{name}"), &[], b""));
+    assert_eq!(res.status, 200);
+    assert_eq!(res.json()["object_id"], 9282);
+    assert!(!mask_path.exists());
+    assert!(!crate::export::mask_manifest_path(&mask_path).exists());
+    assert!(selected_mask_for_object(9282).is_none());
+
+    assert_eq!(send(&request("GET", &format!("/mask/{name}"), &[], b"")).status, 404);
+    assert_eq!(send(&request("DELETE", &format!("/mask/{name}"), &[], b"")).status, 404);
+    assert_eq!(send(&request("DELETE", "/mask/../evil.png", &[], b"")).status, 404);
+}
+
+#[test]
+fn upload_still_works_after_a_panic_while_holding_the_lock() {
+    let _ = thread::spawn(|| {
+        let _guard = upload_lock().lock().unwrap();
+        panic!("panic while holding upload_lock");
+    })
+    .join();
+    assert!(upload_lock().is_poisoned());
+
+    let res = send(&request(
+        "POST",
+        "/mask?object=9284",
+        &[("Content-Type", "image/png"), ("Accept", "application/json")],
+        &tiny_png(),
+    ));
+    assert_eq!(res.status, 200);
+    assert!(selected_mask_for_object(9284).is_some());
+}
+
+#[test]
+fn undo_goes_back_to_the_previous_mask() {
+    let upload = || {
+        let res = send(&request(
+            "POST",
+            "/mask?object=9283",
+            &[("Content-Type", "image/png"), ("Accept", "application/json")],
+            &tiny_png(),
+        ));
+        res.json()["masks"][0]["file"].as_str().unwrap().to_string()
+    };
+    let first = upload();
+    let second = upload();
+    let selected = || {
+        selected_mask_for_object(9283).map(|m| m.path.file_name().unwrap().to_string_lossy().into_owned())
+    };
+    assert_eq!(selected().as_deref(), Some(second.as_str()));
+
+    let res = send(&request("POST", "/mask/undo?object=9283", &[], b""));
+    assert_eq!(res.status, 200);
+    assert_eq!(res.json()["undone"]["file"], second.as_str());
+    assert_eq!(selected().as_deref(), Some(first.as_str()));
+    assert!(test_root().join(&second).exists());
+
+    assert_eq!(send(&request("POST", "/mask/undo?object=9283", &[], b"")).status, 200);
+    assert_eq!(selected(), None);
+    assert_eq!(send(&request("POST", "/mask/undo?object=9283", &[], b"")).status, 404);
+}
+
+#[test]
+fn mask_diff_requires_both_names() {
+    assert_eq!(send(&request("GET", "/api/masks/diff?a=x.png", &[], b"")).status, 400);
+    assert_eq!(
+        send(&request("GET", "/api/masks/diff?a=x.png&b=y.png", &[], b"")).status,
+        400
+    );
+}
+
+#[test]
+fn mask_diff_decodes_percent_encoded_names() {
+    write(test_root().join("差分_a.png"), tiny_png()).unwrap();
+    write(test_root().join("差分_b.png"), tiny_png()).unwrap();
+    let a = percent_encode("差分_a.png");
+    let b = percent_encode("差分_b.png");
+    let res = send(&request("GET", &format!("/api/masks/diff?a={a}&b={b}&format=json"), &[], b""));
+    assert_eq!(res.status, 200);
+}
+
+#[test]
+fn put_rejects_path_traversal_and_missing_masks() {
+    let png = tiny_png();
+    for target in ["/masks/..%2Fevil.png", "/masks/../evil.png", "/masks/missing.png", "/masks/x.txt"] {
+        let res = send(&request("PUT", target, &[], &png));
+        assert_eq!(res.status, 404, "{target}");
+    }
+}
+
+#[test]
+fn mask_and_bundle_names_must_be_a_single_file_name() {
+    for name in ["sam_mask.png", "index.html", "差分.png", "a..b.png"] {
+        assert!(is_plain_file_name(name), "{name}");
+    }
+    for name in ["", ".", "..", "a/b.png", "a\\b.png", "C:evil.png", "mask.png:stream", "/evil.png"] {
+        assert!(!is_plain_file_name(name), "{name}");
+    }
+    assert!(existing_mask_path("C:sam_mask.png").is_err());
+    assert!(existing_mask_path("sam_mask.png:evil.png").is_err());
+}
+
+#[test]
+fn put_replaces_mask_and_keeps_backup() {
+    let name = "sam_mask_put_test.png";
+    let path = test_root().join(name);
+    write(&path, tiny_png()).unwrap();
+
+    let replacement = {
+        let img = image::RgbaImage::from_pixel(4, 4, image::Rgba([0, 0, 0, 0]));
+        let mut png = Vec::new();
+        img.write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        png
+    };
+    let res = send(&request("PUT", &format!("/masks/{name}?backup=1"), &[], &replacement));
+    assert_eq!(res.status, 200);
+    assert_eq!(read(&path).unwrap(), replacement);
+    assert_eq!(read(test_root().join(format!("{name}.bak"))).unwrap(), tiny_png());
+}
+
+#[test]
+fn put_rejects_an_invalid_png_and_keeps_the_mask() {
+    let name = "sam_mask_put_invalid.png";
+    let path = test_root().join(name);
+    write(&path, tiny_png()).unwrap();
+
+    let res = send(&request("PUT", &format!("/masks/{name}?backup=1"), &[], b"not a png at all"));
+    assert_eq!(res.status, 400);
+    assert!(String::from_utf8_lossy(&res.body).contains("Invalid mask"));
+    assert_eq!(read(&path).unwrap(), tiny_png());
+    assert_eq!(read(test_root().join(format!("{name}.bak"))).unwrap(), tiny_png());
+}
+
+#[test]
+fn put_with_empty_body_is_400() {
+    let name = "sam_mask_put_empty.png";
+    write(test_root().join(name), tiny_png()).unwrap();
+    assert_eq!(send(&request("PUT", &format!("/masks/{name}"), &[], b"")).status, 400);
+}
+
+#[test]
+fn truncated_body_is_an_error_and_leaves_no_file() {
+    let dir = test_root().join("truncated_spool");
+    create_dir_all(&dir).unwrap();
+    let mut body = RequestBody {
+        reader: Box::new(Cursor::new(b"abc".to_vec()).chain(Cursor::new(b"de".to_vec()).take(100))),
+        content_length: Some(10),
+    };
+    assert!(body.spool(&dir).is_err());
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+}
+
+#[test]
+fn atomic_write_replaces_the_file_without_leftovers() {
+    let dest = test_root().join("atomic.bin");
+    write(&dest, b"old").unwrap();
+    crate::frame::write_atomic(&dest, b"new").unwrap();
+    assert_eq!(read(&dest).unwrap(), b"new");
+    assert!(!test_root().join("atomic.bin.part").exists());
+}
+
+#[test]
+fn body_is_read_from_leftover_and_stream() {
+    let mut body = RequestBody {
+        reader: Box::new(Cursor::new(b"abc".to_vec()).chain(Cursor::new(b"defgh".to_vec()).take(2))),
+        content_length: Some(5),
+    };
+    let (spool_path, written) = body.spool(test_root()).unwrap();
+    assert_eq!(written, 5);
+    assert_eq!(read(&spool_path).unwrap(), b"abcde");
+    // 同時に受けても一時ファイルが重ならない
+    assert_ne!(new_spool_path(test_root()), new_spool_path(test_root()));
+}
+
+#[test]
+fn chunked_upload_is_decoded() {
+    let png = tiny_png();
+    let (a, b) = png.split_at(10);
+    let mut raw = b"POST /mask?object=9276 HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Type: image/png\r\nAccept: application/json\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+    for chunk in [a, b] {
+        raw.extend_from_slice(format!("{:x};ext=1\r\n", chunk.len()).as_bytes());
+        raw.extend_from_slice(chunk);
+        raw.extend_from_slice(b"\r\n");
+    }
+    raw.extend_from_slice(b"0\r\nX-Trailer: 1\r\n\r\n");
+    let res = send(&raw);
+    assert_eq!(res.status, 200);
+    let path = res.json()["masks"][0]["path"].as_str().unwrap().to_string();
+    assert_eq!(read(path).unwrap(), png);
+
+    let mut truncated = ChunkedReader::new(&b"a\r\nabc"[..]);
+    assert!(truncated.read_to_end(&mut Vec::new()).is_err());
+
+    // 解けない転送コーディングはそのまま保存せずに断る
+    let res = send(b"POST /mask?object=9276 HTTP/1.1\r\nHost: 127.0.0.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n0\r\n\r\n");
+    assert_eq!(res.status, 501);
+}
+
+#[test]
+fn oversized_bodies_are_rejected() {
+    let res = send(b"POST /mask HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Length: 999999999999\r\n\r\n");
+    assert_eq!(res.status, 413);
+
+    let mut limited = LimitedReader {
+        inner: &b"0123456789"[..],
+        remaining: 4,
+    };
+    let e = limited.read_to_end(&mut Vec::new()).unwrap_err();
+    assert!(e.get_ref().unwrap().is::<BodyTooLarge>());
+}
+
+#[test]
+fn multipart_boundary_parsing() {
+    assert_eq!(
+        multipart_boundary("multipart/form-data; boundary=xyz").as_deref(),
+        Some("xyz")
+    );
+    assert_eq!(
+        multipart_boundary("multipart/form-data; charset=utf-8; boundary=\"a b\"").as_deref(),
+        Some("a b")
+    );
+    assert_eq!(multipart_boundary("image/png"), None);
+    assert_eq!(multipart_boundary("multipart/form-data"), None);
+}
+
+#[test]
+fn multipart_parts_survive_any_read_size() {
+    let spool = test_root().join("spool");
+    create_dir_all(&spool).unwrap();
+    let png = tiny_png();
+    let body = multipart_body("XyZ", &[("person", &png), ("dog", b"\r\n--Xy is not the boundary")]);
+
+    for n in [1, 3, 7, 1000] {
+        let parts = read_multipart(&mut Trickle { data: &body, n }, "XyZ", &spool).unwrap();
+        assert_eq!(parts.len(), 2, "chunk size {n}");
+        assert_eq!(parts[0].name, "person");
+        let PartData::File(path) = &parts[0].data else {
+            panic!("file part kept in memory");
+        };
+        assert_eq!(read(path).unwrap(), png, "chunk size {n}");
+        let PartData::File(path) = &parts[1].data else {
+            panic!("file part kept in memory");
+        };
+        assert_eq!(read(path).unwrap(), b"\r\n--Xy is not the boundary");
+        for part in parts {
+            if let PartData::File(path) = part.data {
+                let _ = remove_file(path);
+            }
+        }
+    }
+}
+
+#[test]
+fn bulk_multipart_upload_registers_frames() {
+    let png = tiny_png();
+    let body = multipart_body("b0undary", &[("frame_000003", &png), ("12", &png), ("notes", &png)]);
+    let res = send(&request(
+        "POST",
+        "/api/masks/bulk?object=7",
+        &[("Content-Type", "multipart/form-data; boundary=b0undary")],
+        &body,
+    ));
+    assert_eq!(res.status, 200);
+    assert_eq!(res.json()["frames"], serde_json::json!([3, 12]));
+    assert!(test_root().join("sam_seq_7").join("mask_000003.png").is_file());
+    assert!(test_root().join("sam_seq_7").join("mask_000012.png").is_file());
+}
+
+#[test]
+fn mask_sequence_is_an_alias_of_bulk_upload() {
+    let body = multipart_body("seq", &[("frame_000001", &tiny_png())]);
+    let res = send(&request(
+        "POST",
+        "/mask/sequence?object=9",
+        &[("Content-Type", "multipart/form-data; boundary=seq")],
+        &body,
+    ));
+    assert_eq!(res.status, 200);
+    assert_eq!(res.json()["frames"], serde_json::json!([1]));
+    assert!(test_root().join("sam_seq_9").join("mask_000001.png").is_file());
+}
+
+#[test]
+fn bulk_upload_without_frame_numbers_is_400() {
+    let body = multipart_body("b", &[("mask", &tiny_png())]);
+    let res = send(&request(
+        "POST",
+        "/api/masks/bulk?object=8",
+        &[("Content-Type", "multipart/form-data; boundary=b")],
+        &body,
+    ));
+    assert_eq!(res.status, 400);
+}
+
+#[test]
+fn frame_zip_for_unknown_object_is_404() {
+    let res = send(&request("GET", "/api/frames/0-10.zip?object=999999", &[], b""));
+    assert_eq!(res.status, 404);
+}
+
+#[test]
+fn frame_numbers_from_names() {
+    assert_eq!(frame_number_from_name("frame_000012.png"), Some(12));
+    assert_eq!(frame_number_from_name("dir/sub/7.png"), Some(7));
+    assert_eq!(frame_number_from_name("dir\\0042.mask.png"), Some(42));
+    assert_eq!(frame_number_from_name("mask.png"), None);
+}
+
+#[test]
+fn version_comparison() {
+    assert!(is_newer_version("v0.2.0", "0.1.1"));
+    assert!(is_newer_version("v0.1.10", "0.1.9"));
+    assert!(!is_newer_version("v0.1.1", "0.1.1"));
+    assert!(!is_newer_version("v0.1.0", "0.1.1"));
+}
+
+#[test]
+fn history_is_filtered_by_object_and_since() {
+    record_history(HistoryKind::MaskReceived, Some(31337), "first");
+    let first = send(&request("GET", "/api/history?object=31337", &[], b"")).json();
+    let entries = first["entries"].as_array().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0]["kind"], "mask_received");
+    assert_eq!(entries[0]["message"], "first");
+
+    record_history(HistoryKind::Error, Some(31337), "second");
+    let since = entries[0]["id"].as_u64().unwrap();
+    let next = send(&request("GET", &format!("/api/history?object=31337&since={since}"), &[], b"")).json();
+    assert_eq!(next["entries"].as_array().map(Vec::len), Some(1));
+    assert_eq!(next["entries"][0]["kind"], "error");
+}
+
+#[test]
+fn percent_decoding() {
+    assert_eq!(percent_decode("take+A"), "take A");
+    assert_eq!(percent_decode("chara%42"), "charaB");
+    assert_eq!(percent_decode("%E3%81%82"), "あ");
+    assert_eq!(percent_decode("100%"), "100%");
+    assert_eq!(percent_decode("%zz"), "%zz");
+    assert_eq!(percent_decode_path("a+b%20c"), "a+b c");
+}
+
+#[test]
+fn request_paths_are_percent_decoded() {
+    let head = parse_request_head(b"GET /mask/%E3%81%82%E3%81%84.png?label=a%2Bb HTTP/1.1");
+    assert_eq!(head.path, "/mask/あい.png");
+    assert_eq!(head.query, "label=a%2Bb");
+
+    let name = "日本語のマスク_9297.png";
+    write(test_root().join(name), tiny_png()).unwrap();
+    let res = send(&request("GET", &format!("/mask/{}", percent_encode(name)), &[], b""));
+    assert_eq!(res.status, 200);
+    assert_eq!(res.body, tiny_png());
+
+    // 戻した後の ".." や "/" も今までどおり弾く
+    assert_eq!(send(&request("GET", "/mask/..%2Fevil.png", &[], b"")).status, 404);
+    assert_eq!(send(&request("GET", "/%2e%2e/Cargo.toml", &[], b"")).status, 404);
+}
+
+#[test]
+fn session_tag_goes_into_mask_name() {
+    test_root();
+    object_states()
+        .lock()
+        .unwrap()
+        .entry(4242)
+        .or_default()
+        .session_tag = "take A".into();
+    let path = make_unique_mask_path(4242, Some("person")).unwrap();
+    let name = path.file_name().unwrap().to_string_lossy();
+    assert!(name.starts_with("sam_mask_take_A_person_"), "{name}");
+}
+
+#[test]
+fn sequential_names_continue_from_existing_files() {
+    let root = test_root();
+    {
+        let mut states = object_states().lock().unwrap();
+        let state = states.entry(5151).or_default();
+        state.sequential_names = true;
+        state.session_tag.clear();
+    }
+    write(root.join("mask_obj5151_0007.png"), b"").unwrap();
+    write(root.join("mask_obj5151_person_0002.png"), b"").unwrap();
+
+    let path = make_unique_mask_path(5151, None).unwrap();
+    assert_eq!(path.file_name().unwrap(), "mask_obj5151_0008.png");
+    let path = make_unique_mask_path(5151, Some("person")).unwrap();
+    assert_eq!(path.file_name().unwrap(), "mask_obj5151_person_0003.png");
+    let path = make_unique_mask_path(5151, Some("dog")).unwrap();
+    assert_eq!(path.file_name().unwrap(), "mask_obj5151_dog_0001.png");
+}
+
+#[test]
+fn name_template_fills_placeholders() {
+    let root = test_root();
+    {
+        let mut states = object_states().lock().unwrap();
+        let state = states.entry(5283).or_default();
+        state.name_template = "{project}/cut{object_id}_{label}_{frame}".into();
+        state.sequential_names = true;
+    }
+    let project = root.file_name().unwrap().to_string_lossy();
+    let path = make_unique_mask_path(5283, Some("person")).unwrap();
+    assert_eq!(path.file_name().unwrap().to_string_lossy(), format!("{project}_cut5283_person_.png"));
+
+    write(&path, b"").unwrap();
+    let path = make_unique_mask_path(5283, Some("person")).unwrap();
+    assert_eq!(path.file_name().unwrap().to_string_lossy(), format!("{project}_cut5283_person__1.png"));
+}
+
+#[test]
+fn object_folders_hold_masks_and_stay_listed() {
+    let root = test_root();
+    object_states().lock().unwrap().entry(5284).or_default().object_folders = true;
+    let res = send(&request(
+        "POST",
+        "/mask?object=5284",
+        &[("Content-Type", "image/png"), ("Accept", "application/json")],
+        &tiny_png(),
+    ));
+    assert_eq!(res.status, 200, "{}", String::from_utf8_lossy(&res.body));
+    let name = res.json()["masks"][0]["file"].as_str().unwrap().to_string();
+    assert!(root.join("object_5284").join(&name).is_file());
+
+    let json = send(&request("GET", "/masks?object=5284", &[], b"")).json();
+    assert_eq!(json["masks"][0]["name"], name.as_str());
+    assert_eq!(send(&request("GET", &format!("/mask/{name}"), &[], b"")).status, 200);
+}
+
+#[test]
+fn saved_masks_are_appended_to_the_export_manifest() {
+    let frame_path = test_root().join("object_9285").join("current_frame.png");
+    create_dir_all(frame_path.parent().unwrap()).unwrap();
+    write(&frame_path, tiny_png()).unwrap();
+    crate::state::set_frame_file_for_object(9285, &frame_path, (4, 4));
+    let res = send(&request(
+        "POST",
+        "/mask?object=9285",
+        &[("Content-Type", "image/png"), ("Accept", "application/json")],
+        &tiny_png(),
+    ));
+    let name = res.json()["masks"][0]["file"].as_str().unwrap().to_string();
+
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&read(test_root().join("manifest.json")).unwrap()).unwrap();
+    let entry = manifest["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|e| e["file"] == name.as_str())
+        .expect("mask is not in manifest.json");
+    assert_eq!(entry["kind"], "mask");
+    assert_eq!(entry["object_id"], 9285);
+    // 最後に書き出されたほかのオブジェクトのフレームではなく、このオブジェクトのフレーム
+    let frame_file = entry["frame_file"].as_str().unwrap();
+    assert!(frame_file.starts_with("object_9285") && frame_file.ends_with("current_frame.png"), "{frame_file}");
+    assert!(entry["created_at"].is_string());
+}
+
+#[test]
+fn stats_report_memory_usage() {
+    let json = send(&request("GET", "/api/stats", &[], b"")).json();
+    assert!(json["memory"]["budget_bytes"].as_u64().unwrap() > 0);
+    assert!(json["memory"]["used_bytes"].as_u64().unwrap() <= json["memory"]["budget_bytes"].as_u64().unwrap());
+}
+
+/// 1 回の write で最大 n バイトしか受け取らないライター
+struct ShortWriter {
+    data: Vec<u8>,
+    n: usize,
+}
+
+impl Write for ShortWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.n.min(buf.len());
+        self.data.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn vectored_response_survives_short_writes() {
+    let mut writer = ShortWriter { data: Vec::new(), n: 5 };
+    write_response(&mut writer, None, 200, "OK", b"hello body", "text/plain").unwrap();
+    let text = String::from_utf8(writer.data).unwrap();
+    assert!(text.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(text.contains("Content-Length: 10\r\n"));
+    assert!(text.ends_with("\r\n\r\nhello body"));
+}
+
+#[test]
+fn static_file_range_requests() {
+    assert_eq!(parse_range(None, 10), None);
+    assert_eq!(parse_range(Some("bytes=2-4"), 10), Some(Ok((2, 4))));
+    assert_eq!(parse_range(Some("bytes=7-"), 10), Some(Ok((7, 9))));
+    assert_eq!(parse_range(Some("bytes=-3"), 10), Some(Ok((7, 9))));
+    assert_eq!(parse_range(Some("bytes=5-100"), 10), Some(Ok((5, 9))));
+    assert_eq!(parse_range(Some("bytes=10-"), 10), Some(Err(())));
+    assert_eq!(parse_range(Some("bytes=0-1,4-5"), 10), None);
+
+    let path = test_root().join("range_test.bin");
+    std::fs::write(&path, b"0123456789").unwrap();
+    let open = || StaticFile {
+        body: StaticBody::File(File::open(&path).unwrap()),
+        len: 10,
+        content_type: "application/octet-stream".to_string(),
+        encoding: None,
+        etag: "\"range\"".to_string(),
+        modified: None,
+    };
+
+    let mut out = Vec::new();
+    write_file_response(&mut out, None, Some("bytes=2-4"), open()).unwrap();
+    let text = String::from_utf8(out).unwrap();
+    assert!(text.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+    assert!(text.contains("Content-Range: bytes 2-4/10\r\n"));
+    assert!(text.contains("Content-Length: 3\r\n"));
+    assert!(text.ends_with("\r\n\r\n234"));
+
+    let mut out = Vec::new();
+    write_file_response(&mut out, None, None, open()).unwrap();
+    let text = String::from_utf8(out).unwrap();
+    assert!(text.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(text.contains("Accept-Ranges: bytes\r\n"));
+    assert!(text.ends_with("\r\n\r\n0123456789"));
+
+    let mut out = Vec::new();
+    write_file_response(&mut out, None, Some("bytes=20-"), open()).unwrap();
+    assert!(String::from_utf8(out).unwrap().starts_with("HTTP/1.1 416 "));
+
+    // メモリキャッシュ済みのものも同じように範囲を切り出す
+    let cached = StaticFile {
+        body: StaticBody::Memory(Arc::from(&b"0123456789"[..])),
+        len: 10,
+        content_type: "text/html; charset=utf-8".to_string(),
+        encoding: Some("br"),
+        etag: "\"cached\"".to_string(),
+        modified: None,
+    };
+    let mut out = Vec::new();
+    write_file_response(&mut out, None, Some("bytes=-2"), cached).unwrap();
+    let text = String::from_utf8(out).unwrap();
+    assert!(text.contains("Content-Encoding: br\r\n"));
+    assert!(text.ends_with("\r\n\r\n89"));
+}
+
+#[test]
+fn mask_patch_replaces_only_the_region() {
+    let object_id = 9240;
+    let path = test_root().join("sam_mask_patch_test.png");
+    write(&path, tiny_png()).unwrap();
+    crate::state::set_mask_path_for_object(object_id, None, path.clone());
+
+    let patch = {
+        let img = image::RgbaImage::from_pixel(2, 2, image::Rgba([0, 0, 0, 0]));
+        let mut png = Vec::new();
+        img.write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        png
+    };
+    let target = format!("/api/mask/patch?object={object_id}&x=3&y=3");
+    let res = send(&request("POST", &target, &[], &patch));
+    assert_eq!(res.status, 200);
+
+    let mask = image::open(&path).unwrap().to_rgba8();
+    assert_eq!(mask.get_pixel(2, 2)[3], 255);
+    assert_eq!(mask.get_pixel(3, 3)[3], 0);
+
+    let target = format!("/api/mask/patch?object={object_id}&x=4&y=0");
+    assert_eq!(send(&request("POST", &target, &[], &patch)).status, 400);
+    let target = "/api/mask/patch?object=9241&x=0&y=0";
+    assert_eq!(send(&request("POST", target, &[], &patch)).status, 404);
+}
+
+#[test]
+fn accept_encoding_parsing() {
+    assert!(accepts_encoding(Some("gzip, deflate, br, zstd"), "br"));
+    assert!(accepts_encoding(Some("gzip;q=0.5"), "gzip"));
+    assert!(!accepts_encoding(Some("gzip, br;q=0"), "br"));
+    assert!(accepts_encoding(Some("*"), "gzip"));
+    assert!(!accepts_encoding(Some("identity"), "gzip"));
+    assert!(!accepts_encoding(None, "br"));
+}
+
+#[test]
+fn current_frame_answers_conditional_requests() {
+    test_root();
+    // ほかのテストの編集中オブジェクトのフレームになっても、そのマスク（tiny_png）が拡縮されないよう同じ大きさにする
+    let img = image::RgbaImage::from_pixel(4, 4, image::Rgba([10, 20, 30, 255]));
+    crate::frame::set_current_frame(&img).unwrap();
+
+    let res = send(&request("GET", "/frame/current.png", &[], b""));
+    assert_eq!(res.status, 200);
+    // ファイルを読み直さずメモリの PNG を返す
+    let (_, png) = crate::frame::current_frame_data().unwrap();
+    assert_eq!(res.body, &png[..]);
+    let etag = res.header("ETag").unwrap().to_string();
+    let last_modified = res.header("Last-Modified").unwrap().to_string();
+
+    let res = send(&request("GET", "/frame/current.png", &[("If-None-Match", &etag)], b""));
+    assert_eq!(res.status, 304);
+    assert!(res.body.is_empty());
+    let res = send(&request("GET", "/frame/current.png", &[("If-Modified-Since", &last_modified)], b""));
+    assert_eq!(res.status, 304);
+    let res = send(&request("GET", "/frame/current.png", &[("If-None-Match", "\"stale\"")], b""));
+    assert_eq!(res.status, 200);
+}
+
+#[test]
+fn frame_polling_marks_the_ui_as_open() {
+    let res = send(&request("GET", "/api/frame", &[], b""));
+    assert_eq!(res.status, 200);
+    assert!(res.json().get("etag").is_some());
+    assert!(ui_client_connected());
+}
+
+#[test]
+fn mask_upload_redirects_to_done_page() {
+    let res = send(&request("POST", "/mask?object=9244", &[("Content-Type", "image/png")], &tiny_png()));
+    assert_eq!(res.status, 303);
+    let location = res.header("Location").unwrap().to_string();
+    assert!(location.starts_with("/done?object=9244&mask=sam_mask_"), "{location}");
+    assert_eq!(res.json()["done_url"], location.as_str());
+
+    let res = send(&request("GET", &location, &[], b""));
+    assert_eq!(res.status, 200);
+    let page = String::from_utf8(res.body).unwrap();
+    assert!(page.contains("<code>9244</code>"));
+    assert!(page.contains("<li><code>sam_mask_"));
+    assert!(page.contains("class=\"delete\" data-mask=\"sam_mask_"));
+
+    let res = send(&request("GET", "/done?object=%3Cb%3E&mask=%3Cscript%3E", &[], b""));
+    let page = String::from_utf8(res.body).unwrap();
+    assert!(page.contains("<code>&lt;script&gt;</code>"));
+    assert!(!page.contains("<code><b></code>"));
+}
+
+#[test]
+fn mask_upload_returns_saved_file_metadata() {
+    let res = send(&request(
+        "POST",
+        "/mask?object=9245",
+        &[("Content-Type", "image/png"), ("Accept", "application/json")],
+        &tiny_png(),
+    ));
+    assert_eq!(res.status, 200);
+    let info = res.json();
+    assert_eq!(info["object_id"], 9245);
+    let mask = &info["masks"][0];
+    assert!(mask["file"].as_str().unwrap().starts_with("sam_mask_"));
+    assert!(Path::new(mask["path"].as_str().unwrap()).is_file());
+    assert_eq!((mask["width"].as_u64(), mask["height"].as_u64()), (Some(4), Some(4)));
+    assert!(info["saved_at"].is_string());
+}
+
+#[test]
+fn invalid_mask_uploads_are_rejected_with_a_reason() {
+    // set_current_frame は共有の編集中オブジェクトに書き出すので、このオブジェクトのフレームを直接決める
+    let frame_path = test_root().join("sam_invalid_mask_frame.png");
+    write(&frame_path, tiny_png()).unwrap();
+    crate::state::set_frame_file_for_object(9279, &frame_path, (4, 4));
+
+    let res = send(&request("POST", "/mask?object=9279", &[("Content-Type", "image/png")], b"not a png"));
+    assert_eq!(res.status, 400);
+    assert!(String::from_utf8_lossy(&res.body).contains("not a PNG"));
+
+    let mut wide = Vec::new();
+    image::RgbaImage::new(8, 2)
+        .write_to(&mut Cursor::new(&mut wide), image::ImageFormat::Png)
+        .unwrap();
+    let res = send(&request("POST", "/mask?object=9279", &[("Content-Type", "image/png")], &wide));
+    assert_eq!(res.status, 400);
+    assert!(String::from_utf8_lossy(&res.body).contains("8x2"));
+
+    let mut truncated = tiny_png();
+    truncated.truncate(30);
+    assert_eq!(send(&request("POST", "/mask?object=9279", &[("Content-Type", "image/png")], &truncated)).status, 400);
+}
+
+#[test]
+fn masks_are_checked_against_their_own_objects_frame() {
+    let frame_path = test_root().join("sam_own_frame_test.png");
+    image::RgbaImage::from_pixel(4, 4, image::Rgba([10, 20, 30, 255]))
+        .save(&frame_path)
+        .unwrap();
+    crate::state::set_frame_file_for_object(9290, &frame_path, (4, 4));
+    // オブジェクトごとのフォルダが無いと、別のオブジェクトが同じファイルに書き出す
+    image::RgbaImage::new(8, 2).save(&frame_path).unwrap();
+    crate::state::set_frame_file_for_object(9291, &frame_path, (8, 2));
+
+    let mut wide = Vec::new();
+    image::RgbaImage::new(8, 2)
+        .write_to(&mut Cursor::new(&mut wide), image::ImageFormat::Png)
+        .unwrap();
+    let upload = |object_id: i64, png: &[u8]| {
+        let session = crate::state::start_export_session(object_id);
+        send(&request(
+            "POST",
+            "/mask",
+            &[
+                ("Content-Type", "image/png"),
+                ("Accept", "application/json"),
+                ("X-Sam-Session", &session),
+            ],
+            png,
+        ))
+    };
+
+    assert_eq!(upload(9290, &wide).status, 400);
+    assert_eq!(upload(9291, &wide).status, 200);
+    let res = upload(9290, &tiny_png());
+    assert_eq!(res.status, 200);
+
+    // 上書きされたファイルの色を 9290 のマスクに使わない
+    let mask_path = PathBuf::from(res.json()["masks"][0]["path"].as_str().unwrap());
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&read(crate::export::mask_manifest_path(&mask_path)).unwrap()).unwrap();
+    assert_eq!(manifest["object_id"], 9290);
+    assert!(manifest.get("background_colors").is_none());
+}
+
+#[test]
+fn mask_upload_uses_the_export_session_object() {
+    let session = crate::state::start_export_session(9251);
+    crate::state::set_edit_object(9252);
+    let res = send(&request(
+        "POST",
+        "/mask",
+        &[
+            ("Content-Type", "image/png"),
+            ("Accept", "application/json"),
+            ("X-Sam-Session", &session),
+        ],
+        &tiny_png(),
+    ));
+    assert_eq!(res.status, 200);
+    assert_eq!(res.json()["object_id"], 9251);
+
+    let res = send(&request("POST", "/mask?session=unknown", &[("Content-Type", "image/png")], &tiny_png()));
+    assert_eq!(res.status, 409);
+}
+
+#[test]
+fn multipart_mask_fields_pick_the_object_and_become_metadata() {
+    crate::state::set_edit_object(9261);
+    let mut body = Vec::new();
+    for (name, value) in [("object_id", "9262"), ("frame", "12"), ("points", "[[10,20]]")] {
+        body.extend_from_slice(
+            format!("--m\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n").as_bytes(),
+        );
+    }
+    body.extend(multipart_body("m", &[("mask", &tiny_png())]));
+    let res = send(&request(
+        "POST",
+        "/mask",
+        &[
+            ("Content-Type", "multipart/form-data; boundary=m"),
+            ("Accept", "application/json"),
+        ],
+        &body,
+    ));
+    assert_eq!(res.status, 200, "{}", String::from_utf8_lossy(&res.body));
+    let info = res.json();
+    assert_eq!(info["object_id"], 9262);
+
+    let mask_path = PathBuf::from(info["masks"][0]["path"].as_str().unwrap());
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&read(crate::export::mask_manifest_path(&mask_path)).unwrap()).unwrap();
+    assert_eq!(manifest["metadata"], serde_json::json!({ "frame": 12, "points": [[10, 20]] }));
+}
+
+#[test]
+fn changed_region_is_the_bounding_box_of_differences() {
+    let before = image::RgbaImage::from_pixel(8, 6, image::Rgba([0, 0, 0, 255]));
+    assert_eq!(crate::frame::changed_region(before.as_raw(), &before), None);
+
+    let mut after = before.clone();
+    after.put_pixel(2, 1, image::Rgba([255, 0, 0, 255]));
+    after.put_pixel(5, 3, image::Rgba([0, 255, 0, 255]));
+    assert_eq!(
+        crate::frame::changed_region(before.as_raw(), &after),
+        Some((2, 1, 4, 3))
+    );
+}
+
+#[test]
+fn mask_alpha_is_resized_to_the_object() {
+    let path = test_root().join("sam_mask_alpha_test.png");
+    image::RgbaImage::from_fn(4, 4, |x, _| image::Rgba([255, 255, 255, if x < 2 { 255 } else { 0 }]))
+        .save(&path)
+        .unwrap();
+
+    let alpha = crate::export::mask_alpha_for_size(&path, 4, 4).unwrap();
+    assert_eq!(&alpha[..4], &[255, 255, 0, 0]);
+    let alpha = crate::export::mask_alpha_for_size(&path, 2, 2).unwrap();
+    assert_eq!(alpha.len(), 4);
+    assert!(alpha[0] > alpha[1]);
+}
+
+#[test]
+fn status_reports_plugin_state() {
+    let res = send(&request("GET", "/status", &[], b""));
+    assert_eq!(res.status, 200);
+    let status = res.json();
+    assert_eq!(status["server"], "running");
+    assert_eq!(status["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(status["export_root"], test_root().display().to_string());
+    assert!(status["current_frame"]["exists"].is_boolean());
+    assert!(status["auto_open_browser"].is_boolean());
+}
+
+#[test]
+fn embedded_assets_are_served_when_web_root_is_empty() {
+    let _web_ui = web_ui_test_lock();
+    if web_root_installed() {
+        return;
+    }
+    let res = send(&request("GET", "/", &[], b""));
+    assert_eq!(res.status, 200);
+    assert_eq!(res.body, embedded_asset("index.html").unwrap());
+    let res = send(&request("GET", "/index.js", &[], b""));
+    assert_eq!(res.header("Content-Type"), Some("text/javascript; charset=utf-8"));
+    assert_eq!(send(&request("GET", "/missing.js", &[], b"")).status, 404);
+}
+
+#[test]
+fn root_serves_the_setup_page_when_the_embedded_ui_could_not_be_installed() {
+    let _web_ui = web_ui_test_lock();
+    if web_root_installed() {
+        return;
+    }
+    *lock_recover(embedded_install_error()) = Some("access denied".to_string());
+    let res = send(&request("GET", "/", &[], b""));
+    *lock_recover(embedded_install_error()) = None;
+
+    assert_eq!(res.status, 200);
+    assert_eq!(res.header("Content-Type"), Some("text/html; charset=utf-8"));
+    let page = String::from_utf8_lossy(&res.body);
+    assert!(page.contains("Web UI が見つかりません"));
+    assert!(page.contains("埋め込みの Web UI を展開できませんでした: access denied"));
+}
+
+#[test]
+fn static_files_answer_304_for_a_matching_etag() {
+    let res = send(&request("GET", "/index.js", &[], b""));
+    assert_eq!(res.status, 200);
+    assert_eq!(res.header("Cache-Control"), Some("no-cache"));
+    let etag = res.header("ETag").expect("static files carry an ETag").to_string();
+
+    let res = send(&request("GET", "/index.js", &[("If-None-Match", &etag)], b""));
+    assert_eq!(res.status, 304);
+    assert!(res.body.is_empty());
+    assert_eq!(res.header("ETag"), Some(etag.as_str()));
+
+    let res = send(&request("GET", "/index.js", &[("If-None-Match", "\"stale\"")], b""));
+    assert_eq!(res.status, 200);
+
+    let modified = std::fs::metadata(test_root()).unwrap().modified().unwrap();
+    assert_eq!(static_file_etag(Some(modified), 10, None), static_file_etag(Some(modified), 10, None));
+    assert_ne!(static_file_etag(Some(modified), 10, None), static_file_etag(Some(modified), 10, Some("br")));
+}
+
+#[test]
+fn unknown_page_paths_fall_back_to_index_html() {
+    let _web_ui = web_ui_test_lock();
+    let html = [("Accept", "text/html,application/xhtml+xml,*/*;q=0.8")];
+    let index = send(&request("GET", "/index.html", &[], b"")).body;
+
+    let res = send(&request("GET", "/editor/objects/3", &html, b""));
+    assert_eq!(res.status, 200);
+    assert_eq!(res.header("Content-Type"), Some("text/html; charset=utf-8"));
+    assert_eq!(res.body, index);
+
+    // ファイルや API、ページ遷移でないリクエストは 404 のまま
+    assert_eq!(send(&request("GET", "/editor/missing.js", &html, b"")).status, 404);
+    assert_eq!(send(&request("GET", "/api/nope", &html, b"")).status, 404);
+    assert_eq!(send(&request("GET", "/editor", &[], b"")).status, 404);
+}
+
+#[test]
+fn content_types_follow_the_extension_table_and_config() {
+    assert_eq!(content_type_for("sam/decoder.wasm", None), "application/wasm");
+    assert_eq!(content_type_for("fonts/NotoSans.WOFF2", None), "font/woff2");
+    assert_eq!(content_type_for("icon.svg", None), "image/svg+xml");
+    assert_eq!(content_type_for("README", None), "application/octet-stream");
+
+    let custom = HashMap::from([
+        (".onnx".to_string(), "application/x-onnx".to_string()),
+        ("glb".to_string(), "model/gltf-binary".to_string()),
+    ]);
+    assert_eq!(content_type_for("models/sam.onnx", Some(&custom)), "application/x-onnx");
+    assert_eq!(content_type_for("scene.glb", Some(&custom)), "model/gltf-binary");
+    assert_eq!(content_type_for("index.js", Some(&custom)), "text/javascript; charset=utf-8");
+}
+
+#[test]
+fn downloads_resume_only_from_the_same_version() {
+    let res = send(&request("HEAD", "/index.js", &[], b""));
+    assert_eq!(res.status, 200);
+    assert!(res.body.is_empty());
+    assert_eq!(res.header("Accept-Ranges"), Some("bytes"));
+    let etag = res.header("ETag").unwrap().to_string();
+    let len: usize = res.header("Content-Length").unwrap().parse().unwrap();
+    assert_eq!(send(&request("HEAD", "/missing.onnx", &[], b"")).status, 404);
+
+    let res = send(&request("GET", "/index.js", &[("Range", "bytes=4-"), ("If-Range", &etag)], b""));
+    assert_eq!(res.status, 206);
+    assert_eq!(res.body.len(), len - 4);
+
+    // 途中でファイルが変わっていれば続きではなく全体を返す
+    let res = send(&request("GET", "/index.js", &[("Range", "bytes=4-"), ("If-Range", "\"old\"")], b""));
+    assert_eq!(res.status, 200);
+    assert_eq!(res.body.len(), len);
+}
+
+#[cfg(feature = "native-sam")]
+#[test]
+fn native_sam_rejects_mismatched_points() {
+    let status = send(&request("GET", "/api/sam/status", &[], b"")).json();
+    assert!(status["available"].is_boolean());
+    // モデルを読み込む前に入力を確かめる
+    let body = br#"{"points": [[1, 1], [2, 2]], "labels": [1]}"#;
+    let res = send(&request("POST", "/api/sam/predict", &[], body));
+    assert_eq!(res.status, 500);
+    assert!(res.json()["error"].as_str().unwrap().contains("same non-zero length"));
+}
+
+#[cfg(feature = "native-sam")]
+#[test]
+fn propagation_prompts_with_the_box_and_center_of_the_previous_mask() {
+    // 4x3 の画像の (1, 1)〜(2, 2) が前景
+    let alpha = [0, 0, 0, 0, 0, 255, 255, 0, 0, 255, 200, 0];
+    let (bbox, center) = sam::propagation_prompt(&alpha, 4).unwrap();
+    assert_eq!(bbox, [1.0, 1.0, 3.0, 3.0]);
+    assert_eq!(center, Some([1.5, 1.5]));
+    // 重心が前景の外（輪の中）なら点は付けない
+    let ring = [255, 255, 255, 255, 0, 255, 255, 255, 255];
+    assert_eq!(sam::propagation_prompt(&ring, 3).unwrap().1, None);
+    assert!(sam::propagation_prompt(&[0; 4], 2).is_none());
+
+    let res = send(&request("POST", "/api/sam/propagate?object=9292&from=0", &[], b""));
+    assert_eq!(res.status, 404);
+    assert_eq!(send(&request("POST", "/api/sam/propagate", &[], b"")).status, 400);
+}
+
+#[test]
+fn feather_softens_only_the_mask_edge() {
+    let mut mask = image::RgbaImage::from_fn(40, 8, |x, _| {
+        image::Rgba([200, 100, 50, if x < 20 { 255 } else { 0 }])
+    });
+    crate::export::feather_mask(&mut mask, 2.0);
+    assert_eq!(mask.get_pixel(2, 4)[3], 255);
+    assert_eq!(mask.get_pixel(37, 4)[3], 0);
+    let edge = mask.get_pixel(20, 4);
+    assert!(edge[3] > 0 && edge[3] < 255, "{edge:?}");
+    assert_eq!(&edge.0[..3], &[200, 100, 50]);
+}
+
+#[test]
+fn grow_dilates_and_erodes_the_mask() {
+    let square = image::RgbaImage::from_fn(20, 20, |x, y| {
+        let inside = (5..15).contains(&x) && (5..15).contains(&y);
+        image::Rgba([0, 0, 0, if inside { 255 } else { 0 }])
+    });
+    let opaque = |mask: &image::RgbaImage| mask.pixels().filter(|p| p[3] == 255).count();
+
+    let frame = image::RgbaImage::from_pixel(20, 20, image::Rgba([9, 8, 7, 255]));
+    let mut grown = square.clone();
+    crate::export::grow_mask(&mut grown, 2, Some(&frame));
+    assert!(opaque(&grown) > opaque(&square));
+    assert_eq!(*grown.get_pixel(3, 10), image::Rgba([9, 8, 7, 255]));
+    assert_eq!(grown.get_pixel(1, 10)[3], 0);
+
+    let mut shrunk = square.clone();
+    crate::export::grow_mask(&mut shrunk, -2, None);
+    assert_eq!(shrunk.get_pixel(6, 10)[3], 0);
+    assert_eq!(shrunk.get_pixel(10, 10)[3], 255);
+}
+
+#[test]
+fn invert_keeps_the_background_with_frame_colours() {
+    let mut mask = image::RgbaImage::from_fn(4, 1, |x, _| image::Rgba([0, 0, 0, if x < 2 { 255 } else { 64 }]));
+    let frame = image::RgbaImage::from_pixel(4, 1, image::Rgba([9, 8, 7, 255]));
+    crate::export::invert_mask(&mut mask, Some(&frame));
+    assert_eq!(*mask.get_pixel(0, 0), image::Rgba([9, 8, 7, 0]));
+    assert_eq!(*mask.get_pixel(3, 0), image::Rgba([9, 8, 7, 191]));
+}
+
+#[test]
+fn frames_encode_in_the_selected_format() {
+    use crate::state::FrameFormat;
+    let img = image::RgbaImage::from_pixel(8, 8, image::Rgba([200, 100, 50, 255]));
+    for (format, expected) in [
+        (FrameFormat::Png, image::ImageFormat::Png),
+        (FrameFormat::WebpLossless, image::ImageFormat::WebP),
+        (FrameFormat::Webp(80), image::ImageFormat::WebP),
+        (FrameFormat::Jpeg(90), image::ImageFormat::Jpeg),
+    ] {
+        let data = crate::frame::encode_frame(&img, format).unwrap();
+        assert_eq!(image::guess_format(&data).unwrap(), expected);
+        let decoded = image::load_from_memory(&data).unwrap().to_rgba8();
+        assert_eq!(decoded.dimensions(), (8, 8));
+        let pixel = decoded.get_pixel(4, 4);
+        assert!((pixel[0] as i32 - 200).abs() < 8, "{format:?}: {pixel:?}");
+    }
+}
+
+#[test]
+fn concurrent_instances_keep_each_others_entries() {
+    let path = test_root().join("instances_test.json");
+    let writers: Vec<_> = (0..8u16)
+        .map(|i| {
+            let path = path.clone();
+            thread::spawn(move || {
+                for _ in 0..20 {
+                    update_instances(&path, &format!("{}", 1000 + i), Some(18000 + i)).unwrap();
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    let instances: serde_json::Value = serde_json::from_slice(&read(&path).unwrap()).unwrap();
+    assert_eq!(instances.as_object().unwrap().len(), 8, "{instances}");
+    update_instances(&path, "1003", None).unwrap();
+    let instances: serde_json::Value = serde_json::from_slice(&read(&path).unwrap()).unwrap();
+    assert!(instances.get("1003").is_none());
+    assert_eq!(instances["1004"], 18004);
+}
+
+#[test]
+fn slow_upload_does_not_block_other_requests() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || run_http_server(&listener, None));
+
+    // ボディを送り終えない POST を開いたままにする
+    let mut upload = TcpStream::connect(addr).unwrap();
+    upload
+        .write_all(b"POST /mask?object=9275 HTTP/1.1\r\nHost: x\r\nContent-Type: image/png\r\nContent-Length: 1000000\r\n\r\n")
+        .unwrap();
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.write_all(b"GET /api/frame HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).unwrap();
+    assert!(response.starts_with(b"HTTP/1.1 200"), "{}", String::from_utf8_lossy(&response));
+
+    // 止まったアップロードがあっても、別のマスクの保存は待たされない
+    let png = tiny_png();
+    let mut other = TcpStream::connect(addr).unwrap();
+    other.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    other
+        .write_all(
+            format!(
+                "POST /mask?object=9275 HTTP/1.1\r\nHost: x\r\nAccept: application/json\r\nContent-Type: image/png\r\nContent-Length: {}\r\n\r\n",
+                png.len()
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+    other.write_all(&png).unwrap();
+    let mut response = Vec::new();
+    other.read_to_end(&mut response).unwrap();
+    assert!(response.starts_with(b"HTTP/1.1 200"), "{}", String::from_utf8_lossy(&response));
+    drop(upload);
+}
+
+#[test]
+fn websocket_handshake_and_frames() {
+    // RFC 6455 の例
+    assert_eq!(websocket_accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    assert_eq!(websocket_text_frame("hi"), b"\x81\x02hi");
+    assert_eq!(&websocket_text_frame(&"a".repeat(200))[..4], &[0x81, 126, 0, 200]);
+
+    let res = send(&request(
+        "GET",
+        "/ws",
+        &[("Upgrade", "websocket"), ("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")],
+        b"",
+    ));
+    assert_eq!(res.status, 101);
+    assert_eq!(res.header("Sec-WebSocket-Accept"), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+    assert_eq!(send(&request("GET", "/ws", &[], b"")).status, 400);
+
+    // クライアントの Close フレーム（マスク付き）とその後ろのフレームを読み分ける
+    let mut frames = Cursor::new(b"\x89\x82abcdxy\x88\x80abcd".to_vec());
+    assert_eq!(read_websocket_frame(&mut frames).unwrap(), 0x9);
+    assert_eq!(read_websocket_frame(&mut frames).unwrap(), WEBSOCKET_OPCODE_CLOSE);
+    assert_eq!(read_websocket_frame(&mut frames).unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn closed_websocket_tabs_are_unsubscribed() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || run_http_server(&listener, None));
+    let wait_until = |done: &dyn Fn() -> bool| {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    };
+    let connect = || {
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET /ws HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n")
+            .unwrap();
+        let mut response = [0u8; 12];
+        client.read_exact(&mut response).unwrap();
+        assert_eq!(&response, b"HTTP/1.1 101");
+        client
+    };
+    let ids = || lock_recover(frame_subscribers()).iter().map(|(id, _)| *id).collect::<Vec<_>>();
+
+    // タブを閉じた（EOF）
+    let before = ids();
+    let client = connect();
+    wait_until(&|| ids().iter().any(|id| !before.contains(id)));
+    let id = *ids().iter().find(|id| !before.contains(id)).unwrap();
+    drop(client);
+    wait_until(&|| !ids().contains(&id));
+
+    // Close フレームを送ってきた
+    let before = ids();
+    let mut client = connect();
+    wait_until(&|| ids().iter().any(|id| !before.contains(id)));
+    let id = *ids().iter().find(|id| !before.contains(id)).unwrap();
+    client.write_all(b"\x88\x80abcd").unwrap();
+    wait_until(&|| !ids().contains(&id));
+}
+
+#[test]
+fn mask_exo_places_the_image_on_layer_one() {
+    let scene = crate::state::SceneFormat { width: 1920, height: 1080, rate: 30, scale: 1 };
+    let exo = crate::export::mask_exo(&scene, 90, Path::new(r"C:\out\sam_mask_1.png"));
+    assert!(exo.starts_with("[exedit]\r\nwidth=1920\r\nheight=1080\r\n"));
+    assert!(exo.contains("end=90\r\nlayer=1\r\n"));
+    assert!(exo.contains("_name=画像ファイル\r\nfile=C:\\out\\sam_mask_1.png\r\n"));
+}
+
+#[test]
+fn old_log_files_are_pruned_oldest_first() {
+    let dir = test_root().join("log_prune");
+    create_dir_all(&dir).unwrap();
+    for (name, len) in [
+        ("sam_frame_export_2026_01_01_00_00.log", 10),
+        ("sam_frame_export_2026_01_02_00_00.log", 10),
+        ("sam_frame_export_2026_01_03_00_00.log", 10),
+        ("sam_frame_export_2026_01_04_00_00.log", 10),
+        ("other.log", 10),
+    ] {
+        std::fs::write(dir.join(name), vec![b'x'; len]).unwrap();
+    }
+
+    crate::prune_old_logs(&dir, 3, 1000);
+    assert!(!dir.join("sam_frame_export_2026_01_01_00_00.log").exists());
+    assert!(dir.join("sam_frame_export_2026_01_02_00_00.log").exists());
+    assert!(dir.join("other.log").exists());
+
+    crate::prune_old_logs(&dir, 3, 15);
+    assert!(!dir.join("sam_frame_export_2026_01_03_00_00.log").exists());
+    assert!(dir.join("sam_frame_export_2026_01_04_00_00.log").exists());
+}
+
+#[test]
+fn supervised_threads_are_restarted_after_a_panic() {
+    let runs = Arc::new(Mutex::new(0));
+    let (done_tx, done_rx) = mpsc::channel();
+    let counter = Arc::clone(&runs);
+    spawn_supervised("test-supervised", move || {
+        let mut runs = counter.lock().unwrap();
+        *runs += 1;
+        if *runs == 1 {
+            drop(runs);
+            panic!("first run fails");
+        }
+        done_tx.send(*runs).unwrap();
+    });
+
+    assert_eq!(done_rx.recv_timeout(Duration::from_secs(5)).unwrap(), 2);
+    assert_eq!(panic_message(&*catch_unwind(|| panic!("boom {}", 1)).unwrap_err()), "boom 1");
+}
+
+#[test]
+fn diagnostics_checks_the_export_root_and_web_ui() {
+    let _web_ui = web_ui_test_lock();
+    let res = send(&request("GET", "/diagnostics", &[], b""));
+    assert_eq!(res.status, 200);
+    let diagnostics = res.json();
+    assert_eq!(diagnostics["version"], env!("CARGO_PKG_VERSION"));
+    let checks = diagnostics["checks"].as_array().unwrap();
+    let check = |name: &str| checks.iter().find(|c| c["name"] == name).unwrap().clone();
+    assert_eq!(check("export_root_writable")["ok"], true);
+    assert_eq!(check("index_html_exists")["ok"], web_root_installed() || !EMBEDDED_ASSETS.is_empty());
+    assert!(check("disk_space")["detail"].is_string());
+    // 書き込みの確認に使ったファイルは残さない
+    let leftovers = std::fs::read_dir(test_root())
+        .unwrap()
+        .flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with(".sam_write_test"))
+        .count();
+    assert_eq!(leftovers, 0);
+}
+
+#[test]
+fn setup_page_shows_the_expected_path_and_diagnostics() {
+    let page = setup_page_html();
+    assert!(page.contains(&html_escape(&web_root().display().to_string())));
+    assert!(page.contains(RELEASE_ZIP_NAME));
+    assert!(page.contains(&format!("/releases/tag/v{}", env!("CARGO_PKG_VERSION"))));
+    assert!(page.contains("保存先に書き込めるか"));
+    assert!(!page.contains("{{"));
+}
+
+#[test]
+fn embedded_assets_are_extracted_without_overwriting() {
+    let dir = test_root().join("embedded_install");
+    let _ = std::fs::remove_dir_all(&dir);
+    create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("index.css"), b"/* customized */").unwrap();
+
+    let installed = extract_embedded_assets(&dir).unwrap();
+    assert_eq!(installed.len(), EMBEDDED_ASSETS.iter().filter(|(rel, _)| *rel != "index.css").count());
+    assert!(!installed.iter().any(|rel| rel == "index.css"));
+    assert_eq!(std::fs::read(dir.join("index.css")).unwrap(), b"/* customized */");
+    if let Some(index) = embedded_asset("index.html") {
+        assert_eq!(std::fs::read(dir.join("index.html")).unwrap(), index);
+    }
+    assert!(extract_embedded_assets(&dir).unwrap().is_empty());
+}
+
+#[test]
+fn mask_alpha_follows_the_alpha_combine_setting() {
+    use aviutl2::filter::RgbaPixel;
+    use crate::state::AlphaCombine;
+
+    let apply = |combine| {
+        let mut pixels = vec![RgbaPixel { r: 0, g: 0, b: 0, a: 100 }, RgbaPixel { r: 0, g: 0, b: 0, a: 200 }];
+        crate::apply_mask_alpha(&mut pixels, &[150, 50], combine);
+        pixels.iter().map(|p| p.a).collect::<Vec<_>>()
+    };
+    assert_eq!(apply(AlphaCombine::Replace), [150, 50]);
+    assert_eq!(apply(AlphaCombine::Intersect), [100, 50]);
+    assert_eq!(apply(AlphaCombine::Union), [150, 200]);
+
+    // 保存時に union で合成済みのマスク（max(50, 200)）は、元のアルファのまま残る
+    let mut pixels = vec![RgbaPixel { r: 0, g: 0, b: 0, a: 200 }];
+    crate::apply_mask_alpha(&mut pixels, &[AlphaCombine::Union.combine(50, 200)], AlphaCombine::Union);
+    assert_eq!(pixels[0].a, 200);
+}
+
+#[test]
+fn applied_mask_is_noted_only_when_it_changes() {
+    let object_id = 60_251;
+    assert!(crate::state::note_applied_mask(object_id, Path::new("a.png")));
+    assert!(!crate::state::note_applied_mask(object_id, Path::new("a.png")));
+    assert!(crate::state::note_applied_mask(object_id, Path::new("b.png")));
+    assert_eq!(HistoryKind::MaskApplied.as_str(), "mask_applied");
+}
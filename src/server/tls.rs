@@ -0,0 +1,176 @@
+//! HTTPS（config.toml の https = true）。
+//!
+//! WebGPU やクリップボード API は secure context でないと使えないブラウザ設定があるので、
+//! 自己署名証明書を作って HTTP と同じルーティングを TLS 越しにも提供する。
+//! 証明書は初回に作って保存し、次回以降も同じものを使う（ブラウザの例外登録が消えないように）。
+
+use anyhow::{Context, Result as AnyResult};
+use crate::{
+    config::{plugin_data_dir, sandbox_dir},
+    frame::write_atomic,
+    state::lock_recover,
+};
+use rustls::{
+    ServerConfig, ServerConnection, StreamOwned,
+    crypto::ring::default_provider,
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+};
+use std::{
+    fs::{create_dir_all, read, read_to_string},
+    io::{self, Read, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use super::{HttpStream, WEBSOCKET_WRITE_TIMEOUT};
+
+/// /ws の見張りが 1 回の読み取りでロックを持ち続ける長さ（その間は通知の書き込みが待たされる）
+const WATCH_INTERVAL: Duration = Duration::from_millis(200);
+
+/// 証明書（DER）のファイル名
+const CERT_FILE_NAME: &str = "cert.cer";
+
+/// 秘密鍵（PKCS#8 DER）のファイル名
+const KEY_FILE_NAME: &str = "key.der";
+
+/// 証明書に入れたホスト名・アドレスの一覧（変わったら作り直す）
+const HOSTS_FILE_NAME: &str = "hosts.txt";
+
+/// 証明書を置くフォルダ（サンドボックスではその中）
+pub(super) fn certificate_dir() -> PathBuf {
+    match sandbox_dir() {
+        Some(sandbox) => sandbox.join("https"),
+        None => plugin_data_dir().join("https"),
+    }
+}
+
+/// dir の証明書と秘密鍵を読む。無いか、hosts が前回と違えば作り直して保存する。
+pub(super) fn load_or_create_certificate(
+    dir: &Path,
+    hosts: &[String],
+) -> AnyResult<(CertificateDer<'static>, PrivateKeyDer<'static>)> {
+    let cert_path = dir.join(CERT_FILE_NAME);
+    let key_path = dir.join(KEY_FILE_NAME);
+    let hosts_path = dir.join(HOSTS_FILE_NAME);
+    let hosts_text = hosts.join("\n");
+
+    let same_hosts = read_to_string(&hosts_path).is_ok_and(|saved| saved == hosts_text);
+    if same_hosts && let (Ok(cert), Ok(key)) = (read(&cert_path), read(&key_path)) {
+        return Ok((
+            CertificateDer::from(cert),
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key)),
+        ));
+    }
+
+    log::info!("Generating a self-signed certificate for {}", hosts.join(", "));
+    let certified = rcgen::generate_simple_self_signed(hosts.to_vec())
+        .context("failed to generate a self-signed certificate")?;
+    let cert = certified.cert.der().clone();
+    let key = certified.signing_key.serialize_der();
+
+    create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    write_atomic(&cert_path, &cert)?;
+    write_atomic(&key_path, &key)?;
+    write_atomic(&hosts_path, hosts_text.as_bytes())?;
+
+    Ok((cert, PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key))))
+}
+
+/// 自己署名証明書で TLS を受ける rustls の設定を作る。
+pub(super) fn server_config(dir: &Path, hosts: &[String]) -> AnyResult<Arc<ServerConfig>> {
+    let (cert, key) = load_or_create_certificate(dir, hosts)?;
+    let config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(vec![cert], key)
+        .context("failed to load the HTTPS certificate")?;
+    Ok(Arc::new(config))
+}
+
+/// TLS の接続 1 本分。
+///
+/// ボディの読み取りや /ws の書き込みで同じ接続を複数のハンドルから使うので、
+/// TcpStream::try_clone の代わりに Arc で共有する（ハンドシェイクは最初の読み書きで行う）。
+pub(super) struct TlsStream(Arc<Mutex<TlsConnection>>);
+
+impl TlsStream {
+    pub(super) fn new(config: Arc<ServerConfig>, stream: TcpStream) -> AnyResult<Self> {
+        let connection = ServerConnection::new(config)?;
+        Ok(Self(Arc::new(Mutex::new(TlsConnection(StreamOwned::new(
+            connection, stream,
+        ))))))
+    }
+}
+
+struct TlsConnection(StreamOwned<ServerConnection, TcpStream>);
+
+impl Drop for TlsConnection {
+    /// 最後のハンドルが無くなったら close_notify を送る（応答が途中で切られたと見なされないように）
+    fn drop(&mut self) {
+        let stream = &mut self.0;
+        stream.conn.send_close_notify();
+        while stream.conn.wants_write() {
+            if stream.conn.write_tls(&mut stream.sock).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        lock_recover(&self.0).0.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        lock_recover(&self.0).0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        lock_recover(&self.0).0.flush()
+    }
+}
+
+impl HttpStream for TlsStream {
+    fn try_clone_reader(&self) -> io::Result<Box<dyn Read>> {
+        Ok(Box::new(Self(Arc::clone(&self.0))))
+    }
+
+    fn try_clone_writer(&self) -> io::Result<Box<dyn Write + Send>> {
+        lock_recover(&self.0)
+            .0
+            .sock
+            .set_write_timeout(Some(WEBSOCKET_WRITE_TIMEOUT))?;
+        Ok(Box::new(Self(Arc::clone(&self.0))))
+    }
+
+    fn try_clone_watcher(&self) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(TlsWatcher(Arc::clone(&self.0))))
+    }
+}
+
+/// /ws の接続が閉じられたのを見張るハンドル。
+///
+/// 読み取りで待つ間ずっとロックを持つと通知の書き込みが止まるので、WATCH_INTERVAL ごとにタイムアウトさせて
+/// ロックを放す（タイムアウトは呼び出し側で読み直す）。
+struct TlsWatcher(Arc<Mutex<TlsConnection>>);
+
+impl Read for TlsWatcher {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = {
+            let mut connection = lock_recover(&self.0);
+            connection.0.sock.set_read_timeout(Some(WATCH_INTERVAL))?;
+            connection.0.read(buf)
+        };
+        if result.as_ref().is_err_and(|e| matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock)) {
+            // すぐ取り直すと書き込み側がロックを取れないことがあるので少し譲る
+            thread::sleep(Duration::from_millis(10));
+        }
+        result
+    }
+}
@@ -0,0 +1,635 @@
+//! プラグイン本体と HTTP サーバーで共有するグローバル状態。
+//!
+//! どれも `OnceLock<Mutex<..>>` を返すアクセサ関数で、proc_video（AviUtl2 側のスレッド）と
+//! HTTP サーバーのスレッドの両方から触る。ロックは短く持ち、`lock_recover` で取ること。
+
+use crate::config::{ENV_EXPORT_ROOT, aviutl2_dir, env_path, sandbox_dir};
+use chrono::{DateTime, Local};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    path::{Path, PathBuf},
+    sync::{
+        Mutex, MutexGuard, OnceLock, PoisonError,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+/// Mutex をロックする。別のスレッドがロック中に panic していても中身をそのまま使う
+/// （HTTP の 1 リクエストの panic で以後のリクエストがすべて失敗しないように）。
+pub(crate) fn lock_recover<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// 現在の保存ルートディレクトリ
+/// 既定値: サンドボックスの export フォルダ、環境変数 SAM_EXPORT_ROOT、AviUtl2 標準の Export フォルダの順
+/// ユーザーが #[file] で何かファイルを選んだら、その親ディレクトリに更新
+pub(crate) fn export_root_dir() -> &'static Mutex<PathBuf> {
+    static EXPORT_ROOT_DIR: OnceLock<Mutex<PathBuf>> = OnceLock::new();
+    EXPORT_ROOT_DIR.get_or_init(|| {
+        let root = match sandbox_dir() {
+            Some(sandbox) => sandbox.join("export"),
+            None => env_path(ENV_EXPORT_ROOT).unwrap_or_else(|| aviutl2_dir().join("Export")),
+        };
+        Mutex::new(root)
+    })
+}
+
+/// フレームやマスクの保存先ルートを変更する（サンドボックスでは無視する）。
+pub fn set_export_root(dir: PathBuf) {
+    if sandbox_dir().is_some() {
+        log::debug!("Sandbox mode: ignoring export root {}", dir.display());
+        return;
+    }
+    let mut root = lock_recover(export_root_dir());
+    *root = dir;
+    log::info!("Export root changed to {}", root.display());
+}
+
+/// マスク受信時（HTTP サーバースレッド側）に使う設定。
+///
+/// proc_video のたびに FilterConfig から更新する。
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MaskSettings {
+    pub(crate) supersample: u32,
+    /// 輪郭線画像を書き出すときの (太さ, RGB)
+    pub(crate) outline: Option<(f32, [u8; 3])>,
+    /// 影画像を書き出すときの設定
+    pub(crate) shadow: Option<ShadowSettings>,
+    /// 受け取ったマスクの境界をぼかすガウスぼかしの σ（px、0 で無効）
+    pub(crate) feather: f32,
+    /// 受け取ったマスクを膨張（正）・収縮（負）させる量（px）
+    pub(crate) grow: i32,
+    /// 保存したマスクを画像オブジェクトとして読み込む .exo も書き出す
+    pub(crate) export_exo: bool,
+    /// 受け取ったマスクのアルファを反転して背景を残す
+    pub(crate) invert: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ShadowSettings {
+    /// ガウスぼかしの σ（px）
+    pub(crate) blur: f32,
+    /// シルエットをずらす量（px）
+    pub(crate) offset: (i32, i32),
+    /// 0.0〜1.0
+    pub(crate) opacity: f32,
+}
+
+impl Default for MaskSettings {
+    fn default() -> Self {
+        Self {
+            supersample: 2,
+            outline: None,
+            shadow: None,
+            feather: 0.0,
+            grow: 0,
+            export_exo: false,
+            invert: false,
+        }
+    }
+}
+
+pub(crate) fn mask_settings() -> &'static Mutex<MaskSettings> {
+    static SETTINGS: OnceLock<Mutex<MaskSettings>> = OnceLock::new();
+    SETTINGS.get_or_init(|| Mutex::new(MaskSettings::default()))
+}
+
+/// ffmpeg の実行ファイル（既定では PATH から探す）
+pub(crate) fn ffmpeg_path() -> &'static Mutex<PathBuf> {
+    static FFMPEG_PATH: OnceLock<Mutex<PathBuf>> = OnceLock::new();
+    FFMPEG_PATH.get_or_init(|| Mutex::new(PathBuf::from("ffmpeg")))
+}
+
+/// フレームを書き出したときにブラウザを自動で開くか（false なら URL をログに出すだけ）
+pub(crate) fn auto_open_browser() -> &'static Mutex<bool> {
+    static AUTO_OPEN: OnceLock<Mutex<bool>> = OnceLock::new();
+    AUTO_OPEN.get_or_init(|| Mutex::new(true))
+}
+
+/// シーンの解像度とフレームレート（.exo の書き出しに使う）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SceneFormat {
+    pub(crate) width: i32,
+    pub(crate) height: i32,
+    pub(crate) rate: i32,
+    pub(crate) scale: i32,
+}
+
+pub(crate) fn last_scene_format() -> &'static Mutex<Option<SceneFormat>> {
+    static FORMAT: OnceLock<Mutex<Option<SceneFormat>>> = OnceLock::new();
+    FORMAT.get_or_init(|| Mutex::new(None))
+}
+
+/// 書き出したフレームのオブジェクト情報（マスクのマニフェストに記録する）
+///
+/// フィルタの API から取れるのはオブジェクト画像のサイズと時間位置だけで、
+/// 標準描画の座標・拡大率・回転は取れない。マスクはオブジェクト画像と同じサイズで保存されるので、
+/// 同じオブジェクトのフィルタとして適用すれば位置は一致する。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct ObjectFrameInfo {
+    /// オブジェクト内のフレーム番号
+    pub(crate) frame: i32,
+    pub(crate) frame_total: i32,
+    /// オブジェクト内の時間（秒）
+    pub(crate) time: f64,
+    pub(crate) time_total: f64,
+    /// オブジェクト画像のサイズ（px）
+    pub(crate) width: i32,
+    pub(crate) height: i32,
+    pub(crate) scene: SceneFormat,
+}
+
+impl ObjectFrameInfo {
+    pub(crate) fn to_json(self) -> serde_json::Value {
+        serde_json::json!({
+            "frame": self.frame,
+            "frame_total": self.frame_total,
+            "time": self.time,
+            "time_total": self.time_total,
+            "width": self.width,
+            "height": self.height,
+            "scene": {
+                "width": self.scene.width,
+                "height": self.scene.height,
+                "rate": self.scene.rate,
+                "scale": self.scene.scale,
+            },
+        })
+    }
+}
+
+/// 最後に書き出したフレームのサイズ（受信したマスクをこのサイズに合わせる）
+pub(crate) fn last_frame_size() -> &'static Mutex<Option<(u32, u32)>> {
+    static SIZE: OnceLock<Mutex<Option<(u32, u32)>>> = OnceLock::new();
+    SIZE.get_or_init(|| Mutex::new(None))
+}
+
+/// SAMの起動を確かめるグローバルなオブジェクト状態テーブル
+pub(crate) fn object_states() -> &'static Mutex<HashMap<i64, ObjectState>> {
+    static STATES: OnceLock<Mutex<HashMap<i64, ObjectState>>> = OnceLock::new();
+    STATES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct ObjectState {
+    pub(crate) last_run_sam: bool,
+    /// 前回の「Web UI を開く」チェック（立ち上がりでブラウザを開く）
+    pub(crate) last_open_web_ui: bool,
+    /// 設定で選ばれたマスクのラベル（空なら最新のマスク）
+    pub(crate) apply_label: String,
+    /// 設定のセッションタグ（空なら付けない）
+    pub(crate) session_tag: String,
+    /// マスクを通し番号のファイル名で保存する
+    pub(crate) sequential_names: bool,
+    /// マスクのファイル名テンプレート（空なら既定の名前）
+    pub(crate) name_template: String,
+    /// フレームとマスクを `object_{id}/` に分けて保存する
+    pub(crate) object_folders: bool,
+    /// 受け取ったマスクとオブジェクト元のアルファの合成方法
+    pub(crate) alpha_combine: AlphaCombine,
+    pub(crate) last_export_sequence: bool,
+    /// 連番書き出しで送り済みのフレーム番号
+    pub(crate) sequence_written: HashSet<i32>,
+    /// run_sam で current_frame.png を書き出したときのオブジェクト情報
+    pub(crate) exported_frame: Option<ObjectFrameInfo>,
+    /// set_current_frame でこのオブジェクトのために書き出したフレームの大きさ（px）
+    pub(crate) frame_size: Option<(u32, u32)>,
+    /// そのフレームのファイル（ほかのオブジェクトが同じファイルに書き出したら None）
+    pub(crate) frame_file: Option<PathBuf>,
+    /// シーン全体を書き出しているとき、マスクから切り出す範囲
+    pub(crate) scene_crop: Option<SceneCrop>,
+    /// proc_video で最後に反映したマスク（連番マスクならそのフォルダ）
+    pub(crate) applied_mask: Option<PathBuf>,
+}
+
+/// SAM のマスクとオブジェクトが元から持つアルファの合成方法
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum AlphaCombine {
+    /// SAM のマスクだけを使う（元のアルファは無視）
+    #[default]
+    Replace,
+    /// 両方で不透明な部分だけ残す
+    Intersect,
+    /// どちらかで不透明な部分を残す
+    Union,
+}
+
+impl AlphaCombine {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::Replace => "replace",
+            Self::Intersect => "intersect",
+            Self::Union => "union",
+        }
+    }
+
+    /// マスクのアルファと元のアルファを合成する。
+    pub(crate) fn combine(self, mask: u8, original: u8) -> u8 {
+        match self {
+            Self::Replace => mask,
+            Self::Intersect => mask.min(original),
+            Self::Union => mask.max(original),
+        }
+    }
+}
+
+/// current_frame を書き出す画像形式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum FrameFormat {
+    #[default]
+    Png,
+    /// ロスレス WebP
+    WebpLossless,
+    /// 品質（1〜100）を指定した WebP
+    Webp(u8),
+    /// 品質（1〜100）を指定した JPEG（アルファは捨てる）
+    Jpeg(u8),
+}
+
+impl FrameFormat {
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::WebpLossless | Self::Webp(_) => "webp",
+            Self::Jpeg(_) => "jpg",
+        }
+    }
+
+    pub(crate) fn content_type(self) -> &'static str {
+        match self {
+            Self::Png => "image/png",
+            Self::WebpLossless | Self::Webp(_) => "image/webp",
+            Self::Jpeg(_) => "image/jpeg",
+        }
+    }
+}
+
+/// 書き出す PNG の圧縮レベル（速さとファイルサイズのどちらを取るか）
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum PngCompression {
+    #[default]
+    Fast,
+    Default,
+    Best,
+}
+
+/// 設定で選ばれた PNG の圧縮レベル
+pub(crate) fn png_compression() -> &'static Mutex<PngCompression> {
+    static COMPRESSION: OnceLock<Mutex<PngCompression>> = OnceLock::new();
+    COMPRESSION.get_or_init(|| Mutex::new(PngCompression::default()))
+}
+
+/// 設定で選ばれた current_frame の形式
+pub(crate) fn frame_format() -> &'static Mutex<FrameFormat> {
+    static FORMAT: OnceLock<Mutex<FrameFormat>> = OnceLock::new();
+    FORMAT.get_or_init(|| Mutex::new(FrameFormat::default()))
+}
+
+/// シーン全体のマスクから対象オブジェクトを切り出す範囲（シーンの画素座標、左上基準）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SceneCrop {
+    pub(crate) x: i32,
+    pub(crate) y: i32,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+}
+
+/// オブジェクトに紐づいたマスク 1 枚
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct LabeledMask {
+    /// Web UI が付けたラベル（"person" など）。ラベル無しのアップロードは None
+    pub(crate) label: Option<String>,
+    pub(crate) path: PathBuf,
+}
+
+/// オブジェクトごとに覚えておくマスク履歴の上限（古いものから忘れる。ファイルは消さない）
+const MASK_HISTORY_LIMIT: usize = 32;
+
+/// object_id → 受け取った順のマスク PNG の履歴（ラベルごとに最後の 1 枚が今のマスク）
+pub(crate) fn mask_paths() -> &'static Mutex<HashMap<i64, Vec<LabeledMask>>> {
+    static MASK_PATHS: OnceLock<Mutex<HashMap<i64, Vec<LabeledMask>>>> = OnceLock::new();
+    MASK_PATHS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// proc_video で反映したマスクを覚え、前回と違えば true を返す。
+///
+/// 操作履歴を毎フレーム積まないよう、変わったときだけ記録するために使う。
+pub(crate) fn note_applied_mask(object_id: i64, source: &Path) -> bool {
+    let mut states = lock_recover(object_states());
+    let state = states.entry(object_id).or_default();
+    if state.applied_mask.as_deref() == Some(source) {
+        return false;
+    }
+    state.applied_mask = Some(source.to_path_buf());
+    true
+}
+
+/// 設定（apply_label）に従って、オブジェクトに使うマスクを選ぶ。
+pub(crate) fn selected_mask_for_object(object_id: i64) -> Option<LabeledMask> {
+    let apply_label = lock_recover(object_states())
+        .get(&object_id)
+        .map(|state| state.apply_label.clone())
+        .unwrap_or_default();
+
+    let map = lock_recover(mask_paths());
+    let masks = map.get(&object_id)?;
+    if apply_label.is_empty() {
+        masks.last().cloned()
+    } else {
+        latest_mask_with_label(masks, Some(&apply_label)).cloned()
+    }
+}
+
+/// 履歴のうち label の付いた最新のマスク
+pub(crate) fn latest_mask_with_label<'a>(
+    masks: &'a [LabeledMask],
+    label: Option<&str>,
+) -> Option<&'a LabeledMask> {
+    masks.iter().rev().find(|m| m.label.as_deref() == label)
+}
+
+/// オブジェクトの今のマスク（ラベルごとに最新の 1 枚、受け取った順）
+pub(crate) fn current_masks_for_object(object_id: i64) -> Vec<LabeledMask> {
+    let map = lock_recover(mask_paths());
+    let Some(masks) = map.get(&object_id) else {
+        return Vec::new();
+    };
+    masks
+        .iter()
+        .enumerate()
+        .filter(|(i, m)| !masks[i + 1..].iter().any(|later| later.label == m.label))
+        .map(|(_, m)| m.clone())
+        .collect()
+}
+
+/// run_sam で最後に書き出したフレームのオブジェクト情報
+pub(crate) fn exported_frame_for_object(object_id: i64) -> Option<ObjectFrameInfo> {
+    lock_recover(object_states())
+        .get(&object_id)
+        .and_then(|state| state.exported_frame)
+}
+
+/// set_current_frame で object_id のフレームを書き出したことを覚えておく。
+///
+/// オブジェクトごとのフォルダを使わないと全オブジェクトが同じファイルに書き出すので、
+/// 同じファイルを指していたほかのオブジェクトからはファイルを外す（大きさは残す）。
+pub(crate) fn set_frame_file_for_object(object_id: i64, path: &Path, size: (u32, u32)) {
+    let mut states = lock_recover(object_states());
+    for state in states.values_mut() {
+        if state.frame_file.as_deref() == Some(path) {
+            state.frame_file = None;
+        }
+    }
+    let state = states.entry(object_id).or_default();
+    state.frame_size = Some(size);
+    state.frame_file = Some(path.to_path_buf());
+}
+
+/// object_id のために書き出したフレームの大きさとファイル（ファイルは上書きされていれば None）
+pub(crate) fn frame_file_for_object(object_id: i64) -> Option<((u32, u32), Option<PathBuf>)> {
+    lock_recover(object_states())
+        .get(&object_id)
+        .and_then(|state| Some((state.frame_size?, state.frame_file.clone())))
+}
+
+/// シーン全体を書き出しているオブジェクトの切り出し範囲
+pub(crate) fn scene_crop_for_object(object_id: i64) -> Option<SceneCrop> {
+    lock_recover(object_states())
+        .get(&object_id)
+        .and_then(|state| state.scene_crop)
+}
+
+/// オブジェクトに設定されたアルファの合成方法
+pub(crate) fn alpha_combine_for_object(object_id: i64) -> AlphaCombine {
+    lock_recover(object_states())
+        .get(&object_id)
+        .map(|state| state.alpha_combine)
+        .unwrap_or_default()
+}
+
+/// オブジェクトのマスクを通し番号のファイル名で保存するか
+pub(crate) fn sequential_names_for_object(object_id: i64) -> bool {
+    lock_recover(object_states())
+        .get(&object_id)
+        .is_some_and(|state| state.sequential_names)
+}
+
+/// オブジェクトのフレームとマスクの保存先（オブジェクトごとのフォルダにするなら `object_{id}/`）
+pub(crate) fn export_dir_for_object(object_id: i64) -> PathBuf {
+    let root = lock_recover(export_root_dir()).clone();
+    let per_object = lock_recover(object_states())
+        .get(&object_id)
+        .is_some_and(|state| state.object_folders);
+    if per_object {
+        root.join(object_dir_name(object_id))
+    } else {
+        root
+    }
+}
+
+pub(crate) fn object_dir_name(object_id: i64) -> String {
+    format!("object_{object_id}")
+}
+
+/// オブジェクトに設定されたマスクのファイル名テンプレート（未設定なら None）
+pub(crate) fn name_template_for_object(object_id: i64) -> Option<String> {
+    lock_recover(object_states())
+        .get(&object_id)
+        .map(|state| state.name_template.clone())
+        .filter(|template| !template.is_empty())
+}
+
+/// オブジェクトに設定されたセッションタグ（未設定なら None）
+pub(crate) fn session_tag_for_object(object_id: i64) -> Option<String> {
+    lock_recover(object_states())
+        .get(&object_id)
+        .map(|state| state.session_tag.clone())
+        .filter(|tag| !tag.is_empty())
+}
+
+pub(crate) fn set_mask_path_for_object(object_id: i64, label: Option<&str>, path: PathBuf) {
+    let mut map = lock_recover(mask_paths());
+    let masks = map.entry(object_id).or_default();
+    masks.retain(|m| m.path != path);
+    masks.push(LabeledMask {
+        label: label.map(str::to_string),
+        path,
+    });
+    if masks.len() > MASK_HISTORY_LIMIT {
+        masks.drain(..masks.len() - MASK_HISTORY_LIMIT);
+    }
+}
+
+/// オブジェクトの最新のマスクを履歴から外し、一つ前のマスクに戻す。
+///
+/// label が Some ならそのラベルの中で戻す（None なら最後に受け取ったマスク）。
+/// 外したマスクと、戻った後のそのラベルのマスクを返す。ファイルは消さない。
+pub(crate) fn undo_mask_for_object(
+    object_id: i64,
+    label: Option<&str>,
+) -> Option<(LabeledMask, Option<LabeledMask>)> {
+    let mut map = lock_recover(mask_paths());
+    let masks = map.get_mut(&object_id)?;
+    let index = match label {
+        Some(label) => masks.iter().rposition(|m| m.label.as_deref() == Some(label))?,
+        None => masks.len().checked_sub(1)?,
+    };
+    let undone = masks.remove(index);
+    let current = latest_mask_with_label(masks, undone.label.as_deref()).cloned();
+    if masks.is_empty() {
+        map.remove(&object_id);
+    }
+    Some((undone, current))
+}
+
+/// 削除したマスクをオブジェクトの紐づけから外す。紐づいていたオブジェクトを返す。
+pub(crate) fn remove_mask_path(path: &Path) -> Option<i64> {
+    let mut map = lock_recover(mask_paths());
+    let object_id = map
+        .iter()
+        .find(|(_, masks)| masks.iter().any(|m| m.path == path))
+        .map(|(&id, _)| id)?;
+    let masks = map.get_mut(&object_id)?;
+    masks.retain(|m| m.path != path);
+    if masks.is_empty() {
+        map.remove(&object_id);
+    }
+    Some(object_id)
+}
+
+pub(crate) fn current_edit_object_id() -> &'static Mutex<Option<i64>> {
+    static EDIT_ID: OnceLock<Mutex<Option<i64>>> = OnceLock::new();
+    EDIT_ID.get_or_init(|| Mutex::new(None))
+}
+
+/// 受信したマスクを紐づける編集中のオブジェクトを設定する。
+pub fn set_edit_object(object_id: i64) {
+    *lock_recover(current_edit_object_id()) = Some(object_id);
+}
+
+/// 覚えておくセッションの数（古いものから捨てる）
+const EXPORT_SESSION_CAPACITY: usize = 256;
+
+/// フレームを書き出すたびに発行したセッション ID と、そのとき書き出したオブジェクト（古い順）。
+///
+/// 複数のオブジェクトにフィルタを付けていても、Web UI がマスクと一緒に送り返した ID で
+/// 書き出し元のオブジェクトに紐づけられるようにする。
+fn export_sessions() -> &'static Mutex<VecDeque<(String, i64)>> {
+    static SESSIONS: OnceLock<Mutex<VecDeque<(String, i64)>>> = OnceLock::new();
+    SESSIONS.get_or_init(|| Mutex::new(VecDeque::with_capacity(EXPORT_SESSION_CAPACITY)))
+}
+
+/// object_id のフレームを書き出したセッションを発行し、その ID を返す。
+pub(crate) fn start_export_session(object_id: i64) -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    let id = format!(
+        "{:x}-{}",
+        Local::now().timestamp_millis(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    );
+    let mut sessions = lock_recover(export_sessions());
+    if sessions.len() == EXPORT_SESSION_CAPACITY {
+        sessions.pop_front();
+    }
+    sessions.push_back((id.clone(), object_id));
+    id
+}
+
+/// セッション ID を発行したときのオブジェクト（知らない・古すぎる ID なら None）
+pub(crate) fn export_session_object(session: &str) -> Option<i64> {
+    lock_recover(export_sessions())
+        .iter()
+        .find(|(id, _)| id == session)
+        .map(|&(_, object_id)| object_id)
+}
+
+/// オブジェクトごとの連番マスク（フレーム番号 → パス）
+pub(crate) fn mask_sequences() -> &'static Mutex<HashMap<i64, BTreeMap<i32, PathBuf>>> {
+    static SEQUENCES: OnceLock<Mutex<HashMap<i64, BTreeMap<i32, PathBuf>>>> = OnceLock::new();
+    SEQUENCES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// 操作履歴に残す出来事の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum HistoryKind {
+    /// current_frame.png を書き出した
+    FrameExported,
+    /// 連番フレームの書き出しが範囲の最後まで終わった
+    SequenceExported,
+    /// Web UI からマスクを受け取った
+    MaskReceived,
+    /// Web UI からマスクを削除した
+    MaskDeleted,
+    /// `POST /mask/undo` で一つ前のマスクに戻した
+    MaskUndone,
+    /// proc_video でオブジェクトに反映するマスクが変わった
+    MaskApplied,
+    /// 連番マスクを動画にした
+    #[cfg(feature = "ffmpeg")]
+    VideoEncoded,
+    Error,
+}
+
+impl HistoryKind {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::FrameExported => "frame_exported",
+            Self::SequenceExported => "sequence_exported",
+            Self::MaskReceived => "mask_received",
+            Self::MaskDeleted => "mask_deleted",
+            Self::MaskUndone => "mask_undone",
+            Self::MaskApplied => "mask_applied",
+            #[cfg(feature = "ffmpeg")]
+            Self::VideoEncoded => "video_encoded",
+            Self::Error => "error",
+        }
+    }
+}
+
+/// 操作履歴の 1 件
+#[derive(Debug, Clone)]
+pub(crate) struct HistoryEntry {
+    /// 通し番号（`?since=` で続きだけ取るのに使う）
+    pub(crate) id: u64,
+    pub(crate) time: DateTime<Local>,
+    pub(crate) kind: HistoryKind,
+    pub(crate) object_id: Option<i64>,
+    pub(crate) message: String,
+}
+
+impl HistoryEntry {
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "id": self.id,
+            "time": self.time.to_rfc3339(),
+            "kind": self.kind.as_str(),
+            "object_id": self.object_id,
+            "message": self.message,
+        })
+    }
+}
+
+/// 保持する履歴の件数（古いものから捨てる）
+const HISTORY_CAPACITY: usize = 500;
+
+/// プラグインの操作履歴（古い順）
+pub(crate) fn history() -> &'static Mutex<VecDeque<HistoryEntry>> {
+    static HISTORY: OnceLock<Mutex<VecDeque<HistoryEntry>>> = OnceLock::new();
+    HISTORY.get_or_init(|| Mutex::new(VecDeque::with_capacity(HISTORY_CAPACITY)))
+}
+
+/// 操作履歴に 1 件追加する。ログへの出力は呼び出し側で行う。
+pub(crate) fn record_history(kind: HistoryKind, object_id: Option<i64>, message: impl Into<String>) {
+    let mut history = lock_recover(history());
+    let id = history.back().map_or(1, |last| last.id + 1);
+    if history.len() == HISTORY_CAPACITY {
+        history.pop_front();
+    }
+    history.push_back(HistoryEntry {
+        id,
+        time: Local::now(),
+        kind,
+        object_id,
+        message: message.into(),
+    });
+}
@@ -20,8 +20,25 @@ const maskCanvas = document.getElementById("mask-output");
 const maskContext = maskCanvas.getContext("2d");
 const loadFromAviUtl2Button = document.getElementById("load-from-aviutl2");
 const modelSelect = document.getElementById("model-select"); 
-const AVIUTL2_FRAME_URL = "http://127.0.0.1:17860/frame/current.png";
-const AVIUTL2_MASK_URL = "http://127.0.0.1:17860/mask"; 
+const updateBanner = document.getElementById("update-banner");
+const diagnosticsButton = document.getElementById("diagnostics");
+const diagnosticsResult = document.getElementById("diagnostics-result");
+// タッチ向け UI (index.mobile.html) にだけ存在する、追加/除外の切り替えボタン
+const pointModeButton = document.getElementById("point-mode");
+// プラグインはインスタンスごとに別ポートで待ち受けるので、配信元と同じオリジンを使う
+const AVIUTL2_FRAME_URL = "/frame/current.png";
+const AVIUTL2_MASK_URL = "/mask";
+const AVIUTL2_VERSION_URL = "/api/version?check=1";
+const AVIUTL2_FRAME_VERSION_URL = "/api/frame";
+const AVIUTL2_DIAGNOSTICS_URL = "/diagnostics";
+// プラグインの ONNX Runtime で推論する（native-sam 付きでビルドしたときだけ使える）
+const NATIVE_SAM_STATUS_URL = "/api/sam/status";
+const NATIVE_SAM_ENCODE_URL = "/api/sam/encode";
+const NATIVE_SAM_PREDICT_URL = "/api/sam/predict";
+const NATIVE_MODEL_KEY = "native";
+const AVIUTL2_FRAME_SOCKET_URL = `${location.protocol === "https:" ? "wss" : "ws"}://${location.host}/ws`;
+// AviUtl2 から新しいフレームが書き出されたかを確認する間隔（ms、/ws がつながっている間は確認しない）
+const FRAME_POLL_INTERVAL = 2000;
 const EXAMPLE_URL =
   "https://huggingface.co/datasets/Xenova/transformers.js-docs/resolve/main/corgi.jpg";
 const MODEL_IDS = {
@@ -39,9 +56,16 @@ let isDecoding = false;
 let decodePending = false;
 let lastPoints = null;
 let isMultiMaskMode = false;
+let isNegativeMode = false;
 let imageInput = null;
 let imageProcessed = null;
 let imageEmbeddings = null;
+// 最後に確認した current_frame.png の ETag（変わったら読み込み直す）
+let knownFrameEtag = null;
+// 読み込んだフレームのセッション ID（マスクを送るときに付け、書き出し元のオブジェクトに紐づける）
+let frameSession = null;
+// /ws でフレームの更新通知を受け取れているか
+let frameSocketOpen = false;
 // 現在選択中のキー（セレクトボックスと同期）
 let currentModelKey = "slimsam";
 // 既存の model_id という変数名を維持
@@ -55,6 +79,13 @@ async function loadCurrentModelIfNeeded() {
   const key = modelSelect ? modelSelect.value : currentModelKey;
   currentModelKey = key;
 
+  // ネイティブ推論ではブラウザにモデルを読み込まない
+  if (key === NATIVE_MODEL_KEY) {
+    model = null;
+    processor = null;
+    return;
+  }
+
   // "slimsam" → "Xenova/slimsam-77-uniform" の変換
   model_id = MODEL_IDS[key];
 
@@ -83,6 +114,11 @@ async function decode() {
   }
   isDecoding = true;
 
+  if (currentModelKey === NATIVE_MODEL_KEY) {
+    await decodeNative();
+    return;
+  }
+
   // Prepare inputs for decoding
   const reshaped = imageProcessed.reshaped_input_sizes[0];
   const points = lastPoints
@@ -119,6 +155,53 @@ async function decode() {
   }
 }
 
+// ネイティブ推論: クリックした点をプラグインに送り、返ってきたマスク PNG を重ねる
+async function decodeNative() {
+  try {
+    const body = {
+      points: lastPoints.map((x) => [
+        x.position[0] * imageInput.width,
+        x.position[1] * imageInput.height,
+      ]),
+      labels: lastPoints.map((x) => x.label),
+    };
+    const res = await fetch(NATIVE_SAM_PREDICT_URL, {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify(body),
+    });
+    if (!res.ok) {
+      throw new Error(`HTTP ${res.status} ${res.statusText}`);
+    }
+    const score = Number(res.headers.get("X-Sam-Score") ?? 0);
+    const mask = await createImageBitmap(await res.blob());
+
+    if (maskCanvas.width !== mask.width || maskCanvas.height !== mask.height) {
+      maskCanvas.width = mask.width;
+      maskCanvas.height = mask.height;
+    }
+    // 白いマスクを WebGPU 版と同じ色に塗る
+    maskContext.clearRect(0, 0, maskCanvas.width, maskCanvas.height);
+    maskContext.globalCompositeOperation = "source-over";
+    maskContext.drawImage(mask, 0, 0);
+    maskContext.globalCompositeOperation = "source-in";
+    maskContext.fillStyle = "rgb(0, 114, 189)";
+    maskContext.fillRect(0, 0, maskCanvas.width, maskCanvas.height);
+    maskContext.globalCompositeOperation = "source-over";
+    statusLabel.textContent = `Segment score: ${score.toFixed(2)}`;
+  } catch (err) {
+    console.error("Native SAM prediction failed:", err);
+    statusLabel.textContent = "Native SAM prediction failed";
+  } finally {
+    isDecoding = false;
+  }
+
+  if (decodePending) {
+    decodePending = false;
+    decode();
+  }
+}
+
 function updateMaskOverlay(mask, scores) {
   // Update canvas dimensions (if different)
   if (maskCanvas.width !== mask.width || maskCanvas.height !== mask.height) {
@@ -178,6 +261,7 @@ function resetImageState() {
   imageInput = null;
   imageProcessed = null;
   imageEmbeddings = null;
+  frameSession = null;
   isEncoding = false;
   isDecoding = false;
   decodePending = false;
@@ -199,13 +283,36 @@ resetButton.addEventListener("click", () => {
 });
 
 
-async function encode(url) {
+async function encode(url, fromAviUtl2 = false) {
   if (isEncoding) return;
+  // ネイティブ推論はプラグインが持っている current_frame.png に対して行う
+  if (currentModelKey === NATIVE_MODEL_KEY && !fromAviUtl2) {
+    statusLabel.textContent = "Native SAM works only with frames loaded from AviUtl2";
+    return;
+  }
   isEncoding = true;
   statusLabel.textContent = "Extracting image embedding...";
 
   imageInput = await RawImage.fromURL(url);
 
+  if (currentModelKey === NATIVE_MODEL_KEY) {
+    imageContainer.style.backgroundImage = `url(${url})`;
+    uploadButton.style.display = "none";
+    cutButton.disabled = true;
+    try {
+      const res = await fetch(NATIVE_SAM_ENCODE_URL, { method: "POST" });
+      if (!res.ok) {
+        throw new Error(`HTTP ${res.status} ${res.statusText}`);
+      }
+      // decode() 側は中身を使わないので、エンコード済みの目印だけ置く
+      imageEmbeddings = { native: true };
+      statusLabel.textContent = "Embedding extracted!";
+    } finally {
+      isEncoding = false;
+    }
+    return;
+  }
+
   // Update UI
   imageContainer.style.backgroundImage = `url(${url})`;
   uploadButton.style.display = "none";
@@ -238,11 +345,13 @@ async function loadFromAviUtl2() {
       throw new Error(`HTTP ${response.status} ${response.statusText}`);
     }
 
+    knownFrameEtag = response.headers.get("ETag") ?? knownFrameEtag;
+    frameSession = response.headers.get("X-Sam-Session");
     const blob = await response.blob();
     const objectUrl = URL.createObjectURL(blob);
 
     // 既存の encode() をそのまま利用
-    await encode(objectUrl);
+    await encode(objectUrl, true);
 
     statusLabel.textContent = "Ready";
   } catch (err) {
@@ -330,12 +439,20 @@ function getPoint(e) {
   return {
     position: [mouseX, mouseY],
     label:
-      e.button === 2 // right click
+      e.button === 2 || isNegativeMode // right click (or negative mode on touch UI)
         ? 0 // negative prompt
         : 1, // positive prompt
   };
 }
 
+if (pointModeButton) {
+  pointModeButton.addEventListener("click", () => {
+    isNegativeMode = !isNegativeMode;
+    pointModeButton.textContent = isNegativeMode ? "除外する点" : "追加する点";
+    pointModeButton.classList.toggle("negative", isNegativeMode);
+  });
+}
+
 // Do not show context menu on right click
 imageContainer.addEventListener("contextmenu", (e) => e.preventDefault());
 
@@ -391,29 +508,167 @@ cutButton.addEventListener("click", async () => {
   // (B) AviUtl2 プラグインへ送信
   try {
     statusLabel.textContent = "Sending mask to AviUtl2...";
+    const headers = {
+      "Content-Type": "image/png",
+      Accept: "application/json",
+    };
+    if (frameSession) {
+      headers["X-Sam-Session"] = frameSession;
+    }
     const res = await fetch(AVIUTL2_MASK_URL, {
       method: "POST",
-      headers: {
-        "Content-Type": "image/png",
-      },
+      headers,
       body: blob,
     });
     if (!res.ok) {
-      throw new Error(`HTTP ${res.status} ${res.statusText}`);
+      // 400 のときはプラグインが理由（PNG として読めない、大きさが違うなど）を返す
+      throw new Error(`HTTP ${res.status} ${(await res.text()) || res.statusText}`);
+    }
+    // 保存先を表示してから、プラグインの完了ページ（保存先と次の操作）へ移る
+    const info = await res.json();
+    statusLabel.textContent = `Mask saved: ${info.masks[0]?.path ?? ""}`;
+    if (info.done_url) {
+      location.href = info.done_url;
     }
-    statusLabel.textContent = "Mask sent to AviUtl2";
   } catch (err) {
     console.error("Failed to send mask to AviUtl2:", err);
     statusLabel.textContent = "Failed to send mask to AviUtl2";
   }
 });
 
+// 保存先や Web UI の配置をプラグインに確かめてもらい、結果を一覧で表示する
+diagnosticsButton.addEventListener("click", async () => {
+  diagnosticsResult.hidden = false;
+  diagnosticsResult.classList.remove("failed");
+  diagnosticsResult.textContent = "診断しています...";
+  try {
+    const res = await fetch(AVIUTL2_DIAGNOSTICS_URL, { cache: "no-store" });
+    if (!res.ok) throw new Error(`HTTP ${res.status}`);
+    const info = await res.json();
+    const lines = info.checks.map(
+      (check) => `${check.ok ? "✔" : "✖"} ${check.label}: ${check.detail}`,
+    );
+    diagnosticsResult.textContent = [
+      `プラグイン v${info.version}`,
+      ...lines,
+      info.ok ? "問題は見つかりませんでした。" : "✖ の項目を確認してください。",
+    ].join("\n");
+    diagnosticsResult.classList.toggle("failed", !info.ok);
+  } catch (err) {
+    console.error("Failed to run diagnostics:", err);
+    diagnosticsResult.textContent = "診断できませんでした（AviUtl2 とプラグインが起動しているか確認してください）";
+    diagnosticsResult.classList.add("failed");
+  }
+});
+
+// 新しいリリースがあればバナーで知らせる（失敗しても UI には影響させない）
+async function checkForUpdate() {
+  try {
+    const res = await fetch(AVIUTL2_VERSION_URL, { cache: "no-store" });
+    if (!res.ok) return;
+    const info = await res.json();
+    if (!info.update_available) return;
+
+    updateBanner.textContent = `新しいバージョン ${info.latest} があります（現在 v${info.version}）: `;
+    const link = document.createElement("a");
+    link.href = info.release_url;
+    link.target = "_blank";
+    link.textContent = "リリースページを開く";
+    updateBanner.appendChild(link);
+    updateBanner.hidden = false;
+  } catch (err) {
+    console.warn("Failed to check for updates:", err);
+  }
+}
+
+checkForUpdate();
+
+// AviUtl2 で新しいフレームが書き出されたら、このタブで読み込み直す
+// （プラグインはこの確認が届いている間は新しいブラウザウィンドウを開かない）
+async function pollFrameVersion() {
+  if (frameSocketOpen) {
+    setTimeout(pollFrameVersion, FRAME_POLL_INTERVAL);
+    return;
+  }
+  try {
+    const res = await fetch(AVIUTL2_FRAME_VERSION_URL, { cache: "no-store" });
+    if (res.ok) {
+      const info = await res.json();
+      if (info.etag && info.etag !== knownFrameEtag) {
+        const isFirstCheck = knownFrameEtag === null;
+        knownFrameEtag = info.etag;
+        if (!isFirstCheck && !isEncoding && !isDecoding) {
+          await loadFromAviUtl2();
+        }
+      }
+    }
+  } catch (err) {
+    console.warn("Failed to check for a new frame:", err);
+  } finally {
+    setTimeout(pollFrameVersion, FRAME_POLL_INTERVAL);
+  }
+}
+
+// プラグインから新しいフレームの通知を受け取る（切れたらポーリングに戻り、しばらくして再接続する）
+function connectFrameSocket() {
+  const socket = new WebSocket(AVIUTL2_FRAME_SOCKET_URL);
+  socket.addEventListener("open", () => {
+    frameSocketOpen = true;
+  });
+  socket.addEventListener("message", async (e) => {
+    const info = JSON.parse(e.data);
+    if (info.type !== "frame" || info.etag === knownFrameEtag) return;
+    knownFrameEtag = info.etag;
+    if (!isEncoding && !isDecoding) {
+      await loadFromAviUtl2();
+    }
+  });
+  socket.addEventListener("close", () => {
+    frameSocketOpen = false;
+    setTimeout(connectFrameSocket, FRAME_POLL_INTERVAL * 5);
+  });
+}
+
+// プラグインがネイティブ推論に対応していれば、モデルの選択肢に加える
+async function addNativeModelOption() {
+  if (!modelSelect) return false;
+  try {
+    const res = await fetch(NATIVE_SAM_STATUS_URL, { cache: "no-store" });
+    if (!res.ok) return false;
+    const info = await res.json();
+    if (!info.available) return false;
+    const option = document.createElement("option");
+    option.value = NATIVE_MODEL_KEY;
+    option.textContent = "ネイティブ (ONNX Runtime, WebGPU 不要)";
+    modelSelect.appendChild(option);
+    return true;
+  } catch (err) {
+    console.warn("Failed to check native SAM:", err);
+    return false;
+  }
+}
+
+const nativeAvailable = await addNativeModelOption();
 statusLabel.textContent = "Loading model...";
-await loadCurrentModelIfNeeded();
-statusLabel.textContent = "Ready";
+try {
+  await loadCurrentModelIfNeeded();
+  statusLabel.textContent = "Ready";
+} catch (err) {
+  // WebGPU が使えないブラウザでは、使えるならネイティブ推論に切り替える
+  console.error("Failed to load model:", err);
+  if (nativeAvailable) {
+    modelSelect.value = NATIVE_MODEL_KEY;
+    await loadCurrentModelIfNeeded();
+    statusLabel.textContent = "WebGPU unavailable; using native SAM";
+  } else {
+    statusLabel.textContent = "Failed to load model";
+  }
+}
 
 // Enable the user interface
 fileUpload.disabled = false;
 uploadButton.style.opacity = 1;
 example.style.pointerEvents = "auto";
 loadFromAviUtl2Button.disabled = false;
+pollFrameVersion();
+connectFrameSocket();